@@ -0,0 +1,165 @@
+use crate::coalesce::Fnv1a;
+use std::{collections::HashMap, path::Path};
+
+/// The API keys accepted by the server, each with a label that is logged instead of the key, and optionally a collection scope.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ApiKeys {
+    // key -> label and scope
+    keys: HashMap<String, (String, Option<CollectionScope>)>,
+}
+impl ApiKeys {
+    /// Load the keys from the file of `--api-key-file`.
+    ///
+    /// Each line holds a key, optionally followed by whitespace and a label, for example, `sk-3f9a team-search`, and optionally by a collection scope as the last word, for example, `sk-3f9a team-search collections=docs,faq`. The keys without a label are labeled by their line number. Blank lines and lines starting with `#` are skipped.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Failed to read the API key file `{}`. {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let mut api_keys = Self::default();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, rest) = match line.split_once(char::is_whitespace) {
+                Some((key, rest)) => (key, rest.trim()),
+                None => (line, ""),
+            };
+
+            let (label, scope) = match rest.rsplit_once(char::is_whitespace).unwrap_or(("", rest)) {
+                (label, last) if last.starts_with(SCOPE_PREFIX) => {
+                    let scope = CollectionScope::parse(&last[SCOPE_PREFIX.len()..]).ok_or_else(|| {
+                        format!(
+                            "Invalid API key file `{}`. The collection scope at line {} names no collection.",
+                            path.display(),
+                            idx + 1
+                        )
+                    })?;
+                    (label.trim(), Some(scope))
+                }
+                _ => (rest, None),
+            };
+            let label = match label.is_empty() {
+                true => format!("line {}", idx + 1),
+                false => label.to_string(),
+            };
+
+            // the key is not part of the message, since the error is logged
+            if !api_keys.insert_scoped(key, label, scope) {
+                return Err(format!(
+                    "Invalid API key file `{}`. The key at line {} is a duplicate.",
+                    path.display(),
+                    idx + 1
+                ));
+            }
+        }
+
+        if api_keys.is_empty() {
+            return Err(format!(
+                "Invalid API key file `{}`. The file holds no key.",
+                path.display()
+            ));
+        }
+
+        Ok(api_keys)
+    }
+
+    /// Add a key with its label. Returns `false` if the key is already present.
+    pub(crate) fn insert(&mut self, key: impl Into<String>, label: impl Into<String>) -> bool {
+        self.insert_scoped(key, label, None)
+    }
+
+    /// Add a key with its label and collection scope. Returns `false` if the key is already present.
+    fn insert_scoped(
+        &mut self,
+        key: impl Into<String>,
+        label: impl Into<String>,
+        scope: Option<CollectionScope>,
+    ) -> bool {
+        let key = key.into();
+        if self.keys.contains_key(&key) {
+            return false;
+        }
+
+        self.keys.insert(key, (label.into(), scope));
+        true
+    }
+
+    /// The label of the key, or `None` if the key is not accepted.
+    pub(crate) fn label_of(&self, key: &str) -> Option<&str> {
+        self.keys.get(key).map(|(label, _)| label.as_str())
+    }
+
+    /// The collection scope of the key, or `None` if the key is unscoped or not accepted.
+    pub(crate) fn scope_of(&self, key: &str) -> Option<&CollectionScope> {
+        self.keys.get(key).and_then(|(_, scope)| scope.as_ref())
+    }
+
+    /// The number of the keys with a collection scope.
+    pub(crate) fn scoped_len(&self) -> usize {
+        self.keys
+            .values()
+            .filter(|(_, scope)| scope.is_some())
+            .count()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// The prefix of the collection scope of a key in the API key file
+const SCOPE_PREFIX: &str = "collections=";
+
+/// The collections that the requests with a scoped API key may target. It is set in the extensions of the requests by `handle_request`.
+#[derive(Debug, Clone)]
+pub(crate) struct CollectionScope(Vec<String>);
+impl CollectionScope {
+    /// Parse the comma-separated collection names. Returns `None` if no name is given.
+    fn parse(s: &str) -> Option<Self> {
+        let collections: Vec<String> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect();
+
+        (!collections.is_empty()).then_some(Self(collections))
+    }
+
+    /// Whether the collection is in the scope.
+    pub(crate) fn allows(&self, collection: &str) -> bool {
+        self.0.iter().any(|name| name == collection)
+    }
+}
+impl std::fmt::Display for CollectionScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0.join(","))
+    }
+}
+
+/// A fingerprint of the API key that can be logged to correlate the requests without exposing the key, for example, `sk-3…9f01c2ab`.
+///
+/// It is made of the first 4 characters of the key, which are omitted for keys of at most 8 characters, and the upper 32 bits of the FNV-1a hash of the key. The hash is not cryptographic, so the fingerprint identifies a key among the keys seen by the server, but doesn't prevent guessing a short key offline.
+pub(crate) fn fingerprint(key: &str) -> String {
+    let mut hash = Fnv1a::new();
+    hash.write(key.as_bytes());
+    let hash = hash.finish() >> 32;
+
+    match key.chars().count() > 8 {
+        true => format!("{}…{:08x}", key.chars().take(4).collect::<String>(), hash),
+        false => format!("…{:08x}", hash),
+    }
+}
@@ -1,21 +1,58 @@
 use crate::{
-    error, utils::gen_chat_id, QdrantConfig, CONTEXT_WINDOW, GLOBAL_RAG_PROMPT, KW_SEARCH_CONFIG,
-    SERVER_INFO,
+    auth::CollectionScope,
+    chunking::{chunk_text, ChunkStrategy},
+    code_preprocess::{preprocess_code, CodeLanguage},
+    error,
+    ingestion::ingestion_stats,
+    load::{InFlight, Load, RequestPermit},
+    logger,
+    output_filter::{apply_output_filters, filter_chat_stream, find_stop, stop_chat_stream},
+    qdrant, rerank, routing,
+    tool_args::{check_tools_tokens, validate_tool_call},
+    utils::{
+        cl100k_tokenizer, gen_chat_id, normalize_unicode, redact_url,
+        template_supports_tool_message, truncate_query, url_allowed, ContextFormat,
+        EmbeddingPrecision, RagContextMessage, RetrievalTimeoutAction,
+    },
+    QdrantConfig, RecencyConfig, RerankConfig, AUTO_COLLECTION_ROUTING, CHUNK_CAPACITY,
+    CODE_PREPROCESS, CONFIDENCE_CONFIG, CONTEXT_FORMAT, CONTEXT_WINDOW, EMBEDDINGS_PARTIAL_FAILURE,
+    EMBEDDING_DIMENSIONS, EMBEDDING_PRECISION, EMBEDDING_PREFIXES, EMBEDDING_PROJECTION,
+    EMBEDDING_ROUTES, GLOBAL_RAG_PROMPT, HYBRID_FUSION_K, IGNORE_NONTEXT_PARTS,
+    INGESTION_CHUNK_RETRIES, KW_FALLBACK, KW_SEARCH_CONFIG, KW_SEARCH_LIMIT,
+    KW_SEARCH_URL_ALLOWLIST, LLAMA_API_KEYS, MAX_EMBEDDING_BATCH, MAX_QUERY_TOKENS,
+    MAX_TOOLS_TOKENS, MULTIVECTOR_POOLING, MULTI_QUERY, OUTPUT_FILTERS, RECENCY_CONFIG,
+    RERANK_CONFIG, RETRIEVAL_BREAKDOWN, RETRIEVAL_ONLY, RETRIEVAL_TIMEOUT, RETRIEVAL_TIME_BUDGET,
+    RETRIEVE_FOR_SYSTEM, SERVER_INFO, STREAM_KEEPALIVE, STREAM_RETRIEVAL_PROGRESS,
+    SYSTEM_FINGERPRINT, TIMING_TRAILERS, TURN_DECAY, UNICODE_NORMALIZATION, VALIDATE_TOOL_ARGS,
+    WARN_ON_EMPTY_COLLECTIONS,
+};
+use chat_prompts::{
+    chat::{BuildChatPrompt, ChatPrompt},
+    error as ChatPromptsError, MergeRagContext, MergeRagContextPolicy, PromptTemplateType,
 };
-use chat_prompts::{error as ChatPromptsError, MergeRagContext, MergeRagContextPolicy};
 use endpoints::{
-    chat::{ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent},
-    embeddings::{ChunksRequest, ChunksResponse, EmbeddingRequest, InputText},
+    chat::{
+        ChatCompletionObject, ChatCompletionRequest, ChatCompletionRequestBuilder,
+        ChatCompletionRequestMessage, ChatCompletionUserMessageContent, Tool,
+    },
+    common::FinishReason,
+    embeddings::{
+        ChunksRequest, ChunksResponse, EmbeddingObject, EmbeddingRequest, EmbeddingsResponse,
+        InputText,
+    },
     files::{DeleteFileStatus, FileObject},
-    keyword_search::{DocumentInput, IndexRequest, IndexResponse, QueryRequest, QueryResponse},
+    keyword_search::{
+        DocumentInput, IndexRequest, IndexResponse, QueryRequest, QueryResponse, SearchHit,
+    },
     rag::{CreateRagResponse, RagScoredPoint, RetrieveObject},
 };
-use futures_util::TryStreamExt;
-use hyper::{body::to_bytes, Body, Method, Request, Response};
-use llama_core::{
-    embeddings::{chunk_text, embeddings},
-    rag::{rag_doc_chunks_to_embeddings, rag_query_to_embeddings, rag_retrieve_context},
+use futures::channel::mpsc;
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use hyper::{
+    body::{to_bytes, Bytes},
+    Body, Method, Request, Response,
 };
+use llama_core::error::LlamaCoreError;
 use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
 use multipart_2021 as multipart;
 use std::{
@@ -24,10 +61,11 @@ use std::{
     hash::{Hash, Hasher},
     io::{Cursor, Read, Write},
     path::Path,
-    time::SystemTime,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime},
 };
 
-/// List all models available.
+/// List all models available: the chat model first, then the embedding models, each with its alias and type.
 pub(crate) async fn models_handler() -> Response<Body> {
     // log
     info!(target: "stdout", "Handling the coming model list request.");
@@ -44,6 +82,46 @@ pub(crate) async fn models_handler() -> Response<Body> {
         }
     };
 
+    // list the models in the order of the server info, with their aliases and types, since the core lists them in no order
+    let list_models_response = match SERVER_INFO.get() {
+        Some(server_info) => {
+            let rag_config = &server_info.read().await.rag_config;
+            let data: Vec<serde_json::Value> = rag_config
+                .chat_model
+                .iter()
+                .chain(std::iter::once(&rag_config.embedding_model))
+                .chain(rag_config.extra_embedding_models.iter())
+                .filter_map(|model_config| {
+                    let model = list_models_response
+                        .data
+                        .iter()
+                        .find(|model| model.id == model_config.name)?;
+
+                    let mut value = serde_json::to_value(model).ok()?;
+                    value["alias"] = serde_json::Value::from(model_config.alias.as_str());
+                    value["type"] = serde_json::Value::from(model_config.ty.as_str());
+                    Some(value)
+                })
+                .collect();
+
+            serde_json::json!({
+                "object": "list",
+                "data": data,
+            })
+        }
+        None => match serde_json::to_value(&list_models_response) {
+            Ok(value) => value,
+            Err(e) => {
+                let err_msg = format!("Failed to serialize the model list result. Reason: {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        },
+    };
+
     // serialize response
     let s = match serde_json::to_string(&list_models_response) {
         Ok(s) => s,
@@ -144,10 +222,150 @@ pub(crate) async fn embeddings_handler(mut req: Request<Body>) -> Response<Body>
     // log user id
     info!(target: "stdout", "user: {}", &id);
 
+    // resolve the model alias to the model name
+    embedding_request.model = resolve_model_name(embedding_request.model, "embedding").await;
+    if embedding_request.model.is_none() {
+        embedding_request.model = EMBEDDING_ROUTES
+            .get()
+            .map(|routes| routes.default_model.clone());
+    }
+
+    // get the embedding precision: the `embedding_precision` field in the request prioritized EMBEDDING_PRECISION
+    let precision = match request_embedding_precision(&body_bytes) {
+        Ok(Some(precision)) => precision,
+        Ok(None) => EMBEDDING_PRECISION.get().copied().unwrap_or_default(),
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+    info!(target: "stdout", "embedding precision: {}", precision);
+
+    // whether to report the number of tokens of each input: the `usage_per_input` field in the request
+    let usage_per_input = match request_usage_per_input(&body_bytes) {
+        Ok(usage_per_input) => usage_per_input,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // normalize the input text
+    let form = UNICODE_NORMALIZATION.get().copied().unwrap_or_default();
+    embedding_request.input = match embedding_request.input {
+        InputText::String(text) => InputText::String(normalize_unicode(&text, form)),
+        InputText::ArrayOfStrings(texts) => InputText::ArrayOfStrings(
+            texts
+                .iter()
+                .map(|text| normalize_unicode(text, form))
+                .collect(),
+        ),
+        input => input,
+    };
+
+    // check if the requested embedding model is loaded
+    if let Some(model) = embedding_request.model.as_ref() {
+        let embedding_model_names = match llama_core::utils::embedding_model_names() {
+            Ok(model_names) => model_names,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        if !embedding_model_names.contains(model) {
+            let err_msg = format!(
+                "The embedding model `{}` is not loaded. Available embedding models: {}",
+                model,
+                embedding_model_names.join(", ")
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    }
+
+    // check the number of inputs and the length of each input
+    let ctx_size = match (SERVER_INFO.get(), embedding_request.model.as_ref()) {
+        (Some(server_info), Some(model)) => {
+            let server_info = server_info.read().await;
+            let rag_config = &server_info.rag_config;
+            std::iter::once(&rag_config.embedding_model)
+                .chain(rag_config.extra_embedding_models.iter())
+                .find(|model_config| &model_config.name == model)
+                .map(|model_config| model_config.ctx_size)
+        }
+        _ => None,
+    };
+    if let Err(err_msg) = check_embedding_inputs(
+        &embedding_request,
+        MAX_EMBEDDING_BATCH.get().copied(),
+        ctx_size,
+    ) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::bad_request(err_msg);
+    }
+
+    // stream the embeddings in NDJSON if requested
+    let ndjson = req
+        .headers()
+        .get(hyper::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("application/x-ndjson"))
+        .unwrap_or(false);
+    if ndjson {
+        // log
+        info!(target: "stdout", "Stream the embeddings in NDJSON");
+
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/x-ndjson")
+            .header("user", id)
+            .body(Body::wrap_stream(embeddings_ndjson_stream(
+                embedding_request,
+                precision,
+            )));
+
+        return match result {
+            Ok(response) => response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        };
+    }
+
+    // the inputs are embedded one by one anyway, so counting the tokens of each input costs no extra computation
+    if usage_per_input {
+        let res = embeddings_one_by_one_response(&embedding_request, precision, id, true).await;
+
+        info!(target: "stdout", "Send the embeddings response");
+
+        return res;
+    }
+
     let res = match embeddings(&embedding_request).await {
         Ok(embedding_response) => {
             // serialize embedding object
-            match serde_json::to_string(&embedding_response) {
+            match serialize_embeddings_response(&embedding_response, precision) {
                 Ok(s) => {
                     // return response
                     let result = Response::builder()
@@ -185,7 +403,13 @@ pub(crate) async fn embeddings_handler(mut req: Request<Body>) -> Response<Body>
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            let batch = split_embedding_inputs(&embedding_request.input).len() > 1;
+            match EMBEDDINGS_PARTIAL_FAILURE.get().copied().unwrap_or(false) && batch {
+                true => {
+                    embeddings_one_by_one_response(&embedding_request, precision, id, false).await
+                }
+                false => error::internal_server_error(err_msg),
+            }
         }
     };
 
@@ -194,41 +418,181 @@ pub(crate) async fn embeddings_handler(mut req: Request<Body>) -> Response<Body>
     res
 }
 
-/// Query a user input and return a chat-completion response with the answer from the model.
+/// Compute the embeddings of the inputs one by one, and yield each embedding as a line of NDJSON once it is computed.
 ///
-/// Note that the body of the request is deserialized to a `ChatCompletionRequest` instance.
-pub(crate) async fn rag_query_handler(mut req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming rag query request");
+/// A failed input yields an error line, `{"index": 0, "object": "error", "error": "..."}`, and the remaining inputs are still computed.
+fn embeddings_ndjson_stream(
+    embedding_request: EmbeddingRequest,
+    precision: EmbeddingPrecision,
+) -> impl Stream<Item = Result<String, String>> {
+    let inputs = split_embedding_inputs(&embedding_request.input);
+
+    stream::iter(inputs.into_iter().enumerate()).then(move |(index, input)| {
+        let mut request = embedding_request.clone();
+        request.input = input;
+
+        async move {
+            let line = match embeddings(&request).await {
+                Ok(response) => match response.data.into_iter().next() {
+                    Some(mut embedding_object) => {
+                        embedding_object.index = index as u64;
+                        serialize_embedding_object(&embedding_object, precision)
+                            .map_err(|e| format!("Fail to serialize embedding object. {}", e))
+                    }
+                    None => Err("No embedding is returned.".to_string()),
+                },
+                Err(e) => Err(e.to_string()),
+            };
 
-    if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
+            match line {
+                Ok(line) => Ok(format!("{}\n", line)),
+                Err(err_msg) => {
+                    // log
+                    error!(target: "stdout", "Failed to compute the embedding of the input {}. {}", index, &err_msg);
 
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
+                    Ok(format!(
+                        "{}\n",
+                        serde_json::json!({"index": index, "object": "error", "error": err_msg})
+                    ))
+                }
+            }
+        }
+    })
+}
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+/// Check that the request has at most `max_batch` inputs, and that no input is longer than the context of `ctx_size` tokens of the embedding model, which would be truncated or fail deep in the model.
+///
+/// The token arrays are checked exactly. The text inputs are counted with the `cl100k_base` tokenizer, which only approximates the tokenizer of the embedding model.
+fn check_embedding_inputs(
+    request: &EmbeddingRequest,
+    max_batch: Option<u64>,
+    ctx_size: Option<u64>,
+) -> Result<(), String> {
+    let inputs = split_embedding_inputs(&request.input);
+
+    if let Some(max_batch) = max_batch {
+        if inputs.len() as u64 > max_batch {
+            return Err(format!(
+                "The request has {} inputs, more than the limit of {} inputs per request. Split the inputs into smaller requests.",
+                inputs.len(),
+                max_batch
+            ));
+        }
+    }
 
-                return error::internal_server_error(err_msg);
-            }
+    let ctx_size = match ctx_size {
+        Some(ctx_size) => ctx_size,
+        None => return Ok(()),
+    };
+    let tokenizer = cl100k_tokenizer()
+        .map_err(|e| format!("Failed to create the tokenizer for counting tokens. {}", e))?;
+    for (index, input) in inputs.iter().enumerate() {
+        let (num_tokens, approx) = match input {
+            InputText::String(text) => (tokenizer.encode_ordinary(text).len() as u64, "about "),
+            InputText::ArrayOfTokens(tokens) => (tokens.len() as u64, ""),
+            _ => continue,
+        };
+
+        if num_tokens > ctx_size {
+            return Err(format!(
+                "The input at index {} has {}{} tokens, more than the context size of {} tokens of the embedding model `{}`. Split the input into smaller pieces.",
+                index,
+                approx,
+                num_tokens,
+                ctx_size,
+                request.model.as_deref().unwrap_or_default()
+            ));
         }
     }
 
-    info!(target: "stdout", "Prepare the chat completion request");
+    Ok(())
+}
 
-    // parse request
-    let body_bytes = match to_bytes(req.body_mut()).await {
-        Ok(body_bytes) => body_bytes,
-        Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
+/// Split the input of the embedding request into single inputs.
+fn split_embedding_inputs(input: &InputText) -> Vec<InputText> {
+    match input {
+        InputText::ArrayOfStrings(texts) => texts.iter().map(InputText::from).collect(),
+        InputText::ArrayOfTokenArrays(token_arrays) => token_arrays
+            .iter()
+            .map(|tokens| InputText::from(tokens.as_slice()))
+            .collect(),
+        input => vec![input.clone()],
+    }
+}
+
+/// Compute the embeddings of the inputs one by one, either after the batch failed with `--embeddings-partial-failure` set, or to count the tokens of each input if `usage_per_input` is `true`.
+///
+/// With `--embeddings-partial-failure` set, the successful embeddings are returned with their original indices, plus an `errors` array with the index and the reason of each failed input, and `500 Internal Server Error` is returned only if all the inputs fail. Otherwise, any failed input fails the request.
+///
+/// If `usage_per_input` is `true`, the `usage` object carries an additional `prompt_tokens_per_input` array with the number of tokens of each input, `null` for the failed inputs, which sums up to `prompt_tokens`.
+async fn embeddings_one_by_one_response(
+    embedding_request: &EmbeddingRequest,
+    precision: EmbeddingPrecision,
+    id: String,
+    usage_per_input: bool,
+) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Compute the embeddings of the inputs one by one.");
+
+    let inputs = split_embedding_inputs(&embedding_request.input);
+    let num_inputs = inputs.len();
+    let partial = EMBEDDINGS_PARTIAL_FAILURE.get().copied().unwrap_or(false) && num_inputs > 1;
+
+    let mut embedding_response: Option<EmbeddingsResponse> = None;
+    let mut prompt_tokens_per_input: Vec<Option<u64>> = vec![None; num_inputs];
+    let mut errors = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let mut request = embedding_request.clone();
+        request.input = input;
+
+        match embeddings(&request).await {
+            Ok(response) => match response.data.into_iter().next() {
+                Some(mut embedding_object) => {
+                    embedding_object.index = index as u64;
+
+                    let embedding_response =
+                        embedding_response.get_or_insert_with(|| EmbeddingsResponse {
+                            object: response.object,
+                            data: Vec::new(),
+                            model: response.model,
+                            usage: Default::default(),
+                        });
+                    embedding_response.data.push(embedding_object);
+                    prompt_tokens_per_input[index] = Some(response.usage.prompt_tokens);
+                    embedding_response.usage.prompt_tokens += response.usage.prompt_tokens;
+                    embedding_response.usage.completion_tokens += response.usage.completion_tokens;
+                    embedding_response.usage.total_tokens += response.usage.total_tokens;
+                }
+                None => errors.push((index, "No embedding is returned.".to_string())),
+            },
+            Err(e) => errors.push((index, e.to_string())),
+        }
+    }
+
+    for (index, err_msg) in errors.iter() {
+        // log
+        warn!(target: "stdout", "Failed to compute the embedding of the input {}. {}", index, err_msg);
+    }
+
+    if let (false, Some((index, err_msg))) = (partial, errors.first()) {
+        let err_msg = format!(
+            "Failed to compute the embedding of the input {}. {}",
+            index, err_msg
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    let embedding_response = match embedding_response {
+        Some(embedding_response) => embedding_response,
+        None => {
+            let err_msg = format!(
+                "Failed to compute the embeddings of all the {} inputs.",
+                num_inputs
+            );
 
             // log
             error!(target: "stdout", "{}", &err_msg);
@@ -236,782 +600,3377 @@ pub(crate) async fn rag_query_handler(mut req: Request<Body>) -> Response<Body>
             return error::internal_server_error(err_msg);
         }
     };
-    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(chat_request) => chat_request,
-        Err(e) => {
-            let mut err_msg = format!("Fail to deserialize chat completion request: {}.", e);
 
-            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
+    // log
+    info!(target: "stdout", "Computed the embeddings of {} of {} inputs.", embedding_response.data.len(), num_inputs);
+
+    // serialize the embeddings response with the errors and the token usage of each input
+    let s = match serialize_embeddings_response(&embedding_response, precision)
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s))
+        .and_then(|mut value| {
+            if partial {
+                value["errors"] = errors
+                    .into_iter()
+                    .map(|(index, err_msg)| serde_json::json!({"index": index, "error": err_msg}))
+                    .collect();
             }
+            if usage_per_input {
+                value["usage"]["prompt_tokens_per_input"] =
+                    serde_json::json!(prompt_tokens_per_input);
+            }
+            serde_json::to_string(&value)
+        }) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Fail to serialize embedding object. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            // log body_bytes
-            error!(target: "stdout", "raw data:\n{:?}", &body_bytes.to_ascii_lowercase());
-
-            return error::bad_request(err_msg);
+            return error::internal_server_error(err_msg);
         }
     };
 
-    // check if the user id is provided
-    if chat_request.user.is_none() {
-        chat_request.user = Some(gen_chat_id())
-    };
-    let id = chat_request.user.clone().unwrap();
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .header("user", id)
+        .body(Body::from(s));
 
-    // log user id
-    info!(target: "stdout", "user: {}", &id);
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-    // perform keyword search
-    let mut kw_hits = Vec::new();
-    let mut kw_search_url = match &chat_request.kw_search_url {
-        Some(url) => url.clone(),
-        None => match KW_SEARCH_CONFIG.get() {
-            Some(kw_search_config) => kw_search_config.url.clone(),
-            None => String::new(),
-        },
-    };
-    if !kw_search_url.is_empty() {
-        kw_search_url = kw_search_url.trim_end_matches('/').to_string();
-        info!(target: "stdout", "kw_search_url: {}", &kw_search_url);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-        if let Some(index_name) = &chat_request.kw_index_name {
-            if !index_name.is_empty() {
-                if let Some(ChatCompletionRequestMessage::User(user_message)) =
-                    chat_request.messages.last()
-                {
-                    if let ChatCompletionUserMessageContent::Text(text) = user_message.content() {
-                        info!(target: "stdout", "perform keyword search on the index: {}", &index_name);
+            error::internal_server_error(err_msg)
+        }
+    }
+}
 
-                        let kw_top_k = chat_request.kw_top_k.unwrap();
+/// Read the optional `embedding_precision` field of the embedding request, which is either a string or the number of decimal places.
+fn request_embedding_precision(body_bytes: &Bytes) -> Result<Option<EmbeddingPrecision>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize embedding request: {}.", e))?;
 
-                        let user_query = text.clone();
-                        let query_request = QueryRequest {
-                            query: user_query,
-                            top_k: kw_top_k as usize,
-                            index: index_name.clone(),
-                        };
+    match value.get("embedding_precision") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(precision)) => precision.parse().map(Some),
+        Some(precision) => precision.to_string().parse().map(Some),
+    }
+}
 
-                        let query_url = format!("{}/v1/search", &kw_search_url);
-                        info!(target: "stdout", "query_url: {}", &query_url);
+/// Read the optional `return_sources` field of the chat completion request.
+fn request_return_sources(body_bytes: &Bytes) -> Result<bool, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("return_sources") {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(return_sources)) => Ok(*return_sources),
+        Some(return_sources) => Err(format!(
+            "Invalid return_sources: {}. The value should be a boolean.",
+            return_sources
+        )),
+    }
+}
 
-                        // send query request to the keyword search service
-                        match reqwest::Client::new()
-                            .post(&query_url)
-                            .json(&query_request)
-                            .send()
-                            .await
-                        {
-                            Ok(response) => {
-                                match response.json::<QueryResponse>().await {
-                                    Ok(query_response) => {
-                                        match query_response.error {
-                                            Some(error) => {
-                                                let err_msg = format!(
-                                                    "Failed to perform keyword search. Reason: {}",
-                                                    error
-                                                );
-
-                                                // log
-                                                warn!(target: "stdout", "{}", &err_msg);
-                                            }
-                                            None => {
-                                                info!(target: "stdout", "Number of keyword search hits: {}", &query_response.hits.len());
-
-                                                kw_hits = query_response.hits;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let err_msg = format!(
-                                            "Failed to perform keyword search. Reason: {}",
-                                            e
-                                        );
+/// Read the optional `retrieve_only` field of the chat completion request.
+fn request_retrieve_only(body_bytes: &Bytes) -> Result<bool, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("retrieve_only") {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(retrieve_only)) => Ok(*retrieve_only),
+        Some(retrieve_only) => Err(format!(
+            "Invalid retrieve_only: {}. The value should be a boolean.",
+            retrieve_only
+        )),
+    }
+}
 
-                                        // log
-                                        warn!(target: "stdout", "{}", &err_msg);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                let err_msg =
-                                    format!("Failed to perform keyword search. Reason: {}", e);
+/// Read the optional `usage_per_input` field of the embedding request.
+fn request_usage_per_input(body_bytes: &Bytes) -> Result<bool, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize embedding request: {}.", e))?;
 
-                                // log
-                                warn!(target: "stdout", "{}", &err_msg);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    match value.get("usage_per_input") {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(usage_per_input)) => Ok(*usage_per_input),
+        Some(_) => Err("The `usage_per_input` field should be a boolean.".to_string()),
     }
+}
 
-    // qdrant config
-    let qdrant_config_vec = match get_qdrant_configs(&chat_request).await {
-        Ok(qdrant_config_vec) => qdrant_config_vec,
-        Err(e) => return error::internal_server_error(e.to_string()),
-    };
+/// Serialize the embeddings response with the embedding vectors in the given precision.
+fn serialize_embeddings_response(
+    embedding_response: &EmbeddingsResponse,
+    precision: EmbeddingPrecision,
+) -> Result<String, serde_json::Error> {
+    if precision == EmbeddingPrecision::Float32 {
+        return serde_json::to_string(embedding_response);
+    }
 
-    // retrieve context
-    let mut retrieve_object_vec = match retrieve_context_with_multiple_qdrant_configs(
-        &chat_request,
-        &qdrant_config_vec,
-    )
-    .await
-    {
-        Ok(retrieve_object_vec) => retrieve_object_vec,
-        Err(response) => {
-            return response;
+    let mut value = serde_json::to_value(embedding_response)?;
+    if let Some(data) = value["data"].as_array_mut() {
+        for (embedding_object, item) in embedding_response.data.iter().zip(data.iter_mut()) {
+            item["embedding"] = precision.encode(&embedding_object.embedding);
         }
-    };
+    }
 
-    // log retrieve object
-    debug!(target: "stdout", "retrieve_object_vec:\n{}", serde_json::to_string_pretty(&retrieve_object_vec).unwrap());
+    serde_json::to_string(&value)
+}
 
-    // fuse kw-search and embedding-search results
-    if !kw_hits.is_empty()
-        && !retrieve_object_vec.is_empty()
-        && retrieve_object_vec[0].points.is_some()
-    {
-        let points = retrieve_object_vec[0].points.as_ref().unwrap().clone();
-        if !points.is_empty() {
-            let limit = retrieve_object_vec[0].limit;
-            let score_threshold = retrieve_object_vec[0].score_threshold;
-
-            // create a hash map from retrieve_object_vec: key is the hash value of the source of the point, value is the point
-            let mut em_hits_map = HashMap::new();
-            let mut em_scores = HashMap::new();
-
-            for point in points {
-                let hash_value = calculate_hash(&point.source);
-                em_scores.insert(hash_value, point.score);
-                em_hits_map.insert(hash_value, point);
-            }
-
-            info!(target: "stdout", "em_hits_map: {:#?}", &em_hits_map);
-
-            // normalize the em_scores
-            let em_scores = normalize(&em_scores);
-
-            info!(target: "stdout", "em_scores: {:#?}", &em_scores);
-
-            // create a hash map from kw_hits: key is the hash value of the content of the hit, value is the hit
-            let mut kw_hits_map = HashMap::new();
-            let mut kw_scores = HashMap::new();
-            for hit in kw_hits {
-                let hash_value = calculate_hash(&hit.content);
-                kw_scores.insert(hash_value, hit.score);
-                kw_hits_map.insert(hash_value, hit);
-            }
+/// Serialize the embedding object with the embedding vector in the given precision.
+fn serialize_embedding_object(
+    embedding_object: &EmbeddingObject,
+    precision: EmbeddingPrecision,
+) -> Result<String, serde_json::Error> {
+    let mut value = serde_json::to_value(embedding_object)?;
+    value["embedding"] = precision.encode(&embedding_object.embedding);
 
-            info!(target: "stdout", "kw_hits_map: {:#?}", &kw_hits_map);
+    serde_json::to_string(&value)
+}
 
-            // normalize the kw_scores
-            let kw_scores = normalize(&kw_scores);
+/// Query a user input and return a chat-completion response with the answer from the model.
+///
+/// If `--stream-retrieval-progress` is set and the request is in stream mode, the response is started as soon as the first search completes, and the `retrieval_progress` events are sent before the completion chunks. If `--stream-keepalive-ms` is set, the response is started at the first keep-alive comment instead, if it comes first, and the comments are sent until the completion starts. If the request fails before any event, the error response is returned as is.
+pub(crate) async fn rag_query_handler(req: Request<Body>) -> Response<Body> {
+    let stream_progress = STREAM_RETRIEVAL_PROGRESS.get().copied().unwrap_or(false);
+    let keepalive = STREAM_KEEPALIVE.get().copied();
+    if (!stream_progress && keepalive.is_none()) || req.method() != Method::POST {
+        return rag_query(req, None).await;
+    }
 
-            info!(target: "stdout", "kw_scores: {:#?}", &kw_scores);
+    // read the body to check if the request is in stream mode
+    let (parts, body) = req.into_parts();
+    let body_bytes = match to_bytes(body).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
 
-            // Set weight alpha
-            let alpha = 0.7;
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-            // fuse the two hash maps
-            let final_scores = weighted_fusion(kw_scores, em_scores, alpha);
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let stream_mode = serde_json::from_slice::<serde_json::Value>(&body_bytes)
+        .ok()
+        .and_then(|value| value.get("stream").and_then(serde_json::Value::as_bool))
+        .unwrap_or(false);
+    let req = Request::from_parts(parts, Body::from(body_bytes));
+    if !stream_mode {
+        return rag_query(req, None).await;
+    }
 
-            info!(target: "stdout", "final_scores: {:#?}", &final_scores);
+    let (sender, mut receiver) = mpsc::unbounded();
+    let progress = stream_progress.then(|| RetrievalProgress {
+        sender: sender.clone(),
+    });
+    let mut handle = tokio::spawn(logger::in_request_scope(async move {
+        let query = rag_query(req, progress);
+        match keepalive {
+            Some(interval) => with_keepalive(query, interval, sender).await,
+            None => query.await,
+        }
+    }));
 
-            // Sort by score from high to low
-            let mut final_ranking: Vec<(u64, f32)> = final_scores.into_iter().collect();
-            final_ranking.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    // wait for the first progress event or the response, whichever comes first
+    let first_event = tokio::select! {
+        event = receiver.next() => event,
+        result = &mut handle => return joined_response(result),
+    };
+    let first_event = match first_event {
+        Some(first_event) => first_event,
+        None => return joined_response(handle.await),
+    };
 
-            // Print final ranking
-            info!(target: "stdout", "final_ranking: {:#?}", &final_ranking);
+    // the progress events and the keep-alive comments end when the retrieval is done, and are followed by the completion chunks
+    let completion = stream::once(handle).flat_map(|result| match joined_response(result) {
+        response if response.status().is_success() => {
+            response.into_body().map_err(|e| e.to_string()).boxed()
+        }
+        response => stream::once(async move {
+            let status = response.status().as_u16();
+            let message = to_bytes(response.into_body())
+                .await
+                .map(|body| String::from_utf8_lossy(&body).to_string())
+                .unwrap_or_default();
 
-            let mut retrieved = Vec::new();
-            for (hash_value, score) in final_ranking {
-                if score >= score_threshold {
-                    let mut doc = RagScoredPoint {
-                        source: String::new(),
-                        score,
-                    };
-                    if kw_hits_map.contains_key(&hash_value) {
-                        doc.source = kw_hits_map[&hash_value].content.clone();
-                        retrieved.push(doc);
-                    } else if em_hits_map.contains_key(&hash_value) {
-                        doc.source = em_hits_map[&hash_value].source.clone();
-                        retrieved.push(doc);
-                    }
-                }
-            }
+            Ok(Bytes::from(format!(
+                "event: error\ndata: {}\n\n",
+                serde_json::json!({ "status": status, "message": message })
+            )))
+        })
+        .boxed(),
+    });
+    let events = stream::once(async { first_event })
+        .chain(receiver)
+        .map(|event| Ok::<_, String>(Bytes::from(event)));
 
-            if retrieved.len() > limit {
-                retrieved.truncate(limit);
-            }
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Connection", "keep-alive")
+        .body(Body::wrap_stream(events.chain(completion)));
 
-            info!(target: "stdout", "retrieved: {:#?}", &retrieved);
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-            let retrieve_object = RetrieveObject {
-                limit,
-                score_threshold,
-                points: Some(retrieved),
-            };
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-            retrieve_object_vec = vec![retrieve_object];
+            error::internal_server_error(err_msg)
         }
     }
+}
 
-    // * extract the context from retrieved objects
-    let mut context = String::new();
-    for (idx, retrieve_object) in retrieve_object_vec.iter().enumerate() {
-        match retrieve_object.points.as_ref() {
-            Some(scored_points) => {
-                match scored_points.is_empty() {
-                    false => {
-                        for (idx, point) in scored_points.iter().enumerate() {
-                            // log
-                            info!(target: "stdout", "point: {}, score: {}, source: {}", idx, point.score, &point.source);
-
-                            context.push_str(&point.source);
-                            context.push_str("\n\n");
-                        }
-                    }
-                    true => {
-                        // log
-                        warn!(target: "stdout", "{}", format!("No point retrieved from the collection `{}` (score < threshold {})", qdrant_config_vec[idx].collection_name, qdrant_config_vec[idx].score_threshold));
-                    }
-                }
-            }
-            None => {
-                // log
-                warn!(target: "stdout", "{}", format!("No point retrieved from the collection `{}` (score < threshold {})", qdrant_config_vec[idx].collection_name, qdrant_config_vec[idx].score_threshold));
+/// Send a `: keep-alive` SSE comment to `sender` at each `interval` until the future completes.
+///
+/// The comments stop when the response of the chat request is ready. The prompt processing runs as the first completion chunk is polled, and blocks the thread of the server, so it can't be covered by the comments.
+async fn with_keepalive<F: std::future::Future>(
+    future: F,
+    interval: Duration,
+    sender: mpsc::UnboundedSender<String>,
+) -> F::Output {
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            output = &mut future => return output,
+            _ = tokio::time::sleep(interval) => {
+                // the receiver is gone if the client closed the connection
+                let _ = sender.unbounded_send(": keep-alive\n\n".to_string());
             }
         }
     }
+}
 
-    // * update messages with retrieved context
-    if !context.is_empty() {
-        if chat_request.messages.is_empty() {
-            let err_msg = "No message in the chat request.";
+/// The response of the spawned chat request, or `500 Internal Server Error` if the task failed.
+fn joined_response(result: Result<Response<Body>, tokio::task::JoinError>) -> Response<Body> {
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = format!("Failed to handle the chat request. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            error::internal_server_error(err_msg)
         }
+    }
+}
 
-        let prompt_template =
-            match llama_core::utils::chat_prompt_template(chat_request.model.as_deref()) {
-                Ok(prompt_template) => prompt_template,
-                Err(e) => {
-                    let err_msg = e.to_string();
+/// Query a user input and return a chat-completion response with the answer from the model. The progress of the retrieval is sent to `progress` if it is set.
+///
+/// Note that the body of the request is deserialized to a `ChatCompletionRequest` instance.
+async fn rag_query(mut req: Request<Body>, progress: Option<RetrievalProgress>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming rag query request");
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    // the collection scope of the API key of the request
+    let collection_scope = req.extensions().get::<CollectionScope>().cloned();
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+    if RETRIEVAL_ONLY.get().copied().unwrap_or(false) {
+        // log
+        warn!(target: "stdout", "The chat completions endpoint is disabled in the retrieval-only mode.");
 
-        let rag_policy = match SERVER_INFO.get() {
-            Some(server_info) => server_info.read().await.rag_config.policy,
-            None => {
-                let err_msg = "SERVER_INFO is not initialized.";
+        return error::not_implemented();
+    }
+
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
                 return error::internal_server_error(err_msg);
             }
-        };
+        }
+    }
 
-        // insert rag context into chat request
-        if let Err(e) = RagPromptBuilder::build(
-            &mut chat_request.messages,
-            &[context],
-            prompt_template.has_system_prompt(),
-            rag_policy,
-        ) {
-            let err_msg = e.to_string();
+    info!(target: "stdout", "Prepare the chat completion request");
+
+    // count the chat completion as in flight until the response, or the stream, is sent
+    let in_flight = InFlight::new(Load::ChatCompletion);
+
+    // the slot of `--max-concurrent-requests`, held until the response, or the stream, is sent
+    let request_permit = req.extensions().get::<RequestPermit>().cloned();
+
+    // the timing of the request is sent as trailers if the client accepts them over HTTP/2
+    let request_start = Instant::now();
+    let timing = TIMING_TRAILERS.get().copied().unwrap_or(false);
+    let trailers_accepted = timing && accepts_trailers(&req);
+
+    // parse request
+    let body_bytes = match to_bytes(req.body_mut()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
             return error::internal_server_error(err_msg);
         }
-    }
-
-    // * perform chat completion
-    let res = match llama_core::chat::chat(&mut chat_request).await {
-        Ok(result) => match result {
-            either::Left(stream) => {
-                let stream = stream.map_err(|e| e.to_string());
-
-                let result = Response::builder()
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Access-Control-Allow-Methods", "*")
-                    .header("Access-Control-Allow-Headers", "*")
-                    .header("Content-Type", "text/event-stream")
-                    .header("Cache-Control", "no-cache")
-                    .header("Connection", "keep-alive")
-                    .header("user", id)
-                    .body(Body::wrap_stream(stream));
+    };
 
-                match result {
-                    Ok(response) => {
-                        // log
-                        info!(target: "stdout", "finish chat completions in stream mode");
+    // check the non-text content parts in the messages
+    let body_bytes = match check_nontext_parts(body_bytes) {
+        Ok(body_bytes) => body_bytes,
+        Err(err_msg) => return error::bad_request(err_msg),
+    };
 
-                        response
-                    }
-                    Err(e) => {
-                        let err_msg =
-                            format!("Failed chat completions in stream mode. Reason: {}", e);
+    // take the score threshold override in the request
+    let (body_bytes, score_threshold_override) = match take_score_threshold_override(body_bytes) {
+        Ok(result) => result,
+        Err(err_msg) => return error::bad_request(err_msg),
+    };
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(chat_request) => chat_request,
+        Err(e) => {
+            let mut err_msg = format!("Fail to deserialize chat completion request: {}.", e);
 
-                        error::internal_server_error(err_msg)
-                    }
-                }
+            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
             }
-            either::Right(chat_completion_object) => {
-                // serialize chat completion object
-                let s = match serde_json::to_string(&chat_completion_object) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        let err_msg = format!("Failed to serialize chat completion object. {}", e);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+            // log body_bytes
+            error!(target: "stdout", "raw data:\n{:?}", &body_bytes.to_ascii_lowercase());
 
-                // return response
-                let result = Response::builder()
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Access-Control-Allow-Methods", "*")
-                    .header("Access-Control-Allow-Headers", "*")
-                    .header("Content-Type", "application/json")
-                    .header("user", id)
-                    .body(Body::from(s));
+            return error::bad_request(err_msg);
+        }
+    };
 
-                match result {
-                    Ok(response) => {
-                        // log
-                        info!(target: "stdout", "Finish chat completions in non-stream mode");
+    // check if the messages contain a user message
+    if let Err(err_msg) = check_user_message(&chat_request.messages) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                        response
-                    }
-                    Err(e) => {
-                        let err_msg =
-                            format!("Failed chat completions in non-stream mode. Reason: {}", e);
+        return error::unprocessable_entity(err_msg);
+    }
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    // check the stop sequences of the request, which end the completion in addition to the reverse prompt
+    let stop = match check_stop_sequences(chat_request.stop.as_deref()) {
+        Ok(stop) => stop,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                        error::internal_server_error(err_msg)
-                    }
-                }
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // check if the user id is provided
+    if chat_request.user.is_none() {
+        chat_request.user = Some(gen_chat_id())
+    };
+    let id = chat_request.user.clone().unwrap();
+
+    // log user id
+    info!(target: "stdout", "user: {}", &id);
+
+    // resolve the model alias to the model name
+    chat_request.model = resolve_model_name(chat_request.model, "chat").await;
+
+    // warn if the requested model is unknown, since the default chat model serves the request with its own prompt template
+    if let Some(model) = chat_request.model.as_deref() {
+        warn_unknown_chat_model(model);
+    }
+
+    // check the token cost of the tool definitions
+    if let (Some(max_tools_tokens), Some(tools)) = (MAX_TOOLS_TOKENS.get(), &chat_request.tools) {
+        if !tools.is_empty() {
+            let ctx_size = match SERVER_INFO.get() {
+                Some(server_info) => server_info
+                    .read()
+                    .await
+                    .rag_config
+                    .chat_model
+                    .as_ref()
+                    .map(|chat_model| chat_model.ctx_size)
+                    .unwrap_or(u64::MAX),
+                None => u64::MAX,
+            };
+
+            if let Err(err_msg) =
+                check_tools_tokens(tools, &chat_request.messages, *max_tools_tokens, ctx_size)
+            {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::unprocessable_entity(err_msg);
             }
-        },
-        Err(e) => {
-            let err_msg = format!("Failed to get chat completions. Reason: {}", e);
+        }
+    }
 
+    // get the context format: the `context_format` field in the request prioritized CONTEXT_FORMAT
+    let context_format = match request_context_format(&body_bytes) {
+        Ok(Some(context_format)) => context_format,
+        Ok(None) => CONTEXT_FORMAT.get().copied().unwrap_or_default(),
+        Err(err_msg) => {
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            return error::bad_request(err_msg);
         }
     };
+    info!(target: "stdout", "context format: {}", context_format);
 
-    // log
-    info!(target: "stdout", "Send the rag query response");
-
-    res
-}
-
-async fn retrieve_context_with_single_qdrant_config(
-    chat_request: &ChatCompletionRequest,
-    qdrant_config: &QdrantConfig,
-) -> Result<RetrieveObject, Response<Body>> {
-    info!(target: "stdout", "Compute embeddings for user query.");
-
-    // get context_window: chat_request.context_window prioritized CONTEXT_WINDOW
-    let context_window = chat_request
-        .context_window
-        .or_else(|| CONTEXT_WINDOW.get().copied())
-        .unwrap_or(1);
-    info!(target: "stdout", "context window: {}", context_window);
+    // whether to return the retrieved sources: the `return_sources` field in the request
+    let return_sources = match request_return_sources(&body_bytes) {
+        Ok(return_sources) => return_sources,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-    info!(target: "stdout", "VectorDB config: {}", qdrant_config);
+            return error::bad_request(err_msg);
+        }
+    };
 
-    // compute embeddings for user query
-    let embedding_response = match chat_request.messages.is_empty() {
-        true => {
-            let err_msg = "Messages should not be empty.";
+    // whether to stream the retrieved sources without generation: the `retrieve_only` field in the request
+    let retrieve_only = match request_retrieve_only(&body_bytes) {
+        Ok(true) if !chat_request.stream.unwrap_or(false) => {
+            let err_msg = "The `retrieve_only` field requires `stream` to be `true`. Use `/v1/retrieve` to get the retrieval results in a single response.";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return Err(error::bad_request(err_msg));
+            return error::bad_request(err_msg);
         }
-        false => {
-            // get the last `n` user messages in the context window.
-            // `n` is determined by the `context_window` in the chat request.
-            let mut last_n_user_messages = Vec::new();
-            for (idx, message) in chat_request.messages.iter().rev().enumerate() {
-                if let ChatCompletionRequestMessage::User(user_message) = message {
-                    if let ChatCompletionUserMessageContent::Text(text) = user_message.content() {
-                        if !text.ends_with("<server-health>") {
-                            last_n_user_messages.push(text.clone());
-                        } else if idx == 0 {
-                            let content = text.trim_end_matches("<server-health>").to_string();
-                            last_n_user_messages.push(content);
-                            break;
-                        }
-                    }
-                }
-
-                if last_n_user_messages.len() == context_window as usize {
-                    break;
-                }
-            }
+        Ok(retrieve_only) => retrieve_only,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-            // join the user messages in the context window into a single string
-            let query_text = if !last_n_user_messages.is_empty() {
-                info!(target: "stdout", "Found the latest {} user messages", last_n_user_messages.len());
+            return error::bad_request(err_msg);
+        }
+    };
 
-                last_n_user_messages.reverse();
-                last_n_user_messages.join("\n")
-            } else {
-                let warn_msg = "No user messages found.";
+    // the deadline of the retrieval phase, including keyword search, embedding, vector search and fusion
+    let retrieval_start = Instant::now();
+    let retrieval_deadline = RETRIEVAL_TIME_BUDGET
+        .get()
+        .map(|budget| retrieval_start + *budget);
 
-                // log
-                warn!(target: "stdout", "{}", &warn_msg);
+    // perform keyword search
+    let mut kw_hits = match keyword_search(&chat_request, &body_bytes, retrieval_deadline).await {
+        Ok(kw_hits) => kw_hits,
+        Err(response) => return response,
+    };
 
-                return Err(error::bad_request(warn_msg));
-            };
+    // qdrant config
+    let mut qdrant_config_vec = match get_qdrant_configs(&chat_request).await {
+        Ok(qdrant_config_vec) => qdrant_config_vec,
+        Err(e) => return error::internal_server_error(e.to_string()),
+    };
 
+    // select the collections named in the `collections` field of the request
+    let selected = match request_collections(&body_bytes).and_then(|selected| {
+        select_collections(&mut qdrant_config_vec, selected, collection_scope.as_ref())
+    }) {
+        Ok(selected) => selected,
+        Err(err_msg) => {
             // log
-            info!(target: "stdout", "query text for the context retrieval: {}", query_text);
+            error!(target: "stdout", "{}", &err_msg);
 
-            // get the available embedding models
-            let embedding_model_names = match llama_core::utils::embedding_model_names() {
-                Ok(model_names) => model_names,
-                Err(e) => {
-                    let err_msg = e.to_string();
+            return error::bad_request(err_msg);
+        }
+    };
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    // check the collections against the collection scope of the API key
+    if let Err(err_msg) = check_collection_scope(
+        &mut qdrant_config_vec,
+        chat_request.vdb_collection_name.is_some() || selected,
+        collection_scope.as_ref(),
+    ) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                    return Err(error::internal_server_error(err_msg));
-                }
-            };
+        return error::forbidden(err_msg);
+    }
 
-            // get vdb_api_key if it is provided in the request, otherwise get it from the environment variable `VDB_API_KEY`
-            let vdb_api_key = chat_request
-                .vdb_api_key
-                .clone()
-                .or_else(|| std::env::var("VDB_API_KEY").ok());
+    // override the score thresholds
+    if let Some(score_threshold_override) = &score_threshold_override {
+        if let Err(err_msg) =
+            apply_score_threshold_override(&mut qdrant_config_vec, score_threshold_override).await
+        {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-            // create a embedding request
-            let embedding_request = EmbeddingRequest {
-                model: Some(embedding_model_names[0].clone()),
-                input: InputText::String(query_text),
-                encoding_format: None,
-                user: chat_request.user.clone(),
-                vdb_server_url: Some(qdrant_config.url.clone()),
-                vdb_collection_name: Some(qdrant_config.collection_name.clone()),
-                vdb_api_key,
-            };
+            return error::bad_request(err_msg);
+        }
+    }
 
-            // compute embeddings for query
-            match rag_query_to_embeddings(&embedding_request).await {
-                Ok(embedding_response) => embedding_response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    // exclude the collections named in the `exclude_collections` field of the request
+    if let Err(err_msg) = request_exclude_collections(&body_bytes)
+        .and_then(|excluded| exclude_collections(&mut qdrant_config_vec, &excluded))
+    {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+        return error::bad_request(err_msg);
+    }
 
-                    return Err(error::internal_server_error(err_msg));
-                }
-            }
-        }
-    };
-    let query_embedding: Vec<f32> = match embedding_response.data.first() {
-        Some(embedding) => embedding.embedding.iter().map(|x| *x as f32).collect(),
-        None => {
-            let err_msg = "No embeddings returned";
+    // override the limit and the score threshold by the `rag_limit` and `rag_score_threshold` fields of the request
+    if let Err(err_msg) = apply_rag_overrides(&body_bytes, &mut qdrant_config_vec) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
+        return error::bad_request(err_msg);
+    }
+
+    // override the number of reranked points kept by the `rerank_top_n` field of the request
+    let rerank_config = match request_rerank_config(&body_bytes, &qdrant_config_vec) {
+        Ok(rerank_config) => rerank_config,
+        Err(err_msg) => {
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return Err(error::internal_server_error(err_msg));
+            return error::bad_request(err_msg);
         }
     };
 
-    // get vdb_api_key if it is provided in the request, otherwise get it from the environment variable `VDB_API_KEY`
-    let vdb_api_key = chat_request
-        .vdb_api_key
-        .clone()
-        .or_else(|| std::env::var("VDB_API_KEY").ok());
-
-    // perform the context retrieval
-    let mut retrieve_object: RetrieveObject = match rag_retrieve_context(
-        query_embedding.as_slice(),
-        qdrant_config.url.to_string().as_str(),
-        qdrant_config.collection_name.as_str(),
-        qdrant_config.limit as usize,
-        Some(qdrant_config.score_threshold),
-        vdb_api_key,
+    // retrieve context
+    let (mut retrieve_object_vec, origins) = match retrieve_context_with_multiple_qdrant_configs(
+        &chat_request,
+        &qdrant_config_vec,
+        retrieval_deadline,
+        RECENCY_CONFIG.get(),
+        rerank_config.as_ref(),
+        progress.as_ref(),
     )
     .await
     {
-        Ok(search_result) => search_result,
-        Err(e) => {
-            let err_msg = format!("No point retrieved. {}", e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
-
-            return Err(error::internal_server_error(err_msg));
+        Ok(retrieval) => (retrieval.retrieve_object_vec, retrieval.origins),
+        Err(response) => {
+            return response;
         }
     };
-    if retrieve_object.points.is_none() {
-        retrieve_object.points = Some(Vec::new());
+
+    // check if nothing is retrieved because all the collections are empty
+    let collections_empty = retrieve_object_vec.is_empty()
+        && check_collections_empty(&chat_request, &qdrant_config_vec).await;
+
+    // log retrieve object
+    debug!(target: "stdout", "retrieve_object_vec:\n{}", serde_json::to_string_pretty(&retrieve_object_vec).unwrap());
+
+    // fall back to the kw-search results if the embedding-search retrieves nothing
+    if KW_FALLBACK.get().copied().unwrap_or(false)
+        && !kw_hits.is_empty()
+        && !retrieve_object_vec.iter().any(|retrieve_object| {
+            retrieve_object
+                .points
+                .as_ref()
+                .is_some_and(|points| !points.is_empty())
+        })
+    {
+        let limit = qdrant_config_vec
+            .first()
+            .map(|qdrant_config| qdrant_config.limit as usize)
+            .unwrap_or(kw_hits.len());
+
+        let mut hits = std::mem::take(&mut kw_hits);
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        hits.truncate(limit);
+
+        // log
+        warn!(target: "stdout", "Nothing is retrieved by the vector search. Fall back to {} keyword search result(s).", hits.len());
+
+        retrieve_object_vec = vec![RetrieveObject {
+            limit,
+            score_threshold: 0.0,
+            points: Some(
+                hits.into_iter()
+                    .map(|hit| RagScoredPoint {
+                        source: hit.content,
+                        score: hit.score,
+                    })
+                    .collect(),
+            ),
+        }];
     }
 
-    info!(target: "stdout", "{} point(s) retrieved from the collection `{}`", retrieve_object.points.as_ref().unwrap().len(), qdrant_config.collection_name);
+    // fuse kw-search and embedding-search results
+    fuse_keyword_hits(&mut retrieve_object_vec, kw_hits);
 
-    Ok(retrieve_object)
-}
+    // compute the retrieval confidence
+    let confidence = retrieval_confidence(&retrieve_object_vec);
 
-async fn retrieve_context_with_multiple_qdrant_configs(
-    chat_request: &ChatCompletionRequest,
-    qdrant_config_vec: &[QdrantConfig],
-) -> Result<Vec<RetrieveObject>, Response<Body>> {
-    let mut retrieve_object_vec: Vec<RetrieveObject> = Vec::new();
-    let mut set: HashSet<String> = HashSet::new();
-    for qdrant_config in qdrant_config_vec {
-        let mut retrieve_object =
-            retrieve_context_with_single_qdrant_config(chat_request, qdrant_config).await?;
+    // the retrieved points merged into the prompt, returned to the client
+    let sources = match return_sources || retrieve_only {
+        true => Some(retrieved_sources(&retrieve_object_vec, &origins)),
+        false => None,
+    };
 
-        if let Some(points) = retrieve_object.points.as_mut() {
-            if !points.is_empty() {
-                // find the duplicate points
-                let mut idx_removed = vec![];
-                for (idx, point) in points.iter().enumerate() {
-                    if set.contains(&point.source) {
-                        idx_removed.push(idx);
-                    } else {
-                        set.insert(point.source.clone());
-                    }
-                }
+    // stream the sources, and skip the generation
+    if retrieve_only {
+        // log
+        info!(target: "stdout", "Send the retrieved sources without generation, since `retrieve_only` is set.");
 
-                // remove the duplicate points
-                if !idx_removed.is_empty() {
-                    let num = idx_removed.len();
+        let mut res = retrieve_only_response(sources.as_deref().unwrap_or_default(), id);
+        insert_retrieval_headers(&mut res, collections_empty, confidence);
 
-                    for idx in idx_removed.iter().rev() {
-                        points.remove(*idx);
-                    }
+        return res;
+    }
 
-                    info!(target: "stdout", "removed duplicated {} point(s) retrieved from the collection `{}`", num, qdrant_config.collection_name);
-                }
+    // * extract the context from retrieved objects
+    let mut chunks: Vec<&str> = Vec::new();
+    for (idx, retrieve_object) in retrieve_object_vec.iter().enumerate() {
+        match retrieve_object.points.as_ref() {
+            Some(scored_points) => {
+                match scored_points.is_empty() {
+                    false => {
+                        for (idx, point) in scored_points.iter().enumerate() {
+                            // log
+                            info!(target: "stdout", "point: {}, score: {}, source: {}", idx, point.score, &point.source);
 
-                if !points.is_empty() {
-                    retrieve_object_vec.push(retrieve_object);
+                            chunks.push(&point.source);
+                        }
+                    }
+                    true => {
+                        // log
+                        warn!(target: "stdout", "{}", format!("No point retrieved from the collection `{}` (score < threshold {})", qdrant_config_vec[idx].collection_name, qdrant_config_vec[idx].score_threshold));
+                    }
                 }
             }
+            None => {
+                // log
+                warn!(target: "stdout", "{}", format!("No point retrieved from the collection `{}` (score < threshold {})", qdrant_config_vec[idx].collection_name, qdrant_config_vec[idx].score_threshold));
+            }
         }
     }
 
-    Ok(retrieve_object_vec)
-}
+    let context = match chunks.is_empty() {
+        true => String::new(),
+        false => context_format.format(&chunks),
+    };
 
-#[derive(Debug, Default)]
-struct RagPromptBuilder;
-impl MergeRagContext for RagPromptBuilder {
-    fn build(
-        messages: &mut Vec<endpoints::chat::ChatCompletionRequestMessage>,
-        context: &[String],
-        has_system_prompt: bool,
-        policy: MergeRagContextPolicy,
-    ) -> ChatPromptsError::Result<()> {
-        if messages.is_empty() {
-            error!(target: "stdout", "No message in the chat request.");
+    // * retrieve the context for the system message and append it to the system message
+    if RETRIEVE_FOR_SYSTEM.get().copied().unwrap_or(false) {
+        if let Some(ChatCompletionRequestMessage::System(message)) = chat_request.messages.first() {
+            if let Some(system_context) = retrieve_system_context(
+                message.content(),
+                &chat_request,
+                &qdrant_config_vec,
+                retrieval_deadline,
+                rerank_config.as_ref(),
+                context_format,
+            )
+            .await
+            {
+                let content = format!(
+                    "{}\n{}",
+                    message.content().trim(),
+                    system_context.trim_end()
+                );
 
-            return Err(ChatPromptsError::PromptError::NoMessages);
+                // log
+                info!(target: "stdout", "system message with the retrieved context: {}", &content);
+
+                chat_request.messages[0] = ChatCompletionRequestMessage::new_system_message(
+                    content,
+                    message.name().cloned(),
+                );
+            }
         }
+    }
 
-        if context.is_empty() {
-            let err_msg = "No context provided.";
+    // * update messages with retrieved context
+    if !context.is_empty() {
+        if chat_request.messages.is_empty() {
+            let err_msg = "No message in the chat request.";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return Err(ChatPromptsError::PromptError::Operation(err_msg.into()));
+            return error::internal_server_error(err_msg);
         }
 
-        if policy == MergeRagContextPolicy::SystemMessage && !has_system_prompt {
-            let err_msg = "The chat model does not support system message, while the given rag policy by '--policy' option requires that the RAG context is merged into system message. Please check the relevant CLI options and try again.";
+        let prompt_template =
+            match llama_core::utils::chat_prompt_template(chat_request.model.as_deref()) {
+                Ok(prompt_template) => prompt_template,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-            return Err(ChatPromptsError::PromptError::Operation(err_msg.into()));
-        }
-        info!(target: "stdout", "rag_policy: {}", &policy);
+                    return error::internal_server_error(err_msg);
+                }
+            };
 
-        let context = context[0].trim_end();
-        info!(target: "stdout", "context:\n{}", context);
+        let (rag_policy, rag_context_message) = match SERVER_INFO.get() {
+            Some(server_info) => {
+                let rag_config = &server_info.read().await.rag_config;
+                (rag_config.policy, rag_config.rag_context_message)
+            }
+            None => {
+                let err_msg = "SERVER_INFO is not initialized.";
 
-        match policy {
-            MergeRagContextPolicy::SystemMessage => {
-                info!(target: "stdout", "Merge RAG context into system message.");
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                match &messages[0] {
-                    ChatCompletionRequestMessage::System(message) => {
-                        let system_message = {
-                            match GLOBAL_RAG_PROMPT.get() {
-                                Some(global_rag_prompt) => {
-                                    // compose new system message content
-                                    let content = format!(
-                                        "{system_message}\n{rag_prompt}\n{context}",
-                                        system_message = message.content().trim(),
-                                        rag_prompt = global_rag_prompt.to_owned(),
-                                        context = context
-                                    );
+                return error::internal_server_error(err_msg);
+            }
+        };
 
-                                    // log
-                                    info!(target: "stdout", "system message with RAG context: {}", &content);
+        // insert rag context into chat request
+        let res = match rag_context_message {
+            RagContextMessage::Tool if template_supports_tool_message(prompt_template) => {
+                RagPromptBuilder::append_tool_message(&mut chat_request.messages, &context)
+            }
+            _ => RagPromptBuilder::build(
+                &mut chat_request.messages,
+                &[context],
+                prompt_template.has_system_prompt(),
+                rag_policy,
+            ),
+        };
+        if let Err(e) = res {
+            let err_msg = e.to_string();
 
-                                    // create system message
-                                    ChatCompletionRequestMessage::new_system_message(
-                                        content,
-                                        message.name().cloned(),
-                                    )
-                                }
-                                None => {
-                                    // compose new system message content
-                                    let content = format!(
-                                        "{system_message}\n{context}",
-                                        system_message = message.content().trim(),
-                                        context = context
-                                    );
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    info!(target: "stdout", "system message with RAG context: {}", &content);
+            return error::internal_server_error(err_msg);
+        }
+    }
 
-                                    // create system message
-                                    ChatCompletionRequestMessage::new_system_message(
-                                        content,
-                                        message.name().cloned(),
-                                    )
-                                }
-                            }
-                        };
+    // output filters applied to the completion text
+    let output_filters = OUTPUT_FILTERS
+        .get()
+        .map(|filters| filters.as_slice())
+        .unwrap_or_default();
+
+    // keep a copy of the request for regenerating the completion with invalid tool call arguments
+    let mut tool_request: Option<ChatCompletionRequest> = None;
+    if VALIDATE_TOOL_ARGS.get().copied().unwrap_or(false)
+        && !chat_request.stream.unwrap_or(false)
+        && chat_request
+            .tools
+            .as_ref()
+            .is_some_and(|tools| !tools.is_empty())
+    {
+        match serde_json::to_value(&chat_request).and_then(serde_json::from_value) {
+            Ok(request) => tool_request = Some(request),
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to copy the chat request for the tool call validation. The validation is skipped. {}", e);
+            }
+        }
+    }
 
-                        // replace the original system message
-                        messages[0] = system_message;
+    // at debug level, log the prompt rendered with the chat template, so that the role markers and separators can be inspected
+    if log_enabled!(target: "stdout", log::Level::Debug) {
+        log_rendered_template(&chat_request);
+    }
+
+    let request_timing = RequestTiming {
+        start: request_start,
+        retrieval: retrieval_start.elapsed(),
+        generation_start: Instant::now(),
+    };
+
+    // the retrieval is done, which ends the progress events
+    drop(progress);
+
+    // * perform chat completion
+    let mut res = match llama_core::chat::chat(&mut chat_request).await {
+        Ok(result) => match result {
+            either::Left(stream) => {
+                let stream = stream.map_err(|e| e.to_string()).map_ok(move |data| {
+                    // the stream holds the in-flight count and the request slot until it ends or the client disconnects
+                    let _in_flight = &in_flight;
+                    let _request_permit = &request_permit;
+
+                    // replace the placeholder fingerprint set by the core
+                    match SYSTEM_FINGERPRINT.get() {
+                        Some(system_fingerprint) => data.replace(
+                            r#""system_fingerprint":"fp_44709d6fcb""#,
+                            &format!(r#""system_fingerprint":"{}""#, system_fingerprint),
+                        ),
+                        None => data,
                     }
-                    _ => {
-                        let system_message = match GLOBAL_RAG_PROMPT.get() {
-                            Some(global_rag_prompt) => {
-                                // compose new system message content
-                                let content = format!(
-                                    "{rag_prompt}\n{context}",
-                                    rag_prompt = global_rag_prompt.to_owned(),
-                                    context = context
-                                );
+                });
+                let stream: Pin<Box<dyn Stream<Item = Result<String, String>> + Send>> =
+                    match stop.is_empty() {
+                        true => Box::pin(stream),
+                        false => Box::pin(stop_chat_stream(stream, stop)),
+                    };
+                let stream: Pin<Box<dyn Stream<Item = Result<String, String>> + Send>> =
+                    match output_filters.is_empty() {
+                        true => stream,
+                        false => Box::pin(filter_chat_stream(stream, output_filters)),
+                    };
+                let stream: Pin<Box<dyn Stream<Item = Result<String, String>> + Send>> =
+                    match &sources {
+                        Some(sources) => Box::pin(with_sources_event(stream, sources)),
+                        None => stream,
+                    };
+                // the generation runs as the stream is polled, and its logs carry the request id
+                let stream: Pin<Box<dyn Stream<Item = Result<String, String>> + Send>> =
+                    Box::pin(logger::in_request_scope_stream(stream));
+                let body = match trailers_accepted {
+                    true => body_with_timing_trailers(stream, request_timing),
+                    false => Body::wrap_stream(stream),
+                };
 
-                                // log
-                                info!(target: "stdout", "system message with RAG context: {}", &content);
+                let mut builder = Response::builder();
+                if trailers_accepted {
+                    builder = builder.header(
+                        "Trailer",
+                        "X-Total-Time-Ms, X-Retrieval-Time-Ms, X-Generation-Time-Ms",
+                    );
+                }
 
-                                // create system message
-                                ChatCompletionRequestMessage::new_system_message(content, None)
-                            }
-                            None => {
-                                // compose new system message content
-                                let content = context.to_string();
+                let result = builder
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Methods", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .header("Content-Type", "text/event-stream")
+                    .header("Cache-Control", "no-cache")
+                    .header("Connection", "keep-alive")
+                    .header("user", id)
+                    .body(body);
 
-                                // log
-                                info!(target: "stdout", "system message with RAG context: {}", &content);
+                match result {
+                    Ok(response) => {
+                        // log
+                        info!(target: "stdout", "finish chat completions in stream mode");
 
-                                // create system message
-                                ChatCompletionRequestMessage::new_system_message(content, None)
-                            }
-                        };
+                        response
+                    }
+                    Err(e) => {
+                        let err_msg =
+                            format!("Failed chat completions in stream mode. Reason: {}", e);
 
-                        // insert system message
-                        messages.insert(0, system_message);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        error::internal_server_error(err_msg)
                     }
                 }
             }
-            MergeRagContextPolicy::LastUserMessage => {
-                info!(target: "stdout", "Merge RAG context into last user message.");
+            either::Right(mut chat_completion_object) => {
+                // validate the tool call arguments
+                let mut invalid_tool_args = false;
+                if let Some(tool_request) = tool_request {
+                    (chat_completion_object, invalid_tool_args) =
+                        validate_tool_args(tool_request, chat_completion_object).await;
+                }
 
-                let len = messages.len();
-                match &messages.last() {
-                    Some(ChatCompletionRequestMessage::User(message)) => {
-                        if let ChatCompletionUserMessageContent::Text(content) = message.content() {
-                            // compose new user message content
-                            let content = format!(
-                                    "{context}\nAnswer the question based on the pieces of context above. The question is:\n{user_message}",
-                                    context = context,
-                                    user_message = content.trim(),
-                                );
+                // report the context fill in the `n_predict = -2` mode
+                if chat_request.max_completion_tokens.is_none() {
+                    check_context_fill(&mut chat_completion_object).await;
+                }
+
+                // cut the completion text at the first stop sequence of the request
+                for choice in chat_completion_object.choices.iter_mut() {
+                    if let Some(content) = choice.message.content.as_mut() {
+                        if let Some(idx) = find_stop(content, &stop) {
+                            content.truncate(idx);
+                            choice.finish_reason = FinishReason::stop;
+                        }
+                    }
+                }
+
+                // apply the output filters
+                if !output_filters.is_empty() {
+                    for choice in chat_completion_object.choices.iter_mut() {
+                        if let Some(content) = choice.message.content.as_mut() {
+                            *content = apply_output_filters(content, output_filters);
+                        }
+                    }
+                }
+
+                // serialize chat completion object
+                let s = match serde_json::to_value(&chat_completion_object).and_then(|mut value| {
+                    if invalid_tool_args {
+                        value["choices"][0]["finish_reason"] =
+                            serde_json::Value::from("invalid_tool_args");
+                    }
+                    if let Some(system_fingerprint) = SYSTEM_FINGERPRINT.get() {
+                        value["system_fingerprint"] =
+                            serde_json::Value::from(system_fingerprint.as_str());
+                    }
+                    if let Some(sources) = &sources {
+                        value["sources"] = serde_json::to_value(sources)?;
+                    }
+                    serde_json::to_string(&value)
+                }) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        let err_msg = format!("Failed to serialize chat completion object. {}", e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+
+                // return response
+                let result = Response::builder()
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Methods", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .header("Content-Type", "application/json")
+                    .header("user", id)
+                    .body(Body::from(s));
+
+                match result {
+                    Ok(mut response) => {
+                        // the timing is fully known, so it is sent in the headers
+                        if timing {
+                            response.headers_mut().extend(request_timing.headers());
+                        }
+
+                        // log
+                        info!(target: "stdout", "Finish chat completions in non-stream mode");
+
+                        response
+                    }
+                    Err(e) => {
+                        let err_msg =
+                            format!("Failed chat completions in non-stream mode. Reason: {}", e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        error::internal_server_error(err_msg)
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            let err_msg = format!("Failed to get chat completions. Reason: {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            // the token counts of the core are not exposed on failure, so the prompt tokens are estimated
+            match estimated_usage(&chat_request).await {
+                Some(usage) => error::internal_server_error_with_usage(err_msg, usage),
+                None => error::internal_server_error(err_msg),
+            }
+        }
+    };
+
+    // the stream responses and the errors only know the retrieval time upfront
+    if timing && !res.headers().contains_key("X-Total-Time-Ms") {
+        res.headers_mut().insert(
+            "X-Retrieval-Time-Ms",
+            hyper::header::HeaderValue::from(request_timing.retrieval.as_millis() as u64),
+        );
+    }
+    insert_retrieval_headers(&mut res, collections_empty, confidence);
+
+    // log
+    info!(target: "stdout", "Send the rag query response");
+
+    res
+}
+
+/// Set the `X-RAG-Warning` header if all the collections are empty, and the `X-Retrieval-Confidence` header.
+fn insert_retrieval_headers(res: &mut Response<Body>, collections_empty: bool, confidence: f32) {
+    if collections_empty {
+        res.headers_mut().insert(
+            "X-RAG-Warning",
+            hyper::header::HeaderValue::from_static("collections-empty"),
+        );
+    }
+    if let Ok(value) = hyper::header::HeaderValue::from_str(&format!("{:.2}", confidence)) {
+        res.headers_mut().insert("X-Retrieval-Confidence", value);
+    }
+}
+
+/// The stream response of a `retrieve_only` chat request: the retrieved sources as a `sources` event, followed by `data: [DONE]`.
+fn retrieve_only_response(sources: &[RetrievedSource], id: String) -> Response<Body> {
+    let body = format!(
+        "event: sources\ndata: {}\n\ndata: [DONE]\n\n",
+        serde_json::json!({ "sources": sources })
+    );
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Connection", "keep-alive")
+        .header("user", id)
+        .body(Body::from(body));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+/// The timing of a chat completion request, reported by `--timing-trailers`.
+#[derive(Debug, Clone, Copy)]
+struct RequestTiming {
+    /// When the request is received
+    start: Instant,
+    /// The duration of the retrieval phase
+    retrieval: Duration,
+    /// When the generation starts
+    generation_start: Instant,
+}
+impl RequestTiming {
+    /// The timing headers up to now.
+    fn headers(&self) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        for (name, duration) in [
+            ("X-Total-Time-Ms", self.start.elapsed()),
+            ("X-Retrieval-Time-Ms", self.retrieval),
+            ("X-Generation-Time-Ms", self.generation_start.elapsed()),
+        ] {
+            headers.insert(
+                name,
+                hyper::header::HeaderValue::from(duration.as_millis() as u64),
+            );
+        }
+        headers
+    }
+}
+
+/// Check if the client accepts trailers, i.e., the request is over HTTP/2 and has the `TE: trailers` header. hyper sends the trailers over HTTP/2 only.
+fn accepts_trailers(req: &Request<Body>) -> bool {
+    req.version() == hyper::Version::HTTP_2
+        && req
+            .headers()
+            .get_all(hyper::header::TE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| {
+                value
+                    .split(',')
+                    .any(|te| te.trim().eq_ignore_ascii_case("trailers"))
+            })
+}
+
+/// Forward the stream to the response body, and send the timing of the request as trailers once the stream ends.
+fn body_with_timing_trailers(
+    mut stream: Pin<Box<dyn Stream<Item = Result<String, String>> + Send>>,
+    timing: RequestTiming,
+) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(logger::in_request_scope(async move {
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(data) => {
+                    if sender.send_data(Bytes::from(data)).await.is_err() {
+                        // log
+                        warn!(target: "stdout", "The client closed the stream before the timing trailers are sent.");
+
+                        return;
+                    }
+                }
+                Err(e) => {
+                    // log
+                    error!(target: "stdout", "Failed chat completions in stream mode. Reason: {}", e);
+
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+
+        let trailers = timing.headers();
+
+        // log
+        info!(target: "stdout", "timing trailers: {:?}", &trailers);
+
+        if let Err(e) = sender.send_trailers(trailers).await {
+            // log
+            warn!(target: "stdout", "Failed to send the timing trailers. {}", e);
+        }
+    }));
+
+    body
+}
+
+/// Select the keyword search service of the request: the URL of the `kw_search_url` field if it is permitted by `--kw-search-url-allowlist`, or the URL of `--kw-search-url`. Returns an empty string if no keyword search service is set.
+///
+/// Without an allowlist, the field is ignored, since it would let the clients send requests from the server to any URL. With an allowlist, a URL not covered by it is an error.
+fn select_kw_search_url(requested: Option<&str>) -> Result<String, String> {
+    let default_url = || match KW_SEARCH_CONFIG.get() {
+        Some(kw_search_config) => kw_search_config.url.clone(),
+        None => String::new(),
+    };
+
+    let requested = match requested.map(str::trim) {
+        Some(requested) if !requested.is_empty() => requested,
+        _ => return Ok(default_url()),
+    };
+
+    match KW_SEARCH_URL_ALLOWLIST.get() {
+        Some(allowlist) => match url_allowed(requested, allowlist) {
+            true => Ok(requested.to_string()),
+            false => Err(format!(
+                "The keyword search URL `{}` of the `kw_search_url` field is not permitted by `--kw-search-url-allowlist`.",
+                requested
+            )),
+        },
+        None => {
+            // log
+            warn!(target: "stdout", "The `kw_search_url` field is ignored, since `--kw-search-url-allowlist` is not set.");
+
+            Ok(default_url())
+        }
+    }
+}
+
+/// Send the last user message to the keyword search service of the `kw_search_url` field or `--kw-search-url`, if the request names a `kw_index_name`. Returns no hit if the keyword search is not configured or fails.
+async fn keyword_search(
+    chat_request: &ChatCompletionRequest,
+    body_bytes: &Bytes,
+    retrieval_deadline: Option<Instant>,
+) -> Result<Vec<SearchHit>, Response<Body>> {
+    let mut kw_hits = Vec::new();
+    let mut kw_search_url = match select_kw_search_url(chat_request.kw_search_url.as_deref()) {
+        Ok(kw_search_url) => kw_search_url,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::bad_request(err_msg));
+        }
+    };
+    if !kw_search_url.is_empty() {
+        kw_search_url = kw_search_url.trim_end_matches('/').to_string();
+        info!(target: "stdout", "kw_search_url: {}", &kw_search_url);
+
+        if let Some(index_name) = &chat_request.kw_index_name {
+            if !index_name.is_empty() {
+                if let Some(ChatCompletionRequestMessage::User(user_message)) =
+                    chat_request.messages.last()
+                {
+                    if let ChatCompletionUserMessageContent::Text(text) = user_message.content() {
+                        info!(target: "stdout", "perform keyword search on the index: {}", &index_name);
+
+                        // the `kw_top_k` field in the request is prioritized over KW_SEARCH_LIMIT
+                        let kw_top_k = match request_has_field(body_bytes, "kw_top_k") {
+                            true => chat_request.kw_top_k.unwrap(),
+                            false => KW_SEARCH_LIMIT
+                                .get()
+                                .copied()
+                                .unwrap_or(chat_request.kw_top_k.unwrap()),
+                        };
+                        info!(target: "stdout", "kw_top_k: {}", kw_top_k);
+
+                        let user_query = text.clone();
+                        let query_request = QueryRequest {
+                            query: user_query,
+                            top_k: kw_top_k as usize,
+                            index: index_name.clone(),
+                        };
+
+                        let query_url = format!("{}/v1/search", &kw_search_url);
+                        info!(target: "stdout", "query_url: {}", &query_url);
+
+                        // send query request to the keyword search service
+                        let mut request_builder =
+                            reqwest::Client::new().post(&query_url).json(&query_request);
+                        if let Some(deadline) = retrieval_deadline {
+                            request_builder = request_builder
+                                .timeout(deadline.saturating_duration_since(Instant::now()));
+                        }
+                        let kw_search =
+                            async { request_builder.send().await?.json::<QueryResponse>().await };
+                        match with_retrieval_timeout(kw_search).await {
+                            Ok(Ok(query_response)) => match query_response.error {
+                                Some(error) => {
+                                    let err_msg = format!(
+                                        "Failed to perform keyword search. Reason: {}",
+                                        error
+                                    );
+
+                                    // log
+                                    warn!(target: "stdout", "{}", &err_msg);
+                                }
+                                None => {
+                                    info!(target: "stdout", "Number of keyword search hits: {}", &query_response.hits.len());
+
+                                    kw_hits = query_response.hits;
+                                }
+                            },
+                            Ok(Err(e)) => {
+                                let err_msg =
+                                    format!("Failed to perform keyword search. Reason: {}", e);
+
+                                // log
+                                warn!(target: "stdout", "{}", &err_msg);
+                            }
+                            Err((timeout, action)) => {
+                                let err_msg = format!(
+                                    "The keyword search service at {} timed out after {} ms.",
+                                    &kw_search_url,
+                                    timeout.as_millis()
+                                );
+
+                                match action {
+                                    RetrievalTimeoutAction::Fail => {
+                                        return Err(error::gateway_timeout(err_msg));
+                                    }
+                                    RetrievalTimeoutAction::Proceed => {
+                                        // log
+                                        warn!(target: "stdout", "{} Proceed without the keyword search results.", &err_msg);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(kw_hits)
+}
+
+/// Fuse the keyword search hits with the points of the first collection, by the ranks if `--hybrid-fusion-k` is set, otherwise by the weighted scores. The fused points replace the retrieved points.
+fn fuse_keyword_hits(retrieve_object_vec: &mut Vec<RetrieveObject>, mut kw_hits: Vec<SearchHit>) {
+    if !kw_hits.is_empty()
+        && !retrieve_object_vec.is_empty()
+        && retrieve_object_vec[0].points.is_some()
+    {
+        let points = retrieve_object_vec[0].points.as_ref().unwrap().clone();
+        if !points.is_empty() {
+            let limit = retrieve_object_vec[0].limit;
+            let score_threshold = retrieve_object_vec[0].score_threshold;
+
+            // create a hash map from retrieve_object_vec: key is the hash value of the source of the point, value is the point
+            let mut em_hits_map = HashMap::new();
+            let mut em_scores = HashMap::new();
+            let mut em_ranking = Vec::new();
+
+            for point in points {
+                let hash_value = calculate_hash(&point.source);
+                em_scores.insert(hash_value, point.score);
+                em_hits_map.insert(hash_value, point);
+                em_ranking.push(hash_value);
+            }
+
+            info!(target: "stdout", "em_hits_map: {:#?}", &em_hits_map);
+
+            // normalize the em_scores
+            let em_scores = normalize(&em_scores);
+
+            info!(target: "stdout", "em_scores: {:#?}", &em_scores);
+
+            // create a hash map from kw_hits: key is the hash value of the content of the hit, value is the hit
+            kw_hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+            let mut kw_hits_map = HashMap::new();
+            let mut kw_scores = HashMap::new();
+            let mut kw_ranking = Vec::new();
+            for hit in kw_hits {
+                let hash_value = calculate_hash(&hit.content);
+                kw_scores.insert(hash_value, hit.score);
+                kw_hits_map.insert(hash_value, hit);
+                kw_ranking.push(hash_value);
+            }
+
+            info!(target: "stdout", "kw_hits_map: {:#?}", &kw_hits_map);
+
+            // normalize the kw_scores
+            let kw_scores = normalize(&kw_scores);
+
+            info!(target: "stdout", "kw_scores: {:#?}", &kw_scores);
+
+            // fuse the two hash maps, by the ranks if `--hybrid-fusion-k` is set, otherwise by the weighted scores
+            let rrf_k = HYBRID_FUSION_K.get().copied();
+            let final_scores = match rrf_k {
+                Some(k) => reciprocal_rank_fusion(&kw_ranking, &em_ranking, k),
+                None => {
+                    // Set weight alpha
+                    let alpha = 0.7;
+
+                    weighted_fusion(kw_scores, em_scores, alpha)
+                }
+            };
+
+            info!(target: "stdout", "final_scores: {:#?}", &final_scores);
+
+            // Sort by score from high to low
+            let mut final_ranking: Vec<(u64, f32)> = final_scores.into_iter().collect();
+            final_ranking.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+            // Print final ranking
+            info!(target: "stdout", "final_ranking: {:#?}", &final_ranking);
+
+            // the score threshold applies to the similarity scores, not to the rank-based scores
+            let mut retrieved = Vec::new();
+            for (hash_value, score) in final_ranking {
+                if rrf_k.is_some() || score >= score_threshold {
+                    let mut doc = RagScoredPoint {
+                        source: String::new(),
+                        score,
+                    };
+                    if kw_hits_map.contains_key(&hash_value) {
+                        doc.source = kw_hits_map[&hash_value].content.clone();
+                        retrieved.push(doc);
+                    } else if em_hits_map.contains_key(&hash_value) {
+                        doc.source = em_hits_map[&hash_value].source.clone();
+                        retrieved.push(doc);
+                    }
+                }
+            }
+
+            if retrieved.len() > limit {
+                retrieved.truncate(limit);
+            }
+
+            info!(target: "stdout", "retrieved: {:#?}", &retrieved);
+
+            let retrieve_object = RetrieveObject {
+                limit,
+                score_threshold,
+                points: Some(retrieved),
+            };
+
+            *retrieve_object_vec = vec![retrieve_object];
+        }
+    }
+}
+
+/// Log the messages of the chat request rendered with the chat template of the model, through the same `chat-prompts` code path as the completion. The output contains the full prompt content.
+fn log_rendered_template(chat_request: &ChatCompletionRequest) {
+    match render_prompt(chat_request) {
+        Ok((prompt_template, prompt)) => {
+            // log
+            debug!(target: "stdout", "rendered chat template ({}):\n{}", prompt_template, prompt);
+        }
+        Err(err_msg) => {
+            // log
+            warn!(target: "stdout", "{} The debug output is skipped.", err_msg);
+        }
+    }
+}
+
+/// Render the prompt of the chat request with the chat template of its model.
+fn render_prompt(
+    chat_request: &ChatCompletionRequest,
+) -> Result<(PromptTemplateType, String), String> {
+    let prompt_template = llama_core::utils::chat_prompt_template(chat_request.model.as_deref())
+        .map_err(|e| format!("Failed to get the chat template. {}", e))?;
+
+    let mut messages = chat_request.messages.clone();
+    let prompt = ChatPrompt::from(prompt_template)
+        .build_with_tools(&mut messages, chat_request.tools.as_deref())
+        .map_err(|e| {
+            format!(
+                "Failed to render the chat template `{}`. {}",
+                prompt_template, e
+            )
+        })?;
+
+    Ok((prompt_template, prompt))
+}
+
+/// The `usage` of a failed chat completion: the prompt tokens estimated on the rendered prompt with the `cl100k_base` tokenizer, and the context size of the chat model, so that a failure caused by the context size can be told apart. The completion tokens are unknown. Returns `None` if the prompt can't be rendered.
+///
+/// The estimate is a hint on a request that already failed, marked with `estimated: true`, not a count to trim requests to an exact fit, which would need the tokenizer of the chat model.
+async fn estimated_usage(chat_request: &ChatCompletionRequest) -> Option<serde_json::Value> {
+    let prompt = match render_prompt(chat_request) {
+        Ok((_, prompt)) => prompt,
+        Err(err_msg) => {
+            // log
+            warn!(target: "stdout", "{} The usage is omitted from the error response.", err_msg);
+
+            return None;
+        }
+    };
+    let tokenizer = cl100k_tokenizer().ok()?;
+    let prompt_tokens = tokenizer.encode_with_special_tokens(&prompt).len();
+
+    let mut usage = serde_json::json!({
+        "prompt_tokens": prompt_tokens,
+        "estimated": true,
+    });
+    if let Some(server_info) = SERVER_INFO.get() {
+        if let Some(chat_model) = &server_info.read().await.rag_config.chat_model {
+            usage["context_size"] = serde_json::Value::from(chat_model.ctx_size);
+        }
+    }
+
+    Some(usage)
+}
+
+/// Build the retrieval query from the last user messages in the context window, with the turn decay and the Unicode normalization applied.
+fn retrieval_query_text(
+    chat_request: &ChatCompletionRequest,
+    context_window: u64,
+) -> Result<String, String> {
+    // get the last `n` user messages in the context window.
+    // `n` is determined by the `context_window` in the chat request.
+    let mut last_n_user_messages = Vec::new();
+    for (idx, message) in chat_request.messages.iter().rev().enumerate() {
+        if let ChatCompletionRequestMessage::User(user_message) = message {
+            if let ChatCompletionUserMessageContent::Text(text) = user_message.content() {
+                if !text.ends_with("<server-health>") {
+                    last_n_user_messages.push(text.clone());
+                } else if idx == 0 {
+                    let content = text.trim_end_matches("<server-health>").to_string();
+                    last_n_user_messages.push(content);
+                    break;
+                }
+            }
+        }
+
+        if last_n_user_messages.len() == context_window as usize {
+            break;
+        }
+    }
+
+    // join the user messages in the context window into a single string
+    let query_text = if !last_n_user_messages.is_empty() {
+        info!(target: "stdout", "Found the latest {} user messages", last_n_user_messages.len());
+
+        last_n_user_messages.reverse();
+
+        // truncate the older user messages by the turn decay
+        let turn_decay = TURN_DECAY.get().copied().unwrap_or(1.0);
+        if turn_decay < 1.0 {
+            last_n_user_messages = apply_turn_decay(last_n_user_messages, turn_decay);
+        }
+
+        last_n_user_messages.join("\n")
+    } else {
+        let warn_msg = "No user messages found.";
+
+        // log
+        warn!(target: "stdout", "{}", &warn_msg);
+
+        return Err(warn_msg.to_string());
+    };
+
+    // normalize the query text
+    let form = UNICODE_NORMALIZATION.get().copied().unwrap_or_default();
+    let query_text = normalize_unicode(&query_text, form);
+
+    Ok(query_text)
+}
+
+async fn retrieve_context_with_single_qdrant_config(
+    chat_request: &ChatCompletionRequest,
+    qdrant_config: &QdrantConfig,
+    recency_config: Option<&RecencyConfig>,
+    rerank_config: Option<&RerankConfig>,
+    progress: Option<CollectionProgress<'_>>,
+) -> Result<(RetrieveObject, Payloads, PointOrigins), Response<Body>> {
+    info!(target: "stdout", "Compute embeddings for user query.");
+
+    // get context_window: chat_request.context_window prioritized CONTEXT_WINDOW
+    let context_window = chat_request
+        .context_window
+        .or_else(|| CONTEXT_WINDOW.get().copied())
+        .unwrap_or(1);
+    info!(target: "stdout", "context window: {}", context_window);
+
+    info!(target: "stdout", "VectorDB config: {}", qdrant_config);
+
+    // compute embeddings for user query, and keep the query text for the reranker
+    let (embedding_response, query_text) = match chat_request.messages.is_empty() {
+        true => {
+            let err_msg = "Messages should not be empty.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::bad_request(err_msg));
+        }
+        false => {
+            let query_text =
+                retrieval_query_text(chat_request, context_window).map_err(error::bad_request)?;
+
+            // log
+            info!(target: "stdout", "query text for the context retrieval: {}", query_text);
+
+            // generate the query paraphrases if multi-query retrieval is enabled
+            let multi_query = MULTI_QUERY.get().copied().unwrap_or(1);
+            let mut queries = vec![query_text.clone()];
+            if multi_query > 1 {
+                let paraphrases =
+                    generate_query_paraphrases(&query_text, multi_query - 1, chat_request).await;
+
+                // log
+                debug!(target: "stdout", "generated queries: {:?}", &paraphrases);
+
+                queries.extend(paraphrases);
+            }
+
+            // truncate the queries to fit the context of the embedding model
+            if let Some((max_query_tokens, keep)) = MAX_QUERY_TOKENS.get().copied() {
+                for query in queries.iter_mut() {
+                    match truncate_query(query, max_query_tokens as usize, keep) {
+                        Ok(Some((truncated, num_tokens))) => {
+                            // log
+                            warn!(target: "stdout", "The query of {} tokens is truncated to {} tokens, keeping the {}.", num_tokens, max_query_tokens, keep);
+
+                            *query = truncated;
+                        }
+                        Ok(None) => {}
+                        Err(err_msg) => {
+                            // log
+                            warn!(target: "stdout", "Failed to truncate the query. {}", err_msg);
+                        }
+                    }
+                }
+            }
+
+            // get the embedding model of the collection
+            let model = match embedding_model_for_collection(&qdrant_config.collection_name) {
+                Ok(model) => model,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(error::internal_server_error(err_msg));
+                }
+            };
+            info!(target: "stdout", "embedding model: {}", &model);
+
+            // prepend the query prefix of the collection
+            let query_prefix = query_prefix_for_collection(&qdrant_config.collection_name);
+            if !query_prefix.is_empty() {
+                // log
+                info!(target: "stdout", "query prefix: {:?}", query_prefix);
+
+                for query in queries.iter_mut() {
+                    query.insert_str(0, query_prefix);
+                }
+            }
+
+            // get vdb_api_key if it is provided in the request, otherwise use the default API key of the Qdrant instance
+            let vdb_api_key = chat_request
+                .vdb_api_key
+                .clone()
+                .or_else(qdrant::default_api_key);
+
+            // create a embedding request
+            let embedding_request = EmbeddingRequest {
+                model: Some(model),
+                input: InputText::ArrayOfStrings(queries),
+                encoding_format: None,
+                user: chat_request.user.clone(),
+                vdb_server_url: Some(qdrant_config.url.clone()),
+                vdb_collection_name: Some(qdrant_config.collection_name.clone()),
+                vdb_api_key,
+            };
+
+            // compute embeddings for query
+            match embeddings(&embedding_request).await {
+                Ok(embedding_response) => (embedding_response, query_text),
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(error::internal_server_error(err_msg));
+                }
+            }
+        }
+    };
+    if embedding_response.data.is_empty() {
+        let err_msg = "No embeddings returned";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::internal_server_error(err_msg));
+    }
+
+    // get vdb_api_key if it is provided in the request, otherwise use the default API key of the Qdrant instance
+    let vdb_api_key = chat_request
+        .vdb_api_key
+        .clone()
+        .or_else(qdrant::default_api_key);
+
+    // at debug level, the score threshold is applied locally, so that the candidates below the threshold can be logged
+    let debug_scores = log_enabled!(target: "stdout", log::Level::Debug);
+    let score_threshold = match debug_scores {
+        true => None,
+        false => Some(qdrant_config.score_threshold),
+    };
+
+    // with a reranker, more candidates are fetched than kept
+    let (search_limit, keep) = match rerank_config {
+        Some(rerank_config) => (
+            rerank_config.candidates_for(qdrant_config.limit),
+            rerank_config.top_n_for(qdrant_config.limit),
+        ),
+        None => (qdrant_config.limit, qdrant_config.limit),
+    };
+
+    // perform the context retrieval for each query
+    let mut scored_points = Vec::new();
+    for (query_idx, embedding) in embedding_response.data.iter().enumerate() {
+        let query_embedding: Vec<f32> = embedding.embedding.iter().map(|x| *x as f32).collect();
+        let query_embedding = match project_embedding(query_embedding) {
+            Ok(query_embedding) => query_embedding,
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(error::internal_server_error(err_msg));
+            }
+        };
+
+        let search = qdrant::search_points(
+            &qdrant_config.url,
+            vdb_api_key.as_deref(),
+            &qdrant_config.collection_name,
+            query_embedding.as_slice(),
+            search_limit,
+            score_threshold,
+        );
+        let result = match with_retrieval_timeout(search).await {
+            Ok(result) => result,
+            Err((timeout, action)) => {
+                let err_msg = format!(
+                    "Qdrant at {} timed out after {} ms while searching the collection `{}`.",
+                    &qdrant_config.url,
+                    timeout.as_millis(),
+                    &qdrant_config.collection_name
+                );
+
+                match action {
+                    RetrievalTimeoutAction::Fail => {
+                        return Err(error::gateway_timeout(err_msg));
+                    }
+                    RetrievalTimeoutAction::Proceed => {
+                        // log
+                        warn!(target: "stdout", "{} Proceed with the {} point(s) found so far in the collection.", &err_msg, scored_points.len());
+
+                        // the next queries would hit the same hanging collection
+                        break;
+                    }
+                }
+            }
+        };
+
+        match result {
+            Ok(mut points) => {
+                if debug_scores {
+                    log_retrieval_scores(
+                        &qdrant_config.collection_name,
+                        query_idx,
+                        &points,
+                        qdrant_config.score_threshold,
+                    );
+
+                    points.retain(|point| point.score >= qdrant_config.score_threshold);
+                }
+
+                if let Some(progress) = &progress {
+                    progress.query_completed(
+                        query_idx,
+                        embedding_response.data.len(),
+                        points.len(),
+                    );
+                }
+
+                scored_points.extend(points)
+            }
+            Err(e) => {
+                let err_msg = format!("No point retrieved. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(error::internal_server_error(err_msg));
+            }
+        }
+    }
+
+    // rerank the candidates with the cross-encoder reranker
+    if let Some(rerank_config) = rerank_config {
+        scored_points = rerank_points(
+            rerank_config,
+            &query_text,
+            scored_points,
+            &qdrant_config.collection_name,
+        )
+        .await?;
+    }
+
+    // rerank the points by recency
+    if let Some(recency_config) = recency_config {
+        apply_recency_decay(&mut scored_points, recency_config);
+    }
+
+    // merge the results of all queries: sort by score from high to low, and remove duplicates, which have the same source
+    scored_points.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    let mut payloads = Payloads::new();
+    let mut origins = PointOrigins::new();
+    let mut points: Vec<RagScoredPoint> = Vec::new();
+    for point in scored_points.iter() {
+        if points.len() >= keep as usize {
+            break;
+        }
+
+        if let Some(source) = point.source() {
+            if !payloads.contains_key(source) {
+                payloads.insert(
+                    source.to_string(),
+                    point.payload.clone().unwrap_or_default(),
+                );
+                origins.insert(
+                    source.to_string(),
+                    PointOrigin {
+                        collection_name: qdrant_config.collection_name.clone(),
+                        point_id: point.id.clone(),
+                    },
+                );
+                points.push(RagScoredPoint {
+                    source: source.to_string(),
+                    score: point.score,
+                });
+            }
+        }
+    }
+
+    let retrieve_object = RetrieveObject {
+        points: Some(points),
+        limit: keep as usize,
+        score_threshold: qdrant_config.score_threshold,
+    };
+
+    info!(target: "stdout", "{} point(s) retrieved from the collection `{}`", retrieve_object.points.as_ref().unwrap().len(), qdrant_config.collection_name);
+
+    Ok((retrieve_object, payloads, origins))
+}
+
+/// Replace the similarity scores of the candidates with the relevance scores of the reranker of `--rerank-url`. The candidates found by several queries are scored once.
+///
+/// If the reranker fails, the candidates keep their similarity scores, and the failure is logged. A timeout of `--retrieval-timeout` is handled as for the other upstreams of the retrieval.
+async fn rerank_points(
+    rerank_config: &RerankConfig,
+    query_text: &str,
+    mut scored_points: Vec<qdrant::ScoredPoint>,
+    collection_name: &str,
+) -> Result<Vec<qdrant::ScoredPoint>, Response<Body>> {
+    // keep the best scored candidate of each source
+    scored_points.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut seen = HashSet::new();
+    scored_points.retain(|point| {
+        point
+            .source()
+            .is_some_and(|source| seen.insert(source.to_string()))
+    });
+    if scored_points.is_empty() {
+        return Ok(scored_points);
+    }
+
+    let documents: Vec<&str> = scored_points
+        .iter()
+        .filter_map(|point| point.source())
+        .collect();
+    let request = rerank::rerank(
+        &rerank_config.url,
+        rerank_config.model.as_deref(),
+        query_text,
+        &documents,
+    );
+    let result = match with_retrieval_timeout(request).await {
+        Ok(result) => result,
+        Err((timeout, action)) => {
+            let err_msg = format!(
+                "The reranker at {} timed out after {} ms while reranking the collection `{}`.",
+                &rerank_config.url,
+                timeout.as_millis(),
+                collection_name
+            );
+
+            match action {
+                RetrievalTimeoutAction::Fail => return Err(error::gateway_timeout(err_msg)),
+                RetrievalTimeoutAction::Proceed => {
+                    // log
+                    warn!(target: "stdout", "{} Proceed with the similarity scores.", &err_msg);
+
+                    return Ok(scored_points);
+                }
+            }
+        }
+    };
+
+    match result {
+        Ok(scores) => {
+            // log
+            info!(target: "stdout", "Reranked {} candidate(s) of the collection `{}`.", scores.len(), collection_name);
+
+            for (point, score) in scored_points.iter_mut().zip(scores) {
+                // log
+                debug!(target: "stdout", "rerank: similarity: {}, relevance: {}", point.score, score);
+
+                point.score = score;
+            }
+        }
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to rerank the candidates of the collection `{}`. Proceed with the similarity scores. {}", collection_name, e);
+        }
+    }
+
+    Ok(scored_points)
+}
+
+/// Run an upstream request of the retrieval, to Qdrant or to the keyword search service, within `--retrieval-timeout` if it is set. On timeout, returns the timeout and the configured action.
+async fn with_retrieval_timeout<T>(
+    request: impl std::future::Future<Output = T>,
+) -> Result<T, (std::time::Duration, RetrievalTimeoutAction)> {
+    match RETRIEVAL_TIMEOUT.get() {
+        Some(&(timeout, action)) => tokio::time::timeout(timeout, request)
+            .await
+            .map_err(|_| (timeout, action)),
+        None => Ok(request.await),
+    }
+}
+
+/// Compute the embeddings with the core, and pool the embeddings of a model returning one vector per token into one vector with `--multivector-pooling`.
+///
+/// An embedding is taken as the concatenated vectors of the tokens if its length is a multiple of the dimension of the model found at startup.
+async fn embeddings(
+    embedding_request: &EmbeddingRequest,
+) -> Result<EmbeddingsResponse, LlamaCoreError> {
+    let mut embedding_response = llama_core::embeddings::embeddings(embedding_request).await?;
+
+    let model_name = embedding_request.model.clone().or_else(|| {
+        EMBEDDING_ROUTES
+            .get()
+            .map(|routes| routes.default_model.clone())
+    });
+    let dimension = match (model_name, EMBEDDING_DIMENSIONS.get()) {
+        (Some(model_name), Some(dimensions)) => dimensions.get(&model_name).copied(),
+        _ => None,
+    };
+    if let Some(dimension) = dimension {
+        let pooling = MULTIVECTOR_POOLING.get().copied().unwrap_or_default();
+        for embedding_object in embedding_response.data.iter_mut() {
+            let len = embedding_object.embedding.len();
+            if len > dimension && len.is_multiple_of(dimension) {
+                // log
+                debug!(target: "stdout", "Pool the {} vectors of the embedding {} with the `{}` pooling.", len / dimension, embedding_object.index, pooling);
+
+                embedding_object.embedding = pooling.pool(&embedding_object.embedding, dimension);
+            }
+        }
+    }
+
+    Ok(embedding_response)
+}
+
+/// Project the embedding with the `--embedding-projection` matrix if it is set, otherwise return the embedding as is.
+fn project_embedding(embedding: Vec<f32>) -> Result<Vec<f32>, String> {
+    match EMBEDDING_PROJECTION.get() {
+        Some(projection) => projection.project(&embedding),
+        None => Ok(embedding),
+    }
+}
+
+/// Combine the similarity score of each point with an exponential decay of its age:
+///
+/// `score = (1 - recency_weight) * similarity + recency_weight * 0.5^(age_days / half_life_days)`
+///
+/// The points without a valid `timestamp` payload field keep their similarity score. Future timestamps count as age 0.
+fn apply_recency_decay(points: &mut [qdrant::ScoredPoint], recency_config: &RecencyConfig) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64())
+        .unwrap_or_default();
+
+    for point in points.iter_mut() {
+        if let Some(timestamp) = point.timestamp() {
+            let age_days = ((now - timestamp) / 86400.0).max(0.0);
+            let recency = 0.5_f64.powf(age_days / recency_config.half_life_days);
+            let score = (1.0 - recency_config.weight) * point.score as f64
+                + recency_config.weight * recency;
+
+            // log
+            debug!(target: "stdout", "recency decay: age: {:.1} day(s), similarity: {}, score: {:.4}", age_days, point.score, score);
+
+            point.score = score as f32;
+        }
+    }
+}
+
+/// Log the scores of the candidates retrieved for a query, including those below the score threshold. The normalized score is the min-max normalization of the raw score over the candidates.
+fn log_retrieval_scores(
+    collection_name: &str,
+    query_idx: usize,
+    points: &[qdrant::ScoredPoint],
+    score_threshold: f32,
+) {
+    let min = points.iter().map(|p| p.score).fold(f32::INFINITY, f32::min);
+    let max = points
+        .iter()
+        .map(|p| p.score)
+        .fold(f32::NEG_INFINITY, f32::max);
+
+    debug!(target: "stdout", "{} candidate(s) retrieved from the collection `{}` for query {} (score threshold: {})", points.len(), collection_name, query_idx, score_threshold);
+
+    for (rank, point) in points.iter().enumerate() {
+        let normalized = match max > min {
+            true => (point.score - min) / (max - min),
+            false => 1.0,
+        };
+
+        debug!(target: "stdout", "collection: {}, query: {}, rank: {}, score: {}, normalized_score: {:.4}, passed: {}", collection_name, query_idx, rank, point.score, normalized, point.score >= score_threshold);
+    }
+}
+
+/// Generate `n` paraphrases of the query with the chat model. If the generation fails, an empty vector is returned so that the retrieval falls back to the original query.
+async fn generate_query_paraphrases(
+    query: &str,
+    n: u64,
+    chat_request: &ChatCompletionRequest,
+) -> Vec<String> {
+    info!(target: "stdout", "Generate {} paraphrase(s) of the query for the multi-query retrieval", n);
+
+    let system_message = ChatCompletionRequestMessage::new_system_message(
+        "You are an AI assistant that rewrites questions to improve the retrieval of relevant documents from a vector database.",
+        None,
+    );
+    let user_message = ChatCompletionRequestMessage::new_user_message(
+        ChatCompletionUserMessageContent::Text(format!(
+            "Generate {} different versions of the following question. Provide the alternative questions separated by newlines, without numbering or any other text.\n\nQuestion: {}",
+            n, query
+        )),
+        None,
+    );
+
+    let mut builder = ChatCompletionRequestBuilder::new(&[system_message, user_message]);
+    if let Some(model) = &chat_request.model {
+        builder = builder.with_model(model);
+    }
+    if let Some(user) = &chat_request.user {
+        builder = builder.with_user(user);
+    }
+    let mut request = builder.enable_stream(false).build();
+
+    let content = match llama_core::chat::chat(&mut request).await {
+        Ok(either::Right(chat_completion_object)) => chat_completion_object
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.clone())
+            .unwrap_or_default(),
+        Ok(either::Left(_)) => {
+            // log
+            warn!(target: "stdout", "Failed to generate query paraphrases. Unexpected stream response.");
+
+            return Vec::new();
+        }
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to generate query paraphrases. {}", e);
+
+            return Vec::new();
+        }
+    };
+
+    let mut paraphrases: Vec<String> = Vec::new();
+    for line in content.lines() {
+        // strip list markers, such as `1.`, `2)`, `-` and `*`
+        let paraphrase = line
+            .trim()
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .trim_start_matches(['.', ')', '-', '*'])
+            .trim();
+
+        if !paraphrase.is_empty()
+            && paraphrase != query
+            && !paraphrases.iter().any(|p| p == paraphrase)
+        {
+            paraphrases.push(paraphrase.to_string());
+        }
+
+        if paraphrases.len() == n as usize {
+            break;
+        }
+    }
+
+    paraphrases
+}
+
+/// Validate the tool call arguments of the completion. If any of them is invalid, regenerate the completion once with the violations fed back to the model as tool messages.
+///
+/// Returns the final completion, and whether its tool call arguments are still invalid.
+async fn validate_tool_args(
+    mut tool_request: ChatCompletionRequest,
+    chat_completion_object: ChatCompletionObject,
+) -> (ChatCompletionObject, bool) {
+    let tools = tool_request.tools.clone().unwrap_or_default();
+
+    let violations = match check_tool_calls(&chat_completion_object, &tools) {
+        Some(violations) => violations,
+        None => return (chat_completion_object, false),
+    };
+
+    // log
+    warn!(target: "stdout", "Invalid tool call arguments. Regenerate the completion. {}", violations.join(" "));
+
+    // feed the violations back to the model
+    let message = &chat_completion_object.choices[0].message;
+    tool_request
+        .messages
+        .push(ChatCompletionRequestMessage::new_assistant_message(
+            message.content.clone(),
+            None,
+            Some(message.tool_calls.clone()),
+        ));
+    for tool_call in message.tool_calls.iter() {
+        let content = match validate_tool_call(tool_call, &tools) {
+            Ok(_) => "The arguments are valid, but the call is not executed because other tool calls in the message are invalid.".to_string(),
+            Err(e) => format!("{} Call the tool again with the arguments conforming to its schema.", e),
+        };
+        tool_request
+            .messages
+            .push(ChatCompletionRequestMessage::new_tool_message(
+                content,
+                Some(tool_call.id.clone()),
+            ));
+    }
+
+    match llama_core::chat::chat(&mut tool_request).await {
+        Ok(either::Right(regenerated)) => match check_tool_calls(&regenerated, &tools) {
+            Some(violations) => {
+                // log
+                warn!(target: "stdout", "The tool call arguments are still invalid after regeneration. {}", violations.join(" "));
+
+                (regenerated, true)
+            }
+            None => (regenerated, false),
+        },
+        Ok(either::Left(_)) => {
+            // log
+            warn!(target: "stdout", "Failed to regenerate the completion. Unexpected stream response.");
+
+            (chat_completion_object, true)
+        }
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to regenerate the completion. {}", e);
+
+            (chat_completion_object, true)
+        }
+    }
+}
+
+/// Check the tool calls of the first choice against the tools. Returns the violations, if any.
+fn check_tool_calls(
+    chat_completion_object: &ChatCompletionObject,
+    tools: &[Tool],
+) -> Option<Vec<String>> {
+    let violations: Vec<String> = chat_completion_object
+        .choices
+        .first()?
+        .message
+        .tool_calls
+        .iter()
+        .filter_map(|tool_call| validate_tool_call(tool_call, tools).err())
+        .collect();
+
+    match violations.is_empty() {
+        true => None,
+        false => Some(violations),
+    }
+}
+
+/// Keep the first `decay^k` of words of the user message `k` turns before the latest one, and drop the messages with no word left. The messages are in chronological order.
+fn apply_turn_decay(user_messages: Vec<String>, decay: f64) -> Vec<String> {
+    let num_messages = user_messages.len();
+
+    user_messages
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, message)| {
+            let age = (num_messages - 1 - idx) as i32;
+            if age == 0 {
+                return Some(message);
+            }
+
+            let words: Vec<&str> = message.split_whitespace().collect();
+            let num_words = (words.len() as f64 * decay.powi(age)).floor() as usize;
+
+            // log
+            debug!(target: "stdout", "turn decay: keep {} of {} words of the user message {} turn(s) before the latest one", num_words, words.len(), age);
+
+            match num_words {
+                0 => None,
+                _ => Some(words[..num_words].join(" ")),
+            }
+        })
+        .collect()
+}
+
+/// Retrieve the context from the collections in order. If the deadline is exceeded, the retrieval stops and the context gathered so far is returned. The points are reranked by the reranker if `rerank_config` is set, then by recency if `recency_config` is set.
+async fn retrieve_context_with_multiple_qdrant_configs(
+    chat_request: &ChatCompletionRequest,
+    qdrant_config_vec: &[QdrantConfig],
+    deadline: Option<Instant>,
+    recency_config: Option<&RecencyConfig>,
+    rerank_config: Option<&RerankConfig>,
+    progress: Option<&RetrievalProgress>,
+) -> Result<Retrieval, Response<Body>> {
+    let routed_config_vec = route_collections(chat_request, qdrant_config_vec).await;
+    let qdrant_config_vec = routed_config_vec.as_slice();
+
+    let mut retrieve_object_vec: Vec<RetrieveObject> = Vec::new();
+    let mut breakdown: Vec<CollectionBreakdown> = Vec::new();
+    let mut payloads = Payloads::new();
+    let mut origins = PointOrigins::new();
+    let mut set: HashSet<String> = HashSet::new();
+    for (idx, qdrant_config) in qdrant_config_vec.iter().enumerate() {
+        let collection_progress = progress.map(|progress| CollectionProgress {
+            progress,
+            collection_name: &qdrant_config.collection_name,
+            collection_index: idx,
+            collections: qdrant_config_vec.len(),
+        });
+        let retrieval = retrieve_context_with_single_qdrant_config(
+            chat_request,
+            qdrant_config,
+            recency_config,
+            rerank_config,
+            collection_progress,
+        );
+        let (mut retrieve_object, collection_payloads, mut collection_origins) = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), retrieval).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    // log
+                    warn!(target: "stdout", "The retrieval time budget is exceeded while retrieving from the collection `{}`. Proceed with the context retrieved from {} of {} collection(s).", qdrant_config.collection_name, idx, qdrant_config_vec.len());
+
+                    break;
+                }
+            },
+            None => retrieval.await?,
+        };
+
+        if let Some(points) = retrieve_object.points.as_mut() {
+            if !points.is_empty() {
+                // find the duplicate points
+                let mut idx_removed = vec![];
+                for (idx, point) in points.iter().enumerate() {
+                    if set.contains(&point.source) {
+                        idx_removed.push(idx);
+                    } else {
+                        set.insert(point.source.clone());
+                    }
+                }
+
+                // remove the duplicate points
+                if !idx_removed.is_empty() {
+                    let num = idx_removed.len();
+
+                    for idx in idx_removed.iter().rev() {
+                        points.remove(*idx);
+                    }
+
+                    info!(target: "stdout", "removed duplicated {} point(s) retrieved from the collection `{}`", num, qdrant_config.collection_name);
+                }
+            }
+        }
+
+        // count the points contributed by the collection
+        let points = retrieve_object.points.as_deref().unwrap_or_default();
+        breakdown.push(CollectionBreakdown {
+            collection_name: qdrant_config.collection_name.clone(),
+            count: points.len(),
+            top_score: points.iter().map(|point| point.score).reduce(f32::max),
+        });
+
+        // keep the payloads and the origins of the points left after the deduplication
+        for point in points {
+            if let Some(payload) = collection_payloads.get(&point.source) {
+                payloads
+                    .entry(point.source.clone())
+                    .or_insert_with(|| payload.clone());
+            }
+            if let Some(origin) = collection_origins.remove(&point.source) {
+                origins.entry(point.source.clone()).or_insert(origin);
+            }
+        }
+
+        if !points.is_empty() {
+            retrieve_object_vec.push(retrieve_object);
+        }
+    }
+
+    // log
+    debug!(target: "stdout", "retrieval breakdown: {}", serde_json::to_string(&breakdown).unwrap_or_default());
+
+    Ok(Retrieval {
+        retrieve_object_vec,
+        breakdown,
+        payloads,
+        origins,
+    })
+}
+
+/// Select the collections to search by the similarity of the query to their centroids if `--auto-collection-routing` is set, otherwise return all the collections.
+///
+/// The top-K collections by similarity are selected, and the collections without a centroid, such as the empty ones, are always kept. The order of the collections is preserved.
+async fn route_collections(
+    chat_request: &ChatCompletionRequest,
+    qdrant_config_vec: &[QdrantConfig],
+) -> Vec<QdrantConfig> {
+    let k = match AUTO_COLLECTION_ROUTING.get() {
+        Some(k) if qdrant_config_vec.len() as u64 > *k => *k as usize,
+        _ => return qdrant_config_vec.to_vec(),
+    };
+
+    // the query without the paraphrases of the multi-query retrieval
+    let context_window = chat_request
+        .context_window
+        .or_else(|| CONTEXT_WINDOW.get().copied())
+        .unwrap_or(1);
+    let query_text = match retrieval_query_text(chat_request, context_window) {
+        Ok(query_text) => query_text,
+        Err(_) => return qdrant_config_vec.to_vec(),
+    };
+
+    let vdb_api_key = chat_request
+        .vdb_api_key
+        .clone()
+        .or_else(qdrant::default_api_key);
+
+    // the similarity of the query to the centroid of each collection. The query is embedded once per embedding model and query prefix
+    let mut query_embeddings: HashMap<(String, String), Option<Vec<f32>>> = HashMap::new();
+    let mut similarities: Vec<Option<f32>> = Vec::with_capacity(qdrant_config_vec.len());
+    for qdrant_config in qdrant_config_vec {
+        let centroid = routing::centroid(
+            &qdrant_config.url,
+            vdb_api_key.as_deref(),
+            &qdrant_config.collection_name,
+        )
+        .await;
+        let model = embedding_model_for_collection(&qdrant_config.collection_name)
+            .ok()
+            .map(|model| {
+                let query_prefix = query_prefix_for_collection(&qdrant_config.collection_name);
+                (model, query_prefix.to_string())
+            });
+
+        let similarity = match (centroid, model) {
+            (Some(centroid), Some(key)) => {
+                if !query_embeddings.contains_key(&key) {
+                    let (model, query_prefix) = &key;
+                    let embedding_request = EmbeddingRequest {
+                        model: Some(model.clone()),
+                        input: InputText::String(format!("{}{}", query_prefix, query_text)),
+                        encoding_format: None,
+                        user: chat_request.user.clone(),
+                        vdb_server_url: None,
+                        vdb_collection_name: None,
+                        vdb_api_key: None,
+                    };
+                    let query_embedding = match embeddings(&embedding_request).await {
+                        Ok(response) => response.data.first().and_then(|embedding| {
+                            project_embedding(
+                                embedding.embedding.iter().map(|x| *x as f32).collect(),
+                            )
+                            .ok()
+                        }),
+                        Err(e) => {
+                            // log
+                            warn!(target: "stdout", "Failed to compute the query embedding for the collection routing. {}", e);
+
+                            None
+                        }
+                    };
+                    query_embeddings.insert(key.clone(), query_embedding);
+                }
+
+                query_embeddings[&key]
+                    .as_deref()
+                    .and_then(|query_embedding| {
+                        routing::cosine_similarity(query_embedding, &centroid)
+                    })
+            }
+            _ => None,
+        };
+        similarities.push(similarity);
+    }
+
+    // the similarity of the k-th most similar collection
+    let mut ranked: Vec<f32> = similarities.iter().flatten().copied().collect();
+    ranked.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    let cutoff = ranked.get(k - 1).copied().unwrap_or(f32::MIN);
+
+    let mut selected = Vec::new();
+    let mut num_ranked_selected = 0;
+    for (qdrant_config, similarity) in qdrant_config_vec.iter().zip(similarities.iter()) {
+        let keep = match similarity {
+            Some(similarity) if *similarity >= cutoff && num_ranked_selected < k => {
+                num_ranked_selected += 1;
+                true
+            }
+            Some(_) => false,
+            None => true,
+        };
+
+        // log
+        debug!(target: "stdout", "collection routing: `{}`, similarity: {:?}, searched: {}", qdrant_config.collection_name, similarity, keep);
+
+        if keep {
+            selected.push(qdrant_config.clone());
+        }
+    }
+
+    // log
+    info!(target: "stdout", "Search {} of {} collection(s) selected by the collection routing: {}", selected.len(), qdrant_config_vec.len(), selected.iter().map(|c| c.collection_name.as_str()).collect::<Vec<_>>().join(", "));
+
+    selected
+}
+
+/// The payloads of the retrieved points, keyed by the source of the point.
+type Payloads = HashMap<String, serde_json::Map<String, serde_json::Value>>;
+
+/// Sends the `retrieval_progress` events of `--stream-retrieval-progress`. The events end when it is dropped.
+struct RetrievalProgress {
+    sender: mpsc::UnboundedSender<String>,
+}
+
+/// The progress of the search of a collection.
+struct CollectionProgress<'a> {
+    progress: &'a RetrievalProgress,
+    collection_name: &'a str,
+    collection_index: usize,
+    collections: usize,
+}
+impl CollectionProgress<'_> {
+    /// Send the event of a completed query with the number of points found.
+    fn query_completed(&self, query_index: usize, queries: usize, points: usize) {
+        let event = serde_json::json!({
+            "collection": self.collection_name,
+            "collection_index": self.collection_index,
+            "collections": self.collections,
+            "query_index": query_index,
+            "queries": queries,
+            "points": points,
+        });
+
+        // the receiver is gone if the client closed the connection
+        let _ = self
+            .progress
+            .sender
+            .unbounded_send(format!("event: retrieval_progress\ndata: {}\n\n", event));
+    }
+}
+
+/// The collection and the id of the retrieved points, keyed by the source of the point.
+type PointOrigins = HashMap<String, PointOrigin>;
+
+/// The collection and the id of a retrieved point.
+#[derive(Debug, Clone)]
+struct PointOrigin {
+    collection_name: String,
+    point_id: serde_json::Value,
+}
+
+/// The points retrieved from multiple collections.
+struct Retrieval {
+    retrieve_object_vec: Vec<RetrieveObject>,
+    /// The contribution of each collection
+    breakdown: Vec<CollectionBreakdown>,
+    payloads: Payloads,
+    origins: PointOrigins,
+}
+
+/// A retrieved point merged into the prompt, returned in the `sources` array of the chat completion response if the `return_sources` field of the request is `true`.
+#[derive(Debug, serde::Serialize)]
+struct RetrievedSource {
+    text: String,
+    score: f32,
+    /// The collection of the point, `null` for the keyword search hits
+    collection: Option<String>,
+    /// The id of the point, `null` for the keyword search hits
+    point_id: serde_json::Value,
+}
+
+/// The retrieved points in the order they are merged into the prompt, with their collection and id.
+fn retrieved_sources(
+    retrieve_object_vec: &[RetrieveObject],
+    origins: &PointOrigins,
+) -> Vec<RetrievedSource> {
+    retrieve_object_vec
+        .iter()
+        .flat_map(|retrieve_object| retrieve_object.points.iter().flatten())
+        .map(|point| {
+            let origin = origins.get(&point.source);
+            RetrievedSource {
+                text: point.source.clone(),
+                score: point.score,
+                collection: origin.map(|origin| origin.collection_name.clone()),
+                point_id: origin
+                    .map(|origin| origin.point_id.clone())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Send the retrieved sources as a `sources` event right before `data: [DONE]`. The OpenAI-compatible clients skip the named events.
+fn with_sources_event(
+    stream: impl Stream<Item = Result<String, String>>,
+    sources: &[RetrievedSource],
+) -> impl Stream<Item = Result<String, String>> {
+    let event = format!(
+        "event: sources\ndata: {}\n\n",
+        serde_json::json!({ "sources": sources })
+    );
+
+    stream.map_ok(move |data| match data.starts_with("data: [DONE]") {
+        true => format!("{}{}", event, data),
+        false => data,
+    })
+}
+
+/// Number of points contributed by a collection to the merged retrieval result after deduplication, and the top score among them.
+#[derive(Debug, serde::Serialize)]
+struct CollectionBreakdown {
+    collection_name: String,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_score: Option<f32>,
+}
+
+#[derive(Debug, Default)]
+struct RagPromptBuilder;
+impl RagPromptBuilder {
+    /// Append the RAG context as a tool message after the last user message, for `--rag-context-message tool`.
+    fn append_tool_message(
+        messages: &mut Vec<ChatCompletionRequestMessage>,
+        context: &str,
+    ) -> ChatPromptsError::Result<()> {
+        if !matches!(messages.last(), Some(ChatCompletionRequestMessage::User(_))) {
+            let err_msg = "The last message in the chat request should be a user message.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ChatPromptsError::PromptError::BadMessages(err_msg.into()));
+        }
+
+        info!(target: "stdout", "Append RAG context as tool message.");
+
+        let context = context.trim_end();
+        let content = match GLOBAL_RAG_PROMPT.get() {
+            Some(global_rag_prompt) => format!("{global_rag_prompt}\n{context}"),
+            None => context.to_string(),
+        };
+
+        // log
+        info!(target: "stdout", "tool message with RAG context: {}", &content);
+
+        messages.push(ChatCompletionRequestMessage::new_tool_message(
+            content, None,
+        ));
+
+        Ok(())
+    }
+}
+impl MergeRagContext for RagPromptBuilder {
+    fn build(
+        messages: &mut Vec<endpoints::chat::ChatCompletionRequestMessage>,
+        context: &[String],
+        has_system_prompt: bool,
+        policy: MergeRagContextPolicy,
+    ) -> ChatPromptsError::Result<()> {
+        if messages.is_empty() {
+            error!(target: "stdout", "No message in the chat request.");
+
+            return Err(ChatPromptsError::PromptError::NoMessages);
+        }
+
+        if context.is_empty() {
+            let err_msg = "No context provided.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ChatPromptsError::PromptError::Operation(err_msg.into()));
+        }
+
+        if policy == MergeRagContextPolicy::SystemMessage && !has_system_prompt {
+            let err_msg = "The chat model does not support system message, while the given rag policy by '--policy' option requires that the RAG context is merged into system message. Please check the relevant CLI options and try again.";
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ChatPromptsError::PromptError::Operation(err_msg.into()));
+        }
+        info!(target: "stdout", "rag_policy: {}", &policy);
+
+        let context = context[0].trim_end();
+        info!(target: "stdout", "context:\n{}", context);
+
+        match policy {
+            MergeRagContextPolicy::SystemMessage => {
+                info!(target: "stdout", "Merge RAG context into system message.");
+
+                match &messages[0] {
+                    ChatCompletionRequestMessage::System(message) => {
+                        let system_message = {
+                            match GLOBAL_RAG_PROMPT.get() {
+                                Some(global_rag_prompt) => {
+                                    // compose new system message content
+                                    let content = format!(
+                                        "{system_message}\n{rag_prompt}\n{context}",
+                                        system_message = message.content().trim(),
+                                        rag_prompt = global_rag_prompt.to_owned(),
+                                        context = context
+                                    );
+
+                                    // log
+                                    info!(target: "stdout", "system message with RAG context: {}", &content);
+
+                                    // create system message
+                                    ChatCompletionRequestMessage::new_system_message(
+                                        content,
+                                        message.name().cloned(),
+                                    )
+                                }
+                                None => {
+                                    // compose new system message content
+                                    let content = format!(
+                                        "{system_message}\n{context}",
+                                        system_message = message.content().trim(),
+                                        context = context
+                                    );
+
+                                    // log
+                                    info!(target: "stdout", "system message with RAG context: {}", &content);
+
+                                    // create system message
+                                    ChatCompletionRequestMessage::new_system_message(
+                                        content,
+                                        message.name().cloned(),
+                                    )
+                                }
+                            }
+                        };
+
+                        // replace the original system message
+                        messages[0] = system_message;
+                    }
+                    _ => {
+                        let system_message = match GLOBAL_RAG_PROMPT.get() {
+                            Some(global_rag_prompt) => {
+                                // compose new system message content
+                                let content = format!(
+                                    "{rag_prompt}\n{context}",
+                                    rag_prompt = global_rag_prompt.to_owned(),
+                                    context = context
+                                );
+
+                                // log
+                                info!(target: "stdout", "system message with RAG context: {}", &content);
+
+                                // create system message
+                                ChatCompletionRequestMessage::new_system_message(content, None)
+                            }
+                            None => {
+                                // compose new system message content
+                                let content = context.to_string();
+
+                                // log
+                                info!(target: "stdout", "system message with RAG context: {}", &content);
+
+                                // create system message
+                                ChatCompletionRequestMessage::new_system_message(content, None)
+                            }
+                        };
+
+                        // insert system message
+                        messages.insert(0, system_message);
+                    }
+                }
+            }
+            MergeRagContextPolicy::LastUserMessage => {
+                info!(target: "stdout", "Merge RAG context into last user message.");
+
+                let len = messages.len();
+                match &messages.last() {
+                    Some(ChatCompletionRequestMessage::User(message)) => {
+                        if let ChatCompletionUserMessageContent::Text(content) = message.content() {
+                            // compose new user message content
+                            let content = format!(
+                                    "{context}\nAnswer the question based on the pieces of context above. The question is:\n{user_message}",
+                                    context = context,
+                                    user_message = content.trim(),
+                                );
+
+                            // log
+                            info!(target: "stdout", "last user message with RAG context: {}", &content);
+
+                            let content = ChatCompletionUserMessageContent::Text(content);
+
+                            // create user message
+                            let user_message = ChatCompletionRequestMessage::new_user_message(
+                                content,
+                                message.name().cloned(),
+                            );
+                            // replace the original user message
+                            messages[len - 1] = user_message;
+                        }
+                    }
+                    _ => {
+                        let err_msg =
+                            "The last message in the chat request should be a user message.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(ChatPromptsError::PromptError::BadMessages(err_msg.into()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Upload, download, retrieve and delete a file, or list all files.
+///
+/// - `POST /v1/files`: Upload a file.
+/// - `GET /v1/files`: List all files.
+/// - `GET /v1/files/{file_id}`: Retrieve a file by id.
+/// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
+/// - `GET /v1/files/download/{file_id}`: Download a file by id.
+/// - `DELETE /v1/files/{file_id}`: Delete a file by id.
+///
+pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming files request");
+
+    let res = if req.method() == Method::POST {
+        let boundary = "boundary=";
+
+        let boundary = req.headers().get("content-type").and_then(|ct| {
+            let ct = ct.to_str().ok()?;
+            let idx = ct.find(boundary)?;
+            Some(ct[idx + boundary.len()..].to_string())
+        });
+
+        let req_body = req.into_body();
+        let body_bytes = match to_bytes(req_body).await {
+            Ok(body_bytes) => body_bytes,
+            Err(e) => {
+                let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        let cursor = Cursor::new(body_bytes.to_vec());
+
+        let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
+
+        let mut file_object: Option<FileObject> = None;
+        while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
+            if &*field.headers.name == "file" {
+                let filename = match field.headers.filename {
+                    Some(filename) => filename,
+                    None => {
+                        let err_msg =
+                            "Failed to upload the target file. The filename is not provided.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+
+                if !((filename).to_lowercase().ends_with(".txt")
+                    || (filename).to_lowercase().ends_with(".md")
+                    || (filename).to_lowercase().ends_with(".png")
+                    || (filename).to_lowercase().ends_with(".wav"))
+                {
+                    let err_msg = format!(
+                        "Failed to upload the target file. Only files with 'txt', 'md', 'png', 'wav' extensions are supported. The file to be uploaded is {}.",
+                        &filename
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+
+                let mut buffer = Vec::new();
+                let size_in_bytes = match field.data.read_to_end(&mut buffer) {
+                    Ok(size_in_bytes) => size_in_bytes,
+                    Err(e) => {
+                        let err_msg = format!("Failed to read the target file. {}", e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+
+                // create a unique file id
+                let id = format!("file_{}", uuid::Uuid::new_v4());
+
+                // save the file
+                let path = Path::new("archives");
+                if !path.exists() {
+                    fs::create_dir(path).unwrap();
+                }
+                let file_path = path.join(&id);
+                if !file_path.exists() {
+                    fs::create_dir(&file_path).unwrap();
+                }
+                let mut file = match File::create(file_path.join(&filename)) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        let err_msg =
+                            format!("Failed to create archive document {}. {}", &filename, e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+                file.write_all(&buffer[..]).unwrap();
+
+                // log
+                info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
+
+                let created_at = match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+                    Ok(n) => n.as_secs(),
+                    Err(_) => {
+                        let err_msg = "Failed to get the current time.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+
+                // create a file object
+                file_object = Some(FileObject {
+                    id,
+                    bytes: size_in_bytes as u64,
+                    created_at,
+                    filename,
+                    object: "file".to_string(),
+                    purpose: "assistants".to_string(),
+                });
+
+                break;
+            }
+        }
+
+        match file_object {
+            Some(fo) => {
+                // serialize chat completion object
+                let s = match serde_json::to_string(&fo) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        let err_msg = format!("Failed to serialize file object. {}", e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                };
+
+                // return response
+                let result = Response::builder()
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Methods", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(s));
+
+                match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let err_msg = e.to_string();
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        error::internal_server_error(err_msg)
+                    }
+                }
+            }
+            None => {
+                let err_msg = "Failed to upload the target file. Not found the target file.";
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else if req.method() == Method::GET {
+        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
+
+        // Split the path into segments
+        let segments: Vec<&str> = uri_path.split('/').collect();
+
+        match segments.as_slice() {
+            ["", "v1", "files"] => list_files(),
+            ["", "v1", "files", file_id, "content"] => {
+                if !file_id.starts_with("file_") {
+                    let err_msg = format!("unsupported uri path: {}", uri_path);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+
+                retrieve_file_content(file_id)
+            }
+            ["", "v1", "files", file_id] => {
+                if !file_id.starts_with("file_") {
+                    let err_msg = format!("unsupported uri path: {}", uri_path);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+
+                retrieve_file(file_id)
+            }
+            ["", "v1", "files", "download", file_id] => download_file(file_id),
+            _ => {
+                let err_msg = format!("unsupported uri path: {}", uri_path);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else if req.method() == Method::DELETE {
+        let id = req.uri().path().trim_start_matches("/v1/files/");
+        let status = match llama_core::files::remove_file(id) {
+            Ok(status) => status,
+            Err(e) => {
+                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                DeleteFileStatus {
+                    id: id.into(),
+                    object: "file".to_string(),
+                    deleted: false,
+                }
+            }
+        };
+
+        // serialize status
+        let s = match serde_json::to_string(&status) {
+            Ok(s) => s,
+            Err(e) => {
+                let err_msg = format!(
+                    "Failed to serialize the status of the file deletion operation. {}",
+                    e
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        // return response
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::from(s));
+
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                error::internal_server_error(err_msg)
+            }
+        }
+    } else if req.method() == Method::OPTIONS {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    } else {
+        let err_msg = "Invalid HTTP Method.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        error::internal_server_error(err_msg)
+    };
+
+    info!(target: "stdout", "Send the files response");
+
+    res
+}
+
+fn list_files() -> Response<Body> {
+    match llama_core::files::list_files() {
+        Ok(file_objects) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&file_objects) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file list. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to list all files. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn retrieve_file(id: impl AsRef<str>) -> Response<Body> {
+    match llama_core::files::retrieve_file(id) {
+        Ok(fo) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&fo) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file object. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn retrieve_file_content(id: impl AsRef<str>) -> Response<Body> {
+    match llama_core::files::retrieve_file_content(id) {
+        Ok(content) => {
+            // serialize chat completion object
+            let s = match serde_json::to_string(&content) {
+                Ok(s) => s,
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize file content. {}", e);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+
+            // return response
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::from(s));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+fn download_file(id: impl AsRef<str>) -> Response<Body> {
+    match llama_core::files::download_file(id) {
+        Ok((filename, buffer)) => {
+            // get the extension of the file
+            let extension = filename.split('.').next_back().unwrap_or("unknown");
+            let content_type = match extension {
+                "txt" => "text/plain",
+                "json" => "application/json",
+                "png" => "image/png",
+                "jpg" => "image/jpeg",
+                "jpeg" => "image/jpeg",
+                "wav" => "audio/wav",
+                "mp3" => "audio/mpeg",
+                "mp4" => "video/mp4",
+                "md" => "text/markdown",
+                _ => {
+                    let err_msg = format!("Unsupported file extension: {}", extension);
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return error::internal_server_error(err_msg);
+                }
+            };
+            let content_disposition = format!("attachment; filename={}", filename);
+
+            // return response
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", content_type)
+                .header("Content-Disposition", content_disposition)
+                .body(Body::from(buffer));
+
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("{}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+pub(crate) async fn chunks_handler(mut req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming chunks request");
+
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
+
+    // parse request
+    let body_bytes = match to_bytes(req.body_mut()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    let chunks_request: ChunksRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(chunks_request) => chunks_request,
+        Err(e) => {
+            let mut err_msg = format!("Fail to deserialize chunks request: {}.", e);
+
+            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
+            }
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
+
+    // check if the archives directory exists
+    let path = Path::new("archives");
+    if !path.exists() {
+        let err_msg = "The `archives` directory does not exist.";
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    // check if the archive id exists
+    let archive_path = path.join(&chunks_request.id);
+    if !archive_path.exists() {
+        let err_msg = format!("Not found archive id: {}", &chunks_request.id);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    // check if the file exists
+    let file_path = archive_path.join(&chunks_request.filename);
+    if !file_path.exists() {
+        let err_msg = format!(
+            "Not found file: {} in archive id: {}",
+            &chunks_request.filename, &chunks_request.id
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    // log
+    info!(target: "stdout", "file_id: {}, file_name: {}", &chunks_request.id, &chunks_request.filename);
+
+    // open the file
+    let mut file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) => {
+            let err_msg = format!("Failed to open `{}`. {}", &chunks_request.filename, e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // read the file
+    let mut contents = String::new();
+    if let Err(e) = file.read_to_string(&mut contents) {
+        let err_msg = format!("Failed to read `{}`. {}", &chunks_request.filename, e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return error::internal_server_error(err_msg);
+    }
+
+    // select the chunking strategy by the extension of the file, and then by the contents
+    let strategy = ChunkStrategy::from_filename(&chunks_request.filename)
+        .unwrap_or_else(|| ChunkStrategy::sniff(&contents));
+
+    let res = match chunk_text(&contents, strategy, chunks_request.chunk_capacity) {
+        Ok(chunks) => {
+            let chunks_response = ChunksResponse {
+                id: chunks_request.id,
+                filename: chunks_request.filename,
+                chunks,
+            };
+
+            // serialize embedding object
+            match serde_json::to_string(&chunks_response) {
+                Ok(s) => {
+                    // return response
+                    let result = Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(s));
+                    match result {
+                        Ok(response) => response,
+                        Err(e) => {
+                            let err_msg = e.to_string();
 
                             // log
-                            info!(target: "stdout", "last user message with RAG context: {}", &content);
-
-                            let content = ChatCompletionUserMessageContent::Text(content);
+                            error!(target: "stdout", "{}", &err_msg);
 
-                            // create user message
-                            let user_message = ChatCompletionRequestMessage::new_user_message(
-                                content,
-                                message.name().cloned(),
-                            );
-                            // replace the original user message
-                            messages[len - 1] = user_message;
+                            error::internal_server_error(err_msg)
                         }
                     }
-                    _ => {
-                        let err_msg =
-                            "The last message in the chat request should be a user message.";
+                }
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        return Err(ChatPromptsError::PromptError::BadMessages(err_msg.into()));
-                    }
+                    error::internal_server_error(err_msg)
                 }
             }
         }
+        Err(e) => {
+            let err_msg = format!("Fail to serialize chunks response. {}", e);
 
-        Ok(())
-    }
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    };
+
+    info!(target: "stdout", "Send the chunks response.");
+
+    res
 }
 
-/// Upload, download, retrieve and delete a file, or list all files.
-///
-/// - `POST /v1/files`: Upload a file.
-/// - `GET /v1/files`: List all files.
-/// - `GET /v1/files/{file_id}`: Retrieve a file by id.
-/// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
-/// - `GET /v1/files/download/{file_id}`: Download a file by id.
-/// - `DELETE /v1/files/{file_id}`: Delete a file by id.
-///
-pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
+pub(crate) async fn create_rag_handler(req: Request<Body>) -> Response<Body> {
     // log
-    info!(target: "stdout", "Handling the coming files request");
+    info!(target: "stdout", "Handling the coming doc_to_embeddings request.");
 
-    let res = if req.method() == Method::POST {
+    // the collection scope of the API key of the request
+    let collection_scope = req.extensions().get::<CollectionScope>().cloned();
+
+    // the chunking strategy specified in the request
+    let mut strategy: Option<ChunkStrategy> = None;
+
+    // upload the target rag document
+    let (file_object, vdb_server_url, vdb_collection_name, vdb_api_key, kw_search_url) = if req
+        .method()
+        == Method::POST
+    {
         let boundary = "boundary=";
 
         let boundary = req.headers().get("content-type").and_then(|ct| {
@@ -1037,43 +3996,175 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
 
         let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
 
-        let mut file_object: Option<FileObject> = None;
+        let mut upload: Option<(String, Vec<u8>)> = None;
+        let mut vdb_server_url: String = String::new();
+        let mut vdb_collection_name: String = String::new();
+        let mut vdb_api_key: String = String::new();
+        let mut kw_search_url = String::new();
         while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-            if &*field.headers.name == "file" {
-                let filename = match field.headers.filename {
-                    Some(filename) => filename,
-                    None => {
+            match &*field.headers.name {
+                "file" => {
+                    let filename = match field.headers.filename {
+                        Some(filename) => filename,
+                        None => {
+                            let err_msg =
+                                "Failed to upload the target file. The filename is not provided.";
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+                    };
+
+                    info!(target: "stdout", "filename: {}", &filename);
+
+                    let mut buffer = Vec::new();
+                    if let Err(e) = field.data.read_to_end(&mut buffer) {
+                        let err_msg = format!("Failed to read the target file. {}", e);
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+
+                    // the file is archived once the request is checked, so that a rejected request leaves no file behind
+                    upload = Some((filename, buffer));
+                }
+                "vdb_server_url" => match field.is_text() {
+                    true => {
+                        if let Err(e) = field.data.read_to_string(&mut vdb_server_url) {
+                            let err_msg =
+                                format!("Failed to read the `vdb_server_url` field. {}", e);
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+                    }
+                    false => {
                         let err_msg =
-                            "Failed to upload the target file. The filename is not provided.";
+                        "Failed to get `vdb_server_url`. The `vdb_server_url` field in the request should be a text field.";
 
                         // log
                         error!(target: "stdout", "{}", &err_msg);
 
                         return error::internal_server_error(err_msg);
                     }
-                };
+                },
+                "vdb_collection_name" => match field.is_text() {
+                    true => {
+                        if let Err(e) = field.data.read_to_string(&mut vdb_collection_name) {
+                            let err_msg =
+                                format!("Failed to read the `vdb_collection_name` field. {}", e);
 
-                if !((filename).to_lowercase().ends_with(".txt")
-                    || (filename).to_lowercase().ends_with(".md")
-                    || (filename).to_lowercase().ends_with(".png")
-                    || (filename).to_lowercase().ends_with(".wav"))
-                {
-                    let err_msg = format!(
-                        "Failed to upload the target file. Only files with 'txt', 'md', 'png', 'wav' extensions are supported. The file to be uploaded is {}.",
-                        &filename
-                    );
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+                    }
+                    false => {
+                        let err_msg = "Failed to get `vdb_collection_name`. The `vdb_collection_name` field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                },
+                "vdb_api_key" => match field.is_text() {
+                    true => {
+                        if let Err(e) = field.data.read_to_string(&mut vdb_api_key) {
+                            let err_msg = format!("Failed to read the `vdb_api_key` field. {}", e);
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+                    }
+                    false => {
+                        let err_msg = "Failed to get `vdb_api_key`. The `vdb_api_key` field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                },
+                "kw_search_url" => match field.is_text() {
+                    true => {
+                        if let Err(e) = field.data.read_to_string(&mut kw_search_url) {
+                            let err_msg =
+                                format!("Failed to read the `kw_search_url` field. {}", e);
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+                    }
+                    false => {
+                        let err_msg = "Failed to get `kw_search_url`. The `kw_search_url` field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                },
+                "strategy" => match field.is_text() {
+                    true => {
+                        let mut value = String::new();
+                        if let Err(e) = field.data.read_to_string(&mut value) {
+                            let err_msg = format!("Failed to read the `strategy` field. {}", e);
+
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
+
+                            return error::internal_server_error(err_msg);
+                        }
+
+                        match value.parse::<ChunkStrategy>() {
+                            Ok(value) => strategy = Some(value),
+                            Err(err_msg) => {
+                                // log
+                                error!(target: "stdout", "{}", &err_msg);
+
+                                return error::bad_request(err_msg);
+                            }
+                        }
+                    }
+                    false => {
+                        let err_msg = "Failed to get `strategy`. The `strategy` field in the request should be a text field.";
+
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return error::internal_server_error(err_msg);
+                    }
+                },
+                _ => {
+                    let err_msg = format!("Invalid field name: {}", &field.headers.name);
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
                     return error::internal_server_error(err_msg);
                 }
+            }
+        }
 
-                let mut buffer = Vec::new();
-                let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                    Ok(size_in_bytes) => size_in_bytes,
-                    Err(e) => {
-                        let err_msg = format!("Failed to read the target file. {}", e);
+        // If the request does not provide the vdb_server_url and vdb_collection_name, use the default vdb config from the server info, and use the default API key of the Qdrant instance if it is set.
+        match (vdb_server_url.is_empty(), vdb_collection_name.is_empty()) {
+            (true, true) => {
+                let qdrant_config_vec = match SERVER_INFO.get() {
+                    Some(server_info) => server_info.read().await.qdrant_config.clone(),
+                    None => {
+                        let err_msg = "The server info is not set.";
 
                         // log
                         error!(target: "stdout", "{}", &err_msg);
@@ -1082,6 +4173,59 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
                     }
                 };
 
+                // use the first qdrant config as the default config
+                vdb_server_url = qdrant_config_vec[0].url.clone();
+                vdb_collection_name = qdrant_config_vec[0].collection_name.clone();
+                if vdb_api_key.is_empty() {
+                    vdb_api_key = qdrant::default_api_key().unwrap_or_default();
+                }
+            }
+            (true, false) | (false, true) => {
+                let err_msg = "Failed to get `vdb_server_url` or `vdb_collection_name`. The `vdb_server_url` and `vdb_collection_name` fields in the request should be provided at the same time.";
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::internal_server_error(err_msg);
+            }
+            (false, false) => {}
+        }
+
+        // the `kw_search_url` field is only used if permitted by `--kw-search-url-allowlist`, otherwise the default kw_search_url from KW_SEARCH_CONFIG is used
+        kw_search_url = match select_kw_search_url(Some(&kw_search_url)) {
+            Ok(kw_search_url) => kw_search_url,
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::bad_request(err_msg);
+            }
+        };
+        if !kw_search_url.is_empty() {
+            kw_search_url = kw_search_url.trim_end_matches('/').to_string();
+            info!(target: "stdout", "kw_search_url: {}", &kw_search_url);
+        }
+
+        info!(target: "stdout", "vdb_server_url: {}, vdb_collection_name: {}", &vdb_server_url, &vdb_collection_name);
+
+        // check the collection against the collection scope of the API key
+        if let Some(collection_scope) = &collection_scope {
+            if !collection_scope.allows(&vdb_collection_name) {
+                let err_msg = format!(
+                    "The API key is not allowed to access the collection: {}.",
+                    vdb_collection_name
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return error::forbidden(err_msg);
+            }
+        }
+
+        // archive the uploaded file
+        let file_object = match upload {
+            Some((filename, buffer)) => {
                 // create a unique file id
                 let id = format!("file_{}", uuid::Uuid::new_v4());
 
@@ -1124,168 +4268,294 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
                 };
 
                 // create a file object
-                file_object = Some(FileObject {
+                FileObject {
                     id,
-                    bytes: size_in_bytes as u64,
+                    bytes: buffer.len() as u64,
                     created_at,
                     filename,
                     object: "file".to_string(),
                     purpose: "assistants".to_string(),
-                });
-
-                break;
+                }
             }
-        }
-
-        match file_object {
-            Some(fo) => {
-                // serialize chat completion object
-                let s = match serde_json::to_string(&fo) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        let err_msg = format!("Failed to serialize file object. {}", e);
+            None => {
+                let err_msg = "Failed to upload the target file. Not found the target file.";
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+                return error::internal_server_error(err_msg);
+            }
+        };
 
-                // return response
-                let result = Response::builder()
-                    .header("Access-Control-Allow-Origin", "*")
-                    .header("Access-Control-Allow-Methods", "*")
-                    .header("Access-Control-Allow-Headers", "*")
-                    .header("Content-Type", "application/json")
-                    .body(Body::from(s));
+        (
+            file_object,
+            vdb_server_url,
+            vdb_collection_name,
+            vdb_api_key,
+            kw_search_url,
+        )
+    } else if req.method() == Method::GET {
+        let err_msg = "Not implemented for listing files.";
 
-                match result {
-                    Ok(response) => response,
-                    Err(e) => {
-                        let err_msg = e.to_string();
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+        return error::internal_server_error(err_msg);
+    } else if req.method() == Method::OPTIONS {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
 
-                        error::internal_server_error(err_msg)
-                    }
-                }
-            }
-            None => {
-                let err_msg = "Failed to upload the target file. Not found the target file.";
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
+                return error::internal_server_error(err_msg);
             }
         }
-    } else if req.method() == Method::GET {
-        let uri_path = req.uri().path().trim_end_matches('/').to_lowercase();
+    } else {
+        let err_msg = "Invalid HTTP Method.";
 
-        // Split the path into segments
-        let segments: Vec<&str> = uri_path.split('/').collect();
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-        match segments.as_slice() {
-            ["", "v1", "files"] => list_files(),
-            ["", "v1", "files", file_id, "content"] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
+        return error::internal_server_error(err_msg);
+    };
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    // chunk the text
+    let (chunks, strategy) = {
+        info!(target: "stdout", "file_id: {}, file_name: {}", &file_object.id, &file_object.filename);
 
-                    return error::internal_server_error(err_msg);
-                }
+        // check if the archives directory exists
+        let path = Path::new("archives");
+        if !path.exists() {
+            let err_msg = "The `archives` directory does not exist.";
 
-                retrieve_file_content(file_id)
-            }
-            ["", "v1", "files", file_id] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            return error::internal_server_error(err_msg);
+        }
 
-                    return error::internal_server_error(err_msg);
-                }
+        // check if the archive id exists
+        let archive_path = path.join(&file_object.id);
+        if !archive_path.exists() {
+            let err_msg = format!("Not found archive id: {}", &file_object.id);
 
-                retrieve_file(file_id)
-            }
-            ["", "v1", "files", "download", file_id] => download_file(file_id),
-            _ => {
-                let err_msg = format!("unsupported uri path: {}", uri_path);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+
+        // check if the file exists
+        let file_path = archive_path.join(&file_object.filename);
+        if !file_path.exists() {
+            let err_msg = format!(
+                "Not found file: {} in archive id: {}",
+                &file_object.filename, &file_object.id
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+
+        info!(target: "stdout", "Open and read the file.");
+
+        // open the file
+        let mut file = match File::open(&file_path) {
+            Ok(file) => file,
+            Err(e) => {
+                let err_msg = format!("Failed to open `{}`. {}", &file_object.filename, e);
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
+                return error::internal_server_error(err_msg);
             }
+        };
+
+        // read the file
+        let mut contents = String::new();
+        if let Err(e) = file.read_to_string(&mut contents) {
+            let err_msg = format!("Failed to read `{}`. {}", &file_object.filename, e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
         }
-    } else if req.method() == Method::DELETE {
-        let id = req.uri().path().trim_start_matches("/v1/files/");
-        let status = match llama_core::files::remove_file(id) {
-            Ok(status) => status,
-            Err(e) => {
-                let err_msg = format!("Failed to delete the target file with id {}. {}", id, e);
+
+        // select the chunking strategy: the `strategy` field in the request is prioritized, then the extension of the file, and then the contents
+        let strategy = strategy
+            .or_else(|| ChunkStrategy::from_filename(&file_object.filename))
+            .unwrap_or_else(|| ChunkStrategy::sniff(&contents));
+
+        // the chunk capacity of the target collection
+        let chunk_capacity = match CHUNK_CAPACITY.get() {
+            Some(chunk_capacity) => chunk_capacity.capacity_for(&vdb_collection_name),
+            None => {
+                let err_msg = "CHUNK_CAPACITY is not initialized.";
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                DeleteFileStatus {
-                    id: id.into(),
-                    object: "file".to_string(),
-                    deleted: false,
-                }
+                return error::internal_server_error(err_msg);
             }
         };
 
-        // serialize status
-        let s = match serde_json::to_string(&status) {
-            Ok(s) => s,
+        info!(target: "stdout", "Chunk the file contents with the chunk capacity {}.", chunk_capacity);
+
+        match chunk_text(&contents, strategy, chunk_capacity) {
+            Ok(chunks) => (chunks, strategy),
             Err(e) => {
-                let err_msg = format!(
-                    "Failed to serialize the status of the file deletion operation. {}",
-                    e
-                );
+                let err_msg = e.to_string();
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
                 return error::internal_server_error(err_msg);
             }
-        };
+        }
+    };
 
-        // return response
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::from(s));
+    // create index for chunks
+    let mut index_response: Option<IndexResponse> = None;
+    if !kw_search_url.is_empty() {
+        let index_url = format!("{}/v1/index/create", &kw_search_url);
+        info!(target: "stdout", "index_url: {}", &index_url);
 
-        match result {
-            Ok(response) => response,
+        let mut index_request = IndexRequest { documents: vec![] };
+        for chunk in chunks.iter() {
+            let document_input = DocumentInput {
+                content: chunk.clone(),
+                title: None,
+            };
+            index_request.documents.push(document_input);
+        }
+
+        info!(target: "stdout", "Sending index request to kw-search-server");
+
+        if let Ok(response) = reqwest::Client::new()
+            .post(&index_url)
+            .json(&index_request)
+            .send()
+            .await
+        {
+            if let Ok(idx_response) = response.json::<IndexResponse>().await {
+                index_response = Some(idx_response);
+            }
+        }
+    }
+
+    // compute embeddings for chunks
+    let (embeddings_response, failed_chunks) = {
+        // get the name of the embedding model of the collection
+        let model = match embedding_model_for_collection(&vdb_collection_name) {
+            Ok(model) => model,
             Err(e) => {
                 let err_msg = e.to_string();
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
+                return error::internal_server_error(err_msg);
+            }
+        };
+
+        info!(target: "stdout", "Prepare the rag embedding request.");
+
+        // normalize the chunks
+        let form = UNICODE_NORMALIZATION.get().copied().unwrap_or_default();
+        let sources: Vec<String> = chunks
+            .iter()
+            .map(|chunk| normalize_unicode(chunk, form))
+            .collect();
+
+        // preprocess the source code chunks, while the payloads keep the original chunks
+        let code_preprocess = CODE_PREPROCESS.get().map(Vec::as_slice).unwrap_or_default();
+        let language = CodeLanguage::from_filename(&file_object.filename);
+        let chunks: Vec<String> = match !code_preprocess.is_empty()
+            && (strategy == ChunkStrategy::Code || language.is_some())
+        {
+            true => {
+                // log
+                info!(target: "stdout", "Preprocess the code chunks. language: {}, steps: {:?}", language.map(|l| l.to_string()).unwrap_or_else(|| "unknown".to_string()), code_preprocess);
+
+                sources
+                    .iter()
+                    .map(|chunk| preprocess_code(chunk, language, code_preprocess))
+                    .collect()
+            }
+            false => sources.clone(),
+        };
+
+        // prepend the passage prefix of the collection, while the payloads keep the chunks without it
+        let passage_prefix = passage_prefix_for_collection(&vdb_collection_name);
+        let chunks: Vec<String> = match passage_prefix.is_empty() {
+            true => chunks,
+            false => {
+                // log
+                info!(target: "stdout", "passage prefix: {:?}", passage_prefix);
+
+                chunks
+                    .into_iter()
+                    .map(|chunk| format!("{}{}", passage_prefix, chunk))
+                    .collect()
+            }
+        };
+
+        let api_key = match vdb_api_key.is_empty() {
+            true => None,
+            false => Some(vdb_api_key),
+        };
+
+        // create an embedding request
+        let embedding_request = EmbeddingRequest {
+            model: Some(model),
+            input: chunks.into(),
+            encoding_format: None,
+            user: None,
+            vdb_server_url: Some(vdb_server_url),
+            vdb_collection_name: Some(vdb_collection_name),
+            vdb_api_key: api_key,
+        };
+
+        match doc_chunks_to_embeddings(&embedding_request, &sources).await {
+            Ok(result) => {
+                // recompute the centroid of the collection for the collection routing
+                if AUTO_COLLECTION_ROUTING.get().is_some() {
+                    if let (Some(url), Some(collection_name)) = (
+                        embedding_request.vdb_server_url,
+                        embedding_request.vdb_collection_name,
+                    ) {
+                        let api_key = embedding_request.vdb_api_key;
+                        tokio::spawn(logger::in_request_scope(async move {
+                            if let Err(e) = routing::refresh_centroid(
+                                &url,
+                                api_key.as_deref(),
+                                &collection_name,
+                            )
+                            .await
+                            {
+                                // log
+                                warn!(target: "stdout", "Failed to recompute the centroid of the collection `{}`. {}", collection_name, e);
+                            }
+                        }));
+                    }
+                }
+
+                result
             }
-        }
-    } else if req.method() == Method::OPTIONS {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return response,
             Err(e) => {
                 let err_msg = e.to_string();
 
@@ -1295,36 +4565,31 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
                 return error::internal_server_error(err_msg);
             }
         }
-    } else {
-        let err_msg = "Invalid HTTP Method.";
-
-        // log
-        error!(target: "stdout", "{}", &err_msg);
-
-        error::internal_server_error(err_msg)
     };
 
-    info!(target: "stdout", "Send the files response");
-
-    res
-}
-
-fn list_files() -> Response<Body> {
-    match llama_core::files::list_files() {
-        Ok(file_objects) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&file_objects) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file list. {}", e);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
-            };
+    // create the create rag response
+    let create_rag_response = if index_response.is_some() {
+        CreateRagResponse {
+            index_response,
+            embeddings_response,
+        }
+    } else {
+        CreateRagResponse {
+            index_response: None,
+            embeddings_response,
+        }
+    };
 
+    // serialize embedding response with the id of the archived file, the number of chunks, and the failed chunks if any
+    let res = match serde_json::to_value(&create_rag_response).and_then(|mut value| {
+        value["file_id"] = serde_json::Value::from(file_object.id.clone());
+        value["chunk_count"] = serde_json::Value::from(chunks.len());
+        if !failed_chunks.is_empty() {
+            value["failed_chunks"] = serde_json::to_value(&failed_chunks)?;
+        }
+        serde_json::to_string(&value)
+    }) {
+        Ok(s) => {
             // return response
             let result = Response::builder()
                 .header("Access-Control-Allow-Origin", "*")
@@ -1332,7 +4597,6 @@ fn list_files() -> Response<Body> {
                 .header("Access-Control-Allow-Headers", "*")
                 .header("Content-Type", "application/json")
                 .body(Body::from(s));
-
             match result {
                 Ok(response) => response,
                 Err(e) => {
@@ -1346,282 +4610,401 @@ fn list_files() -> Response<Body> {
             }
         }
         Err(e) => {
-            let err_msg = format!("Failed to list all files. {}", e);
+            let err_msg = format!("Fail to serialize embedding object. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
             error::internal_server_error(err_msg)
         }
-    }
-}
+    };
 
-fn retrieve_file(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::retrieve_file(id) {
-        Ok(fo) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&fo) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file object. {}", e);
+    info!(target: "stdout", "Send the doc_to_embeddings response");
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    res
+}
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+/// A document chunk that failed to be embedded or upserted after all the retries.
+#[derive(Debug, serde::Serialize)]
+struct FailedChunk {
+    index: usize,
+    error: String,
+}
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
+/// Compute embeddings for the document chunks in the embedding request, and persist them into the target Qdrant collection. The `sources` are the texts stored in the payloads, one per chunk.
+///
+/// If the batch fails and `--ingestion-chunk-retries` is set, the chunks are retried one by one with exponential backoff. The chunks still failing after the retries fail the request, unless `--embeddings-partial-failure` is set, in which case they are returned alongside the embeddings of the ingested chunks.
+async fn doc_chunks_to_embeddings(
+    embedding_request: &EmbeddingRequest,
+    sources: &[String],
+) -> Result<(EmbeddingsResponse, Vec<FailedChunk>), error::ServerError> {
+    let retries = INGESTION_CHUNK_RETRIES.get().copied().unwrap_or(0);
+
+    let err_msg = match persist_chunk_embeddings(embedding_request, sources).await {
+        Ok(embeddings_response) => return Ok((embeddings_response, Vec::new())),
+        Err(e) if retries == 0 => return Err(e),
+        Err(e) => e.to_string(),
+    };
 
-            match result {
-                Ok(response) => response,
+    // log
+    warn!(target: "stdout", "Failed to ingest the document chunks in a batch. {}. Retry the chunks one by one.", err_msg);
+
+    let inputs = split_embedding_inputs(&embedding_request.input);
+    let num_chunks = inputs.len();
+
+    let mut embeddings_response: Option<EmbeddingsResponse> = None;
+    let mut failed_chunks = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let mut request = embedding_request.clone();
+        request.input = input;
+
+        let mut attempt = 0;
+        let result = loop {
+            match persist_chunk_embeddings(&request, &sources[index..=index]).await {
+                Ok(response) => break Ok(response),
+                Err(e) if attempt == retries => break Err(e.to_string()),
                 Err(e) => {
-                    let err_msg = e.to_string();
+                    let backoff = Duration::from_millis(100 * 2u64.saturating_pow(attempt as u32));
 
                     // log
-                    error!(target: "stdout", "{}", &err_msg);
+                    warn!(target: "stdout", "Failed to ingest the chunk {}. {}. Retry in {} ms.", index, e, backoff.as_millis());
 
-                    error::internal_server_error(err_msg)
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
                 }
             }
+        };
+
+        match result {
+            Ok(response) => {
+                let embeddings_response =
+                    embeddings_response.get_or_insert_with(|| EmbeddingsResponse {
+                        object: response.object,
+                        data: Vec::new(),
+                        model: response.model,
+                        usage: Default::default(),
+                    });
+                embeddings_response
+                    .data
+                    .extend(response.data.into_iter().map(|mut embedding_object| {
+                        embedding_object.index = index as u64;
+                        embedding_object
+                    }));
+                embeddings_response.usage.prompt_tokens += response.usage.prompt_tokens;
+                embeddings_response.usage.completion_tokens += response.usage.completion_tokens;
+                embeddings_response.usage.total_tokens += response.usage.total_tokens;
+            }
+            Err(err_msg) => {
+                // log
+                error!(target: "stdout", "Failed to ingest the chunk {} after {} retries. {}", index, retries, &err_msg);
+
+                failed_chunks.push(FailedChunk {
+                    index,
+                    error: err_msg,
+                });
+            }
         }
-        Err(e) => {
-            let err_msg = format!("{}", e);
+    }
+
+    let embeddings_response = match embeddings_response {
+        Some(embeddings_response) => embeddings_response,
+        None => {
+            let err_msg = format!("Failed to ingest all the {} chunks.", num_chunks);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            return Err(error::ServerError::Operation(err_msg));
         }
-    }
-}
+    };
 
-fn retrieve_file_content(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::retrieve_file_content(id) {
-        Ok(content) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&content) {
-                Ok(s) => s,
-                Err(e) => {
-                    let err_msg = format!("Failed to serialize file content. {}", e);
+    if !failed_chunks.is_empty() && !EMBEDDINGS_PARTIAL_FAILURE.get().copied().unwrap_or(false) {
+        let indices: Vec<String> = failed_chunks.iter().map(|c| c.index.to_string()).collect();
+        let err_msg = format!(
+            "Failed to ingest {} of {} chunks: {}. The other chunks are ingested.",
+            failed_chunks.len(),
+            num_chunks,
+            indices.join(", ")
+        );
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
+        return Err(error::ServerError::Operation(err_msg));
+    }
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
+    // log
+    info!(target: "stdout", "Ingested {} of {} chunks.", num_chunks - failed_chunks.len(), num_chunks);
 
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    Ok((embeddings_response, failed_chunks))
+}
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+/// Compute embeddings for the document chunks in the embedding request, and persist them into the target Qdrant collection with the `sources` in the payloads. The collection is created if it does not exist.
+async fn persist_chunk_embeddings(
+    embedding_request: &EmbeddingRequest,
+    sources: &[String],
+) -> Result<EmbeddingsResponse, error::ServerError> {
+    let (vdb_server_url, vdb_collection_name) = match (
+        embedding_request.vdb_server_url.as_deref(),
+        embedding_request.vdb_collection_name.as_deref(),
+    ) {
+        (Some(url), Some(collection_name)) => (url, collection_name),
+        _ => {
+            let err_msg = "The VectorDB server URL and collection name should be provided.";
 
-                    error::internal_server_error(err_msg)
-                }
-            }
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::ServerError::Operation(err_msg.into()));
         }
-        Err(e) => {
-            let err_msg = format!("{}", e);
+    };
+    let vdb_api_key = embedding_request.vdb_api_key.as_deref();
+
+    info!(target: "stdout", "Compute embeddings for document chunks.");
+
+    // compute embeddings for the document
+    let embeddings_response = embeddings(embedding_request)
+        .await
+        .map_err(|e| error::ServerError::Operation(e.to_string()))?;
+    let dim = match (embeddings_response.data.first(), EMBEDDING_PROJECTION.get()) {
+        (Some(_), Some(projection)) => projection.output_dim(),
+        (Some(embedding), None) => embedding.embedding.len(),
+        (None, _) => {
+            let err_msg = "No embeddings returned";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+            return Err(error::ServerError::Operation(err_msg.into()));
         }
+    };
+
+    // create the collection if it does not exist
+    if !qdrant::collection_exists(vdb_server_url, vdb_api_key, vdb_collection_name).await? {
+        qdrant::create_collection(vdb_server_url, vdb_api_key, vdb_collection_name, dim).await?;
     }
-}
 
-fn download_file(id: impl AsRef<str>) -> Response<Body> {
-    match llama_core::files::download_file(id) {
-        Ok((filename, buffer)) => {
-            // get the extension of the file
-            let extension = filename.split('.').next_back().unwrap_or("unknown");
-            let content_type = match extension {
-                "txt" => "text/plain",
-                "json" => "application/json",
-                "png" => "image/png",
-                "jpg" => "image/jpeg",
-                "jpeg" => "image/jpeg",
-                "wav" => "audio/wav",
-                "mp3" => "audio/mpeg",
-                "mp4" => "video/mp4",
-                "md" => "text/markdown",
-                _ => {
-                    let err_msg = format!("Unsupported file extension: {}", extension);
+    // create and upsert points
+    let mut points = Vec::with_capacity(embeddings_response.data.len());
+    for embedding in embeddings_response.data.iter() {
+        let mut payload = serde_json::Map::new();
+        payload.insert(
+            "source".to_string(),
+            serde_json::Value::from(sources[embedding.index as usize].clone()),
+        );
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+        let vector = project_embedding(embedding.embedding.iter().map(|x| *x as f32).collect())
+            .map_err(|err_msg| {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
-                }
-            };
-            let content_disposition = format!("attachment; filename={}", filename);
+                error::ServerError::Operation(err_msg)
+            })?;
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", content_type)
-                .header("Content-Disposition", content_disposition)
-                .body(Body::from(buffer));
+        points.push(qdrant::Point {
+            id: serde_json::Value::from(uuid::Uuid::new_v4().to_string()),
+            vector,
+            payload,
+        });
+    }
+    qdrant::upsert_points(vdb_server_url, vdb_api_key, vdb_collection_name, points).await?;
 
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    Ok(embeddings_response)
+}
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+/// The status of a model in the health report.
+#[derive(Debug, serde::Serialize)]
+struct ModelStatus {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
 
-                    error::internal_server_error(err_msg)
+/// The status of a Qdrant collection in the health report.
+#[derive(Debug, serde::Serialize)]
+struct CollectionStatus {
+    url: String,
+    collection_name: String,
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Report whether the server is ready to serve requests.
+///
+/// The models are checked to be initialized in the core context, and each configured Qdrant collection is checked to be reachable. The response is `503 Service Unavailable` if any of them is not. A collection that doesn't exist yet is reported as `missing`, but doesn't fail the check, since it is created by the first ingestion.
+pub(crate) async fn health_handler() -> Response<Body> {
+    let mut ready = true;
+
+    // check the models
+    let mut models = Vec::new();
+    let model_names = match RETRIEVAL_ONLY.get().copied().unwrap_or(false) {
+        true => vec![("embedding", llama_core::utils::embedding_model_names())],
+        false => vec![
+            ("chat", llama_core::utils::chat_model_names()),
+            ("embedding", llama_core::utils::embedding_model_names()),
+        ],
+    };
+    for (ty, result) in model_names {
+        match result {
+            Ok(names) if !names.is_empty() => {
+                for name in names {
+                    models.push(ModelStatus {
+                        ty,
+                        name: Some(name),
+                        status: "ok",
+                        error: None,
+                    });
                 }
             }
-        }
-        Err(e) => {
-            let err_msg = format!("{}", e);
-
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+            result => {
+                let err_msg = match result {
+                    Err(e) => e.to_string(),
+                    Ok(_) => format!("No {} model is initialized.", ty),
+                };
 
-            error::internal_server_error(err_msg)
+                // log
+                error!(target: "stdout", "Health check: {}", &err_msg);
+
+                ready = false;
+                models.push(ModelStatus {
+                    ty,
+                    name: None,
+                    status: "unavailable",
+                    error: Some(err_msg),
+                });
+            }
         }
     }
-}
-
-pub(crate) async fn chunks_handler(mut req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming chunks request");
-
-    if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
 
-        match result {
-            Ok(response) => return response,
+    // check the qdrant collections
+    let qdrant_config_vec = match SERVER_INFO.get() {
+        Some(server_info) => server_info.read().await.qdrant_config.clone(),
+        None => Vec::new(),
+    };
+    let vdb_api_key = qdrant::default_api_key();
+    let mut collections = Vec::new();
+    for qdrant_config in qdrant_config_vec.iter() {
+        let (status, error) = match qdrant::collection_exists(
+            &qdrant_config.url,
+            vdb_api_key.as_deref(),
+            &qdrant_config.collection_name,
+        )
+        .await
+        {
+            Ok(true) => ("ok", None),
+            Ok(false) => ("missing", None),
             Err(e) => {
                 let err_msg = e.to_string();
 
                 // log
-                error!(target: "stdout", "{}", &err_msg);
+                error!(target: "stdout", "Health check: failed to reach the collection `{}` at {}. {}", &qdrant_config.collection_name, &qdrant_config.url, &err_msg);
 
-                return error::internal_server_error(err_msg);
+                ready = false;
+                ("unreachable", Some(err_msg))
             }
-        }
-    }
-
-    // parse request
-    let body_bytes = match to_bytes(req.body_mut()).await {
-        Ok(body_bytes) => body_bytes,
-        Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
+        };
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+        collections.push(CollectionStatus {
+            url: qdrant_config.url.clone(),
+            collection_name: qdrant_config.collection_name.clone(),
+            status,
+            error,
+        });
+    }
 
-            return error::internal_server_error(err_msg);
-        }
+    let (status_code, status) = match ready {
+        true => (hyper::StatusCode::OK, "ok"),
+        false => (hyper::StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
     };
+    let mut body = serde_json::json!({
+        "status": status,
+        "models": models,
+        "collections": collections,
+    });
+    if let Some(stats) = ingestion_stats() {
+        body["ingestions"] = serde_json::json!(stats);
+    }
 
-    let chunks_request: ChunksRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(chunks_request) => chunks_request,
-        Err(e) => {
-            let mut err_msg = format!("Fail to deserialize chunks request: {}.", e);
+    let result = Response::builder()
+        .status(status_code)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()));
 
-            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
-            }
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::bad_request(err_msg);
+            error::internal_server_error(err_msg)
         }
-    };
-
-    // check if the archives directory exists
-    let path = Path::new("archives");
-    if !path.exists() {
-        let err_msg = "The `archives` directory does not exist.";
-
-        // log
-        error!(target: "stdout", "{}", &err_msg);
-
-        return error::internal_server_error(err_msg);
     }
+}
 
-    // check if the archive id exists
-    let archive_path = path.join(&chunks_request.id);
-    if !archive_path.exists() {
-        let err_msg = format!("Not found archive id: {}", &chunks_request.id);
-
-        // log
-        error!(target: "stdout", "{}", &err_msg);
-
-        return error::internal_server_error(err_msg);
+/// Report the load of the server: the requests in flight and the ingestion slots. Unlike `/v1/health`, no dependency is checked, so the endpoint is cheap enough for the clients to poll before each request.
+pub(crate) async fn status_handler() -> Response<Body> {
+    let mut body = serde_json::json!({
+        "status": "ok",
+        "requests_in_flight": Load::Request.in_flight(),
+        "chat_completions_in_flight": Load::ChatCompletion.in_flight(),
+    });
+    if let Some(stats) = ingestion_stats() {
+        body["ingestions"] = serde_json::json!(stats);
     }
 
-    // check if the file exists
-    let file_path = archive_path.join(&chunks_request.filename);
-    if !file_path.exists() {
-        let err_msg = format!(
-            "Not found file: {} in archive id: {}",
-            &chunks_request.filename, &chunks_request.id
-        );
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(body.to_string()));
 
-        // log
-        error!(target: "stdout", "{}", &err_msg);
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-        return error::internal_server_error(err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
     }
+}
 
+pub(crate) async fn server_info_handler() -> Response<Body> {
     // log
-    info!(target: "stdout", "file_id: {}, file_name: {}", &chunks_request.id, &chunks_request.filename);
+    info!(target: "stdout", "Handling the coming server info request.");
 
-    // get the extension of the archived file
-    let extension = match file_path.extension().and_then(std::ffi::OsStr::to_str) {
-        Some(extension) => extension,
+    // get the server info
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info.read().await,
         None => {
-            let err_msg = format!(
-                "Failed to get the extension of the archived `{}`.",
-                &chunks_request.filename
-            );
+            let err_msg = "The server info is not set.";
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return error::internal_server_error("The server info is not set.");
         }
     };
 
-    // open the file
-    let mut file = match File::open(&file_path) {
-        Ok(file) => file,
+    // serialize server info
+    let mut value = match serde_json::to_value(&*server_info) {
+        Ok(value) => value,
         Err(e) => {
-            let err_msg = format!("Failed to open `{}`. {}", &chunks_request.filename, e);
+            let err_msg = format!("Fail to serialize server info. {}", e);
 
             // log
             error!(target: "stdout", "{}", &err_msg);
@@ -1629,60 +5012,25 @@ pub(crate) async fn chunks_handler(mut req: Request<Body>) -> Response<Body> {
             return error::internal_server_error(err_msg);
         }
     };
+    drop(server_info);
 
-    // read the file
-    let mut contents = String::new();
-    if let Err(e) = file.read_to_string(&mut contents) {
-        let err_msg = format!("Failed to read `{}`. {}", &chunks_request.filename, e);
-
-        // log
-        error!(target: "stdout", "{}", &err_msg);
-
-        return error::internal_server_error(err_msg);
+    // the info is readable by any holder of an API key, so the Qdrant URLs are not exposed in full
+    if LLAMA_API_KEYS.get().is_some() {
+        redact_server_info(&mut value);
     }
+    let s = value.to_string();
 
-    let res = match chunk_text(&contents, extension, chunks_request.chunk_capacity) {
-        Ok(chunks) => {
-            let chunks_response = ChunksResponse {
-                id: chunks_request.id,
-                filename: chunks_request.filename,
-                chunks,
-            };
-
-            // serialize embedding object
-            match serde_json::to_string(&chunks_response) {
-                Ok(s) => {
-                    // return response
-                    let result = Response::builder()
-                        .header("Access-Control-Allow-Origin", "*")
-                        .header("Access-Control-Allow-Methods", "*")
-                        .header("Access-Control-Allow-Headers", "*")
-                        .header("Content-Type", "application/json")
-                        .body(Body::from(s));
-                    match result {
-                        Ok(response) => response,
-                        Err(e) => {
-                            let err_msg = e.to_string();
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            error::internal_server_error(err_msg)
-                        }
-                    }
-                }
-                Err(e) => {
-                    let err_msg = e.to_string();
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    error::internal_server_error(err_msg)
-                }
-            }
-        }
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
+    let res = match result {
+        Ok(response) => response,
         Err(e) => {
-            let err_msg = format!("Fail to serialize chunks response. {}", e);
+            let err_msg = e.to_string();
 
             // log
             error!(target: "stdout", "{}", &err_msg);
@@ -1691,739 +5039,1467 @@ pub(crate) async fn chunks_handler(mut req: Request<Body>) -> Response<Body> {
         }
     };
 
-    info!(target: "stdout", "Send the chunks response.");
+    info!(target: "stdout", "Send the server info response.");
 
     res
 }
 
-pub(crate) async fn create_rag_handler(
-    req: Request<Body>,
-    chunk_capacity: usize,
-) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming doc_to_embeddings request.");
+/// Reduce the Qdrant URLs of the server info to their origin, also in the errors of the collection info, which may quote the URLs.
+fn redact_server_info(value: &mut serde_json::Value) {
+    let mut urls = Vec::new();
+    if let Some(qdrant_configs) = value
+        .get_mut("qdrant_config")
+        .and_then(|qdrant_configs| qdrant_configs.as_array_mut())
+    {
+        for url in qdrant_configs
+            .iter_mut()
+            .filter_map(|qdrant_config| qdrant_config.get_mut("url"))
+        {
+            if let Some(full_url) = url.as_str() {
+                let redacted = redact_url(full_url);
+                urls.push((full_url.to_string(), redacted.clone()));
+                *url = serde_json::Value::String(redacted);
+            }
+        }
+    }
+    // the longer URLs first, so that a URL is not partly replaced by a prefix of it
+    urls.sort_by_key(|(url, _)| std::cmp::Reverse(url.len()));
 
-    // upload the target rag document
-    let (file_object, vdb_server_url, vdb_collection_name, vdb_api_key, kw_search_url) = if req
-        .method()
-        == Method::POST
+    if let Some(collection_infos) = value
+        .get_mut("collection_info")
+        .and_then(|collection_infos| collection_infos.as_array_mut())
     {
-        let boundary = "boundary=";
+        for error in collection_infos
+            .iter_mut()
+            .filter_map(|collection_info| collection_info.get_mut("error"))
+        {
+            if let Some(message) = error.as_str() {
+                let message =
+                    urls.iter()
+                        .fold(message.to_string(), |message, (full_url, redacted)| {
+                            message.replace(full_url.trim_end_matches('/'), redacted)
+                        });
+                *error = serde_json::Value::String(message);
+            }
+        }
+    }
+}
 
-        let boundary = req.headers().get("content-type").and_then(|ct| {
-            let ct = ct.to_str().ok()?;
-            let idx = ct.find(boundary)?;
-            Some(ct[idx + boundary.len()..].to_string())
-        });
+pub(crate) async fn retrieve_handler(mut req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming retrieve request.");
 
-        let req_body = req.into_body();
-        let body_bytes = match to_bytes(req_body).await {
-            Ok(body_bytes) => body_bytes,
+    // the collection scope of the API key of the request
+    let collection_scope = req.extensions().get::<CollectionScope>().cloned();
+
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
+
+        match result {
+            Ok(response) => return response,
             Err(e) => {
-                let err_msg = format!("Fail to read buffer from request body. {}", e);
+                let err_msg = e.to_string();
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
                 return error::internal_server_error(err_msg);
             }
-        };
+        }
+    }
 
-        let cursor = Cursor::new(body_bytes.to_vec());
+    info!(target: "stdout", "Prepare the chat completion request.");
 
-        let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
+    // parse request
+    let body_bytes = match to_bytes(req.body_mut()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
 
-        let mut file_object: Option<FileObject> = None;
-        let mut vdb_server_url: String = String::new();
-        let mut vdb_collection_name: String = String::new();
-        let mut vdb_api_key: String = String::new();
-        let mut kw_search_url = String::new();
-        while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-            match &*field.headers.name {
-                "file" => {
-                    let filename = match field.headers.filename {
-                        Some(filename) => filename,
-                        None => {
-                            let err_msg =
-                                "Failed to upload the target file. The filename is not provided.";
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            return error::internal_server_error(err_msg);
+        }
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    };
+    // parse the request and prepare the collections to search
+    let (body_bytes, chat_request, qdrant_config_vec) =
+        match prepare_retrieve_request(body_bytes, collection_scope.as_ref()).await {
+            Ok(prepared) => prepared,
+            Err(response) => return response,
+        };
+    let id = chat_request.user.clone().unwrap_or_default();
 
-                    info!(target: "stdout", "filename: {}", &filename);
+    // override the number of reranked points kept by the `rerank_top_n` field of the request
+    let rerank_config = match request_rerank_config(&body_bytes, &qdrant_config_vec) {
+        Ok(rerank_config) => rerank_config,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::bad_request(err_msg);
+        }
+    };
 
-                    if !((filename).to_lowercase().ends_with(".txt")
-                        || (filename).to_lowercase().ends_with(".md"))
-                    {
-                        let err_msg = "Failed to upload the target file. Only files with 'txt' and 'md' extensions are supported.";
+    // check if the results are grouped by document
+    let group_by_document = match request_group_by_document(&body_bytes) {
+        Ok(group_by_document) => group_by_document,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+            return error::bad_request(err_msg);
+        }
+    };
 
-                        return error::internal_server_error(err_msg);
-                    }
+    // the deadline of the retrieval phase
+    let retrieval_deadline = RETRIEVAL_TIME_BUDGET
+        .get()
+        .map(|budget| Instant::now() + *budget);
 
-                    let mut buffer = Vec::new();
-                    let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                        Ok(size_in_bytes) => size_in_bytes,
-                        Err(e) => {
-                            let err_msg = format!("Failed to read the target file. {}", e);
+    // retrieve context
+    let Retrieval {
+        retrieve_object_vec,
+        breakdown,
+        payloads,
+        ..
+    } = match retrieve_context_with_multiple_qdrant_configs(
+        &chat_request,
+        &qdrant_config_vec,
+        retrieval_deadline,
+        RECENCY_CONFIG.get(),
+        rerank_config.as_ref(),
+        None,
+    )
+    .await
+    {
+        Ok(retrieval) => retrieval,
+        Err(response) => {
+            return response;
+        }
+    };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    // check if nothing is retrieved because all the collections are empty
+    let collections_empty = retrieve_object_vec.is_empty()
+        && check_collections_empty(&chat_request, &qdrant_config_vec).await;
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    };
+    // log retrieve object
+    debug!(target: "stdout", "retrieve_object_vec:\n{}", serde_json::to_string_pretty(&retrieve_object_vec).unwrap());
+
+    // compute the retrieval confidence
+    let confidence = retrieval_confidence(&retrieve_object_vec);
+
+    let mut res = {
+        // serialize retrieve object
+        let result = match group_by_document {
+            true => {
+                serde_json::to_string(&group_points_by_document(&retrieve_object_vec, &payloads))
+            }
+            false => serde_json::to_string(&retrieve_object_vec),
+        };
+        let s = match result {
+            Ok(s) => s,
+            Err(e) => {
+                let err_msg = format!("Fail to serialize retrieve object. {}", e);
 
-                    // create a unique file id
-                    let id = format!("file_{}", uuid::Uuid::new_v4());
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                    // save the file
-                    let path = Path::new("archives");
-                    if !path.exists() {
-                        fs::create_dir(path).unwrap();
-                    }
-                    let file_path = path.join(&id);
-                    if !file_path.exists() {
-                        fs::create_dir(&file_path).unwrap();
-                    }
-                    let mut file = match File::create(file_path.join(&filename)) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            let err_msg =
-                                format!("Failed to create archive document {}. {}", &filename, e);
+                return error::internal_server_error(err_msg);
+            }
+        };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+        // return response
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .header("user", id)
+            .body(Body::from(s));
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    };
-                    file.write_all(&buffer[..]).unwrap();
+        match result {
+            Ok(response) => response,
+            Err(e) => {
+                let err_msg = e.to_string();
 
-                    // log
-                    info!(target: "stdout", "file_id: {}, file_name: {}", &id, &filename);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                    let created_at = match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                        Ok(n) => n.as_secs(),
-                        Err(_) => {
-                            let err_msg = "Failed to get the current time.";
+                error::internal_server_error(e.to_string())
+            }
+        }
+    };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    insert_retrieval_headers(&mut res, collections_empty, confidence);
+    if RETRIEVAL_BREAKDOWN.get().copied().unwrap_or(false) {
+        let breakdown = serde_json::to_string(&breakdown).unwrap_or_default();
+        match hyper::header::HeaderValue::from_str(&breakdown) {
+            Ok(value) => {
+                res.headers_mut().insert("X-Retrieval-Breakdown", value);
+            }
+            Err(_) => {
+                // log
+                warn!(target: "stdout", "The retrieval breakdown is not a valid header value: {}", breakdown);
+            }
+        }
+    }
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    };
+    info!(target: "stdout", "Send the retrieve response.");
 
-                    // create a file object
-                    file_object = Some(FileObject {
-                        id,
-                        bytes: size_in_bytes as u64,
-                        created_at,
-                        filename,
-                        object: "file".to_string(),
-                        purpose: "assistants".to_string(),
-                    });
-                }
-                "vdb_server_url" => match field.is_text() {
-                    true => {
-                        if let Err(e) = field.data.read_to_string(&mut vdb_server_url) {
-                            let err_msg =
-                                format!("Failed to read the `vdb_server_url` field. {}", e);
+    res
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+/// Run the query of a `/v1/retrieve` request through several retrieval strategies, and return the results of each strategy side by side. Served only if `--debug-endpoints` is set.
+pub(crate) async fn retrieve_compare_handler(mut req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming retrieve compare request.");
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    }
-                    false => {
-                        let err_msg =
-                        "Failed to get `vdb_server_url`. The `vdb_server_url` field in the request should be a text field.";
+    // the collection scope of the API key of the request
+    let collection_scope = req.extensions().get::<CollectionScope>().cloned();
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
 
-                        return error::internal_server_error(err_msg);
-                    }
-                },
-                "vdb_collection_name" => match field.is_text() {
-                    true => {
-                        if let Err(e) = field.data.read_to_string(&mut vdb_collection_name) {
-                            let err_msg =
-                                format!("Failed to read the `vdb_collection_name` field. {}", e);
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    }
-                    false => {
-                        let err_msg = "Failed to get `vdb_collection_name`. The `vdb_collection_name` field in the request should be a text field.";
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    // parse request
+    let body_bytes = match to_bytes(req.body_mut()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
 
-                        return error::internal_server_error(err_msg);
-                    }
-                },
-                "vdb_api_key" => match field.is_text() {
-                    true => {
-                        if let Err(e) = field.data.read_to_string(&mut vdb_api_key) {
-                            let err_msg = format!("Failed to read the `vdb_api_key` field. {}", e);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            return error::internal_server_error(err_msg);
+        }
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    }
-                    false => {
-                        let err_msg = "Failed to get `vdb_api_key`. The `vdb_api_key` field in the request should be a text field.";
+    // the strategies to compare: the `strategies` field in the request
+    let strategies = match request_retrieval_strategies(&body_bytes) {
+        Ok(strategies) => strategies,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+            return error::bad_request(err_msg);
+        }
+    };
 
-                        return error::internal_server_error(err_msg);
-                    }
-                },
-                "kw_search_url" => match field.is_text() {
-                    true => {
-                        if let Err(e) = field.data.read_to_string(&mut kw_search_url) {
-                            let err_msg =
-                                format!("Failed to read the `kw_search_url` field. {}", e);
+    // parse the request and prepare the collections to search
+    let (body_bytes, chat_request, qdrant_config_vec) =
+        match prepare_retrieve_request(body_bytes, collection_scope.as_ref()).await {
+            Ok(prepared) => prepared,
+            Err(response) => return response,
+        };
+    let id = chat_request.user.clone().unwrap_or_default();
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    // override the number of reranked points kept by the `rerank_top_n` field of the request
+    let rerank_config = match request_rerank_config(&body_bytes, &qdrant_config_vec) {
+        Ok(rerank_config) => rerank_config,
+        Err(err_msg) => {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    }
-                    false => {
-                        let err_msg = "Failed to get `kw_search_url`. The `kw_search_url` field in the request should be a text field.";
+            return error::bad_request(err_msg);
+        }
+    };
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    // run the strategies one after another, so that their timings don't interfere
+    let mut results = Vec::with_capacity(strategies.len());
+    for strategy in strategies {
+        // log
+        info!(target: "stdout", "Retrieve with the strategy `{}`.", strategy);
+
+        let result = match retrieve_with_strategy(
+            strategy,
+            &chat_request,
+            &body_bytes,
+            &qdrant_config_vec,
+            rerank_config.as_ref(),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(response) => return response,
+        };
+        results.push(result);
+    }
 
-                        return error::internal_server_error(err_msg);
-                    }
-                },
-                _ => {
-                    let err_msg = format!("Invalid field name: {}", &field.headers.name);
+    let s = match serde_json::to_string(&serde_json::json!({ "strategies": results })) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Fail to serialize the comparison. {}", e);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
-                }
-            }
+            return error::internal_server_error(err_msg);
         }
+    };
 
-        // If the request does not provide the vdb_server_url and vdb_collection_name, use the default vdb config from the server info, and get the vdb_api_key from the environment variable `VDB_API_KEY` if it is set.
-        match (vdb_server_url.is_empty(), vdb_collection_name.is_empty()) {
-            (true, true) => {
-                let qdrant_config_vec = match SERVER_INFO.get() {
-                    Some(server_info) => server_info.read().await.qdrant_config.clone(),
-                    None => {
-                        let err_msg = "The server info is not set.";
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .header("user", id)
+        .body(Body::from(s));
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+    let res = match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-                        return error::internal_server_error(err_msg);
-                    }
-                };
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                // use the first qdrant config as the default config
-                vdb_server_url = qdrant_config_vec[0].url.clone();
-                vdb_collection_name = qdrant_config_vec[0].collection_name.clone();
-                if vdb_api_key.is_empty() {
-                    vdb_api_key = std::env::var("VDB_API_KEY").unwrap_or_default();
-                }
-            }
-            (true, false) | (false, true) => {
-                let err_msg = "Failed to get `vdb_server_url` or `vdb_collection_name`. The `vdb_server_url` and `vdb_collection_name` fields in the request should be provided at the same time.";
+            error::internal_server_error(err_msg)
+        }
+    };
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    info!(target: "stdout", "Send the retrieve compare response.");
 
-                return error::internal_server_error(err_msg);
-            }
-            (false, false) => {}
+    res
+}
+
+/// A retrieval strategy compared by `/v1/retrieve/compare`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RetrievalStrategy {
+    /// The vector search only
+    Vector,
+    /// The vector search fused with the keyword search
+    Hybrid,
+    /// The vector search reranked by recency, compared in addition to the vector, hybrid and reranked strategies, since `--recency-decay` also reorders the context
+    Recency,
+    /// The vector search reranked by the cross-encoder reranker
+    Reranked,
+}
+impl RetrievalStrategy {
+    const ALL: [RetrievalStrategy; 4] = [
+        RetrievalStrategy::Vector,
+        RetrievalStrategy::Hybrid,
+        RetrievalStrategy::Recency,
+        RetrievalStrategy::Reranked,
+    ];
+}
+impl std::fmt::Display for RetrievalStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RetrievalStrategy::Vector => write!(f, "vector"),
+            RetrievalStrategy::Hybrid => write!(f, "hybrid"),
+            RetrievalStrategy::Recency => write!(f, "recency"),
+            RetrievalStrategy::Reranked => write!(f, "reranked"),
         }
+    }
+}
+
+/// The results of a retrieval strategy in the response of `/v1/retrieve/compare`.
+#[derive(Debug, serde::Serialize)]
+struct StrategyResult {
+    strategy: RetrievalStrategy,
+    /// The reason why the strategy is not run, if it is not configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results: Option<Vec<RetrieveObject>>,
+}
 
-        // if kw_search_url is not provided, try to get the default kw_search_url from KW_SEARCH_CONFIG
-        if kw_search_url.is_empty() {
-            if let Some(kw_search_config) = KW_SEARCH_CONFIG.get() {
-                kw_search_url = kw_search_config.url.clone();
+/// Read the optional `strategies` field of the retrieve compare request. Defaults to all the strategies.
+fn request_retrieval_strategies(body_bytes: &Bytes) -> Result<Vec<RetrievalStrategy>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("strategies") {
+        None | Some(serde_json::Value::Null) => Ok(RetrievalStrategy::ALL.to_vec()),
+        Some(strategies) => {
+            let strategies: Vec<RetrievalStrategy> =
+                serde_json::from_value(strategies.clone()).map_err(|_| {
+                    format!(
+                        "Invalid strategies: {}. The value should be an array of `vector`, `hybrid`, `recency` and `reranked`.",
+                        strategies
+                    )
+                })?;
+            // a repeated strategy is run once, at its first position
+            let mut seen = HashSet::new();
+            let strategies: Vec<RetrievalStrategy> = strategies
+                .into_iter()
+                .filter(|strategy| seen.insert(*strategy))
+                .collect();
+
+            match strategies.is_empty() {
+                true => {
+                    Err("Invalid strategies: []. At least one strategy is required.".to_string())
+                }
+                false => Ok(strategies),
             }
         }
-        if !kw_search_url.is_empty() {
-            kw_search_url = kw_search_url.trim_end_matches('/').to_string();
-            info!(target: "stdout", "kw_search_url: {}", &kw_search_url);
-        }
+    }
+}
 
-        info!(target: "stdout", "vdb_server_url: {}, vdb_collection_name: {}", &vdb_server_url, &vdb_collection_name);
+/// Retrieve the context with the strategy, through the same code paths as `/v1/chat/completions`. A strategy that is not configured is reported as skipped.
+async fn retrieve_with_strategy(
+    strategy: RetrievalStrategy,
+    chat_request: &ChatCompletionRequest,
+    body_bytes: &Bytes,
+    qdrant_config_vec: &[QdrantConfig],
+    rerank_config: Option<&RerankConfig>,
+) -> Result<StrategyResult, Response<Body>> {
+    let skipped = |reason: &str| StrategyResult {
+        strategy,
+        skipped: Some(reason.to_string()),
+        time_ms: None,
+        confidence: None,
+        results: None,
+    };
 
-        match file_object {
-            Some(fo) => (
-                fo,
-                vdb_server_url,
-                vdb_collection_name,
-                vdb_api_key,
-                kw_search_url,
-            ),
+    let recency_config = match strategy {
+        RetrievalStrategy::Recency => match RECENCY_CONFIG.get() {
+            Some(recency_config) => Some(recency_config),
             None => {
-                let err_msg = "Failed to upload the target file. Not found the target file.";
-
+                return Ok(skipped(
+                    "The recency reranking is not enabled by `--recency-decay`.",
+                ))
+            }
+        },
+        _ => None,
+    };
+    // only the `reranked` strategy runs the reranker, so that the others show the results without it
+    let rerank_config = match strategy {
+        RetrievalStrategy::Reranked => match rerank_config {
+            Some(rerank_config) => Some(rerank_config),
+            None => {
+                return Ok(skipped(
+                    "The cross-encoder reranking is not enabled by `--rerank-url`.",
+                ))
+            }
+        },
+        _ => None,
+    };
+    if strategy == RetrievalStrategy::Hybrid {
+        let kw_search_configured = match select_kw_search_url(chat_request.kw_search_url.as_deref())
+        {
+            Ok(kw_search_url) => !kw_search_url.is_empty(),
+            Err(err_msg) => {
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                return error::internal_server_error(err_msg);
+                return Err(error::bad_request(err_msg));
             }
+        };
+        let kw_index_named = chat_request
+            .kw_index_name
+            .as_ref()
+            .is_some_and(|index_name| !index_name.is_empty());
+        if !kw_search_configured || !kw_index_named {
+            return Ok(skipped("The keyword search requires `--kw-search-url`, or a `kw_search_url` field permitted by `--kw-search-url-allowlist`, and the `kw_index_name` field."));
         }
-    } else if req.method() == Method::GET {
-        let err_msg = "Not implemented for listing files.";
+    }
+
+    // each strategy has its own retrieval time budget
+    let start = Instant::now();
+    let deadline = RETRIEVAL_TIME_BUDGET.get().map(|budget| start + *budget);
+
+    let kw_hits = match strategy {
+        RetrievalStrategy::Hybrid => keyword_search(chat_request, body_bytes, deadline).await?,
+        _ => Vec::new(),
+    };
 
-        // log
-        error!(target: "stdout", "{}", &err_msg);
+    let mut retrieve_object_vec = retrieve_context_with_multiple_qdrant_configs(
+        chat_request,
+        qdrant_config_vec,
+        deadline,
+        recency_config,
+        rerank_config,
+        None,
+    )
+    .await?
+    .retrieve_object_vec;
+
+    fuse_keyword_hits(&mut retrieve_object_vec, kw_hits);
+
+    Ok(StrategyResult {
+        strategy,
+        skipped: None,
+        time_ms: Some(start.elapsed().as_millis()),
+        confidence: Some(retrieval_confidence(&retrieve_object_vec)),
+        results: Some(retrieve_object_vec),
+    })
+}
 
-        return error::internal_server_error(err_msg);
-    } else if req.method() == Method::OPTIONS {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
+/// Parse the request of `/v1/retrieve` or `/v1/retrieve/compare`, and prepare the collections to search with the overrides of the request applied. Returns the body without the score threshold override, the parsed request, and the Qdrant configs.
+async fn prepare_retrieve_request(
+    body_bytes: Bytes,
+    collection_scope: Option<&CollectionScope>,
+) -> Result<(Bytes, ChatCompletionRequest, Vec<QdrantConfig>), Response<Body>> {
+    // check the non-text content parts in the messages
+    let body_bytes = match check_nontext_parts(body_bytes) {
+        Ok(body_bytes) => body_bytes,
+        Err(err_msg) => return Err(error::bad_request(err_msg)),
+    };
 
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
+    // take the score threshold override in the request
+    let (body_bytes, score_threshold_override) = match take_score_threshold_override(body_bytes) {
+        Ok(result) => result,
+        Err(err_msg) => return Err(error::bad_request(err_msg)),
+    };
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(chat_request) => chat_request,
+        Err(e) => {
+            let mut err_msg = format!("Fail to deserialize chat completion request: {}.", e);
 
-                return error::internal_server_error(err_msg);
+            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
             }
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(error::bad_request(err_msg));
         }
-    } else {
-        let err_msg = "Invalid HTTP Method.";
+    };
 
+    // check if the messages contain a user message
+    if let Err(err_msg) = check_user_message(&chat_request.messages) {
         // log
         error!(target: "stdout", "{}", &err_msg);
 
-        return error::internal_server_error(err_msg);
+        return Err(error::unprocessable_entity(err_msg));
+    }
+
+    if chat_request.user.is_none() {
+        chat_request.user = Some(gen_chat_id())
     };
 
-    // chunk the text
-    let chunks = {
-        info!(target: "stdout", "file_id: {}, file_name: {}", &file_object.id, &file_object.filename);
+    // log user id
+    info!(target: "stdout", "user: {}", chat_request.user.as_deref().unwrap_or_default());
 
-        // check if the archives directory exists
-        let path = Path::new("archives");
-        if !path.exists() {
-            let err_msg = "The `archives` directory does not exist.";
+    // qdrant config
+    let mut qdrant_config_vec = match get_qdrant_configs(&chat_request).await {
+        Ok(qdrant_config_vec) => qdrant_config_vec,
+        Err(e) => return Err(error::internal_server_error(e.to_string())),
+    };
 
+    // select the collections named in the `collections` field of the request
+    let selected = match request_collections(&body_bytes)
+        .and_then(|selected| select_collections(&mut qdrant_config_vec, selected, collection_scope))
+    {
+        Ok(selected) => selected,
+        Err(err_msg) => {
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return Err(error::bad_request(err_msg));
         }
+    };
 
-        // check if the archive id exists
-        let archive_path = path.join(&file_object.id);
-        if !archive_path.exists() {
-            let err_msg = format!("Not found archive id: {}", &file_object.id);
+    // check the collections against the collection scope of the API key
+    if let Err(err_msg) = check_collection_scope(
+        &mut qdrant_config_vec,
+        chat_request.vdb_collection_name.is_some() || selected,
+        collection_scope,
+    ) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(error::forbidden(err_msg));
+    }
 
+    // override the score thresholds
+    if let Some(score_threshold_override) = &score_threshold_override {
+        if let Err(err_msg) =
+            apply_score_threshold_override(&mut qdrant_config_vec, score_threshold_override).await
+        {
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return Err(error::bad_request(err_msg));
         }
+    }
 
-        // check if the file exists
-        let file_path = archive_path.join(&file_object.filename);
-        if !file_path.exists() {
-            let err_msg = format!(
-                "Not found file: {} in archive id: {}",
-                &file_object.filename, &file_object.id
-            );
+    // exclude the collections named in the `exclude_collections` field of the request
+    if let Err(err_msg) = request_exclude_collections(&body_bytes)
+        .and_then(|excluded| exclude_collections(&mut qdrant_config_vec, &excluded))
+    {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+        return Err(error::bad_request(err_msg));
+    }
 
-            return error::internal_server_error(err_msg);
-        }
+    // override the limit and the score threshold by the `rag_limit` and `rag_score_threshold` fields of the request
+    if let Err(err_msg) = apply_rag_overrides(&body_bytes, &mut qdrant_config_vec) {
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-        // get the extension of the archived file
-        let extension = match file_path.extension().and_then(std::ffi::OsStr::to_str) {
-            Some(extension) => extension,
-            None => {
-                let err_msg = format!(
-                    "Failed to get the extension of the archived `{}`.",
-                    &file_object.filename
-                );
+        return Err(error::bad_request(err_msg));
+    }
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    Ok((body_bytes, chat_request, qdrant_config_vec))
+}
 
-                return error::internal_server_error(err_msg);
-            }
-        };
+/// Log the token budget left for the completion after the prompt if the chat model runs with `n_predict = -2`, and set the finish reason to `length` if the completion stopped because the context is filled.
+///
+/// The core caps the completion at the tokens left in the context, but reports the stop at the cap as `stop`.
+async fn check_context_fill(chat_completion_object: &mut ChatCompletionObject) {
+    let ctx_size = match SERVER_INFO.get() {
+        Some(server_info) => match &server_info.read().await.rag_config.chat_model {
+            Some(chat_model) if chat_model.n_predict == -2 => chat_model.ctx_size,
+            _ => return,
+        },
+        None => return,
+    };
 
-        info!(target: "stdout", "Open and read the file.");
+    let usage = &chat_completion_object.usage;
+    let budget = ctx_size.saturating_sub(usage.prompt_tokens);
 
-        // open the file
-        let mut file = match File::open(&file_path) {
-            Ok(file) => file,
-            Err(e) => {
-                let err_msg = format!("Failed to open `{}`. {}", &file_object.filename, e);
+    // log
+    info!(target: "stdout", "n_predict = -2: {} of {} context tokens are left for the completion after the prompt of {} tokens, and the completion takes {} tokens.", budget, ctx_size, usage.prompt_tokens, usage.completion_tokens);
 
+    if usage.completion_tokens >= budget {
+        for choice in chat_completion_object.choices.iter_mut() {
+            if choice.finish_reason == FinishReason::stop {
                 // log
-                error!(target: "stdout", "{}", &err_msg);
+                info!(target: "stdout", "The context is filled. Set the finish reason to `length`.");
 
-                return error::internal_server_error(err_msg);
+                choice.finish_reason = FinishReason::length;
             }
-        };
+        }
+    }
+}
 
-        // read the file
-        let mut contents = String::new();
-        if let Err(e) = file.read_to_string(&mut contents) {
-            let err_msg = format!("Failed to read `{}`. {}", &file_object.filename, e);
+/// Retrieve the context with the system message content as the query. Returns `None` if nothing is retrieved or the retrieval fails, in which case the system message is left unchanged.
+async fn retrieve_system_context(
+    system_content: &str,
+    chat_request: &ChatCompletionRequest,
+    qdrant_config_vec: &[QdrantConfig],
+    deadline: Option<Instant>,
+    rerank_config: Option<&RerankConfig>,
+    context_format: ContextFormat,
+) -> Option<String> {
+    info!(target: "stdout", "Retrieve the context for the system message.");
+
+    // create a request with the system message content as the only user message
+    let user_message = ChatCompletionRequestMessage::new_user_message(
+        ChatCompletionUserMessageContent::Text(system_content.to_string()),
+        None,
+    );
+    let mut builder = ChatCompletionRequestBuilder::new(&[user_message]);
+    if let Some(model) = &chat_request.model {
+        builder = builder.with_model(model);
+    }
+    if let Some(user) = &chat_request.user {
+        builder = builder.with_user(user);
+    }
+    let mut request = builder.enable_stream(false).build();
+    request.context_window = Some(1);
+    request.vdb_api_key = chat_request.vdb_api_key.clone();
 
+    let retrieve_object_vec = match retrieve_context_with_multiple_qdrant_configs(
+        &request,
+        qdrant_config_vec,
+        deadline,
+        RECENCY_CONFIG.get(),
+        rerank_config,
+        None,
+    )
+    .await
+    {
+        Ok(retrieval) => retrieval.retrieve_object_vec,
+        Err(_) => {
             // log
-            error!(target: "stdout", "{}", &err_msg);
+            warn!(target: "stdout", "Failed to retrieve the context for the system message. The system message is left unchanged.");
 
-            return error::internal_server_error(err_msg);
+            return None;
         }
+    };
+
+    let chunks: Vec<&str> = retrieve_object_vec
+        .iter()
+        .flat_map(|retrieve_object| retrieve_object.points.iter().flatten())
+        .map(|point| point.source.as_str())
+        .collect();
 
-        info!(target: "stdout", "Chunk the file contents.");
+    // log
+    info!(target: "stdout", "{} point(s) retrieved for the system message", chunks.len());
 
-        match chunk_text(&contents, extension, chunk_capacity) {
-            Ok(chunks) => chunks,
-            Err(e) => {
-                let err_msg = e.to_string();
+    match chunks.is_empty() {
+        true => None,
+        false => Some(context_format.format(&chunks)),
+    }
+}
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+/// Read the optional `collections` field of the request, the names of the collections the retrieval is limited to. Defaults to all the collections.
+fn request_collections(body_bytes: &Bytes) -> Result<Option<Vec<String>>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
 
-                return error::internal_server_error(err_msg);
-            }
-        }
+    let invalid = |value: &serde_json::Value| {
+        format!(
+            "Invalid collections: {}. The value should be a non-empty array of collection names.",
+            value
+        )
     };
+    match value.get("collections") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::Array(names)) if !names.is_empty() => names
+            .iter()
+            .map(|name| {
+                name.as_str()
+                    .map(|name| name.to_string())
+                    .ok_or_else(|| invalid(name))
+            })
+            .collect::<Result<Vec<String>, String>>()
+            .map(Some),
+        Some(names) => Err(invalid(names)),
+    }
+}
 
-    // create index for chunks
-    let mut index_response: Option<IndexResponse> = None;
-    if !kw_search_url.is_empty() {
-        let index_url = format!("{}/v1/index/create", &kw_search_url);
-        info!(target: "stdout", "index_url: {}", &index_url);
+/// Limit the collections of the retrieval to the selected ones, if any. An error is returned if a selected name doesn't match any collection in the scope of the API key. Returns whether the collections are selected.
+fn select_collections(
+    qdrant_config_vec: &mut Vec<QdrantConfig>,
+    selected: Option<Vec<String>>,
+    collection_scope: Option<&CollectionScope>,
+) -> Result<bool, String> {
+    let selected = match selected {
+        Some(selected) => selected,
+        None => return Ok(false),
+    };
 
-        let mut index_request = IndexRequest { documents: vec![] };
-        for chunk in chunks.iter() {
-            let document_input = DocumentInput {
-                content: chunk.clone(),
-                title: None,
-            };
-            index_request.documents.push(document_input);
-        }
+    // the collections outside the scope of the API key are reported as unknown, so that their names are never disclosed
+    let visible = |name: &str| collection_scope.is_none_or(|scope| scope.allows(name));
+    let unknown: Vec<&str> = selected
+        .iter()
+        .filter(|name| {
+            !visible(name)
+                || !qdrant_config_vec
+                    .iter()
+                    .any(|qdrant_config| &qdrant_config.collection_name == *name)
+        })
+        .map(|name| name.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "Unknown collections: {}. The collections of the request are: {}.",
+            unknown.join(", "),
+            qdrant_config_vec
+                .iter()
+                .map(|qdrant_config| qdrant_config.collection_name.as_str())
+                .filter(|name| visible(name))
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ));
+    }
 
-        info!(target: "stdout", "Sending index request to kw-search-server");
+    qdrant_config_vec.retain(|qdrant_config| selected.contains(&qdrant_config.collection_name));
 
-        if let Ok(response) = reqwest::Client::new()
-            .post(&index_url)
-            .json(&index_request)
-            .send()
-            .await
-        {
-            if let Ok(idx_response) = response.json::<IndexResponse>().await {
-                index_response = Some(idx_response);
-            }
+    info!(target: "stdout", "selected collections: {}", selected.join(","));
+
+    Ok(true)
+}
+
+/// Read the optional `exclude_collections` field of the request, the names of the collections excluded from the retrieval. Defaults to none.
+fn request_exclude_collections(body_bytes: &Bytes) -> Result<Vec<String>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("exclude_collections") {
+        None | Some(serde_json::Value::Null) => Ok(Vec::new()),
+        Some(serde_json::Value::Array(names)) => names
+            .iter()
+            .map(|name| {
+                name.as_str().map(|name| name.to_string()).ok_or_else(|| {
+                    format!(
+                        "Invalid exclude_collections: {}. The value should be an array of collection names.",
+                        name
+                    )
+                })
+            })
+            .collect(),
+        Some(names) => Err(format!(
+            "Invalid exclude_collections: {}. The value should be an array of collection names.",
+            names
+        )),
+    }
+}
+
+/// Check the collections of a request against the collection scope of its API key, if any.
+///
+/// The collections named in the request must all be in the scope. Otherwise, the default collections are narrowed to the scope, and the request is rejected if none is left.
+fn check_collection_scope(
+    qdrant_config_vec: &mut Vec<QdrantConfig>,
+    named: bool,
+    collection_scope: Option<&CollectionScope>,
+) -> Result<(), String> {
+    let collection_scope = match collection_scope {
+        Some(collection_scope) => collection_scope,
+        None => return Ok(()),
+    };
+
+    if named {
+        let denied: Vec<&str> = qdrant_config_vec
+            .iter()
+            .filter(|qdrant_config| !collection_scope.allows(&qdrant_config.collection_name))
+            .map(|qdrant_config| qdrant_config.collection_name.as_str())
+            .collect();
+        if !denied.is_empty() {
+            return Err(format!(
+                "The API key is not allowed to access the collections: {}.",
+                denied.join(", ")
+            ));
         }
+
+        return Ok(());
     }
 
-    // compute embeddings for chunks
-    let embeddings_response = {
-        // get the name of embedding model
-        let model = match llama_core::utils::embedding_model_names() {
-            Ok(model_names) => model_names[0].clone(),
-            Err(e) => {
-                let err_msg = e.to_string();
+    qdrant_config_vec
+        .retain(|qdrant_config| collection_scope.allows(&qdrant_config.collection_name));
+    if qdrant_config_vec.is_empty() {
+        return Err(
+            "The API key is not allowed to access any of the default collections.".to_string(),
+        );
+    }
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    info!(target: "stdout", "collections in the scope of the API key: {}", qdrant_config_vec.iter().map(|qdrant_config| qdrant_config.collection_name.as_str()).collect::<Vec<&str>>().join(","));
 
-                return error::internal_server_error(err_msg);
-            }
-        };
+    Ok(())
+}
 
-        info!(target: "stdout", "Prepare the rag embedding request.");
+/// Remove the excluded collections from the collections of the retrieval. The names not matching any collection are ignored. An error is returned if no collection is left.
+fn exclude_collections(
+    qdrant_config_vec: &mut Vec<QdrantConfig>,
+    excluded: &[String],
+) -> Result<(), String> {
+    if excluded.is_empty() {
+        return Ok(());
+    }
 
-        let api_key = match vdb_api_key.is_empty() {
-            true => None,
-            false => Some(vdb_api_key),
-        };
+    for name in excluded {
+        if !qdrant_config_vec
+            .iter()
+            .any(|qdrant_config| &qdrant_config.collection_name == name)
+        {
+            // log
+            debug!(target: "stdout", "Ignore the excluded collection `{}`, which is not used by the request.", name);
+        }
+    }
 
-        // create an embedding request
-        let embedding_request = EmbeddingRequest {
-            model: Some(model),
-            input: chunks.into(),
-            encoding_format: None,
-            user: None,
-            vdb_server_url: Some(vdb_server_url),
-            vdb_collection_name: Some(vdb_collection_name),
-            vdb_api_key: api_key,
-        };
+    qdrant_config_vec.retain(|qdrant_config| !excluded.contains(&qdrant_config.collection_name));
+    if qdrant_config_vec.is_empty() {
+        return Err(format!(
+            "All the collections of the request are excluded by exclude_collections: {}.",
+            excluded.join(", ")
+        ));
+    }
 
-        match rag_doc_chunks_to_embeddings(&embedding_request).await {
-            Ok(embedding_response) => embedding_response,
-            Err(e) => {
-                let err_msg = e.to_string();
+    info!(target: "stdout", "collections after exclusion: {}", qdrant_config_vec.iter().map(|qdrant_config| qdrant_config.collection_name.as_str()).collect::<Vec<&str>>().join(","));
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    Ok(())
+}
 
-                return error::internal_server_error(err_msg);
+/// Override the limit and the score threshold of all the collections by the optional `rag_limit` and `rag_score_threshold` fields of the request. The limit should be at least 1, and the score threshold in [0, 1].
+fn apply_rag_overrides(
+    body_bytes: &Bytes,
+    qdrant_config_vec: &mut [QdrantConfig],
+) -> Result<(), String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    let limit = match value.get("rag_limit") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(limit) => match limit.as_u64() {
+            Some(limit) if limit >= 1 => Some(limit),
+            _ => {
+                return Err(format!(
+                    "Invalid rag_limit: {}. The value should be an integer no less than 1.",
+                    limit
+                ))
             }
-        }
+        },
     };
 
-    // create the create rag response
-    let create_rag_response = if index_response.is_some() {
-        CreateRagResponse {
-            index_response,
-            embeddings_response,
+    let score_threshold = match value.get("rag_score_threshold") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(score_threshold) => match score_threshold.as_f64() {
+            Some(score_threshold) if (0.0..=1.0).contains(&score_threshold) => {
+                Some(score_threshold as f32)
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid rag_score_threshold: {}. The value should be a number in [0, 1].",
+                    score_threshold
+                ))
+            }
+        },
+    };
+
+    for qdrant_config in qdrant_config_vec.iter_mut() {
+        if let Some(limit) = limit {
+            qdrant_config.limit = limit;
         }
-    } else {
-        CreateRagResponse {
-            index_response: None,
-            embeddings_response,
+        if let Some(score_threshold) = score_threshold {
+            qdrant_config.score_threshold = score_threshold;
         }
-    };
+    }
 
-    // serialize embedding response
-    let res = match serde_json::to_string(&create_rag_response) {
-        Ok(s) => {
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .body(Body::from(s));
-            match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let err_msg = e.to_string();
+    if let Some(limit) = limit {
+        info!(target: "stdout", "rag_limit: {}", limit);
+    }
+    if let Some(score_threshold) = score_threshold {
+        info!(target: "stdout", "rag_score_threshold: {}", score_threshold);
+    }
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+    Ok(())
+}
 
-                    error::internal_server_error(err_msg)
-                }
+/// Read the optional `rerank_top_n` field of the request, and return the reranker config of `--rerank-url` with the number of reranked points kept from each collection overridden. The value should be at least 1, and no more than the candidates fetched from any of the collections. Returns `None` if no reranker is configured.
+fn request_rerank_config(
+    body_bytes: &Bytes,
+    qdrant_config_vec: &[QdrantConfig],
+) -> Result<Option<RerankConfig>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    let top_n = match value.get("rerank_top_n") {
+        None | Some(serde_json::Value::Null) => return Ok(RERANK_CONFIG.get().cloned()),
+        Some(top_n) => match top_n.as_u64() {
+            Some(top_n) if top_n >= 1 => top_n,
+            _ => {
+                return Err(format!(
+                    "Invalid rerank_top_n: {}. The value should be an integer no less than 1.",
+                    top_n
+                ))
             }
+        },
+    };
+
+    let mut rerank_config = match RERANK_CONFIG.get() {
+        Some(rerank_config) => rerank_config.clone(),
+        None => {
+            return Err(
+                "The `rerank_top_n` field requires a reranker, which is not configured by `--rerank-url`."
+                    .to_string(),
+            )
         }
-        Err(e) => {
-            let err_msg = format!("Fail to serialize embedding object. {}", e);
+    };
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+    if let Some(candidates) = qdrant_config_vec
+        .iter()
+        .map(|qdrant_config| rerank_config.candidates_for(qdrant_config.limit))
+        .min()
+    {
+        if top_n > candidates {
+            return Err(format!(
+                "Invalid rerank_top_n: {}. The value should be no more than the {} candidates retrieved per query.",
+                top_n, candidates
+            ));
+        }
+    }
 
-            error::internal_server_error(err_msg)
+    info!(target: "stdout", "rerank_top_n: {}", top_n);
+
+    rerank_config.top_n = Some(top_n);
+
+    Ok(Some(rerank_config))
+}
+
+/// Read the optional `group_by_document` field of the retrieve request. Defaults to `false`.
+fn request_group_by_document(body_bytes: &Bytes) -> Result<bool, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("group_by_document") {
+        None | Some(serde_json::Value::Null) => Ok(false),
+        Some(serde_json::Value::Bool(group_by_document)) => Ok(*group_by_document),
+        Some(group_by_document) => Err(format!(
+            "Invalid group_by_document: {}. The value should be a boolean.",
+            group_by_document
+        )),
+    }
+}
+
+/// The retrieved points of a source document.
+#[derive(Debug, serde::Serialize)]
+struct DocumentGroup {
+    /// The `doc_id` payload field of the points, or `null` for the points without it
+    doc_id: serde_json::Value,
+    /// The `title` payload field of the first point with it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    chunks: Vec<RagScoredPoint>,
+}
+
+/// Group the retrieved points by their `doc_id` payload field. The groups are ordered by their top score, and the chunks in each group by score.
+fn group_points_by_document(
+    retrieve_object_vec: &[RetrieveObject],
+    payloads: &Payloads,
+) -> Vec<DocumentGroup> {
+    let mut points: Vec<&RagScoredPoint> = retrieve_object_vec
+        .iter()
+        .flat_map(|retrieve_object| retrieve_object.points.iter().flatten())
+        .collect();
+    points.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+    let mut groups: Vec<DocumentGroup> = Vec::new();
+    for point in points {
+        let payload = payloads.get(&point.source);
+        let doc_id = payload
+            .and_then(|payload| payload.get("doc_id"))
+            .cloned()
+            .unwrap_or_default();
+        let title = payload
+            .and_then(|payload| payload.get("title"))
+            .and_then(serde_json::Value::as_str);
+
+        let group = match groups.iter().position(|group| group.doc_id == doc_id) {
+            Some(idx) => &mut groups[idx],
+            None => {
+                groups.push(DocumentGroup {
+                    doc_id,
+                    title: None,
+                    chunks: Vec::new(),
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        if group.title.is_none() {
+            group.title = title.map(str::to_string);
         }
-    };
+        group.chunks.push(point.clone());
+    }
 
-    info!(target: "stdout", "Send the doc_to_embeddings response");
+    groups
+}
 
-    res
+/// Read the optional `context_format` field of the chat request.
+fn request_context_format(body_bytes: &Bytes) -> Result<Option<ContextFormat>, String> {
+    let value: serde_json::Value = serde_json::from_slice(body_bytes)
+        .map_err(|e| format!("Fail to deserialize chat completion request: {}.", e))?;
+
+    match value.get("context_format") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(context_format)) => context_format.parse().map(Some),
+        Some(context_format) => Err(format!(
+            "Invalid context format: {}. Supported formats: plain, xml, markdown.",
+            context_format
+        )),
+    }
 }
 
-pub(crate) async fn server_info_handler() -> Response<Body> {
+/// Check if the field is set to a non-null value in the JSON body of the request.
+fn request_has_field(body_bytes: &Bytes, field: &str) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body_bytes)
+        .ok()
+        .and_then(|value| value.get(field).cloned())
+        .is_some_and(|value| !value.is_null())
+}
+
+/// Compute the retrieval confidence in [0, 1] from the scores of the retrieved points:
+///
+/// `confidence = 0.5 * clamp(top_score, 0, 1) + 0.5 * min(strong_chunks / confidence_strong_chunks, 1)`
+///
+/// where `strong_chunks` is the number of points with a score of at least `confidence_strong_score`. Nothing retrieved gives `0`.
+fn retrieval_confidence(retrieve_object_vec: &[RetrieveObject]) -> f32 {
+    let config = CONFIDENCE_CONFIG.get().cloned().unwrap_or_default();
+
+    let scores: Vec<f32> = retrieve_object_vec
+        .iter()
+        .flat_map(|retrieve_object| retrieve_object.points.iter().flatten())
+        .map(|point| point.score)
+        .collect();
+
+    let top_score = scores
+        .iter()
+        .copied()
+        .fold(0.0_f32, f32::max)
+        .clamp(0.0, 1.0);
+    let strong_chunks = scores
+        .iter()
+        .filter(|score| **score >= config.strong_score)
+        .count();
+    let coverage = (strong_chunks as f32 / config.strong_chunks as f32).min(1.0);
+    let confidence = 0.5 * top_score + 0.5 * coverage;
+
     // log
-    info!(target: "stdout", "Handling the coming server info request.");
+    debug!(target: "stdout", "retrieval confidence: {:.2} (top score: {:.2}, strong chunks: {})", confidence, top_score, strong_chunks);
 
-    // get the server info
-    let server_info = match SERVER_INFO.get() {
-        Some(server_info) => server_info.read().await,
-        None => {
-            let err_msg = "The server info is not set.";
+    confidence
+}
+
+/// Check if all the collections are empty if the `--warn-on-empty-collections` option is enabled. The check is performed only when nothing is retrieved, and a warning is logged if the collections are empty.
+async fn check_collections_empty(
+    chat_request: &ChatCompletionRequest,
+    qdrant_config_vec: &[QdrantConfig],
+) -> bool {
+    if !WARN_ON_EMPTY_COLLECTIONS.get().copied().unwrap_or(false) {
+        return false;
+    }
+
+    // get vdb_api_key if it is provided in the request, otherwise use the default API key of the Qdrant instance
+    let vdb_api_key = chat_request
+        .vdb_api_key
+        .clone()
+        .or_else(qdrant::default_api_key);
+
+    match qdrant::all_collections_empty(qdrant_config_vec, vdb_api_key.as_deref()).await {
+        Ok(true) => {
+            let collection_names = qdrant_config_vec
+                .iter()
+                .map(|qdrant_config| qdrant_config.collection_name.clone())
+                .collect::<Vec<String>>()
+                .join(",");
 
             // log
-            error!(target: "stdout", "{}", &err_msg);
+            warn!(target: "stdout", "Nothing retrieved since all the collections are empty: {}", collection_names);
 
-            return error::internal_server_error("The server info is not set.");
+            true
         }
-    };
-
-    // serialize server info
-    let s = match serde_json::to_string(&*server_info) {
-        Ok(s) => s,
+        Ok(false) => false,
         Err(e) => {
-            let err_msg = format!("Fail to serialize server info. {}", e);
-
             // log
-            error!(target: "stdout", "{}", &err_msg);
+            warn!(target: "stdout", "Failed to check if the collections are empty. {}", e);
 
-            return error::internal_server_error(err_msg);
+            false
         }
+    }
+}
+
+/// The score threshold in the request, which overrides the configured score thresholds of the collections.
+#[derive(Debug, Clone)]
+enum ScoreThresholdOverride {
+    /// The threshold applied to all the collections
+    All(f32),
+    /// The thresholds of the collections, keyed by the collection name
+    PerCollection(HashMap<String, f32>),
+}
+
+/// Take the `score_threshold` field out of the request if it is a number or a map from the collection names to the thresholds. An array is left in the request, and paired with the `vdb_collection_name` field as before.
+fn take_score_threshold_override(
+    body_bytes: Bytes,
+) -> Result<(Bytes, Option<ScoreThresholdOverride>), String> {
+    // leave the malformed request to the deserialization of the chat request
+    let mut json_value = match serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+        Ok(json_value) => json_value,
+        Err(_) => return Ok((body_bytes, None)),
+    };
+    let object = match json_value.as_object_mut() {
+        Some(object) => object,
+        None => return Ok((body_bytes, None)),
     };
 
-    // return response
-    let result = Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .header("Content-Type", "application/json")
-        .body(Body::from(s));
-    let res = match result {
-        Ok(response) => response,
-        Err(e) => {
-            let err_msg = e.to_string();
+    let score_threshold_override = match object.get("score_threshold") {
+        Some(serde_json::Value::Number(threshold)) => {
+            ScoreThresholdOverride::All(threshold.as_f64().unwrap_or_default() as f32)
+        }
+        Some(serde_json::Value::Object(thresholds)) => {
+            let mut per_collection = HashMap::new();
+            for (collection_name, threshold) in thresholds {
+                let threshold = threshold.as_f64().ok_or_else(|| {
+                    format!(
+                        "Invalid score threshold for the collection `{}`: {}. The threshold should be a number.",
+                        collection_name, threshold
+                    )
+                })?;
+                per_collection.insert(collection_name.clone(), threshold as f32);
+            }
+            ScoreThresholdOverride::PerCollection(per_collection)
+        }
+        _ => return Ok((body_bytes, None)),
+    };
+    object.remove("score_threshold");
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+    let body_bytes = serde_json::to_vec(&json_value)
+        .map(Bytes::from)
+        .map_err(|e| format!("Fail to serialize the request. {}", e))?;
 
-            error::internal_server_error(err_msg)
+    Ok((body_bytes, Some(score_threshold_override)))
+}
+
+/// Override the score thresholds of the collections. The threshold should be in [0, 1], unless the collection is known to use the `Euclid` or `Manhattan` distance, where the threshold is the maximum distance, by the collection info fetched with `--enrich-info`.
+async fn apply_score_threshold_override(
+    qdrant_config_vec: &mut [QdrantConfig],
+    score_threshold_override: &ScoreThresholdOverride,
+) -> Result<(), String> {
+    if let ScoreThresholdOverride::PerCollection(thresholds) = score_threshold_override {
+        for collection_name in thresholds.keys() {
+            if !qdrant_config_vec
+                .iter()
+                .any(|qdrant_config| &qdrant_config.collection_name == collection_name)
+            {
+                return Err(format!(
+                    "Invalid score threshold: the collection `{}` is not used by the request.",
+                    collection_name
+                ));
+            }
         }
+    }
+
+    // the distances of the collections, if known
+    let distances: HashMap<String, String> = match SERVER_INFO.get() {
+        Some(server_info) => server_info
+            .read()
+            .await
+            .collection_info
+            .iter()
+            .flatten()
+            .filter_map(|info| {
+                info.distance
+                    .clone()
+                    .map(|distance| (info.collection_name.clone(), distance))
+            })
+            .collect(),
+        None => HashMap::new(),
     };
 
-    info!(target: "stdout", "Send the server info response.");
+    for qdrant_config in qdrant_config_vec.iter_mut() {
+        let score_threshold = match score_threshold_override {
+            ScoreThresholdOverride::All(threshold) => *threshold,
+            ScoreThresholdOverride::PerCollection(thresholds) => {
+                match thresholds.get(&qdrant_config.collection_name) {
+                    Some(threshold) => *threshold,
+                    None => continue,
+                }
+            }
+        };
 
-    res
+        let distance_based = matches!(
+            distances
+                .get(&qdrant_config.collection_name)
+                .map(String::as_str),
+            Some("Euclid") | Some("Manhattan")
+        );
+        if !distance_based && !(0.0..=1.0).contains(&score_threshold) {
+            return Err(format!(
+                "Invalid score threshold for the collection `{}`: {}. The threshold should be in [0, 1].",
+                qdrant_config.collection_name, score_threshold
+            ));
+        }
+
+        info!(target: "stdout", "override the score threshold of the collection `{}`: {} -> {}", qdrant_config.collection_name, qdrant_config.score_threshold, score_threshold);
+
+        qdrant_config.score_threshold = score_threshold;
+    }
+
+    Ok(())
 }
 
-pub(crate) async fn retrieve_handler(mut req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming retrieve request.");
+// The maximum number of the stop sequences of a chat request, as in the OpenAI API
+const MAX_STOP_SEQUENCES: usize = 4;
 
-    if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
+/// Check the `stop` field of a chat request: up to `MAX_STOP_SEQUENCES` non-empty sequences.
+fn check_stop_sequences(stop: Option<&[String]>) -> Result<Vec<String>, String> {
+    let stop = stop.unwrap_or_default();
 
-        match result {
-            Ok(response) => return response,
-            Err(e) => {
-                let err_msg = e.to_string();
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(format!(
+            "The `stop` field accepts at most {} sequences, while {} are given.",
+            MAX_STOP_SEQUENCES,
+            stop.len()
+        ));
+    }
+    if stop.iter().any(|stop| stop.is_empty()) {
+        return Err("The sequences of the `stop` field should not be empty.".to_string());
+    }
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    Ok(stop.to_vec())
+}
 
-                return error::internal_server_error(err_msg);
-            }
-        }
+/// Check that the messages of a chat request contain at least one user message. Without one, the retrieval has no query and the model has no turn to answer.
+fn check_user_message(messages: &[ChatCompletionRequestMessage]) -> Result<(), String> {
+    match messages
+        .iter()
+        .any(|message| matches!(message, ChatCompletionRequestMessage::User(_)))
+    {
+        true => Ok(()),
+        false if messages.is_empty() => Err("The `messages` field is empty. At least one user message is required.".to_string()),
+        false => Err("The `messages` field contains no user message. At least one user message is required, since the retrieval query and the answer are based on the user messages.".to_string()),
     }
+}
 
-    info!(target: "stdout", "Prepare the chat completion request.");
+/// Detect the non-text content parts, such as images and audio, in the messages of a chat request. An error message is returned unless `--ignore-nontext-parts` is set, in which case only the text parts are kept.
+fn check_nontext_parts(body_bytes: Bytes) -> Result<Bytes, String> {
+    // leave the malformed request to the deserialization of the chat request
+    let mut json_value = match serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+        Ok(json_value) => json_value,
+        Err(_) => return Ok(body_bytes),
+    };
+    let messages = match json_value
+        .get_mut("messages")
+        .and_then(serde_json::Value::as_array_mut)
+    {
+        Some(messages) => messages,
+        None => return Ok(body_bytes),
+    };
 
-    // parse request
-    let body_bytes = match to_bytes(req.body_mut()).await {
-        Ok(body_bytes) => body_bytes,
-        Err(e) => {
-            let err_msg = format!("Fail to read buffer from request body. {}", e);
+    let ignore_nontext_parts = IGNORE_NONTEXT_PARTS.get().copied().unwrap_or(false);
+    let mut modified = false;
+    for (idx, message) in messages.iter_mut().enumerate() {
+        let parts = match message
+            .get_mut("content")
+            .and_then(serde_json::Value::as_array_mut)
+        {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        let nontext_types: Vec<String> = parts
+            .iter()
+            .filter_map(|part| part.get("type").and_then(serde_json::Value::as_str))
+            .filter(|ty| *ty != "text")
+            .map(|ty| ty.to_string())
+            .collect();
+        if nontext_types.is_empty() {
+            continue;
+        }
+
+        if !ignore_nontext_parts {
+            let modalities: Vec<&str> = nontext_types
+                .iter()
+                .map(|ty| match ty.as_str() {
+                    "image_url" | "image" => "image",
+                    "input_audio" | "audio" => "audio",
+                    ty => ty,
+                })
+                .collect();
+            let err_msg = format!(
+                "The message at index {} contains unsupported {} content. This server only supports text content. Start the server with `--ignore-nontext-parts` to use only the text parts.",
+                idx,
+                modalities.join(", ")
+            );
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::internal_server_error(err_msg);
+            return Err(err_msg);
         }
-    };
-    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(chat_request) => chat_request,
-        Err(e) => {
-            let mut err_msg = format!("Fail to deserialize chat completion request: {}.", e);
 
-            if let Ok(json_value) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-                err_msg = format!("{}\njson_value: {}", err_msg, json_value);
-            }
+        // log
+        warn!(target: "stdout", "Ignore the non-text content parts in the message at index {}: {}", idx, nontext_types.join(", "));
+
+        parts.retain(|part| part.get("type").and_then(serde_json::Value::as_str) == Some("text"));
+        if parts.is_empty() {
+            let err_msg = format!(
+                "The message at index {} contains no text content after ignoring the non-text content parts.",
+                idx
+            );
 
             // log
             error!(target: "stdout", "{}", &err_msg);
 
-            return error::bad_request(err_msg);
+            return Err(err_msg);
         }
-    };
 
-    if chat_request.user.is_none() {
-        chat_request.user = Some(gen_chat_id())
-    };
-    let id = chat_request.user.clone().unwrap();
+        modified = true;
+    }
 
-    // log user id
-    info!(target: "stdout", "user: {}", &id);
+    match modified {
+        true => Ok(Bytes::from(json_value.to_string())),
+        false => Ok(body_bytes),
+    }
+}
 
-    // qdrant config
-    let qdrant_config_vec = match get_qdrant_configs(&chat_request).await {
-        Ok(qdrant_config_vec) => qdrant_config_vec,
-        Err(e) => return error::internal_server_error(e.to_string()),
-    };
+/// Map the alias of a model of the given type (`chat` or `embedding`) to the model name, so that the `model` field of the response is the name of the model serving the request. Other values are returned as is.
+async fn resolve_model_name(model: Option<String>, ty: &str) -> Option<String> {
+    let model = model?;
 
-    // retrieve context
-    let retrieve_object_vec = match retrieve_context_with_multiple_qdrant_configs(
-        &chat_request,
-        &qdrant_config_vec,
-    )
-    .await
-    {
-        Ok(retrieve_object_vec) => retrieve_object_vec,
-        Err(response) => {
-            return response;
-        }
-    };
+    if let Some(server_info) = SERVER_INFO.get() {
+        let server_info = server_info.read().await;
+        let rag_config = &server_info.rag_config;
 
-    // log retrieve object
-    debug!(target: "stdout", "retrieve_object_vec:\n{}", serde_json::to_string_pretty(&retrieve_object_vec).unwrap());
+        let models = rag_config
+            .chat_model
+            .iter()
+            .chain(std::iter::once(&rag_config.embedding_model))
+            .chain(rag_config.extra_embedding_models.iter());
+        for model_config in models {
+            if model_config.ty == ty && model_config.alias == model && model_config.name != model {
+                info!(target: "stdout", "Resolve the model alias `{}` to the model `{}`", &model, &model_config.name);
 
-    let res = {
-        // serialize retrieve object
-        let s = match serde_json::to_string(&retrieve_object_vec) {
-            Ok(s) => s,
-            Err(e) => {
-                let err_msg = format!("Fail to serialize retrieve object. {}", e);
+                return Some(model_config.name.clone());
+            }
+        }
+    }
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+    Some(model)
+}
 
-                return error::internal_server_error(err_msg);
-            }
-        };
+/// Log a warning naming the requested model and the model and the prompt template actually applied if the requested chat model is not loaded. The core then falls back to the default chat model.
+fn warn_unknown_chat_model(model: &str) {
+    let chat_model_names = match llama_core::utils::chat_model_names() {
+        Ok(chat_model_names) => chat_model_names,
+        Err(_) => return,
+    };
+    if chat_model_names.iter().any(|name| name == model) {
+        return;
+    }
 
-        // return response
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .header("user", id)
-            .body(Body::from(s));
+    // the core falls back to the first chat model, whose template is the one of the default chat model
+    let applied_model = chat_model_names.first().map(String::as_str).unwrap_or("-");
+    let applied_template = llama_core::utils::chat_prompt_template(None)
+        .map(|template| template.to_string())
+        .unwrap_or_else(|_| "-".to_string());
 
-        match result {
-            Ok(response) => response,
-            Err(e) => {
-                let err_msg = e.to_string();
+    // log
+    warn!(target: "stdout", "The requested chat model `{}` is not loaded. The request is served by the model `{}` with the prompt template `{}`.", model, applied_model, applied_template);
+}
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+/// The query prefix of the collection set by `--query-prefix` or `--collection-query-prefix`, or an empty string.
+fn query_prefix_for_collection(collection_name: &str) -> &'static str {
+    EMBEDDING_PREFIXES
+        .get()
+        .map(|prefixes| prefixes.query_prefix_for(collection_name))
+        .unwrap_or_default()
+}
 
-                error::internal_server_error(e.to_string())
-            }
-        }
-    };
+/// The passage prefix of the collection set by `--passage-prefix` or `--collection-passage-prefix`, or an empty string.
+fn passage_prefix_for_collection(collection_name: &str) -> &'static str {
+    EMBEDDING_PREFIXES
+        .get()
+        .map(|prefixes| prefixes.passage_prefix_for(collection_name))
+        .unwrap_or_default()
+}
 
-    info!(target: "stdout", "Send the retrieve response.");
+/// Get the name of the embedding model of the collection: the model mapped to the collection by `--embedding-collection-map`, or the default embedding model.
+fn embedding_model_for_collection(collection_name: &str) -> Result<String, error::ServerError> {
+    if let Some(routes) = EMBEDDING_ROUTES.get() {
+        return Ok(routes.model_for(collection_name).to_string());
+    }
 
-    res
+    let model_names = llama_core::utils::embedding_model_names()
+        .map_err(|e| error::ServerError::Operation(e.to_string()))?;
+    model_names
+        .into_iter()
+        .next()
+        .ok_or_else(|| error::ServerError::Operation("No embedding model is available.".into()))
 }
 
 async fn get_qdrant_configs(
@@ -2521,6 +6597,26 @@ fn normalize(scores: &HashMap<u64, f32>) -> HashMap<u64, f32> {
         .collect()
 }
 
+/// Fuse the rankings of the keyword search and the vector search with Reciprocal Rank Fusion: each document scores `1 / (k + rank)` in each ranking it appears in, with the rank starting at 1.
+///
+/// The scores are divided by the score of a document ranked first in both rankings, `2 / (k + 1)`, so that they are in `[0, 1]` like the similarity scores.
+fn reciprocal_rank_fusion(kw_ranking: &[u64], em_ranking: &[u64], k: u64) -> HashMap<u64, f32> {
+    let k = k as f32;
+
+    let mut scores: HashMap<u64, f32> = HashMap::new();
+    for ranking in [kw_ranking, em_ranking] {
+        for (idx, doc_id) in ranking.iter().enumerate() {
+            *scores.entry(*doc_id).or_insert(0.0) += 1.0 / (k + idx as f32 + 1.0);
+        }
+    }
+
+    let max_score = 2.0 / (k + 1.0);
+    scores
+        .into_iter()
+        .map(|(doc_id, score)| (doc_id, score / max_score))
+        .collect()
+}
+
 fn weighted_fusion(
     bm25_scores: HashMap<u64, f32>,
     embedding_scores: HashMap<u64, f32>,
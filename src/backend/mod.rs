@@ -1,21 +1,44 @@
 pub(crate) mod ggml;
 
-use crate::error;
-use hyper::{Body, Request, Response};
+use crate::{
+    coalesce::coalesce,
+    error,
+    ingestion::limit_ingestion,
+    load::{limit_requests, InFlight, Load},
+    DEBUG_ENDPOINTS, READ_ONLY,
+};
+use hyper::{Body, Method, Request, Response};
+
+pub(crate) async fn handle_llama_request(req: Request<Body>) -> Response<Body> {
+    if READ_ONLY.get().copied().unwrap_or(false) && is_mutation(&req) {
+        return error::forbidden(format!(
+            "`{} {}` is disabled because the server runs in the read-only mode.",
+            req.method(),
+            req.uri().path()
+        ));
+    }
+
+    // the status requests are not counted, so that polling doesn't show as load
+    let _in_flight = (req.uri().path() != "/v1/status").then(|| InFlight::new(Load::Request));
 
-pub(crate) async fn handle_llama_request(
-    req: Request<Body>,
-    chunk_capacity: usize,
-) -> Response<Body> {
     match req.uri().path() {
-        "/v1/chat/completions" => ggml::rag_query_handler(req).await,
+        "/v1/chat/completions" => {
+            coalesce(req, |req| limit_requests(req, ggml::rag_query_handler)).await
+        }
         "/v1/models" => ggml::models_handler().await,
-        "/v1/embeddings" => ggml::embeddings_handler(req).await,
+        "/v1/embeddings" => {
+            coalesce(req, |req| limit_requests(req, ggml::embeddings_handler)).await
+        }
         "/v1/files" => ggml::files_handler(req).await,
         "/v1/chunks" => ggml::chunks_handler(req).await,
-        "/v1/retrieve" => ggml::retrieve_handler(req).await,
-        "/v1/create/rag" => ggml::create_rag_handler(req, chunk_capacity).await,
+        "/v1/retrieve" => limit_requests(req, ggml::retrieve_handler).await,
+        "/v1/retrieve/compare" if DEBUG_ENDPOINTS.get().copied().unwrap_or(false) => {
+            limit_requests(req, ggml::retrieve_compare_handler).await
+        }
+        "/v1/create/rag" => limit_ingestion(req, ggml::create_rag_handler).await,
         "/v1/info" => ggml::server_info_handler().await,
+        "/v1/health" => ggml::health_handler().await,
+        "/v1/status" => ggml::status_handler().await,
         path => {
             if path.starts_with("/v1/files/") {
                 ggml::files_handler(req).await
@@ -25,3 +48,15 @@ pub(crate) async fn handle_llama_request(
         }
     }
 }
+
+/// Check if the request alters the files or the collections.
+fn is_mutation(req: &Request<Body>) -> bool {
+    let path = req.uri().path();
+    let method = req.method();
+
+    match path {
+        "/v1/files" => method == Method::POST,
+        "/v1/create/rag" => method != Method::OPTIONS,
+        _ => path.starts_with("/v1/files/") && method == Method::DELETE,
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{code_preprocess::CodeLanguage, error::ServerError, utils::cl100k_tokenizer};
+use serde::{Deserialize, Serialize};
+use text_splitter::{MarkdownSplitter, TextSplitter};
+use tiktoken_rs::CoreBPE;
+
+/// The strategy used to split a document into chunks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ChunkStrategy {
+    /// Split plain text by tokens, respecting paragraph and sentence boundaries.
+    Token,
+    /// Split Markdown text, respecting headings, lists and code blocks.
+    Markdown,
+    /// Split source code at top-level blocks, such as functions and classes.
+    Code,
+}
+impl ChunkStrategy {
+    /// Select the strategy by the extension of the filename. Returns `None` if the extension is unknown.
+    pub(crate) fn from_filename(filename: impl AsRef<str>) -> Option<Self> {
+        if CodeLanguage::from_filename(&filename).is_some() {
+            return Some(ChunkStrategy::Code);
+        }
+
+        let extension = std::path::Path::new(filename.as_ref())
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)?
+            .to_lowercase();
+
+        match extension.as_str() {
+            "md" | "markdown" | "mdx" => Some(ChunkStrategy::Markdown),
+            "txt" | "text" => Some(ChunkStrategy::Token),
+            _ => None,
+        }
+    }
+
+    /// Select the strategy by sniffing the contents. Falls back to `Token` if neither Markdown nor source code is detected.
+    pub(crate) fn sniff(contents: impl AsRef<str>) -> Self {
+        let lines: Vec<&str> = contents
+            .as_ref()
+            .lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .collect();
+        if lines.is_empty() {
+            return ChunkStrategy::Token;
+        }
+
+        let markdown_lines = lines
+            .iter()
+            .filter(|line| {
+                let line = line.trim_start();
+                line.starts_with("# ")
+                    || line.starts_with("## ")
+                    || line.starts_with("### ")
+                    || line.starts_with("```")
+                    || line.starts_with("- ")
+                    || line.starts_with("* ")
+            })
+            .count();
+        let code_lines = lines
+            .iter()
+            .filter(|line| line.ends_with(';') || line.ends_with('{') || line.ends_with('}'))
+            .count();
+
+        // at least 20% of non-empty lines should match the pattern
+        if code_lines * 5 >= lines.len() && code_lines > markdown_lines {
+            ChunkStrategy::Code
+        } else if markdown_lines * 5 >= lines.len() {
+            ChunkStrategy::Markdown
+        } else {
+            ChunkStrategy::Token
+        }
+    }
+}
+impl std::fmt::Display for ChunkStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChunkStrategy::Token => write!(f, "token"),
+            ChunkStrategy::Markdown => write!(f, "markdown"),
+            ChunkStrategy::Code => write!(f, "code"),
+        }
+    }
+}
+impl std::str::FromStr for ChunkStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "token" => Ok(ChunkStrategy::Token),
+            "markdown" => Ok(ChunkStrategy::Markdown),
+            "code" => Ok(ChunkStrategy::Code),
+            _ => Err(format!(
+                "Invalid chunking strategy: {}. Supported strategies: token, markdown, code.",
+                s
+            )),
+        }
+    }
+}
+
+/// Split the text into chunks of at most `chunk_capacity` tokens with the given strategy.
+pub(crate) fn chunk_text(
+    text: impl AsRef<str>,
+    strategy: ChunkStrategy,
+    chunk_capacity: usize,
+) -> Result<Vec<String>, ServerError> {
+    info!(target: "stdout", "Chunk the contents with the {} strategy.", strategy);
+
+    let tokenizer = cl100k_tokenizer().map_err(|e| {
+        let err_msg = format!("Failed to create the tokenizer for chunking. {}", e);
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        ServerError::Operation(err_msg)
+    })?;
+
+    let chunks = match strategy {
+        ChunkStrategy::Token => TextSplitter::new(tokenizer)
+            .with_trim_chunks(true)
+            .chunks(text.as_ref(), chunk_capacity)
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>(),
+        ChunkStrategy::Markdown => MarkdownSplitter::new(tokenizer)
+            .with_trim_chunks(true)
+            .chunks(text.as_ref(), chunk_capacity)
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>(),
+        ChunkStrategy::Code => chunk_code(text.as_ref(), tokenizer, chunk_capacity),
+    };
+
+    info!(target: "stdout", "Number of chunks: {}", chunks.len());
+
+    Ok(chunks)
+}
+
+/// Split source code into top-level blocks, which start at an unindented line after a blank line, and pack consecutive blocks into chunks. Blocks exceeding the capacity are split by lines. The indentation of the code is preserved.
+fn chunk_code(text: &str, tokenizer: &CoreBPE, chunk_capacity: usize) -> Vec<String> {
+    let num_tokens = |s: &str| tokenizer.encode_ordinary(s).len();
+
+    // split the code into top-level blocks
+    let mut blocks: Vec<String> = Vec::new();
+    let mut block = String::new();
+    let mut after_blank_line = false;
+    for line in text.lines() {
+        let starts_block = after_blank_line && !line.starts_with(char::is_whitespace);
+        if starts_block && !block.trim().is_empty() {
+            blocks.push(std::mem::take(&mut block));
+        }
+        block.push_str(line);
+        block.push('\n');
+        after_blank_line = line.trim().is_empty();
+    }
+    if !block.trim().is_empty() {
+        blocks.push(block);
+    }
+
+    // pack the blocks into chunks
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_tokens = 0;
+    for block in blocks {
+        let block_tokens = num_tokens(&block);
+
+        if chunk_tokens + block_tokens <= chunk_capacity {
+            chunk.push_str(&block);
+            chunk_tokens += block_tokens;
+            continue;
+        }
+
+        if !chunk.trim().is_empty() {
+            chunks.push(chunk.trim_start_matches('\n').trim_end().to_string());
+        }
+        chunk = String::new();
+        chunk_tokens = 0;
+
+        if block_tokens <= chunk_capacity {
+            chunk = block;
+            chunk_tokens = block_tokens;
+            continue;
+        }
+
+        // the block is too large, so split it by lines
+        for line in block.split_inclusive('\n') {
+            let line_tokens = num_tokens(line);
+            if chunk_tokens + line_tokens > chunk_capacity && !chunk.trim().is_empty() {
+                chunks.push(chunk.trim_start_matches('\n').trim_end().to_string());
+                chunk = String::new();
+                chunk_tokens = 0;
+            }
+            chunk.push_str(line);
+            chunk_tokens += line_tokens;
+        }
+    }
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk.trim_start_matches('\n').trim_end().to_string());
+    }
+
+    chunks
+}
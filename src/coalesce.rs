@@ -0,0 +1,271 @@
+use crate::{error, COALESCE_REQUESTS, REQUEST_HASH_HEADER};
+use endpoints::{chat::ChatCompletionRequest, embeddings::EmbeddingRequest};
+use futures::channel::oneshot;
+use hyper::{
+    body::{to_bytes, Bytes},
+    header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION},
+    Body, Method, Request, Response, StatusCode,
+};
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+// The waiters of the in-flight requests, keyed by the effective request
+static IN_FLIGHT: Lazy<Mutex<HashMap<EffectiveRequest, Vec<oneshot::Sender<CoalescedResponse>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The effective request, i.e., the path, the `Authorization` header and the canonical body. Two requests are identical if their effective requests are equal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct EffectiveRequest {
+    path: String,
+    authorization: Option<Vec<u8>>,
+    body: Bytes,
+}
+impl EffectiveRequest {
+    /// Build the effective request.
+    ///
+    /// The body of a chat completion or embeddings request is parsed, so that the defaults of the omitted fields are filled in, and serialized again with sorted keys, so that the JSON formatting and the order of the fields don't matter. The fields unknown to the request type, such as `rag_limit`, are kept as they are. Other JSON bodies are only serialized again with sorted keys, and the bodies that fail to parse are kept as is.
+    pub(crate) fn new(path: &str, authorization: Option<&[u8]>, body_bytes: &Bytes) -> Self {
+        let effective = serde_json::from_slice::<serde_json::Value>(body_bytes)
+            .ok()
+            .map(|mut value| {
+                let parsed = match path {
+                    "/v1/chat/completions" => {
+                        serde_json::from_slice::<ChatCompletionRequest>(body_bytes)
+                            .ok()
+                            .and_then(|request| serde_json::to_value(request).ok())
+                    }
+                    "/v1/embeddings" => serde_json::from_slice::<EmbeddingRequest>(body_bytes)
+                        .ok()
+                        .and_then(|request| serde_json::to_value(request).ok()),
+                    _ => None,
+                };
+                // the fields read by the server only, such as `rag_limit`, are kept as they are
+                if let (Some(object), Some(serde_json::Value::Object(parsed))) =
+                    (value.as_object_mut(), parsed)
+                {
+                    object.extend(parsed);
+                }
+                value
+            });
+        // the keys of `serde_json::Value` objects are sorted
+        let body = match effective.and_then(|value| serde_json::to_vec(&value).ok()) {
+            Some(canonical) => Bytes::from(canonical),
+            None => body_bytes.clone(),
+        };
+
+        Self {
+            path: path.to_string(),
+            authorization: authorization.map(|authorization| authorization.to_vec()),
+            body,
+        }
+    }
+}
+
+/// A buffered response shared with the waiters of an identical request.
+#[derive(Debug, Clone)]
+struct CoalescedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+impl CoalescedResponse {
+    fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+/// Removes the entry of the leading request on drop, so that the waiters stop waiting even if the leading request is cancelled.
+struct InFlightGuard(EffectiveRequest);
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = IN_FLIGHT.lock() {
+            in_flight.remove(&self.0);
+        }
+    }
+}
+
+/// Handle the request with the handler, sharing the response among identical concurrent requests if the `--coalesce-requests` option is enabled, and returning the hash of the request in the `X-Request-Hash` header if the `--request-hash-header` option is enabled.
+///
+/// Two requests are identical if their effective requests are equal, see [`EffectiveRequest`]; the hash is never used to tell them apart. Only non-stream `POST` requests are coalesced.
+pub(crate) async fn coalesce<F, Fut>(req: Request<Body>, handler: F) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Response<Body>>,
+{
+    let coalescing = COALESCE_REQUESTS.get().copied().unwrap_or(false);
+    let hash_header = REQUEST_HASH_HEADER.get().copied().unwrap_or(false);
+    if !(coalescing || hash_header) || req.method() != Method::POST {
+        return handler(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let body_bytes = match to_bytes(body).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from request body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    let key = EffectiveRequest::new(
+        parts.uri.path(),
+        parts
+            .headers
+            .get(AUTHORIZATION)
+            .map(|value| value.as_bytes()),
+        &body_bytes,
+    );
+    let hash = request_hash(&key);
+    let with_hash = |mut response: Response<Body>| {
+        if hash_header {
+            if let Ok(value) = HeaderValue::from_str(&format!("{:016x}", hash)) {
+                response.headers_mut().insert("X-Request-Hash", value);
+            }
+        }
+        response
+    };
+
+    if !coalescing || is_stream_request(&parts.headers, &body_bytes) {
+        return with_hash(handler(Request::from_parts(parts, Body::from(body_bytes))).await);
+    }
+
+    // wait for the identical in-flight request if any, otherwise lead
+    let receiver = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get_mut(&key) {
+            Some(waiters) => {
+                let (sender, receiver) = oneshot::channel();
+                waiters.push(sender);
+                Some(receiver)
+            }
+            None => {
+                in_flight.insert(key.clone(), Vec::new());
+                None
+            }
+        }
+    };
+
+    if let Some(receiver) = receiver {
+        // log
+        info!(target: "stdout", "Wait for the identical in-flight request.");
+
+        match receiver.await {
+            Ok(response) => return with_hash(response.into_response()),
+            Err(_) => {
+                // log
+                warn!(target: "stdout", "The identical in-flight request is cancelled. Handle the request on its own.");
+
+                return with_hash(
+                    handler(Request::from_parts(parts, Body::from(body_bytes))).await,
+                );
+            }
+        }
+    }
+
+    let guard = InFlightGuard(key);
+    let response = handler(Request::from_parts(parts, Body::from(body_bytes))).await;
+
+    let waiters = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .remove(&guard.0)
+        .unwrap_or_default();
+    drop(guard);
+
+    if waiters.is_empty() {
+        return with_hash(response);
+    }
+
+    // buffer the response to share it with the waiters
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            let err_msg = format!("Fail to read buffer from response body. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+    let coalesced = CoalescedResponse {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    };
+
+    // log
+    info!(target: "stdout", "Share the response with {} identical request(s).", waiters.len());
+
+    for waiter in waiters {
+        let _ = waiter.send(coalesced.clone());
+    }
+
+    with_hash(coalesced.into_response())
+}
+
+/// Compute the hash of the effective request for the `X-Request-Hash` header, which is stable across restarts and instances of the same server version.
+///
+/// The path and the `Authorization` header are hashed along with the canonical body, so that the requests of different API keys never share a hash.
+pub(crate) fn request_hash(request: &EffectiveRequest) -> u64 {
+    let EffectiveRequest {
+        path,
+        authorization,
+        body,
+    } = request;
+
+    let mut hash = Fnv1a::new();
+    hash.write(path.as_bytes());
+    hash.write(&[0xff]);
+    if let Some(authorization) = authorization {
+        hash.write(authorization);
+    }
+    hash.write(&[0xff]);
+    hash.write(body);
+    hash.finish()
+}
+
+/// The 64-bit FNV-1a hash, which, unlike `DefaultHasher`, is specified and never changes with the Rust version. It is unkeyed and easy to collide on purpose, so it only serves as an identifier, never to tell requests apart.
+pub(crate) struct Fnv1a(u64);
+impl Fnv1a {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub(crate) fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Check if the response of the request is streamed, either as server-sent events or as NDJSON.
+fn is_stream_request(headers: &HeaderMap, body_bytes: &Bytes) -> bool {
+    let ndjson = headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/x-ndjson"));
+
+    let stream = serde_json::from_slice::<serde_json::Value>(body_bytes)
+        .ok()
+        .and_then(|value| value.get("stream").and_then(serde_json::Value::as_bool))
+        .unwrap_or(false);
+
+    ndjson || stream
+}
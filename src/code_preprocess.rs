@@ -0,0 +1,323 @@
+use serde::{Deserialize, Serialize};
+
+/// A preprocessing step applied to the source code chunks before embedding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CodePreprocess {
+    /// Remove the comments, keeping the string literals intact.
+    StripComments,
+    /// Remove the trailing whitespace, collapse the blank lines and the runs of spaces, keeping the indentation.
+    NormalizeWhitespace,
+    /// Prepend a `language: <name>` line.
+    LanguageTag,
+}
+impl std::fmt::Display for CodePreprocess {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CodePreprocess::StripComments => write!(f, "strip-comments"),
+            CodePreprocess::NormalizeWhitespace => write!(f, "normalize-whitespace"),
+            CodePreprocess::LanguageTag => write!(f, "language-tag"),
+        }
+    }
+}
+
+/// A programming language recognized by the extension of the source file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum CodeLanguage {
+    Rust,
+    Python,
+    JavaScript,
+    TypeScript,
+    Go,
+    Java,
+    Kotlin,
+    Scala,
+    C,
+    Cpp,
+    CSharp,
+    Ruby,
+    Php,
+    Swift,
+    Shell,
+    Lua,
+}
+impl CodeLanguage {
+    /// Recognize the language by the extension of the filename. Returns `None` if the extension is unknown.
+    pub(crate) fn from_filename(filename: impl AsRef<str>) -> Option<Self> {
+        let extension = std::path::Path::new(filename.as_ref())
+            .extension()
+            .and_then(std::ffi::OsStr::to_str)?
+            .to_lowercase();
+
+        match extension.as_str() {
+            "rs" => Some(CodeLanguage::Rust),
+            "py" => Some(CodeLanguage::Python),
+            "js" | "jsx" => Some(CodeLanguage::JavaScript),
+            "ts" | "tsx" => Some(CodeLanguage::TypeScript),
+            "go" => Some(CodeLanguage::Go),
+            "java" => Some(CodeLanguage::Java),
+            "kt" => Some(CodeLanguage::Kotlin),
+            "scala" => Some(CodeLanguage::Scala),
+            "c" | "h" => Some(CodeLanguage::C),
+            "cc" | "cpp" | "hpp" => Some(CodeLanguage::Cpp),
+            "cs" => Some(CodeLanguage::CSharp),
+            "rb" => Some(CodeLanguage::Ruby),
+            "php" => Some(CodeLanguage::Php),
+            "swift" => Some(CodeLanguage::Swift),
+            "sh" => Some(CodeLanguage::Shell),
+            "lua" => Some(CodeLanguage::Lua),
+            _ => None,
+        }
+    }
+
+    /// The name of the language in the `language:` tag.
+    fn name(&self) -> &'static str {
+        match self {
+            CodeLanguage::Rust => "rust",
+            CodeLanguage::Python => "python",
+            CodeLanguage::JavaScript => "javascript",
+            CodeLanguage::TypeScript => "typescript",
+            CodeLanguage::Go => "go",
+            CodeLanguage::Java => "java",
+            CodeLanguage::Kotlin => "kotlin",
+            CodeLanguage::Scala => "scala",
+            CodeLanguage::C => "c",
+            CodeLanguage::Cpp => "cpp",
+            CodeLanguage::CSharp => "csharp",
+            CodeLanguage::Ruby => "ruby",
+            CodeLanguage::Php => "php",
+            CodeLanguage::Swift => "swift",
+            CodeLanguage::Shell => "shell",
+            CodeLanguage::Lua => "lua",
+        }
+    }
+
+    /// The comment syntax of the language.
+    fn syntax(&self) -> CommentSyntax {
+        let c_like = CommentSyntax {
+            line: &["//"],
+            block: Some(("/*", "*/")),
+            quotes: &['"'],
+            char_literals: true,
+            word_start: false,
+        };
+
+        match self {
+            CodeLanguage::Rust
+            | CodeLanguage::Java
+            | CodeLanguage::C
+            | CodeLanguage::Cpp
+            | CodeLanguage::CSharp
+            | CodeLanguage::Kotlin
+            | CodeLanguage::Scala
+            | CodeLanguage::Swift => c_like,
+            CodeLanguage::Go => CommentSyntax {
+                quotes: &['"', '`'],
+                ..c_like
+            },
+            CodeLanguage::JavaScript | CodeLanguage::TypeScript => CommentSyntax {
+                quotes: &['"', '\'', '`'],
+                char_literals: false,
+                ..c_like
+            },
+            CodeLanguage::Php => CommentSyntax {
+                line: &["//", "#"],
+                quotes: &['"', '\''],
+                char_literals: false,
+                ..c_like
+            },
+            CodeLanguage::Python | CodeLanguage::Ruby => CommentSyntax {
+                line: &["#"],
+                block: None,
+                quotes: &['"', '\''],
+                char_literals: false,
+                word_start: false,
+            },
+            CodeLanguage::Shell => CommentSyntax {
+                line: &["#"],
+                block: None,
+                quotes: &['"', '\''],
+                char_literals: false,
+                word_start: true,
+            },
+            CodeLanguage::Lua => CommentSyntax {
+                line: &["--"],
+                block: Some(("--[[", "]]")),
+                quotes: &['"', '\''],
+                char_literals: false,
+                word_start: false,
+            },
+        }
+    }
+}
+impl std::fmt::Display for CodeLanguage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The comment and string literal syntax of a language, enough to tell the comments from the strings.
+#[derive(Debug, Copy, Clone)]
+struct CommentSyntax {
+    /// The markers of the line comments
+    line: &'static [&'static str],
+    /// The start and end markers of the block comments
+    block: Option<(&'static str, &'static str)>,
+    /// The delimiters of the string literals
+    quotes: &'static [char],
+    /// Whether `'` delimits a character literal, such as `'a'` or `'\n'`, rather than a string. Otherwise, a single quote that isn't in `quotes` is kept as is, for example, a Rust lifetime
+    char_literals: bool,
+    /// Whether the line comments start at the beginning of a word only, as in shell scripts, where `$#` isn't a comment
+    word_start: bool,
+}
+
+/// Preprocess a source code chunk before embedding with the given steps, in the order of comment stripping, whitespace normalization and language tagging. The comments are stripped and the chunk is tagged only if the language is recognized.
+pub(crate) fn preprocess_code(
+    chunk: &str,
+    language: Option<CodeLanguage>,
+    steps: &[CodePreprocess],
+) -> String {
+    let mut code = chunk.to_string();
+
+    if let (true, Some(language)) = (steps.contains(&CodePreprocess::StripComments), language) {
+        code = strip_comments(&code, language.syntax());
+    }
+
+    if steps.contains(&CodePreprocess::NormalizeWhitespace) {
+        code = normalize_whitespace(&code);
+    }
+
+    if let (true, Some(language)) = (steps.contains(&CodePreprocess::LanguageTag), language) {
+        code = format!("language: {}\n{}", language, code);
+    }
+
+    code
+}
+
+/// Remove the line and block comments outside the string literals. The lines left empty by the removal are dropped.
+fn strip_comments(code: &str, syntax: CommentSyntax) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let starts_with = |i: usize, marker: &str| {
+        marker
+            .chars()
+            .enumerate()
+            .all(|(k, c)| chars.get(i + k) == Some(&c))
+    };
+
+    let mut stripped = String::with_capacity(code.len());
+    // whether the current line of the output had a comment removed
+    let mut line_has_comment = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+
+        // block comment, checked first since Lua block comments start with the line comment marker
+        if let Some((start, end)) = syntax.block {
+            if starts_with(i, start) {
+                i += start.chars().count();
+                while i < chars.len() && !starts_with(i, end) {
+                    i += 1;
+                }
+                i = (i + end.chars().count()).min(chars.len());
+                line_has_comment = true;
+                continue;
+            }
+        }
+
+        // line comment
+        let at_word_start = i == 0 || chars[i - 1].is_whitespace();
+        if syntax.line.iter().any(|marker| starts_with(i, marker))
+            && (!syntax.word_start || at_word_start)
+        {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            line_has_comment = true;
+            continue;
+        }
+
+        // string literal, which may span lines
+        if syntax.quotes.contains(&c) {
+            stripped.push(c);
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    stripped.push(chars[i]);
+                    i += 1;
+                }
+                stripped.push(chars[i]);
+                i += 1;
+            }
+            if i < chars.len() {
+                stripped.push(chars[i]);
+                i += 1;
+            }
+            continue;
+        }
+
+        // character literal, such as `'"'` or `'\''`
+        if c == '\'' && syntax.char_literals {
+            let len = match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some('\\'), _) => chars
+                    .get(i + 3..)
+                    .and_then(|rest| rest.iter().position(|&c| c == '\''))
+                    .map(|pos| pos + 4),
+                (Some(_), Some('\'')) => Some(3),
+                _ => None,
+            };
+            if let Some(len) = len {
+                stripped.extend(&chars[i..(i + len).min(chars.len())]);
+                i += len;
+                continue;
+            }
+        }
+
+        if c == '\n' {
+            strip_emptied_line(&mut stripped, line_has_comment);
+            line_has_comment = false;
+        }
+        stripped.push(c);
+        i += 1;
+    }
+    strip_emptied_line(&mut stripped, line_has_comment);
+
+    stripped.trim_start_matches('\n').to_string()
+}
+
+/// Drop the current line of the output if a comment was removed from it and nothing but whitespace is left, and trim its trailing whitespace otherwise.
+fn strip_emptied_line(stripped: &mut String, line_has_comment: bool) {
+    if !line_has_comment {
+        return;
+    }
+
+    let line_start = stripped.rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+    match stripped[line_start..].trim().is_empty() {
+        // drop the line with its line break
+        true => stripped.truncate(line_start.saturating_sub(1)),
+        false => {
+            let trimmed_len = stripped.trim_end().len();
+            stripped.truncate(trimmed_len);
+        }
+    }
+}
+
+/// Remove the trailing whitespace of each line, collapse the consecutive blank lines into one and the runs of spaces and tabs inside the lines into one space. The indentation is kept, since it is significant in some languages.
+fn normalize_whitespace(code: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in code.lines() {
+        let line = line.trim_end();
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+
+        let content = line.trim_start();
+        let indent = &line[..line.len() - content.len()];
+        let content = content.split_whitespace().collect::<Vec<_>>().join(" ");
+        lines.push(format!("{}{}", indent, content));
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+
+    lines.join("\n")
+}
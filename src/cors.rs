@@ -0,0 +1,156 @@
+use crate::{error::ServerError, CORS_ALLOWED_ORIGINS};
+use hyper::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    Body, Method, Request, Response, StatusCode,
+};
+
+// The methods allowed in cross-origin requests
+const ALLOWED_METHODS: &str = "GET, POST, DELETE, OPTIONS";
+// How long the browsers may cache the preflight responses, in seconds
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+/// The origins allowed to call the server from a browser, set by `--cors-allowed-origins`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum CorsOrigins {
+    /// Any origin, i.e., `*`
+    Any,
+    /// The listed origins, such as `https://app.example.com`
+    List(Vec<String>),
+}
+impl CorsOrigins {
+    /// Parse the comma-separated origins, or `*` for any origin.
+    pub(crate) fn parse(origins: &str) -> Result<Self, ServerError> {
+        let origins: Vec<String> = origins
+            .split(',')
+            .map(|origin| origin.trim().trim_end_matches('/').to_lowercase())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        if origins.is_empty() {
+            return Err(ServerError::ArgumentError(
+                "`--cors-allowed-origins` should be `*` or a comma-separated list of origins."
+                    .to_string(),
+            ));
+        }
+
+        if origins.iter().any(|origin| origin == "*") {
+            return match origins.len() {
+                1 => Ok(Self::Any),
+                _ => Err(ServerError::ArgumentError(
+                    "`--cors-allowed-origins` can't mix `*` with other origins.".to_string(),
+                )),
+            };
+        }
+
+        if let Some(origin) = origins
+            .iter()
+            .find(|origin| !origin.starts_with("http://") && !origin.starts_with("https://"))
+        {
+            return Err(ServerError::ArgumentError(format!(
+                "Invalid origin `{}` in `--cors-allowed-origins`. An origin should be a scheme and a host, such as `https://app.example.com`.",
+                origin
+            )));
+        }
+
+        Ok(Self::List(origins))
+    }
+
+    /// The value of the `Access-Control-Allow-Origin` header for the origin of the request, if the origin is allowed.
+    fn allow_origin(&self, origin: Option<&str>) -> Option<HeaderValue> {
+        match self {
+            Self::Any => Some(HeaderValue::from_static("*")),
+            Self::List(origins) => {
+                let origin = origin?;
+                let normalized = origin.trim_end_matches('/').to_lowercase();
+                match origins.contains(&normalized) {
+                    true => HeaderValue::from_str(origin).ok(),
+                    false => None,
+                }
+            }
+        }
+    }
+}
+
+/// The `Origin` header of the request, kept before the request is consumed.
+pub(crate) fn request_origin(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Answer the CORS preflight request if `--cors-allowed-origins` is set. Returns `None` if the request is not a preflight request or CORS is not configured.
+pub(crate) fn preflight_response(req: &Request<Body>) -> Option<Response<Body>> {
+    let cors_origins = CORS_ALLOWED_ORIGINS.get()?;
+
+    if req.method() != Method::OPTIONS
+        || !req.headers().contains_key(ORIGIN)
+        || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+
+    let origin = request_origin(req);
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+
+    let headers = response.headers_mut();
+    if let CorsOrigins::List(_) = cors_origins {
+        headers.insert(VARY, HeaderValue::from_static("Origin"));
+    }
+    match cors_origins.allow_origin(origin.as_deref()) {
+        Some(allow_origin) => {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static(ALLOWED_METHODS),
+            );
+            // the wildcard doesn't cover the `Authorization` header, so the requested headers are echoed
+            let allow_headers = req
+                .headers()
+                .get(ACCESS_CONTROL_REQUEST_HEADERS)
+                .cloned()
+                .unwrap_or_else(|| HeaderValue::from_static("*"));
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            headers.insert(
+                ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_static(PREFLIGHT_MAX_AGE),
+            );
+        }
+        None => {
+            // log
+            warn!(target: "stdout", "Reject the CORS preflight request from the origin {:?}, which is not in `--cors-allowed-origins`.", origin.unwrap_or_default());
+        }
+    }
+
+    Some(response)
+}
+
+/// Set the CORS headers of the response for the origin of the request if `--cors-allowed-origins` is set. Otherwise, the response is left unchanged.
+pub(crate) fn apply_cors_headers(response: &mut Response<Body>, origin: Option<&str>) {
+    let cors_origins = match CORS_ALLOWED_ORIGINS.get() {
+        Some(cors_origins) => cors_origins,
+        None => return,
+    };
+
+    let headers = response.headers_mut();
+    if let CorsOrigins::List(_) = cors_origins {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+    match cors_origins.allow_origin(origin) {
+        Some(allow_origin) => {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            // let the browser clients read the custom headers, such as `X-Retrieval-Confidence`
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static("*"));
+        }
+        None => {
+            headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+            headers.remove(ACCESS_CONTROL_ALLOW_METHODS);
+            headers.remove(ACCESS_CONTROL_ALLOW_HEADERS);
+        }
+    }
+}
@@ -1,7 +1,6 @@
 use hyper::{Body, Response};
 use thiserror::Error;
 
-#[allow(dead_code)]
 pub(crate) fn not_implemented() -> Response<Body> {
     // log error
     error!(target: "stdout", "501 Not Implemented");
@@ -33,6 +32,34 @@ pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Response<Body> {
         .unwrap()
 }
 
+/// A `500 Internal Server Error` response with a JSON body that carries the `usage` of the failed request, for the chat completions failed in the generation.
+pub(crate) fn internal_server_error_with_usage(
+    msg: impl AsRef<str>,
+    usage: serde_json::Value,
+) -> Response<Body> {
+    let err_msg = format!("500 Internal Server Error: {}", msg.as_ref());
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    let body = serde_json::json!({
+        "error": {
+            "message": err_msg,
+            "type": "internal_server_error",
+        },
+        "usage": usage,
+    });
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
 pub(crate) fn bad_request(msg: impl AsRef<str>) -> Response<Body> {
     let err_msg = match msg.as_ref().is_empty() {
         true => "400 Bad Request".to_string(),
@@ -69,6 +96,142 @@ pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Response<Body> {
         .unwrap()
 }
 
+pub(crate) fn forbidden(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "403 Forbidden".to_string(),
+        false => format!("403 Forbidden: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::FORBIDDEN)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+pub(crate) fn payload_too_large(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "413 Payload Too Large".to_string(),
+        false => format!("413 Payload Too Large: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+pub(crate) fn service_unavailable(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "503 Service Unavailable".to_string(),
+        false => format!("503 Service Unavailable: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+pub(crate) fn too_many_requests(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "429 Too Many Requests".to_string(),
+        false => format!("429 Too Many Requests: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::TOO_MANY_REQUESTS)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+pub(crate) fn gateway_timeout(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "504 Gateway Timeout".to_string(),
+        false => format!("504 Gateway Timeout: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::GATEWAY_TIMEOUT)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
+/// A `404 Not Found` response with a JSON body, for the unknown paths when the Web UI is disabled with `--no-web-ui`.
+pub(crate) fn not_found_json(path: impl AsRef<str>) -> Response<Body> {
+    let err_msg = format!(
+        "404 The requested service endpoint is not found: {}",
+        path.as_ref()
+    );
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    let body = serde_json::json!({
+        "error": {
+            "message": err_msg,
+            "type": "not_found",
+            "path": path.as_ref(),
+        }
+    });
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .status(hyper::StatusCode::NOT_FOUND)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub(crate) fn unprocessable_entity(msg: impl AsRef<str>) -> Response<Body> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "422 Unprocessable Entity".to_string(),
+        false => format!("422 Unprocessable Entity: {}", msg.as_ref()),
+    };
+
+    // log error
+    error!(target: "stdout", "{}", &err_msg);
+
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .status(hyper::StatusCode::UNPROCESSABLE_ENTITY)
+        .body(Body::from(err_msg))
+        .unwrap()
+}
+
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
     let err_msg = match msg.as_ref().is_empty() {
         true => "404 The requested service endpoint is not found".to_string(),
@@ -0,0 +1,109 @@
+use crate::{error, INGESTION_QUEUE_SIZE, MAX_CONCURRENT_INGESTIONS};
+use futures::channel::oneshot;
+use hyper::{Body, Method, Request, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{collections::VecDeque, future::Future, sync::Mutex};
+
+// The running and queued ingestion requests
+static SLOTS: Lazy<Mutex<IngestionSlots>> = Lazy::new(|| Mutex::new(IngestionSlots::default()));
+
+#[derive(Debug, Default)]
+struct IngestionSlots {
+    // number of the running ingestion requests
+    active: u64,
+    // the waiters of the queued ingestion requests, in the order of arrival
+    queue: VecDeque<oneshot::Sender<IngestionPermit>>,
+}
+
+/// The counts of the ingestion requests reported by `/v1/health`.
+#[derive(Debug, Serialize)]
+pub(crate) struct IngestionStats {
+    pub active: u64,
+    pub queued: u64,
+    pub max_concurrent: u64,
+}
+
+/// A slot of `--max-concurrent-ingestions`. Frees the slot on drop, or hands it over to the first queued request that is still waiting.
+#[derive(Debug)]
+struct IngestionPermit;
+impl Drop for IngestionPermit {
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(waiter) = slots.queue.pop_front() {
+            match waiter.send(IngestionPermit) {
+                // the slot is handed over, so the count is unchanged
+                Ok(()) => return,
+                // the waiter is gone. The returned permit is forgotten, since dropping it here would lock the slots again
+                Err(permit) => std::mem::forget(permit),
+            }
+        }
+        slots.active = slots.active.saturating_sub(1);
+    }
+}
+
+/// Take a slot, waiting in the queue if all the slots are taken. Returns `None` if the queue is full.
+async fn acquire(max_concurrent: u64, queue_size: Option<u64>) -> Option<IngestionPermit> {
+    let receiver = {
+        let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+        if slots.active < max_concurrent {
+            slots.active += 1;
+            return Some(IngestionPermit);
+        }
+
+        // drop the waiters of the cancelled requests before checking the queue size
+        slots.queue.retain(|waiter| !waiter.is_canceled());
+        if queue_size.is_some_and(|size| slots.queue.len() as u64 >= size) {
+            return None;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        slots.queue.push_back(sender);
+
+        // log
+        info!(target: "stdout", "All the {} ingestion slots are taken. The ingestion request is queued at position {}.", max_concurrent, slots.queue.len());
+
+        receiver
+    };
+
+    receiver.await.ok()
+}
+
+/// Handle the ingestion request with the handler if a slot of `--max-concurrent-ingestions` is free. Otherwise, the request waits in the queue, or is rejected with `429 Too Many Requests` if the queue already holds `--ingestion-queue-size` requests.
+///
+/// Only `POST` requests take a slot. The slot is held until the handler returns the response.
+pub(crate) async fn limit_ingestion<F, Fut>(req: Request<Body>, handler: F) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Response<Body>>,
+{
+    let max_concurrent = match MAX_CONCURRENT_INGESTIONS.get() {
+        Some(&max_concurrent) if req.method() == Method::POST => max_concurrent,
+        _ => return handler(req).await,
+    };
+
+    let _permit = match acquire(max_concurrent, INGESTION_QUEUE_SIZE.get().copied()).await {
+        Some(permit) => permit,
+        None => {
+            return error::too_many_requests(format!(
+                "All the {} ingestion slots are taken and the ingestion queue is full. Retry later.",
+                max_concurrent
+            ));
+        }
+    };
+
+    handler(req).await
+}
+
+/// The counts of the running and queued ingestion requests. Returns `None` if `--max-concurrent-ingestions` is not set.
+pub(crate) fn ingestion_stats() -> Option<IngestionStats> {
+    let max_concurrent = MAX_CONCURRENT_INGESTIONS.get().copied()?;
+    let mut slots = SLOTS.lock().unwrap_or_else(|e| e.into_inner());
+    slots.queue.retain(|waiter| !waiter.is_canceled());
+
+    Some(IngestionStats {
+        active: slots.active,
+        queued: slots.queue.len() as u64,
+        max_concurrent,
+    })
+}
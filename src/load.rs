@@ -0,0 +1,105 @@
+use crate::{error, QUEUE_TIMEOUT, REQUEST_SLOTS};
+use hyper::{Body, Method, Request, Response};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::OwnedSemaphorePermit;
+
+// The number of the requests to the API endpoints being handled
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+// The number of the chat completions being handled, including the streams being sent
+static CHAT_COMPLETIONS: AtomicU64 = AtomicU64::new(0);
+
+/// The kind of an in-flight request counted by `/v1/status`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Load {
+    /// A request to the API endpoints, counted until the handler returns the response.
+    Request,
+    /// A chat completion, counted until the response, or the stream, is sent.
+    ChatCompletion,
+}
+impl Load {
+    fn counter(self) -> &'static AtomicU64 {
+        match self {
+            Load::Request => &REQUESTS,
+            Load::ChatCompletion => &CHAT_COMPLETIONS,
+        }
+    }
+
+    /// The number of the in-flight requests of the kind.
+    pub(crate) fn in_flight(self) -> u64 {
+        self.counter().load(Ordering::Relaxed)
+    }
+}
+
+/// Count an in-flight request of the kind until dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight(Load);
+impl InFlight {
+    pub(crate) fn new(load: Load) -> Self {
+        load.counter().fetch_add(1, Ordering::Relaxed);
+
+        Self(load)
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.counter().fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A slot of `--max-concurrent-requests`, set in the extensions of the request, so that a chat completion stream can hold it until the stream is sent. The slot is freed when the last clone is dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestPermit {
+    _permit: Arc<OwnedSemaphorePermit>,
+}
+
+/// Handle the request with the handler if a slot of `--max-concurrent-requests` is free. Otherwise, the request waits up to `--queue-timeout` for a slot, or is rejected with `503 Service Unavailable`.
+///
+/// Only `POST` requests take a slot. The slot is held until the handler returns the response, or until the stream of the response is sent if the handler keeps the `RequestPermit`.
+pub(crate) async fn limit_requests<F, Fut>(mut req: Request<Body>, handler: F) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Response<Body>>,
+{
+    let slots = match REQUEST_SLOTS.get() {
+        Some(slots) if req.method() == Method::POST => slots.clone(),
+        _ => return handler(req).await,
+    };
+
+    let permit = match QUEUE_TIMEOUT.get() {
+        Some(&queue_timeout) => {
+            // log
+            if slots.available_permits() == 0 {
+                info!(target: "stdout", "All the request slots are taken. The request waits up to {}ms.", queue_timeout.as_millis());
+            }
+
+            tokio::time::timeout(queue_timeout, slots.acquire_owned())
+                .await
+                .ok()
+                .and_then(|permit| permit.ok())
+        }
+        None => slots.try_acquire_owned().ok(),
+    };
+    let permit = match permit {
+        Some(permit) => RequestPermit {
+            _permit: Arc::new(permit),
+        },
+        None => {
+            return error::service_unavailable(
+                "All the request slots of --max-concurrent-requests are taken. Retry later.",
+            );
+        }
+    };
+
+    req.extensions_mut().insert(permit.clone());
+
+    let response = handler(req).await;
+    drop(permit);
+
+    response
+}
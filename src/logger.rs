@@ -0,0 +1,128 @@
+use crate::utils::LogFormat;
+use futures::Stream;
+use log::{
+    kv::{Key, Value, VisitSource},
+    Log, Metadata, Record, SetLoggerError,
+};
+use serde_json::Map;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+tokio::task_local! {
+    /// The id of the request being handled, included in the JSON log lines as `request_id`.
+    pub(crate) static REQUEST_ID: String;
+}
+
+// The longest `X-Request-Id` of a request that is kept as the request id
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Install the global logger of the `--log-format`.
+pub(crate) fn install(log_format: LogFormat) -> Result<(), SetLoggerError> {
+    match log_format {
+        LogFormat::Text => wasi_logger::Logger::install().map(|_| ()),
+        LogFormat::Json => {
+            static LOGGER: JsonLogger = JsonLogger;
+
+            log::set_logger(&LOGGER)
+        }
+    }
+}
+
+/// The id of a request: its `X-Request-Id` header if it is a short printable token, so that the logs can be correlated with the logs of a proxy, or a new UUID.
+pub(crate) fn request_id(headers: &hyper::HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= MAX_REQUEST_ID_LEN
+                && value.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+/// Run the future in the scope of the id of the current request, if any, for the futures spawned as separate tasks.
+pub(crate) async fn in_request_scope<F: Future>(future: F) -> F::Output {
+    match REQUEST_ID.try_with(|request_id| request_id.clone()) {
+        Ok(request_id) => REQUEST_ID.scope(request_id, future).await,
+        Err(_) => future.await,
+    }
+}
+
+/// Poll the stream in the scope of the id of the current request, if any, for the streams of the response bodies, which are polled after the handler returns.
+pub(crate) fn in_request_scope_stream<S: Stream>(stream: S) -> RequestScopedStream<S> {
+    RequestScopedStream {
+        request_id: REQUEST_ID.try_with(|request_id| request_id.clone()).ok(),
+        stream: Box::pin(stream),
+    }
+}
+
+/// A stream polled in the scope of a request id.
+pub(crate) struct RequestScopedStream<S> {
+    request_id: Option<String>,
+    stream: Pin<Box<S>>,
+}
+impl<S: Stream> Stream for RequestScopedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        match this.request_id.clone() {
+            Some(request_id) => {
+                REQUEST_ID.sync_scope(request_id, || this.stream.as_mut().poll_next(cx))
+            }
+            None => this.stream.as_mut().poll_next(cx),
+        }
+    }
+}
+
+/// Write each log record as a JSON object on one line of the standard output, with the `timestamp`, `level`, `target` and `message` fields, the `request_id` of the request being handled, and the key-value pairs of the record.
+struct JsonLogger;
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = Map::new();
+        line.insert(
+            "timestamp".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+        line.insert(
+            "level".to_string(),
+            record.level().as_str().to_lowercase().into(),
+        );
+        line.insert("target".to_string(), record.target().into());
+        line.insert("message".to_string(), record.args().to_string().into());
+        if let Ok(request_id) = REQUEST_ID.try_with(|request_id| request_id.clone()) {
+            line.insert("request_id".to_string(), request_id.into());
+        }
+        let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+        println!("{}", serde_json::Value::Object(line));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Add the key-value pairs of a log record to the JSON object of the line.
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
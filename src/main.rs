@@ -1,13 +1,31 @@
 #[macro_use]
 extern crate log;
 
+mod auth;
 mod backend;
+mod chunking;
+mod coalesce;
+mod code_preprocess;
+mod cors;
 mod error;
+mod ingestion;
+mod load;
+mod logger;
+mod output_filter;
+mod projection;
+mod qdrant;
+mod rerank;
+mod routing;
+mod tool_args;
 mod utils;
 
 use anyhow::Result;
+use auth::ApiKeys;
 use chat_prompts::{MergeRagContextPolicy, PromptTemplateType};
 use clap::{ArgGroup, Parser};
+use code_preprocess::CodePreprocess;
+use cors::CorsOrigins;
+use endpoints::embeddings::{EmbeddingRequest, InputText};
 use error::ServerError;
 use hyper::{
     body::HttpBody,
@@ -18,10 +36,27 @@ use hyper::{
 };
 use llama_core::metadata::ggml::GgmlMetadataBuilder;
 use once_cell::sync::OnceCell;
+use output_filter::OutputFilter;
+use projection::EmbeddingProjection;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt, net::SocketAddr, path::PathBuf};
-use tokio::{net::TcpListener, sync::RwLock};
-use utils::{is_valid_url, LogLevel};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{RwLock, Semaphore},
+};
+use utils::{
+    combined_log_line, estimate_template_overhead, is_valid_url, parse_info_extra,
+    template_stop_marker, template_supports_tool_message, AccessLogFormat, ContextFormat,
+    EmbeddingPrecision, LogFormat, LogLevel, MultivectorPooling, QueryTruncation,
+    RagContextMessage, RetrievalTimeoutAction, UnicodeNormalization,
+};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -29,12 +64,111 @@ type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 pub(crate) static GLOBAL_RAG_PROMPT: OnceCell<String> = OnceCell::new();
 // server info
 pub(crate) static SERVER_INFO: OnceCell<RwLock<ServerInfo>> = OnceCell::new();
-// API key
-pub(crate) static LLAMA_API_KEY: OnceCell<String> = OnceCell::new();
+// API keys from the `API_KEY` environment variable and the API key file
+pub(crate) static LLAMA_API_KEYS: OnceCell<ApiKeys> = OnceCell::new();
 // Global context window used for setting the max number of user messages for the retrieval
 pub(crate) static CONTEXT_WINDOW: OnceCell<u64> = OnceCell::new();
+// Decay factor applied to the older user messages in the context window
+pub(crate) static TURN_DECAY: OnceCell<f64> = OnceCell::new();
 // Global keyword search configuration
 pub(crate) static KW_SEARCH_CONFIG: OnceCell<KeywordSearchConfig> = OnceCell::new();
+// The keyword search URLs that the `kw_search_url` field of a request may select. The field is ignored if not set
+pub(crate) static KW_SEARCH_URL_ALLOWLIST: OnceCell<Vec<url::Url>> = OnceCell::new();
+// Number of keyword search results fetched per query before the fusion
+pub(crate) static KW_SEARCH_LIMIT: OnceCell<u64> = OnceCell::new();
+// Constant of the Reciprocal Rank Fusion of the keyword and vector search results. The weighted score fusion is used if not set
+pub(crate) static HYBRID_FUSION_K: OnceCell<u64> = OnceCell::new();
+// Whether to use the keyword search results alone if the vector search retrieves nothing
+pub(crate) static KW_FALLBACK: OnceCell<bool> = OnceCell::new();
+// Default format of the retrieved context merged into the prompt
+pub(crate) static CONTEXT_FORMAT: OnceCell<ContextFormat> = OnceCell::new();
+// Whether to retrieve the context for the system message as well
+pub(crate) static RETRIEVE_FOR_SYSTEM: OnceCell<bool> = OnceCell::new();
+// Default precision of the embedding vectors returned by `/v1/embeddings`
+pub(crate) static EMBEDDING_PRECISION: OnceCell<EmbeddingPrecision> = OnceCell::new();
+// How the multi-vector embeddings are pooled into one vector
+pub(crate) static MULTIVECTOR_POOLING: OnceCell<MultivectorPooling> = OnceCell::new();
+// The dimension of the single vectors of each embedding model, found at startup
+pub(crate) static EMBEDDING_DIMENSIONS: OnceCell<HashMap<String, usize>> = OnceCell::new();
+// Whether to return the successful embeddings of a batch with the errors of the failed inputs instead of failing the whole batch
+pub(crate) static EMBEDDINGS_PARTIAL_FAILURE: OnceCell<bool> = OnceCell::new();
+// Maximum number of inputs of an embeddings request
+pub(crate) static MAX_EMBEDDING_BATCH: OnceCell<u64> = OnceCell::new();
+// Number of retries of a document chunk that failed to be embedded or upserted in `/v1/create/rag`
+pub(crate) static INGESTION_CHUNK_RETRIES: OnceCell<u64> = OnceCell::new();
+// Maximum number of `/v1/create/rag` requests running at once. Unlimited if not set
+pub(crate) static MAX_CONCURRENT_INGESTIONS: OnceCell<u64> = OnceCell::new();
+// Maximum number of `/v1/create/rag` requests waiting for a free ingestion slot. Unlimited if not set
+pub(crate) static INGESTION_QUEUE_SIZE: OnceCell<u64> = OnceCell::new();
+// The slots of `--max-concurrent-requests` taken by the requests to the model endpoints. Unlimited if not set
+pub(crate) static REQUEST_SLOTS: OnceCell<Arc<Semaphore>> = OnceCell::new();
+// Maximum time a request waits for a free slot of `--max-concurrent-requests`. The requests are rejected at once if not set
+pub(crate) static QUEUE_TIMEOUT: OnceCell<std::time::Duration> = OnceCell::new();
+// Maximum number of tokens of the retrieval query, and the part of the query kept when it is truncated
+pub(crate) static MAX_QUERY_TOKENS: OnceCell<(u64, QueryTruncation)> = OnceCell::new();
+// Unicode normalization form applied to the chunks and queries before embedding
+pub(crate) static UNICODE_NORMALIZATION: OnceCell<UnicodeNormalization> = OnceCell::new();
+// Preprocessing steps applied to the source code chunks before embedding
+pub(crate) static CODE_PREPROCESS: OnceCell<Vec<CodePreprocess>> = OnceCell::new();
+// Linear projection applied to the embeddings before they are searched in or upserted to Qdrant
+pub(crate) static EMBEDDING_PROJECTION: OnceCell<EmbeddingProjection> = OnceCell::new();
+// Number of queries used for the context retrieval, including the original user query
+pub(crate) static MULTI_QUERY: OnceCell<u64> = OnceCell::new();
+// Time budget of the retrieval phase per request. Unlimited if not set
+pub(crate) static RETRIEVAL_TIME_BUDGET: OnceCell<std::time::Duration> = OnceCell::new();
+// Timeout of each Qdrant and keyword search request of the retrieval, and what to do when it is exceeded. Unlimited if not set
+pub(crate) static RETRIEVAL_TIMEOUT: OnceCell<(std::time::Duration, RetrievalTimeoutAction)> =
+    OnceCell::new();
+// Maximum number of retries of a Qdrant request failing with a connection error or a 5xx status, and the initial backoff. No retry if not set
+pub(crate) static QDRANT_RETRY: OnceCell<(u64, std::time::Duration)> = OnceCell::new();
+// The API key of the Qdrant instance, set by `--qdrant-api-key` or the `QDRANT_API_KEY` environment variable. Not set if neither is given
+pub(crate) static QDRANT_API_KEY: OnceCell<String> = OnceCell::new();
+// Thresholds of the retrieval confidence
+pub(crate) static CONFIDENCE_CONFIG: OnceCell<ConfidenceConfig> = OnceCell::new();
+// Recency decay applied to the scores of the retrieved points. Disabled if not set
+pub(crate) static RECENCY_CONFIG: OnceCell<RecencyConfig> = OnceCell::new();
+// The cross-encoder reranker applied to the retrieved points. Disabled if not set
+pub(crate) static RERANK_CONFIG: OnceCell<RerankConfig> = OnceCell::new();
+// Embedding model of each Qdrant collection
+pub(crate) static EMBEDDING_ROUTES: OnceCell<EmbeddingRoutes> = OnceCell::new();
+// Prefixes prepended to the queries and the passages before embedding
+pub(crate) static EMBEDDING_PREFIXES: OnceCell<EmbeddingPrefixes> = OnceCell::new();
+// Maximum number of tokens of each chunk of the documents ingested into each Qdrant collection
+pub(crate) static CHUNK_CAPACITY: OnceCell<ChunkCapacity> = OnceCell::new();
+// Whether the server runs in retrieval-only mode, i.e., no chat model is loaded
+pub(crate) static RETRIEVAL_ONLY: OnceCell<bool> = OnceCell::new();
+// Whether the endpoints that alter the files or the collections are disabled
+pub(crate) static READ_ONLY: OnceCell<bool> = OnceCell::new();
+// Origins allowed to call the server from a browser. The CORS headers of the handlers are left unchanged if not set
+pub(crate) static CORS_ALLOWED_ORIGINS: OnceCell<CorsOrigins> = OnceCell::new();
+// Whether to check if the collections are empty when nothing is retrieved for a request
+pub(crate) static WARN_ON_EMPTY_COLLECTIONS: OnceCell<bool> = OnceCell::new();
+// Number of collections searched per query, selected by the similarity of the query to the collection centroids. All the collections are searched if not set
+pub(crate) static AUTO_COLLECTION_ROUTING: OnceCell<u64> = OnceCell::new();
+// Whether to return the number of points and the top score per collection in the `/v1/retrieve` responses
+pub(crate) static RETRIEVAL_BREAKDOWN: OnceCell<bool> = OnceCell::new();
+// Whether to report the timing of the chat completion requests in headers and trailers
+pub(crate) static TIMING_TRAILERS: OnceCell<bool> = OnceCell::new();
+// Whether to send the progress of the retrieval as SSE events in the stream responses
+pub(crate) static STREAM_RETRIEVAL_PROGRESS: OnceCell<bool> = OnceCell::new();
+// Interval of the keep-alive comments sent in the stream responses until the completion starts
+pub(crate) static STREAM_KEEPALIVE: OnceCell<std::time::Duration> = OnceCell::new();
+// Whether to serve the debug endpoints, such as `/v1/retrieve/compare`
+pub(crate) static DEBUG_ENDPOINTS: OnceCell<bool> = OnceCell::new();
+// Whether to share the response among identical concurrent non-stream requests
+pub(crate) static COALESCE_REQUESTS: OnceCell<bool> = OnceCell::new();
+// Whether to return the hash of the effective request in the `X-Request-Hash` header
+pub(crate) static REQUEST_HASH_HEADER: OnceCell<bool> = OnceCell::new();
+// Fingerprint of the chat model configuration, returned as `system_fingerprint` in the chat completion responses
+pub(crate) static SYSTEM_FINGERPRINT: OnceCell<String> = OnceCell::new();
+// Whether to ignore the non-text content parts in the messages instead of rejecting the request
+pub(crate) static IGNORE_NONTEXT_PARTS: OnceCell<bool> = OnceCell::new();
+// Regex substitutions applied to the completion text
+pub(crate) static OUTPUT_FILTERS: OnceCell<Vec<OutputFilter>> = OnceCell::new();
+// Whether to validate the tool call arguments against the tool schemas
+pub(crate) static VALIDATE_TOOL_ARGS: OnceCell<bool> = OnceCell::new();
+// Maximum number of tokens of the tool definitions in a chat request. Unlimited if not set
+pub(crate) static MAX_TOOLS_TOKENS: OnceCell<u64> = OnceCell::new();
 
 // default port
 const DEFAULT_PORT: &str = "8080";
@@ -48,7 +182,7 @@ pub struct AppState {
 #[command(name = "LlamaEdge-RAG API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "LlamaEdge-RAG API Server")]
 #[command(group = ArgGroup::new("socket_address_group").multiple(false).args(&["socket_addr", "port"]))]
 struct Cli {
-    /// Sets names for chat and embedding models. The names are separated by comma without space, for example, '--model-name Llama-2-7b,all-minilm'.
+    /// Sets names for chat and embedding models. The names are separated by comma without space, for example, '--model-name Llama-2-7b,all-minilm'. The first name is for the chat model, and the others are for the embedding models; the first embedding model is the default one.
     #[arg(short, long, value_delimiter = ',', required = true)]
     model_name: Vec<String>,
     /// Model aliases for chat and embedding models
@@ -71,7 +205,7 @@ struct Cli {
     /// Sets prompt templates for chat and embedding models, respectively. The prompt templates are separated by comma without space, for example, '--prompt-template llama-2-chat,embedding'. The first value is for the chat model, and the second is for the embedding model.
     #[arg(short, long, value_delimiter = ',', value_parser = clap::value_parser!(PromptTemplateType), required = true)]
     prompt_template: Vec<PromptTemplateType>,
-    /// Halt generation at PROMPT, return control.
+    /// Halt generation at PROMPT, return control. Defaults to the end-of-turn marker of the prompt template of the chat model, for example, `<|im_end|>` for `chatml` and `[INST]` for `llama-2-chat`.
     #[arg(short, long)]
     reverse_prompt: Option<String>,
     /// Number of tokens to predict, -1 = infinity, -2 = until context filled.
@@ -110,9 +244,21 @@ struct Cli {
     /// Strategy for merging RAG context into chat messages.
     #[arg(long = "rag-policy", default_value_t, value_enum)]
     policy: MergeRagContextPolicy,
+    /// Where the RAG context is placed: `merged` into the system or last user message by `--rag-policy`, or `tool` for a dedicated tool message after the last user message. Falls back to `merged` if the prompt template does not render tool messages
+    #[arg(long, default_value_t, value_enum)]
+    rag_context_message: RagContextMessage,
+    /// Format of the retrieved context merged into the prompt. Can be overridden per request by the `context_format` field
+    #[arg(long, default_value_t, value_enum)]
+    context_format: ContextFormat,
+    /// Also retrieve the context with the system message content as the query, and append it to the system message. The retrieval for the user messages is unaffected
+    #[arg(long)]
+    retrieve_for_system: bool,
     /// URL of Qdrant REST Service
     #[arg(long, default_value = "http://127.0.0.1:6333")]
     qdrant_url: String,
+    /// API key of the Qdrant instance, sent as the `api-key` header of every Qdrant request, for an authenticated instance such as Qdrant Cloud. Falls back to the `QDRANT_API_KEY` environment variable, then to `VDB_API_KEY`. The `vdb_api_key` field of a request takes precedence
+    #[arg(long)]
+    qdrant_api_key: Option<String>,
     /// Name of Qdrant collection
     #[arg(long, default_value = "default", value_delimiter = ',')]
     qdrant_collection_name: Vec<String>,
@@ -122,18 +268,195 @@ struct Cli {
     /// Minimal score threshold for the search result
     #[arg(long, default_value = "0.4", value_delimiter = ',', value_parser = clap::value_parser!(f32))]
     qdrant_score_threshold: Vec<f32>,
-    /// Maximum number of tokens each chunk contains
-    #[arg(long, default_value = "100", value_parser = clap::value_parser!(usize))]
-    chunk_capacity: usize,
+    /// Number of collections searched per query (no less than 1). The collections are selected by the similarity of the query to their centroids, which are computed at startup and after each ingestion. All the collections are searched if not set
+    #[arg(long, value_name = "K", value_parser = clap::value_parser!(u64).range(1..))]
+    auto_collection_routing: Option<u64>,
+    /// Maximum number of tokens each chunk contains. Either one value for all collections, or one value per collection in the order of `--qdrant-collection-name`, separated by comma. The first value applies to the collections not listed in `--qdrant-collection-name`
+    #[arg(long, default_value = "100", value_delimiter = ',', value_parser = clap::value_parser!(usize))]
+    chunk_capacity: Vec<usize>,
+    /// Maximum number of tokens of the retrieval query assembled from the user messages (no less than 1). A longer query is truncated before embedding. Defaults to the context size of the default embedding model
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_query_tokens: Option<u64>,
+    /// The part of the retrieval query kept when it is truncated to `--max-query-tokens`: `head` keeps the beginning, i.e., the oldest user messages, and `tail` keeps the end, i.e., the latest user message
+    #[arg(long, default_value_t, value_enum)]
+    query_truncation: QueryTruncation,
+    /// Precision of the embedding vectors returned by `/v1/embeddings`: `float32`, `float16` (base64-encoded), or the number of decimal places to round to. Can be overridden per request by the `embedding_precision` field
+    #[arg(long, default_value = "float32")]
+    embedding_precision: EmbeddingPrecision,
+    /// How the vectors of an embedding model returning one vector per token, such as a ColBERT-style model, are pooled into the single vector stored in and searched against Qdrant: `mean` or `max`
+    #[arg(long, default_value_t, value_enum)]
+    multivector_pooling: MultivectorPooling,
+    /// Return the successful embeddings of a batch together with an `errors` array listing the failed inputs, instead of failing the whole batch. The response is not OpenAI-compatible when an input fails
+    #[arg(long)]
+    embeddings_partial_failure: bool,
+    /// Maximum number of inputs of a `/v1/embeddings` request (no less than 1). Larger requests are rejected with `400 Bad Request`. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_embedding_batch: Option<u64>,
+    /// Number of retries, with exponential backoff, of each document chunk that fails to be embedded or upserted in `/v1/create/rag` after the whole batch failed. With `--embeddings-partial-failure`, the chunks still failing after the retries are reported in a `failed_chunks` array instead of failing the request
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u64))]
+    ingestion_chunk_retries: u64,
+    /// Maximum number of `/v1/create/rag` requests running at once (no less than 1), independent of the other requests. The excess requests wait in a queue. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrent_ingestions: Option<u64>,
+    /// Maximum number of `/v1/create/rag` requests waiting for a free slot of `--max-concurrent-ingestions`. The requests beyond are rejected with `429 Too Many Requests`. `0` rejects the excess requests without queuing them. Unlimited if not set
+    #[arg(long, requires = "max_concurrent_ingestions")]
+    ingestion_queue_size: Option<u64>,
+    /// Maximum number of requests to `/v1/chat/completions`, `/v1/embeddings`, `/v1/retrieve` and `/v1/retrieve/compare` running at once (no less than 1), including the chat completion streams being sent. The excess requests wait up to `--queue-timeout`, or are rejected with `503 Service Unavailable`. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrent_requests: Option<u64>,
+    /// Maximum time in milliseconds a request waits for a free slot of `--max-concurrent-requests` before it is rejected with `503 Service Unavailable`. The excess requests are rejected at once if not set
+    #[arg(long, requires = "max_concurrent_requests", value_parser = clap::value_parser!(u64).range(1..))]
+    queue_timeout: Option<u64>,
+    /// Unicode normalization form applied to the chunk and query text before embedding. `nfc` is recommended, so that the same text in different normalization forms gets the same embedding
+    #[arg(long, default_value_t, value_enum)]
+    unicode_normalization: UnicodeNormalization,
+    /// Preprocessing steps applied to the source code chunks in `/v1/create/rag` before embedding, separated by comma, for example, `strip-comments,language-tag`. Applied to the files chunked with the `code` strategy or with the extension of a recognized language. The original chunks are stored in the payloads. Disabled by default
+    #[arg(long, value_delimiter = ',', value_enum)]
+    code_preprocess: Vec<CodePreprocess>,
+    /// Path to a JSON file with a linear projection matrix, an array of rows of numbers, that maps the embeddings of the model to the vector size of existing collections. The projected embeddings are used for the search and the upsert in Qdrant. Disabled by default
+    #[arg(long)]
+    embedding_projection: Option<PathBuf>,
     /// Maximum number of user messages used in the retrieval
     #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64))]
     context_window: u64,
+    /// Decay factor in (0, 1] applied to the older user messages in the context window. The user message `k` turns before the latest one keeps only its first `turn_decay^k` of words in the retrieval query, and is dropped if no word is left. The latest user message is always kept in full. The value 1 disables the decay
+    #[arg(long, default_value = "1.0", value_parser = clap::value_parser!(f64))]
+    turn_decay: f64,
+    /// Number of queries used in the retrieval. If greater than 1, the chat model generates `N - 1` paraphrases of the user query, and the deduplicated results of all queries are merged
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    multi_query: u64,
+    /// Time budget in milliseconds for the retrieval phase of a request, including keyword search, query embedding and vector search. If the budget is exceeded, the generation proceeds with the context retrieved so far. Defaults to unlimited
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    retrieval_time_budget: Option<u64>,
+    /// Timeout in milliseconds of each Qdrant search and keyword search request of the retrieval, so that a hanging upstream doesn't hang the request. Defaults to unlimited
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    retrieval_timeout: Option<u64>,
+    /// What to do when a request exceeds `--retrieval-timeout`: `fail` returns `504 Gateway Timeout` naming the upstream, and `proceed` continues without the context of that upstream
+    #[arg(long, default_value_t, value_enum)]
+    retrieval_timeout_action: RetrievalTimeoutAction,
+    /// Maximum number of retries of a Qdrant request, such as a search or an upsert, that fails with a connection error or a 5xx status. The requests failing with a 4xx status are not retried
+    #[arg(long, default_value = "0")]
+    qdrant_max_retries: u64,
+    /// Backoff in milliseconds before the first retry of a Qdrant request. The backoff doubles with each retry, and a random jitter of up to half the backoff is subtracted
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u64).range(1..))]
+    qdrant_retry_backoff_ms: u64,
+    /// Score at or above which a retrieved chunk counts as a strong match in the retrieval confidence
+    #[arg(long, default_value = "0.6", value_parser = clap::value_parser!(f32))]
+    confidence_strong_score: f32,
+    /// Number of strong matches at which the retrieval confidence reaches its maximum
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(u64).range(1..))]
+    confidence_strong_chunks: u64,
+    /// Embedding model of a Qdrant collection, in the format of `collection=model`, where `model` is the name or the alias of one of the embedding models. The mappings are separated by comma without space. The collections not in the map use the default embedding model
+    #[arg(long, value_delimiter = ',', value_name = "COLLECTION=MODEL")]
+    embedding_collection_map: Vec<String>,
+    /// Prefix prepended to the retrieval queries before embedding, for example, `search_query: ` for the models trained with instruction prefixes. Disabled by default
+    #[arg(long)]
+    query_prefix: Option<String>,
+    /// Prefix prepended to the chunks of `/v1/create/rag` before embedding, for example, `search_document: `. The payloads keep the chunks without the prefix. Disabled by default
+    #[arg(long)]
+    passage_prefix: Option<String>,
+    /// Query prefix of a Qdrant collection, in the format of `collection=prefix`, overriding `--query-prefix` for the collection. The option can be repeated
+    #[arg(long, value_name = "COLLECTION=PREFIX")]
+    collection_query_prefix: Vec<String>,
+    /// Passage prefix of a Qdrant collection, in the format of `collection=prefix`, overriding `--passage-prefix` for the collection. The option can be repeated
+    #[arg(long, value_name = "COLLECTION=PREFIX")]
+    collection_passage_prefix: Vec<String>,
+    /// Half-life in days of the recency decay. If set, the retrieved points with a `timestamp` payload field are reranked by combining the similarity score with an exponential decay of their age. Disabled by default
+    #[arg(long, value_parser = clap::value_parser!(f64))]
+    recency_decay: Option<f64>,
+    /// Weight in [0, 1] of the recency in the reranking score. Only used with `--recency-decay`
+    #[arg(long, default_value = "0.3", value_parser = clap::value_parser!(f64))]
+    recency_weight: f64,
+    /// URL of a reranker service following the rerank API of Jina and Cohere, for example, `http://localhost:8081/v1/rerank` of a llama.cpp server. If set, the candidates retrieved from each collection are reordered by the relevance scores of the reranker, and the top `--rerank-top-n` points are kept. Disabled by default
+    #[arg(long)]
+    rerank_url: Option<String>,
+    /// Model name sent to the reranker service of `--rerank-url`, for the services serving several models
+    #[arg(long, requires = "rerank_url")]
+    rerank_model: Option<String>,
+    /// Number of candidates fetched from each collection for the reranker of `--rerank-url`, per query. Defaults to 3 times the limit of the collection
+    #[arg(long, requires = "rerank_url", value_parser = clap::value_parser!(u64).range(1..))]
+    rerank_candidates: Option<u64>,
+    /// Number of reranked points kept from each collection, overridable per request by the `rerank_top_n` field. Defaults to the limit of the collection
+    #[arg(long, requires = "rerank_url", value_parser = clap::value_parser!(u64).range(1..))]
+    rerank_top_n: Option<u64>,
     /// URL of the keyword search service
     #[arg(long)]
     kw_search_url: Option<String>,
+    /// URLs of the keyword search services that the `kw_search_url` field of a request may select, separated by comma, for example, `http://kw-a:9069,http://kw-b:9069`. A requested URL must have the scheme, host and port of an allowed URL, and lie under its path. If not set, the `kw_search_url` field is ignored
+    #[arg(long, value_delimiter = ',')]
+    kw_search_url_allowlist: Vec<String>,
+    /// Number of keyword search results fetched per query before they are fused with the vector search results (no less than 1). Can be overridden per request by the `kw_top_k` field. Defaults to the first value of `--qdrant-limit`
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    kw_search_limit: Option<u64>,
+    /// Fuse the keyword and vector search results with Reciprocal Rank Fusion, scoring each result by `1 / (k + rank)` in each list, with the given constant `k`, for example, `60`. By default, the normalized scores are fused by a weighted sum
+    #[arg(long, value_name = "K")]
+    hybrid_fusion_k: Option<u64>,
+    /// Use the keyword search results alone as the context if the vector search retrieves nothing above the score threshold. Requires a keyword search service
+    #[arg(long)]
+    kw_fallback: bool,
     /// Whether to include usage in the stream response. Defaults to false.
     #[arg(long, default_value = "false")]
     include_usage: bool,
+    /// Run in retrieval-only mode: only the embedding model is loaded, and `/v1/chat/completions` is disabled. In this mode, the model-related options accept a single value for the embedding model; if two values are given, the last one is used.
+    #[arg(long)]
+    retrieval_only: bool,
+    /// Run in read-only mode: the endpoints that alter the files or the collections, i.e., `POST /v1/files`, `DELETE /v1/files/{id}` and `POST /v1/create/rag`, return `403 Forbidden`
+    #[arg(long)]
+    read_only: bool,
+    /// Warn if nothing is retrieved for a request because all the collections are empty. The warning is logged and returned in the `X-RAG-Warning: collections-empty` response header
+    #[arg(long)]
+    warn_on_empty_collections: bool,
+    /// Return the number of points and the top score contributed by each collection in the `X-Retrieval-Breakdown` header of the `/v1/retrieve` responses
+    #[arg(long)]
+    retrieval_breakdown: bool,
+    /// Share one response among identical concurrent non-stream requests to `/v1/chat/completions` and `/v1/embeddings`. Requests are identical if they have the same body and `Authorization` header
+    #[arg(long)]
+    coalesce_requests: bool,
+    /// Return the hash of the effective request, the key of `--coalesce-requests`, in the `X-Request-Hash` header of the `/v1/chat/completions` and `/v1/embeddings` responses, so that the clients can use it as a cache key
+    #[arg(long)]
+    request_hash_header: bool,
+    /// Report the timing of the chat completion requests in the `X-Total-Time-Ms`, `X-Retrieval-Time-Ms` and `X-Generation-Time-Ms` headers. For stream requests over HTTP/2 with `TE: trailers`, the timing is sent as trailers after the stream
+    #[arg(long)]
+    timing_trailers: bool,
+    /// Send a `retrieval_progress` SSE event as the search of each collection and query completes in the stream responses of `/v1/chat/completions`, before the completion chunks. The response headers are then sent before the retrieval, so they don't carry the retrieval metadata
+    #[arg(long)]
+    stream_retrieval_progress: bool,
+    /// Interval in milliseconds of the `: keep-alive` SSE comments sent in the stream responses of `/v1/chat/completions` while the retrieval is running, so that the proxies don't close the idle connection before the first completion chunk. The response headers are then sent before the retrieval, as with `--stream-retrieval-progress`. Disabled by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    stream_keepalive_ms: Option<u64>,
+    /// Serve the debug endpoints, such as `/v1/retrieve/compare`, which runs a query through several retrieval strategies and returns their results side by side. They are meant for tuning, and should not be exposed to untrusted clients
+    #[arg(long)]
+    debug_endpoints: bool,
+    /// Ignore the non-text content parts, such as images and audio, in the messages and use only the text parts. By default, such requests are rejected with `400 Bad Request`
+    #[arg(long)]
+    ignore_nontext_parts: bool,
+    /// Regex substitution applied to the completion text, in the format of `regex=>replacement`. The option can be repeated, and the substitutions are applied in order
+    #[arg(long, value_name = "REGEX=>REPLACEMENT")]
+    output_filter: Vec<String>,
+    /// Validate the tool call arguments against the JSON schemas of the tools in the request. On mismatch, the completion is regenerated once with the violations fed back to the model; if the arguments are still invalid, the choice is returned with `finish_reason` set to `invalid_tool_args`. Applies to non-stream requests only
+    #[arg(long)]
+    validate_tool_args: bool,
+    /// Maximum number of tokens of the tool definitions in a chat request. Requests with larger tool definitions, or whose tool definitions and messages fill the context, are rejected with `422 Unprocessable Entity`. Unlimited if not set
+    #[arg(long)]
+    max_tools_tokens: Option<u64>,
+    /// Minimum number of tokens of the context of the chat model left for the messages, the retrieved context and the completion after the estimated fixed overhead of the prompt template and the RAG prompt. A smaller room is reported at startup as a warning, or as an error with `--strict`
+    #[arg(long, default_value = "256", value_parser = clap::value_parser!(u64))]
+    min_input_tokens: u64,
+    /// Compare the tokenizer of each embedding model with the `cl100k_base` tokenizer used for the token estimates, such as the chunk sizes and the context budgets, by embedding a probe text at startup. A warning is logged if the token counts differ by more than the given fraction, for example, 0.25. Disabled if not set
+    #[arg(long, value_parser = clap::value_parser!(f64))]
+    tokenizer_check_threshold: Option<f64>,
+    /// Format of the logs. `json` writes each log line as a JSON object with the `timestamp`, `level`, `target` and `message` fields, and the `request_id` of the request being handled, to the standard output
+    #[arg(long, default_value_t, value_enum)]
+    log_format: LogFormat,
+    /// Format of the access logs. `combined` writes one line per request in the Combined Log Format of Apache and nginx to the standard output, instead of the request and response fields logged by default
+    #[arg(long, default_value_t, value_enum)]
+    access_log_format: AccessLogFormat,
+    /// Origins allowed to call the server from a browser, separated by comma, for example, `https://app.example.com,http://localhost:3000`, or `*` for any origin. The server answers the CORS preflight requests and sets `Access-Control-Allow-Origin` for the allowed origins only
+    #[arg(long, value_name = "ORIGINS")]
+    cors_allowed_origins: Option<String>,
+    /// Path to a file of API keys, one per line, each optionally followed by whitespace and a label, for example, `sk-3f9a team-search`. The requests may use any key of the file, and the label is logged instead of the key. A key may be limited to some collections by a last word like `collections=docs,faq`. Blank lines and lines starting with `#` are skipped. Combined with the key of the `API_KEY` environment variable, if set
+    #[arg(long)]
+    api_key_file: Option<PathBuf>,
     /// Socket address of LlamaEdge-RAG API Server instance. For example, `0.0.0.0:8080`.
     #[arg(long, default_value = None, value_parser = clap::value_parser!(SocketAddr), group = "socket_address_group")]
     socket_addr: Option<SocketAddr>,
@@ -143,6 +466,27 @@ struct Cli {
     /// Root path for the Web UI files
     #[arg(long, default_value = "chatbot-ui")]
     web_ui: PathBuf,
+    /// Fetch the vector size, distance and point count of each collection from Qdrant at startup, and include them in `/v1/info`
+    #[arg(long)]
+    enrich_info: bool,
+    /// Extra information in the format of `key=value` included in the `extras` object of `/v1/info`. The option can be repeated. If a key is given more than once, the last value wins, or the server fails to start with `--strict`
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_info_extra)]
+    info_extra: Vec<(String, String)>,
+    /// Disable the Web UI. Only the API endpoints are served, and the other paths get a JSON `404 Not Found` without reading the filesystem
+    #[arg(long)]
+    no_web_ui: bool,
+    /// Treat the recoverable misconfigurations found at startup, such as a missing Web UI directory, as errors instead of warnings
+    #[arg(long)]
+    strict: bool,
+    /// Whether to keep HTTP/1 connections alive for reuse across requests
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    http1_keepalive: bool,
+    /// Maximum number of concurrent streams per HTTP/2 connection. Defaults to no limit
+    #[arg(long)]
+    http2_max_concurrent_streams: Option<u32>,
+    /// Maximum size in bytes of a request body (no less than 1). Larger requests are rejected with `413 Payload Too Large`, either right away from their `content-length` header, or as soon as a chunked body exceeds the limit. Unlimited if not set
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_body_size: Option<u64>,
     /// Deprecated. Print prompt strings to stdout
     #[arg(long)]
     log_prompts: bool,
@@ -168,49 +512,92 @@ async fn main() -> Result<(), ServerError> {
     if log_level == LogLevel::Debug || log_level == LogLevel::Trace {
         plugin_debug = true;
     }
+    // parse the command line arguments
+    let cli = Cli::parse();
+
     // set global logger
-    wasi_logger::Logger::install().expect("failed to install wasi_logger::Logger");
+    logger::install(cli.log_format).expect("failed to install the logger");
     log::set_max_level(log_level.into());
 
-    if let Ok(api_key) = std::env::var("API_KEY") {
-        // define a const variable for the API key
-        if let Err(e) = LLAMA_API_KEY.set(api_key) {
-            let err_msg = format!("Failed to set API key. {}", e);
+    info!(target: "stdout", "log_level: {}", log_level);
+    info!(target: "stdout", "log_format: {}", cli.log_format);
 
-            error!(target: "stdout", "{}", err_msg);
+    // log the version of the server
+    info!(target: "stdout", "server_version: {}", env!("CARGO_PKG_VERSION"));
 
-            return Err(ServerError::Operation(err_msg));
+    // log retrieval-only mode
+    info!(target: "stdout", "retrieval_only: {}", cli.retrieval_only);
+    RETRIEVAL_ONLY
+        .set(cli.retrieval_only)
+        .map_err(|_| ServerError::Operation("Failed to set `RETRIEVAL_ONLY`.".to_string()))?;
+
+    // log read-only mode
+    info!(target: "stdout", "read_only: {}", cli.read_only);
+    READ_ONLY
+        .set(cli.read_only)
+        .map_err(|_| ServerError::Operation("Failed to set `READ_ONLY`.".to_string()))?;
+
+    // load the API keys
+    let mut api_keys = match &cli.api_key_file {
+        Some(path) => ApiKeys::load(path).map_err(|err_msg| {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            ServerError::ArgumentError(err_msg)
+        })?,
+        None => ApiKeys::default(),
+    };
+    if let Ok(api_key) = std::env::var("API_KEY") {
+        if !api_keys.insert(api_key, "API_KEY") {
+            // log
+            warn!(target: "stdout", "The key of the `API_KEY` environment variable is also in the API key file. The label of the file is used.");
         }
     }
+    if !api_keys.is_empty() {
+        match &cli.api_key_file {
+            Some(path) => {
+                info!(target: "stdout", "api_key_file: {}, {} keys, {} scoped", path.display(), api_keys.len(), api_keys.scoped_len())
+            }
+            None => info!(target: "stdout", "api_key: set by `API_KEY`"),
+        }
+        LLAMA_API_KEYS
+            .set(api_keys)
+            .map_err(|_| ServerError::Operation("Failed to set `LLAMA_API_KEYS`.".to_string()))?;
+    }
 
-    // parse the command line arguments
-    let cli = Cli::parse();
-
-    info!(target: "stdout", "log_level: {}", log_level);
+    // log cors_allowed_origins
+    if let Some(cors_allowed_origins) = &cli.cors_allowed_origins {
+        let cors_origins = CorsOrigins::parse(cors_allowed_origins)?;
+        info!(target: "stdout", "cors_allowed_origins: {:?}", &cors_origins);
+        CORS_ALLOWED_ORIGINS.set(cors_origins).map_err(|_| {
+            ServerError::Operation("Failed to set `CORS_ALLOWED_ORIGINS`.".to_string())
+        })?;
+    }
 
-    // log the version of the server
-    info!(target: "stdout", "server_version: {}", env!("CARGO_PKG_VERSION"));
+    // The model-related options take one value per model, or two values, one for the chat model and one shared by all the embedding models. In retrieval-only mode, they also accept a single value for the embedding models.
+    let num_values_allowed =
+        |len: usize| len == 2 || len == cli.model_name.len() || (cli.retrieval_only && len == 1);
 
     // log model name
-    if cli.model_name.len() != 2 {
+    if cli.model_name.len() < 2 && !(cli.retrieval_only && cli.model_name.len() == 1) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires a chat model and an embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires a chat model and at least one embedding model, or embedding models only in the retrieval-only mode.".to_owned(),
         ));
     }
     info!(target: "stdout", "model_name: {}", cli.model_name.join(","));
 
     // log model alias
-    if cli.model_alias.len() != 2 {
+    if !num_values_allowed(cli.model_alias.len()) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires two model aliases: one for chat model, one for embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires two model aliases: one for chat model, one for embedding model, or one per model.".to_owned(),
         ));
     }
     info!(target: "stdout", "model_alias: {}", cli.model_alias.join(","));
 
     // log context size
-    if cli.ctx_size.len() != 2 {
+    if !num_values_allowed(cli.ctx_size.len()) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires two context sizes: one for chat model, one for embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires two context sizes: one for chat model, one for embedding model, or one per model.".to_owned(),
         ));
     }
     let ctx_sizes_str: String = cli
@@ -222,9 +609,9 @@ async fn main() -> Result<(), ServerError> {
     info!(target: "stdout", "ctx_size: {}", ctx_sizes_str);
 
     // log batch size
-    if cli.batch_size.len() != 2 {
+    if !num_values_allowed(cli.batch_size.len()) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires two batch sizes: one for chat model, one for embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires two batch sizes: one for chat model, one for embedding model, or one per model.".to_owned(),
         ));
     }
     let batch_sizes_str: String = cli
@@ -236,9 +623,9 @@ async fn main() -> Result<(), ServerError> {
     info!(target: "stdout", "batch_size: {}", batch_sizes_str);
 
     // log ubatch size
-    if cli.ubatch_size.len() != 2 {
+    if !num_values_allowed(cli.ubatch_size.len()) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires two ubatch sizes: one for chat model, one for embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires two ubatch sizes: one for chat model, one for embedding model, or one per model.".to_owned(),
         ));
     }
     let ubatch_sizes_str: String = cli
@@ -250,9 +637,9 @@ async fn main() -> Result<(), ServerError> {
     info!(target: "stdout", "ubatch_size: {}", ubatch_sizes_str);
 
     // log prompt template
-    if cli.prompt_template.len() != 2 {
+    if !num_values_allowed(cli.prompt_template.len()) {
         return Err(ServerError::ArgumentError(
-            "LlamaEdge RAG API server requires two prompt templates: one for chat model, one for embedding model.".to_owned(),
+            "LlamaEdge RAG API server requires two prompt templates: one for chat model, one for embedding model, or one per model.".to_owned(),
         ));
     }
     let prompt_template_str: String = cli
@@ -263,13 +650,37 @@ async fn main() -> Result<(), ServerError> {
         .join(",");
     info!(target: "stdout", "prompt_template: {}", prompt_template_str);
 
+    // the reverse prompt of the chat model defaults to the end-of-turn marker of its prompt template
+    let template_stop = match cli.retrieval_only {
+        true => None,
+        false => template_stop_marker(cli.prompt_template[0]),
+    };
+    let reverse_prompt = match (cli.reverse_prompt.clone(), template_stop) {
+        (Some(reverse_prompt), Some(template_stop)) if reverse_prompt != template_stop => {
+            // log
+            warn!(target: "stdout", "The reverse prompt `{}` replaces the end-of-turn marker `{}` of the prompt template `{}`, since only one reverse prompt is supported. The generation may not stop at the end of the turn.", reverse_prompt, template_stop, cli.prompt_template[0]);
+
+            Some(reverse_prompt)
+        }
+        (Some(reverse_prompt), _) => Some(reverse_prompt),
+        (None, template_stop) => template_stop.map(String::from),
+    };
+
     // log reverse prompt
-    if let Some(reverse_prompt) = &cli.reverse_prompt {
-        info!(target: "stdout", "reverse_prompt: {}", reverse_prompt);
+    if let Some(reverse_prompt) = &reverse_prompt {
+        match cli.reverse_prompt.is_some() {
+            true => info!(target: "stdout", "reverse_prompt: {}", reverse_prompt),
+            false => {
+                info!(target: "stdout", "reverse_prompt: {} (from the prompt template)", reverse_prompt)
+            }
+        }
     }
 
     // log n_predict
     info!(target: "stdout", "n_predict: {}", &cli.n_predict);
+    if cli.n_predict == -2 && !cli.retrieval_only {
+        warn!(target: "stdout", "With n_predict = -2, the completion is generated until the context of {} tokens is filled. The prompt, including the retrieved context, shares the context with the completion, so a long prompt leaves little room for the completion.", cli.ctx_size[0]);
+    }
 
     // log n_gpu_layers
     info!(target: "stdout", "n_gpu_layers: {}", &cli.n_gpu_layers);
@@ -325,6 +736,20 @@ async fn main() -> Result<(), ServerError> {
     }
     info!(target: "stdout", "qdrant_url: {}", &cli.qdrant_url);
 
+    // the API key of the Qdrant instance, which is never logged
+    let qdrant_api_key = match &cli.qdrant_api_key {
+        Some(qdrant_api_key) => Some((qdrant_api_key.clone(), "--qdrant-api-key")),
+        None => std::env::var("QDRANT_API_KEY")
+            .ok()
+            .map(|qdrant_api_key| (qdrant_api_key, "QDRANT_API_KEY")),
+    };
+    if let Some((qdrant_api_key, source)) = qdrant_api_key.filter(|(key, _)| !key.is_empty()) {
+        info!(target: "stdout", "qdrant_api_key: set by `{}`", source);
+        QDRANT_API_KEY
+            .set(qdrant_api_key)
+            .map_err(|_| ServerError::Operation("Failed to set `QDRANT_API_KEY`.".to_string()))?;
+    }
+
     if cli.qdrant_collection_name.len() != cli.qdrant_limit.len()
         && cli.qdrant_limit.len() > 1
         && cli.qdrant_score_threshold.len() > 1
@@ -395,8 +820,238 @@ async fn main() -> Result<(), ServerError> {
         qdrant_config_vec.push(qdrant_config);
     }
 
+    // check if the collections are empty
+    let vdb_api_key = qdrant::default_api_key();
+    match qdrant::all_collections_empty(&qdrant_config_vec, vdb_api_key.as_deref()).await {
+        Ok(true) => {
+            warn!(target: "stdout", "All the collections are empty. No context will be retrieved until documents are ingested.");
+        }
+        Ok(false) => {}
+        Err(e) => {
+            warn!(target: "stdout", "Failed to check if the collections are empty. {}", e);
+        }
+    }
+
+    // log auto_collection_routing
+    if let Some(k) = cli.auto_collection_routing {
+        info!(target: "stdout", "auto_collection_routing: {}", k);
+        AUTO_COLLECTION_ROUTING.set(k).map_err(|_| {
+            ServerError::Operation("Failed to set `AUTO_COLLECTION_ROUTING`.".to_string())
+        })?;
+
+        // compute the centroids of the collections
+        if qdrant_config_vec.len() as u64 > k {
+            for qdrant_config in qdrant_config_vec.iter() {
+                if let Err(e) = routing::refresh_centroid(
+                    &qdrant_config.url,
+                    vdb_api_key.as_deref(),
+                    &qdrant_config.collection_name,
+                )
+                .await
+                {
+                    // log
+                    warn!(target: "stdout", "Failed to compute the centroid of the collection `{}`. The collection is searched for every query until the centroid is computed. {}", qdrant_config.collection_name, e);
+                }
+            }
+        } else {
+            // log
+            warn!(target: "stdout", "`--auto-collection-routing {}` is not less than the number of collections, so all the collections are searched.", k);
+        }
+    }
+
+    // log warn_on_empty_collections
+    info!(target: "stdout", "warn_on_empty_collections: {}", cli.warn_on_empty_collections);
+    WARN_ON_EMPTY_COLLECTIONS
+        .set(cli.warn_on_empty_collections)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `WARN_ON_EMPTY_COLLECTIONS`.".to_string())
+        })?;
+
+    // log retrieval_breakdown
+    info!(target: "stdout", "retrieval_breakdown: {}", cli.retrieval_breakdown);
+    RETRIEVAL_BREAKDOWN
+        .set(cli.retrieval_breakdown)
+        .map_err(|_| ServerError::Operation("Failed to set `RETRIEVAL_BREAKDOWN`.".to_string()))?;
+
+    // log coalesce_requests
+    info!(target: "stdout", "coalesce_requests: {}", cli.coalesce_requests);
+    COALESCE_REQUESTS
+        .set(cli.coalesce_requests)
+        .map_err(|_| ServerError::Operation("Failed to set `COALESCE_REQUESTS`.".to_string()))?;
+
+    // log request_hash_header
+    info!(target: "stdout", "request_hash_header: {}", cli.request_hash_header);
+    REQUEST_HASH_HEADER
+        .set(cli.request_hash_header)
+        .map_err(|_| ServerError::Operation("Failed to set `REQUEST_HASH_HEADER`.".to_string()))?;
+
+    // log timing_trailers
+    info!(target: "stdout", "timing_trailers: {}", cli.timing_trailers);
+    TIMING_TRAILERS
+        .set(cli.timing_trailers)
+        .map_err(|_| ServerError::Operation("Failed to set `TIMING_TRAILERS`.".to_string()))?;
+
+    // log stream_retrieval_progress
+    info!(target: "stdout", "stream_retrieval_progress: {}", cli.stream_retrieval_progress);
+    STREAM_RETRIEVAL_PROGRESS
+        .set(cli.stream_retrieval_progress)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `STREAM_RETRIEVAL_PROGRESS`.".to_string())
+        })?;
+
+    // log stream_keepalive_ms
+    if let Some(stream_keepalive_ms) = cli.stream_keepalive_ms {
+        info!(target: "stdout", "stream_keepalive_ms: {}ms", stream_keepalive_ms);
+        STREAM_KEEPALIVE
+            .set(std::time::Duration::from_millis(stream_keepalive_ms))
+            .map_err(|_| ServerError::Operation("Failed to set `STREAM_KEEPALIVE`.".to_string()))?;
+    }
+
+    // log debug_endpoints
+    info!(target: "stdout", "debug_endpoints: {}", cli.debug_endpoints);
+    DEBUG_ENDPOINTS
+        .set(cli.debug_endpoints)
+        .map_err(|_| ServerError::Operation("Failed to set `DEBUG_ENDPOINTS`.".to_string()))?;
+
+    if cli.qdrant_collection_name.len() != cli.chunk_capacity.len() && cli.chunk_capacity.len() > 1
+    {
+        return Err(ServerError::ArgumentError(
+            "LlamaEdge RAG API server requires the same number of Qdrant collection names and chunk capacities; or the chunk capacity is only one value for all collections.".to_owned(),
+        ));
+    }
+
     // log chunk capacity
-    info!(target: "stdout", "chunk_capacity: {}", &cli.chunk_capacity);
+    let chunk_capacity_str: String = cli
+        .chunk_capacity
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    info!(target: "stdout", "chunk_capacity: {}", chunk_capacity_str);
+
+    // create the chunk capacity of each collection
+    let mut chunk_capacity = ChunkCapacity {
+        default_capacity: cli.chunk_capacity[0],
+        collection_capacities: HashMap::new(),
+    };
+    if cli.chunk_capacity.len() > 1 {
+        for (col_name, capacity) in cli
+            .qdrant_collection_name
+            .iter()
+            .zip(cli.chunk_capacity.iter())
+        {
+            chunk_capacity
+                .collection_capacities
+                .insert(col_name.clone(), *capacity);
+        }
+    }
+    CHUNK_CAPACITY
+        .set(chunk_capacity)
+        .map_err(|_| ServerError::Operation("Failed to set `CHUNK_CAPACITY`.".to_string()))?;
+
+    // load the embedding projection
+    if let Some(path) = &cli.embedding_projection {
+        let projection = EmbeddingProjection::load(path).map_err(|err_msg| {
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            ServerError::ArgumentError(err_msg)
+        })?;
+
+        info!(target: "stdout", "embedding_projection: {}, {} -> {} dimensions", path.display(), projection.input_dim(), projection.output_dim());
+        warn!(target: "stdout", "The embeddings are projected to a different dimension, which degrades the retrieval quality compared to a collection built with the current embedding model.");
+
+        EMBEDDING_PROJECTION.set(projection).map_err(|_| {
+            ServerError::Operation("Failed to set `EMBEDDING_PROJECTION`.".to_string())
+        })?;
+    }
+
+    // log unicode normalization
+    info!(target: "stdout", "unicode_normalization: {}", cli.unicode_normalization);
+    UNICODE_NORMALIZATION
+        .set(cli.unicode_normalization)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `UNICODE_NORMALIZATION`.".to_string())
+        })?;
+
+    // log code preprocessing
+    let code_preprocess: Vec<String> = cli.code_preprocess.iter().map(|s| s.to_string()).collect();
+    info!(target: "stdout", "code_preprocess: {:?}", &code_preprocess);
+    CODE_PREPROCESS
+        .set(cli.code_preprocess.clone())
+        .map_err(|_| ServerError::Operation("Failed to set `CODE_PREPROCESS`.".to_string()))?;
+
+    // log embedding precision
+    info!(target: "stdout", "embedding_precision: {}", cli.embedding_precision);
+    EMBEDDING_PRECISION
+        .set(cli.embedding_precision)
+        .map_err(|_| ServerError::Operation("Failed to set `EMBEDDING_PRECISION`.".to_string()))?;
+
+    // log multivector_pooling
+    info!(target: "stdout", "multivector_pooling: {}", cli.multivector_pooling);
+    MULTIVECTOR_POOLING
+        .set(cli.multivector_pooling)
+        .map_err(|_| ServerError::Operation("Failed to set `MULTIVECTOR_POOLING`.".to_string()))?;
+
+    // log embeddings_partial_failure
+    info!(target: "stdout", "embeddings_partial_failure: {}", cli.embeddings_partial_failure);
+    EMBEDDINGS_PARTIAL_FAILURE
+        .set(cli.embeddings_partial_failure)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `EMBEDDINGS_PARTIAL_FAILURE`.".to_string())
+        })?;
+
+    // log max_embedding_batch
+    if let Some(max_embedding_batch) = cli.max_embedding_batch {
+        info!(target: "stdout", "max_embedding_batch: {}", max_embedding_batch);
+        MAX_EMBEDDING_BATCH.set(max_embedding_batch).map_err(|_| {
+            ServerError::Operation("Failed to set `MAX_EMBEDDING_BATCH`.".to_string())
+        })?;
+    }
+
+    // log ingestion_chunk_retries
+    info!(target: "stdout", "ingestion_chunk_retries: {}", cli.ingestion_chunk_retries);
+    INGESTION_CHUNK_RETRIES
+        .set(cli.ingestion_chunk_retries)
+        .map_err(|_| {
+            ServerError::Operation("Failed to set `INGESTION_CHUNK_RETRIES`.".to_string())
+        })?;
+
+    // log max_concurrent_ingestions
+    if let Some(max_concurrent_ingestions) = cli.max_concurrent_ingestions {
+        info!(target: "stdout", "max_concurrent_ingestions: {}", max_concurrent_ingestions);
+        MAX_CONCURRENT_INGESTIONS
+            .set(max_concurrent_ingestions)
+            .map_err(|_| {
+                ServerError::Operation("Failed to set `MAX_CONCURRENT_INGESTIONS`.".to_string())
+            })?;
+    }
+
+    // log ingestion_queue_size
+    if let Some(ingestion_queue_size) = cli.ingestion_queue_size {
+        info!(target: "stdout", "ingestion_queue_size: {}", ingestion_queue_size);
+        INGESTION_QUEUE_SIZE
+            .set(ingestion_queue_size)
+            .map_err(|_| {
+                ServerError::Operation("Failed to set `INGESTION_QUEUE_SIZE`.".to_string())
+            })?;
+    }
+
+    // log max_concurrent_requests
+    if let Some(max_concurrent_requests) = cli.max_concurrent_requests {
+        info!(target: "stdout", "max_concurrent_requests: {}", max_concurrent_requests);
+        REQUEST_SLOTS
+            .set(Arc::new(Semaphore::new(max_concurrent_requests as usize)))
+            .map_err(|_| ServerError::Operation("Failed to set `REQUEST_SLOTS`.".to_string()))?;
+    }
+
+    // log queue_timeout
+    if let Some(queue_timeout) = cli.queue_timeout {
+        info!(target: "stdout", "queue_timeout: {}ms", queue_timeout);
+        QUEUE_TIMEOUT
+            .set(std::time::Duration::from_millis(queue_timeout))
+            .map_err(|_| ServerError::Operation("Failed to set `QUEUE_TIMEOUT`.".to_string()))?;
+    }
 
     // log context window
     info!(target: "stdout", "context_window: {}", &cli.context_window);
@@ -404,17 +1059,73 @@ async fn main() -> Result<(), ServerError> {
         .set(cli.context_window)
         .map_err(|e| ServerError::Operation(format!("Failed to set `CONTEXT_WINDOW`. {}", e)))?;
 
+    // log turn decay
+    if !(cli.turn_decay > 0.0 && cli.turn_decay <= 1.0) {
+        let err_msg = format!(
+            "Invalid turn decay: {}. The value should be in (0, 1].",
+            cli.turn_decay
+        );
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(ServerError::ArgumentError(err_msg));
+    }
+    info!(target: "stdout", "turn_decay: {}", cli.turn_decay);
+    TURN_DECAY
+        .set(cli.turn_decay)
+        .map_err(|e| ServerError::Operation(format!("Failed to set `TURN_DECAY`. {}", e)))?;
+
+    // log multi-query
+    info!(target: "stdout", "multi_query: {}", &cli.multi_query);
+    let mut multi_query = cli.multi_query;
+    if cli.retrieval_only && multi_query > 1 {
+        warn!(target: "stdout", "The '--multi-query' option requires a chat model to generate query paraphrases. Ignored in the retrieval-only mode.");
+
+        multi_query = 1;
+    }
+    MULTI_QUERY
+        .set(multi_query)
+        .map_err(|e| ServerError::Operation(format!("Failed to set `MULTI_QUERY`. {}", e)))?;
+
+    // log context_format
+    info!(target: "stdout", "context_format: {}", cli.context_format);
+    CONTEXT_FORMAT
+        .set(cli.context_format)
+        .map_err(|_| ServerError::Operation("Failed to set `CONTEXT_FORMAT`.".to_string()))?;
+
+    // log retrieve_for_system
+    info!(target: "stdout", "retrieve_for_system: {}", cli.retrieve_for_system);
+    RETRIEVE_FOR_SYSTEM
+        .set(cli.retrieve_for_system)
+        .map_err(|_| ServerError::Operation("Failed to set `RETRIEVE_FOR_SYSTEM`.".to_string()))?;
+
     // RAG policy
     info!(target: "stdout", "rag_policy: {}", &cli.policy);
 
     let mut policy = cli.policy;
-    if policy == MergeRagContextPolicy::SystemMessage && !cli.prompt_template[0].has_system_prompt()
+    if !cli.retrieval_only
+        && policy == MergeRagContextPolicy::SystemMessage
+        && !cli.prompt_template[0].has_system_prompt()
     {
         warn!(target: "server_config", "{}", format!("The chat model does not support system message, while the '--policy' option sets to \"{}\". Update the RAG policy to {}.", cli.policy, MergeRagContextPolicy::LastUserMessage));
 
         policy = MergeRagContextPolicy::LastUserMessage;
     }
 
+    // RAG context message
+    info!(target: "stdout", "rag_context_message: {}", &cli.rag_context_message);
+
+    let mut rag_context_message = cli.rag_context_message;
+    if !cli.retrieval_only
+        && rag_context_message == RagContextMessage::Tool
+        && !template_supports_tool_message(cli.prompt_template[0])
+    {
+        warn!(target: "server_config", "{}", format!("The prompt template \"{}\" does not support tool message, while the '--rag-context-message' option sets to \"{}\". Merge the RAG context by the RAG policy {}.", cli.prompt_template[0], cli.rag_context_message, policy));
+
+        rag_context_message = RagContextMessage::Merged;
+    }
+
     // keyword search configuration
     if let Some(kw_search_url) = &cli.kw_search_url {
         let kw_search_config = KeywordSearchConfig {
@@ -423,111 +1134,580 @@ async fn main() -> Result<(), ServerError> {
         KW_SEARCH_CONFIG.set(kw_search_config).unwrap();
     }
 
+    // log rerank_url
+    if let Some(rerank_url) = &cli.rerank_url {
+        if !is_valid_url(rerank_url) {
+            let err_msg = format!(
+                "Invalid reranker URL: {}. The value should be a valid URL.",
+                rerank_url
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+
+        info!(target: "stdout", "rerank_url: {}", rerank_url);
+        if let Some(rerank_model) = &cli.rerank_model {
+            info!(target: "stdout", "rerank_model: {}", rerank_model);
+        }
+        if let Some(rerank_candidates) = cli.rerank_candidates {
+            info!(target: "stdout", "rerank_candidates: {}", rerank_candidates);
+        }
+        if let Some(rerank_top_n) = cli.rerank_top_n {
+            if let Some(rerank_candidates) = cli.rerank_candidates {
+                if rerank_top_n > rerank_candidates {
+                    let err_msg = format!(
+                        "Invalid rerank top n: {}. The value should be no more than the {} candidates of `--rerank-candidates`.",
+                        rerank_top_n, rerank_candidates
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(ServerError::ArgumentError(err_msg));
+                }
+            }
+
+            info!(target: "stdout", "rerank_top_n: {}", rerank_top_n);
+        }
+        RERANK_CONFIG
+            .set(RerankConfig {
+                url: rerank_url.clone(),
+                model: cli.rerank_model.clone(),
+                candidates: cli.rerank_candidates,
+                top_n: cli.rerank_top_n,
+            })
+            .map_err(|_| ServerError::Operation("Failed to set `RERANK_CONFIG`.".to_string()))?;
+    }
+
+    // log kw_search_url_allowlist
+    if !cli.kw_search_url_allowlist.is_empty() {
+        let mut allowlist = Vec::with_capacity(cli.kw_search_url_allowlist.len());
+        for allowed in cli.kw_search_url_allowlist.iter() {
+            match url::Url::parse(allowed) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {
+                    allowlist.push(url)
+                }
+                _ => {
+                    let err_msg = format!(
+                        "Invalid URL in `--kw-search-url-allowlist`: {}. The value should be an HTTP or HTTPS URL.",
+                        allowed
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(ServerError::ArgumentError(err_msg));
+                }
+            }
+        }
+
+        info!(target: "stdout", "kw_search_url_allowlist: {}", cli.kw_search_url_allowlist.join(","));
+        KW_SEARCH_URL_ALLOWLIST.set(allowlist).map_err(|_| {
+            ServerError::Operation("Failed to set `KW_SEARCH_URL_ALLOWLIST`.".to_string())
+        })?;
+    }
+
+    // log kw_search_limit
+    let kw_search_limit = cli.kw_search_limit.unwrap_or(cli.qdrant_limit[0]);
+    info!(target: "stdout", "kw_search_limit: {}", kw_search_limit);
+    KW_SEARCH_LIMIT
+        .set(kw_search_limit)
+        .map_err(|_| ServerError::Operation("Failed to set `KW_SEARCH_LIMIT`.".to_string()))?;
+
+    // log hybrid_fusion_k
+    if let Some(hybrid_fusion_k) = cli.hybrid_fusion_k {
+        info!(target: "stdout", "hybrid_fusion_k: {}", hybrid_fusion_k);
+        HYBRID_FUSION_K
+            .set(hybrid_fusion_k)
+            .map_err(|_| ServerError::Operation("Failed to set `HYBRID_FUSION_K`.".to_string()))?;
+    }
+
+    // log kw_fallback
+    info!(target: "stdout", "kw_fallback: {}", cli.kw_fallback);
+    KW_FALLBACK
+        .set(cli.kw_fallback)
+        .map_err(|_| ServerError::Operation("Failed to set `KW_FALLBACK`.".to_string()))?;
+
+    // log retrieval confidence thresholds
+    info!(target: "stdout", "confidence_strong_score: {}", cli.confidence_strong_score);
+    info!(target: "stdout", "confidence_strong_chunks: {}", cli.confidence_strong_chunks);
+    CONFIDENCE_CONFIG
+        .set(ConfidenceConfig {
+            strong_score: cli.confidence_strong_score,
+            strong_chunks: cli.confidence_strong_chunks as usize,
+        })
+        .map_err(|_| ServerError::Operation("Failed to set `CONFIDENCE_CONFIG`.".to_string()))?;
+
+    // log recency decay
+    if let Some(recency_decay) = cli.recency_decay {
+        if recency_decay <= 0.0 {
+            let err_msg = format!(
+                "Invalid recency decay: {}. The half-life should be greater than 0.",
+                recency_decay
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+        if !(0.0..=1.0).contains(&cli.recency_weight) {
+            let err_msg = format!(
+                "Invalid recency weight: {}. The value should be in [0, 1].",
+                cli.recency_weight
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+
+        info!(target: "stdout", "recency_decay: {} day(s)", recency_decay);
+        info!(target: "stdout", "recency_weight: {}", cli.recency_weight);
+        RECENCY_CONFIG
+            .set(RecencyConfig {
+                half_life_days: recency_decay,
+                weight: cli.recency_weight,
+            })
+            .map_err(|_| ServerError::Operation("Failed to set `RECENCY_CONFIG`.".to_string()))?;
+    }
+
+    // log retrieval_time_budget
+    if let Some(retrieval_time_budget) = cli.retrieval_time_budget {
+        info!(target: "stdout", "retrieval_time_budget: {}ms", retrieval_time_budget);
+        RETRIEVAL_TIME_BUDGET
+            .set(std::time::Duration::from_millis(retrieval_time_budget))
+            .map_err(|_| {
+                ServerError::Operation("Failed to set `RETRIEVAL_TIME_BUDGET`.".to_string())
+            })?;
+    }
+
+    // log retrieval_timeout
+    if let Some(retrieval_timeout) = cli.retrieval_timeout {
+        info!(target: "stdout", "retrieval_timeout: {}ms", retrieval_timeout);
+        info!(target: "stdout", "retrieval_timeout_action: {}", cli.retrieval_timeout_action);
+        RETRIEVAL_TIMEOUT
+            .set((
+                std::time::Duration::from_millis(retrieval_timeout),
+                cli.retrieval_timeout_action,
+            ))
+            .map_err(|_| {
+                ServerError::Operation("Failed to set `RETRIEVAL_TIMEOUT`.".to_string())
+            })?;
+    }
+
+    // log qdrant_max_retries
+    if cli.qdrant_max_retries > 0 {
+        info!(target: "stdout", "qdrant_max_retries: {}", cli.qdrant_max_retries);
+        info!(target: "stdout", "qdrant_retry_backoff_ms: {}", cli.qdrant_retry_backoff_ms);
+        QDRANT_RETRY
+            .set((
+                cli.qdrant_max_retries,
+                std::time::Duration::from_millis(cli.qdrant_retry_backoff_ms),
+            ))
+            .map_err(|_| ServerError::Operation("Failed to set `QDRANT_RETRY`.".to_string()))?;
+    }
+
+    // log ignore_nontext_parts
+    info!(target: "stdout", "ignore_nontext_parts: {}", cli.ignore_nontext_parts);
+    IGNORE_NONTEXT_PARTS
+        .set(cli.ignore_nontext_parts)
+        .map_err(|_| ServerError::Operation("Failed to set `IGNORE_NONTEXT_PARTS`.".to_string()))?;
+
+    // log output filters
+    let mut output_filters = Vec::new();
+    for filter in cli.output_filter.iter() {
+        let filter: OutputFilter = filter.parse().map_err(ServerError::ArgumentError)?;
+        info!(target: "stdout", "output_filter: {}", &filter);
+
+        output_filters.push(filter);
+    }
+    OUTPUT_FILTERS
+        .set(output_filters)
+        .map_err(|_| ServerError::Operation("Failed to set `OUTPUT_FILTERS`.".to_string()))?;
+
+    // log validate_tool_args
+    info!(target: "stdout", "validate_tool_args: {}", cli.validate_tool_args);
+    VALIDATE_TOOL_ARGS
+        .set(cli.validate_tool_args)
+        .map_err(|_| ServerError::Operation("Failed to set `VALIDATE_TOOL_ARGS`.".to_string()))?;
+
+    // log max_tools_tokens
+    if let Some(max_tools_tokens) = cli.max_tools_tokens {
+        info!(target: "stdout", "max_tools_tokens: {}", max_tools_tokens);
+        MAX_TOOLS_TOKENS
+            .set(max_tools_tokens)
+            .map_err(|_| ServerError::Operation("Failed to set `MAX_TOOLS_TOKENS`.".to_string()))?;
+    }
+
     // log include_usage
     info!(target: "stdout", "include_usage: {}", cli.include_usage);
 
     // create metadata for chat model
-    let chat_metadata = GgmlMetadataBuilder::new(
-        cli.model_name[0].clone(),
-        cli.model_alias[0].clone(),
-        cli.prompt_template[0],
-    )
-    .with_ctx_size(cli.ctx_size[0])
-    .with_reverse_prompt(cli.reverse_prompt)
-    .with_batch_size(cli.batch_size[0])
-    .with_ubatch_size(cli.ubatch_size[0])
-    .with_n_predict(cli.n_predict)
-    .with_n_gpu_layers(cli.n_gpu_layers)
-    .with_split_mode(cli.split_mode.clone())
-    .with_main_gpu(cli.main_gpu)
-    .with_tensor_split(cli.tensor_split.clone())
-    .with_threads(cli.threads)
-    .with_grammar(cli.grammar)
-    .with_json_schema(cli.json_schema)
-    .enable_plugin_log(true)
-    .enable_debug_log(plugin_debug)
-    .include_usage(cli.include_usage)
-    .build();
-
-    let chat_model_info = ModelConfig {
-        name: chat_metadata.model_name.clone(),
-        ty: "chat".to_string(),
-        prompt_template: chat_metadata.prompt_template,
-        n_predict: chat_metadata.n_predict,
-        reverse_prompt: chat_metadata.reverse_prompt.clone(),
-        n_gpu_layers: chat_metadata.n_gpu_layers,
-        ctx_size: chat_metadata.ctx_size,
-        batch_size: chat_metadata.batch_size,
-        ubatch_size: chat_metadata.ubatch_size,
-        temperature: chat_metadata.temperature,
-        top_p: chat_metadata.top_p,
-        repeat_penalty: chat_metadata.repeat_penalty,
-        presence_penalty: chat_metadata.presence_penalty,
-        frequency_penalty: chat_metadata.frequency_penalty,
-        split_mode: chat_metadata.split_mode.clone(),
-        main_gpu: chat_metadata.main_gpu,
-        tensor_split: chat_metadata.tensor_split.clone(),
+    let mut chat_models = Vec::new();
+    let chat_model_info = match cli.retrieval_only {
+        true => None,
+        false => {
+            let chat_metadata = GgmlMetadataBuilder::new(
+                cli.model_name[0].clone(),
+                cli.model_alias[0].clone(),
+                cli.prompt_template[0],
+            )
+            .with_ctx_size(cli.ctx_size[0])
+            .with_reverse_prompt(reverse_prompt)
+            .with_batch_size(cli.batch_size[0])
+            .with_ubatch_size(cli.ubatch_size[0])
+            .with_n_predict(cli.n_predict)
+            .with_n_gpu_layers(cli.n_gpu_layers)
+            .with_split_mode(cli.split_mode.clone())
+            .with_main_gpu(cli.main_gpu)
+            .with_tensor_split(cli.tensor_split.clone())
+            .with_threads(cli.threads)
+            .with_grammar(cli.grammar)
+            .with_json_schema(cli.json_schema)
+            .enable_plugin_log(true)
+            .enable_debug_log(plugin_debug)
+            .include_usage(cli.include_usage)
+            .build();
+
+            let chat_model_info = ModelConfig {
+                name: chat_metadata.model_name.clone(),
+                alias: chat_metadata.model_alias.clone(),
+                ty: "chat".to_string(),
+                prompt_template: chat_metadata.prompt_template,
+                n_predict: chat_metadata.n_predict,
+                reverse_prompt: chat_metadata.reverse_prompt.clone(),
+                n_gpu_layers: chat_metadata.n_gpu_layers,
+                ctx_size: chat_metadata.ctx_size,
+                batch_size: chat_metadata.batch_size,
+                ubatch_size: chat_metadata.ubatch_size,
+                temperature: chat_metadata.temperature,
+                top_p: chat_metadata.top_p,
+                repeat_penalty: chat_metadata.repeat_penalty,
+                presence_penalty: chat_metadata.presence_penalty,
+                frequency_penalty: chat_metadata.frequency_penalty,
+                split_mode: chat_metadata.split_mode.clone(),
+                main_gpu: chat_metadata.main_gpu,
+                tensor_split: chat_metadata.tensor_split.clone(),
+            };
+
+            // chat model
+            chat_models.push(chat_metadata);
+
+            Some(chat_model_info)
+        }
+    };
+
+    // check that the fixed overhead of the prompt template leaves room for the input
+    if let Some(chat_model_info) = &chat_model_info {
+        match estimate_template_overhead(chat_model_info.prompt_template, cli.rag_prompt.as_deref())
+        {
+            Ok(overhead) => {
+                let room = chat_model_info.ctx_size.saturating_sub(overhead);
+                info!(target: "stdout", "template_overhead: about {} of {} context tokens, min_input_tokens: {}", overhead, chat_model_info.ctx_size, cli.min_input_tokens);
+
+                if room < cli.min_input_tokens {
+                    let err_msg = format!(
+                        "The prompt template `{}` and the RAG prompt take about {} of the {} context tokens of the chat model, which leaves about {} tokens for the messages, the retrieved context and the completion, less than `--min-input-tokens` of {}. Increase `--ctx-size`, or shorten `--rag-prompt`.",
+                        chat_model_info.prompt_template,
+                        overhead,
+                        chat_model_info.ctx_size,
+                        room,
+                        cli.min_input_tokens
+                    );
+
+                    if cli.strict {
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        return Err(ServerError::ArgumentError(err_msg));
+                    }
+
+                    // log
+                    warn!(target: "stdout", "{}", &err_msg);
+                }
+            }
+            Err(err_msg) => {
+                // log
+                warn!(target: "stdout", "Failed to estimate the overhead of the prompt template. {}", err_msg);
+            }
+        }
+    }
+
+    // create metadata for embedding models
+    // the model slot 0 is the chat model, and the slots from 1 are the embedding models. In retrieval-only mode, a single model name is the embedding model of the slot 1.
+    let embedding_slots: Vec<(usize, String)> = match cli.model_name.len() {
+        1 => vec![(1, cli.model_name[0].clone())],
+        _ => cli.model_name.iter().cloned().enumerate().skip(1).collect(),
     };
+    // the value of a model-related option for the slot: the options with fewer values apply their last value to the remaining embedding models
+    let value_idx = |len: usize, slot: usize| if len == 1 { 0 } else { slot.min(len - 1) };
+
+    let mut embedding_models = Vec::with_capacity(embedding_slots.len());
+    let mut embedding_model_infos = Vec::with_capacity(embedding_slots.len());
+    for (slot, model_name) in embedding_slots {
+        // an alias is not shared among the embedding models, so the models without an alias of their own are aliased by their names
+        let model_alias = match cli.model_alias.len() == 1 || slot < cli.model_alias.len() {
+            true => cli.model_alias[value_idx(cli.model_alias.len(), slot)].clone(),
+            false => model_name.clone(),
+        };
+
+        let embedding_metadata = GgmlMetadataBuilder::new(
+            model_name,
+            model_alias,
+            cli.prompt_template[value_idx(cli.prompt_template.len(), slot)],
+        )
+        .with_ctx_size(cli.ctx_size[value_idx(cli.ctx_size.len(), slot)])
+        .with_batch_size(cli.batch_size[value_idx(cli.batch_size.len(), slot)])
+        .with_ubatch_size(cli.ubatch_size[value_idx(cli.ubatch_size.len(), slot)])
+        .with_split_mode(cli.split_mode.clone())
+        .with_main_gpu(cli.main_gpu)
+        .with_tensor_split(cli.tensor_split.clone())
+        .with_threads(cli.threads)
+        .enable_plugin_log(true)
+        .enable_debug_log(plugin_debug)
+        .build();
+
+        embedding_model_infos.push(ModelConfig {
+            name: embedding_metadata.model_name.clone(),
+            alias: embedding_metadata.model_alias.clone(),
+            ty: "embedding".to_string(),
+            ctx_size: embedding_metadata.ctx_size,
+            batch_size: embedding_metadata.batch_size,
+            ubatch_size: embedding_metadata.ubatch_size,
+            prompt_template: embedding_metadata.prompt_template,
+            n_predict: embedding_metadata.n_predict,
+            reverse_prompt: embedding_metadata.reverse_prompt.clone(),
+            n_gpu_layers: embedding_metadata.n_gpu_layers,
+            temperature: embedding_metadata.temperature,
+            top_p: embedding_metadata.top_p,
+            repeat_penalty: embedding_metadata.repeat_penalty,
+            presence_penalty: embedding_metadata.presence_penalty,
+            frequency_penalty: embedding_metadata.frequency_penalty,
+            split_mode: embedding_metadata.split_mode.clone(),
+            main_gpu: embedding_metadata.main_gpu,
+            tensor_split: embedding_metadata.tensor_split.clone(),
+        });
+
+        embedding_models.push(embedding_metadata);
+    }
+
+    // check that the embedding models have different names and aliases
+    for (idx, model_info) in embedding_model_infos.iter().enumerate() {
+        if let Some(other) = embedding_model_infos[..idx].iter().find(|other| {
+            other.name == model_info.name
+                || other.alias == model_info.alias
+                || other.alias == model_info.name
+                || other.name == model_info.alias
+        }) {
+            let err_msg = format!(
+                "The embedding models `{}` and `{}` have the same name or alias. Set a different alias for each embedding model with `--model-alias`.",
+                other.name, model_info.name
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+    }
 
-    // chat model
-    let chat_models = [chat_metadata];
-
-    // create metadata for embedding model
-    let embedding_metadata = GgmlMetadataBuilder::new(
-        cli.model_name[1].clone(),
-        cli.model_alias[1].clone(),
-        cli.prompt_template[1],
-    )
-    .with_ctx_size(cli.ctx_size[1])
-    .with_batch_size(cli.batch_size[1])
-    .with_ubatch_size(cli.ubatch_size[1])
-    .with_split_mode(cli.split_mode)
-    .with_main_gpu(cli.main_gpu)
-    .with_tensor_split(cli.tensor_split)
-    .with_threads(cli.threads)
-    .enable_plugin_log(true)
-    .enable_debug_log(plugin_debug)
-    .build();
-
-    let embedding_model_info = ModelConfig {
-        name: embedding_metadata.model_name.clone(),
-        ty: "embedding".to_string(),
-        ctx_size: embedding_metadata.ctx_size,
-        batch_size: embedding_metadata.batch_size,
-        ubatch_size: embedding_metadata.ubatch_size,
-        prompt_template: embedding_metadata.prompt_template,
-        n_predict: embedding_metadata.n_predict,
-        reverse_prompt: embedding_metadata.reverse_prompt.clone(),
-        n_gpu_layers: embedding_metadata.n_gpu_layers,
-        temperature: embedding_metadata.temperature,
-        top_p: embedding_metadata.top_p,
-        repeat_penalty: embedding_metadata.repeat_penalty,
-        presence_penalty: embedding_metadata.presence_penalty,
-        frequency_penalty: embedding_metadata.frequency_penalty,
-        split_mode: embedding_metadata.split_mode.clone(),
-        main_gpu: embedding_metadata.main_gpu,
-        tensor_split: embedding_metadata.tensor_split.clone(),
+    // create the embedding routes
+    let mut embedding_routes = EmbeddingRoutes {
+        default_model: embedding_model_infos[0].name.clone(),
+        collection_models: HashMap::new(),
     };
+    for mapping in cli.embedding_collection_map.iter() {
+        let (collection_name, model) = match mapping.split_once('=') {
+            Some((collection_name, model)) if !collection_name.is_empty() && !model.is_empty() => {
+                (collection_name, model)
+            }
+            _ => {
+                let err_msg = format!(
+                    "Invalid embedding collection mapping `{}`. The mapping should be in the format of `collection=model`.",
+                    mapping
+                );
 
-    // embedding model
-    let embedding_models = [embedding_metadata];
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::ArgumentError(err_msg));
+            }
+        };
+
+        if !cli
+            .qdrant_collection_name
+            .iter()
+            .any(|name| name == collection_name)
+        {
+            let err_msg = format!(
+                "Invalid embedding collection mapping `{}`. The collection `{}` is not one of the Qdrant collections: {}.",
+                mapping,
+                collection_name,
+                cli.qdrant_collection_name.join(", ")
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+
+        let model_name = match embedding_model_infos
+            .iter()
+            .find(|model_info| model_info.name == model || model_info.alias == model)
+        {
+            Some(model_info) => model_info.name.clone(),
+            None => {
+                let err_msg = format!(
+                    "Invalid embedding collection mapping `{}`. The model `{}` is not one of the embedding models: {}.",
+                    mapping,
+                    model,
+                    embedding_model_infos
+                        .iter()
+                        .map(|model_info| model_info.name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::ArgumentError(err_msg));
+            }
+        };
+
+        if embedding_routes
+            .collection_models
+            .insert(collection_name.to_string(), model_name)
+            .is_some()
+        {
+            let err_msg = format!(
+                "Invalid embedding collection mapping `{}`. The collection `{}` is mapped more than once.",
+                mapping, collection_name
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+    }
+
+    // create the embedding prefixes
+    let embedding_prefixes = EmbeddingPrefixes {
+        query_prefix: cli.query_prefix.clone().unwrap_or_default(),
+        passage_prefix: cli.passage_prefix.clone().unwrap_or_default(),
+        collection_query_prefixes: parse_collection_prefixes(
+            &cli.collection_query_prefix,
+            "collection query prefix",
+            &cli.qdrant_collection_name,
+        )?,
+        collection_passage_prefixes: parse_collection_prefixes(
+            &cli.collection_passage_prefix,
+            "collection passage prefix",
+            &cli.qdrant_collection_name,
+        )?,
+    };
+    info!(target: "stdout", "query_prefix: {:?}, passage_prefix: {:?}", &embedding_prefixes.query_prefix, &embedding_prefixes.passage_prefix);
+    for (collection_name, prefix) in embedding_prefixes.collection_query_prefixes.iter() {
+        info!(target: "stdout", "collection_query_prefix: {} -> {:?}", collection_name, prefix);
+    }
+    for (collection_name, prefix) in embedding_prefixes.collection_passage_prefixes.iter() {
+        info!(target: "stdout", "collection_passage_prefix: {} -> {:?}", collection_name, prefix);
+    }
+    EMBEDDING_PREFIXES
+        .set(embedding_prefixes)
+        .map_err(|_| ServerError::Operation("Failed to set `EMBEDDING_PREFIXES`.".to_string()))?;
+
+    // log max_query_tokens
+    let max_query_tokens = cli
+        .max_query_tokens
+        .unwrap_or(embedding_model_infos[0].ctx_size);
+    info!(target: "stdout", "max_query_tokens: {}, query_truncation: {}", max_query_tokens, cli.query_truncation);
+    MAX_QUERY_TOKENS
+        .set((max_query_tokens, cli.query_truncation))
+        .map_err(|_| ServerError::Operation("Failed to set `MAX_QUERY_TOKENS`.".to_string()))?;
+
+    // log embedding routes
+    info!(target: "stdout", "default_embedding_model: {}", &embedding_routes.default_model);
+    for (collection_name, model_name) in embedding_routes.collection_models.iter() {
+        info!(target: "stdout", "embedding_collection_map: {} -> {}", collection_name, model_name);
+    }
+    EMBEDDING_ROUTES
+        .set(embedding_routes)
+        .map_err(|_| ServerError::Operation("Failed to set `EMBEDDING_ROUTES`.".to_string()))?;
 
     // create rag config
+    let mut embedding_model_infos = embedding_model_infos.into_iter();
     let rag_config = RagConfig {
         chat_model: chat_model_info,
-        embedding_model: embedding_model_info,
+        embedding_model: embedding_model_infos.next().unwrap(),
+        extra_embedding_models: embedding_model_infos.collect(),
         policy,
+        rag_context_message,
     };
 
     // initialize the core context
-    llama_core::init_ggml_rag_context(&chat_models[..], &embedding_models[..]).map_err(|e| {
-        let err_msg = format!("Failed to initialize the core context. {}", e);
+    let model_names: Vec<&str> = chat_models
+        .iter()
+        .chain(embedding_models.iter())
+        .map(|metadata| metadata.model_name.as_str())
+        .collect();
+    info!(target: "stdout", "Initialize the core context with the model(s): {}. This may take a while for large models.", model_names.join(", "));
+    let init_start = std::time::Instant::now();
+    let init_result = match cli.retrieval_only {
+        true => llama_core::init_ggml_embeddings_context(&embedding_models[..]),
+        false => llama_core::init_ggml_rag_context(&chat_models[..], &embedding_models[..]),
+    };
+    init_result.map_err(|e| {
+        let err_msg = format!(
+            "Failed to initialize the core context after {:.2}s. {}",
+            init_start.elapsed().as_secs_f64(),
+            e
+        );
 
         // log
         error!(target: "stdout", "{}", &err_msg);
 
         ServerError::Operation(err_msg)
     })?;
+    info!(target: "stdout", "The core context is initialized in {:.2}s.", init_start.elapsed().as_secs_f64());
+
+    // find the dimension of the embedding models, and whether they return one vector per token
+    let mut embedding_dimensions = HashMap::new();
+    for metadata in embedding_models.iter() {
+        if let Some(dimension) = probe_embedding_dimension(&metadata.model_name).await {
+            embedding_dimensions.insert(metadata.model_name.clone(), dimension);
+        }
+    }
+    EMBEDDING_DIMENSIONS
+        .set(embedding_dimensions)
+        .map_err(|_| ServerError::Operation("Failed to set `EMBEDDING_DIMENSIONS`.".to_string()))?;
+
+    // compare the tokenizers of the embedding models with the tokenizer of the token estimates
+    if let Some(threshold) = cli.tokenizer_check_threshold {
+        if !threshold.is_finite() || threshold < 0.0 {
+            let err_msg = format!(
+                "Invalid tokenizer check threshold: {}. The value should be a non-negative number.",
+                threshold
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::ArgumentError(err_msg));
+        }
+
+        info!(target: "stdout", "tokenizer_check_threshold: {}", threshold);
+        for metadata in embedding_models.iter() {
+            check_embedding_tokenizer(&metadata.model_name, threshold).await;
+        }
+    }
 
     // get the plugin version info
     let plugin_info =
@@ -551,9 +1731,90 @@ async fn main() -> Result<(), ServerError> {
     // get the environment variable `NODE_VERSION`
     // Note that this is for satisfying the requirement of `gaianet-node` project.
     let node = std::env::var("NODE_VERSION").ok();
-    if node.is_some() {
+    if let Some(node) = &node {
         // log node version
-        info!(target: "stdout", "gaianet_node_version: {}", node.as_ref().unwrap());
+        info!(target: "stdout", "gaianet_node_version: {}", node);
+    }
+
+    // log enrich_info
+    info!(target: "stdout", "enrich_info: {}", cli.enrich_info);
+
+    // fetch the collection info from Qdrant
+    let collection_info = match cli.enrich_info {
+        true => {
+            let vdb_api_key = qdrant::default_api_key();
+
+            let mut collection_info = Vec::new();
+            for qdrant_config in qdrant_config_vec.iter() {
+                let info = match qdrant::collection_info(
+                    &qdrant_config.url,
+                    vdb_api_key.as_deref(),
+                    &qdrant_config.collection_name,
+                )
+                .await
+                {
+                    Ok(info) => {
+                        info!(target: "stdout", "collection_info: {}, vector_size: {:?}, distance: {:?}, points_count: {:?}", info.collection_name, info.vector_size, info.distance, info.points_count);
+
+                        info
+                    }
+                    Err(e) => {
+                        // log
+                        warn!(target: "stdout", "Failed to fetch the info of the collection `{}`. {}", qdrant_config.collection_name, e);
+
+                        qdrant::CollectionInfo {
+                            collection_name: qdrant_config.collection_name.clone(),
+                            error: Some(e.to_string()),
+                            ..Default::default()
+                        }
+                    }
+                };
+                collection_info.push(info);
+            }
+
+            Some(collection_info)
+        }
+        false => None,
+    };
+
+    // compute the system fingerprint from the chat model config and the plugin version
+    if let Some(chat_model) = &rag_config.chat_model {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(chat_model)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        plugin_version.hash(&mut hasher);
+        let system_fingerprint = format!("fp_{:010x}", hasher.finish() & 0xff_ffff_ffff);
+
+        // log
+        info!(target: "stdout", "system_fingerprint: {}", &system_fingerprint);
+
+        SYSTEM_FINGERPRINT.set(system_fingerprint).map_err(|_| {
+            ServerError::Operation("Failed to set `SYSTEM_FINGERPRINT`.".to_string())
+        })?;
+    }
+
+    // log info_extra
+    let mut extras = HashMap::new();
+    for (key, value) in cli.info_extra.iter() {
+        info!(target: "stdout", "info_extra: {}={}", key, value);
+
+        if let Some(previous) = extras.insert(key.clone(), value.clone()) {
+            let err_msg = format!(
+                "The key `{}` of `--info-extra` is given more than once. The value `{}` overrides `{}`.",
+                key, value, previous
+            );
+
+            if cli.strict {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::ArgumentError(err_msg));
+            }
+
+            // log
+            warn!(target: "stdout", "{}", &err_msg);
+        }
     }
 
     // create server info
@@ -567,31 +1828,98 @@ async fn main() -> Result<(), ServerError> {
         },
         rag_config,
         qdrant_config: qdrant_config_vec,
-        extras: HashMap::new(),
+        collection_info,
+        extras,
     };
     SERVER_INFO
         .set(RwLock::new(server_info))
         .map_err(|_| ServerError::Operation("Failed to set `SERVER_INFO`.".to_string()))?;
 
+    // log strict
+    info!(target: "stdout", "strict: {}", cli.strict);
+
+    // log access_log_format
+    info!(target: "stdout", "access_log_format: {}", cli.access_log_format);
+
+    // log web_ui
+    match cli.no_web_ui {
+        true => info!(target: "stdout", "web_ui: disabled"),
+        false => {
+            info!(target: "stdout", "web_ui: {}", cli.web_ui.to_string_lossy());
+
+            // check if the web ui directory is ready to serve
+            if !cli.web_ui.join("index.html").is_file() {
+                let err_msg = format!(
+                    "The Web UI directory `{}` does not exist or does not contain `index.html`. The Web UI requests will fail with `404 Not Found`. Set `--web-ui` to the directory of the Web UI files, or `--no-web-ui` to serve the API endpoints only.",
+                    cli.web_ui.to_string_lossy()
+                );
+
+                if cli.strict {
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    return Err(ServerError::ArgumentError(err_msg));
+                }
+
+                // log
+                warn!(target: "stdout", "{}", &err_msg);
+            }
+        }
+    }
+
     let new_service = make_service_fn(move |conn: &AddrStream| {
         // log socket address
         info!(target: "stdout", "remote_addr: {}, local_addr: {}", conn.remote_addr().to_string(), conn.local_addr().to_string());
 
-        let web_ui = cli.web_ui.to_string_lossy().to_string();
-        let chunk_capacity = cli.chunk_capacity;
+        let remote_addr = conn.remote_addr();
+        let access_log_format = cli.access_log_format;
+        let log_format = cli.log_format;
+        let max_body_size = cli.max_body_size;
 
+        let web_ui = match cli.no_web_ui {
+            true => None,
+            false => Some(cli.web_ui.to_string_lossy().to_string()),
+        };
         async move {
             Ok::<_, Error>(service_fn(move |req| {
-                handle_request(req, chunk_capacity, web_ui.clone())
+                // the logs of the request carry its id
+                let request_id = logger::request_id(req.headers());
+                logger::REQUEST_ID.scope(
+                    request_id,
+                    handle_request(
+                        req,
+                        web_ui.clone(),
+                        remote_addr,
+                        access_log_format,
+                        log_format,
+                        max_body_size,
+                    ),
+                )
             }))
         }
     });
 
+    // log http1_keepalive
+    info!(target: "stdout", "http1_keepalive: {}", cli.http1_keepalive);
+
+    // log http2_max_concurrent_streams
+    if let Some(max) = cli.http2_max_concurrent_streams {
+        info!(target: "stdout", "http2_max_concurrent_streams: {}", max);
+    }
+
+    // log max_body_size
+    if let Some(max_body_size) = cli.max_body_size {
+        info!(target: "stdout", "max_body_size: {}", max_body_size);
+    }
+
     let tcp_listener = TcpListener::bind(addr).await.unwrap();
     info!(target: "stdout", "Listening on {}", addr);
 
+    // HTTP/1 and HTTP/2 (cleartext with prior knowledge) are both served on the same port
     let server = Server::from_tcp(tcp_listener.into_std().unwrap())
         .unwrap()
+        .http1_keepalive(cli.http1_keepalive)
+        .http2_max_concurrent_streams(cli.http2_max_concurrent_streams)
         .serve(new_service);
 
     match server.await {
@@ -602,8 +1930,11 @@ async fn main() -> Result<(), ServerError> {
 
 async fn handle_request(
     req: Request<Body>,
-    chunk_capacity: usize,
-    web_ui: String,
+    web_ui: Option<String>,
+    remote_addr: SocketAddr,
+    access_log_format: AccessLogFormat,
+    log_format: LogFormat,
+    max_body_size: Option<u64>,
 ) -> Result<Response<Body>, hyper::Error> {
     let path_str = req.uri().path();
     let path_buf = PathBuf::from(path_str);
@@ -612,31 +1943,106 @@ async fn handle_request(
     let root_path = path_iter.next().unwrap_or_default();
     let root_path = "/".to_owned() + root_path.to_str().unwrap_or_default();
 
+    // the origin of the request, used to set the CORS headers of the response
+    let origin = cors::request_origin(&req);
+
     // check if the API key is valid
-    if let Some(auth_header) = req.headers().get("authorization") {
-        if !auth_header.is_empty() {
-            let auth_header = match auth_header.to_str() {
-                Ok(auth_header) => auth_header,
-                Err(e) => {
-                    let err_msg = format!("Failed to get authorization header: {}", e);
-                    return Ok(error::unauthorized(err_msg));
-                }
-            };
+    let mut collection_scope = None;
+    let auth_header = req
+        .headers()
+        .get("authorization")
+        .filter(|auth_header| !auth_header.is_empty());
+    // a request without a key would bypass the collection scopes, so it is rejected if any key is scoped. The CORS preflight requests carry no key
+    if auth_header.is_none() && req.method() != hyper::http::Method::OPTIONS {
+        if let Some(api_keys) = LLAMA_API_KEYS.get() {
+            if api_keys.scoped_len() > 0 {
+                let err_msg = "Missing API key. The `Authorization` header is required.";
+
+                // log
+                warn!(target: "stdout", "{}", err_msg);
+
+                let mut response = error::unauthorized(err_msg);
+                cors::apply_cors_headers(&mut response, origin.as_deref());
+                return Ok(response);
+            }
+        }
+    }
+    if let Some(auth_header) = auth_header {
+        let auth_header = match auth_header.to_str() {
+            Ok(auth_header) => auth_header,
+            Err(e) => {
+                let err_msg = format!("Failed to get authorization header: {}", e);
+                let mut response = error::unauthorized(err_msg);
+                cors::apply_cors_headers(&mut response, origin.as_deref());
+                return Ok(response);
+            }
+        };
+
+        let api_key = auth_header.split(" ").nth(1).unwrap_or_default();
 
-            let api_key = auth_header.split(" ").nth(1).unwrap_or_default();
-            info!(target: "stdout", "API Key: {}", api_key);
+        // the key is logged by its label or fingerprint, never as is
+        match LLAMA_API_KEYS.get() {
+            Some(api_keys) => match api_keys.label_of(api_key) {
+                Some(label) => {
+                    info!(target: "stdout", "API key: {}", label);
 
-            if let Some(stored_api_key) = LLAMA_API_KEY.get() {
-                if api_key != stored_api_key {
+                    collection_scope = api_keys.scope_of(api_key).cloned();
+                }
+                None => {
                     let err_msg = "Invalid API key.";
-                    return Ok(error::unauthorized(err_msg));
+
+                    // log
+                    warn!(target: "stdout", "{} Fingerprint: {}", err_msg, auth::fingerprint(api_key));
+
+                    let mut response = error::unauthorized(err_msg);
+                    cors::apply_cors_headers(&mut response, origin.as_deref());
+                    return Ok(response);
                 }
-            }
+            },
+            None => info!(target: "stdout", "API key: {}", auth::fingerprint(api_key)),
         }
     }
 
+    // the request fields of the access log line, collected before the request is consumed
+    let access_log = match access_log_format {
+        AccessLogFormat::Default => None,
+        AccessLogFormat::Combined => {
+            let header_str = |name: header::HeaderName| {
+                req.headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string())
+            };
+            let request_target = req
+                .uri()
+                .path_and_query()
+                .map(|path_and_query| path_and_query.as_str())
+                .unwrap_or(path_str);
+
+            Some((
+                chrono::Utc::now(),
+                format!("{} {} {:?}", req.method(), request_target, req.version()),
+                header_str(header::REFERER),
+                header_str(header::USER_AGENT),
+            ))
+        }
+    };
+
     // log request
-    {
+    if access_log_format == AccessLogFormat::Default && log_format == LogFormat::Json {
+        let size: u64 = req
+            .headers()
+            .get("content-length")
+            .and_then(|content_length| content_length.to_str().ok())
+            .and_then(|content_length| content_length.parse().ok())
+            .unwrap_or_default();
+
+        info!(target: "stdout", method = req.method().as_str(), path = req.uri().path(), http_version = format!("{:?}", req.version()), content_length = size; "request");
+    } else if access_log_format == AccessLogFormat::Default {
+        if let Ok(request_id) = logger::REQUEST_ID.try_with(|request_id| request_id.clone()) {
+            info!(target: "stdout", "request_id: {}", request_id);
+        }
+
         let method = hyper::http::Method::as_str(req.method()).to_string();
         let path = req.uri().path().to_string();
         let version = format!("{:?}", req.version());
@@ -654,14 +2060,63 @@ async fn handle_request(
         }
     }
 
-    let response = match root_path.as_str() {
-        "/echo" => Response::new(Body::from("echo test")),
-        "/v1" => backend::handle_llama_request(req, chunk_capacity).await,
-        _ => static_response(path_str, web_ui),
+    let mut response = match cors::preflight_response(&req) {
+        Some(response) => response,
+        None => match root_path.as_str() {
+            "/echo" => Response::new(Body::from("echo test")),
+            "/v1" => {
+                // the collection scope of the key, checked by the handlers of the requests that target collections
+                let mut req = req;
+                if let Some(collection_scope) = collection_scope {
+                    info!(target: "stdout", "collection scope: {}", &collection_scope);
+
+                    req.extensions_mut().insert(collection_scope);
+                }
+
+                // reject the request bodies larger than `--max-body-size`
+                match max_body_size {
+                    Some(max_body_size) => match limit_body_size(req, max_body_size).await {
+                        Ok(req) => backend::handle_llama_request(req).await,
+                        Err(response) => response,
+                    },
+                    None => backend::handle_llama_request(req).await,
+                }
+            }
+            _ => match web_ui {
+                Some(web_ui) => static_response(path_str, web_ui),
+                None => error::not_found_json(path_str),
+            },
+        },
     };
+    cors::apply_cors_headers(&mut response, origin.as_deref());
+
+    // return the request id, so that the client can find the logs of the request
+    if let Ok(Ok(request_id)) = logger::REQUEST_ID.try_with(|request_id| request_id.parse()) {
+        response.headers_mut().insert("x-request-id", request_id);
+    }
 
     // log response
-    {
+    if let Some((time, request_line, referer, user_agent)) = access_log {
+        println!(
+            "{}",
+            combined_log_line(
+                &remote_addr.ip().to_string(),
+                time,
+                &request_line,
+                response.status().as_u16(),
+                response.body().size_hint().exact(),
+                referer.as_deref(),
+                user_agent.as_deref(),
+            )
+        );
+    } else if log_format == LogFormat::Json {
+        let status = response.status().as_u16();
+        let body_size = response.body().size_hint().lower();
+        match status < 400 {
+            true => info!(target: "stdout", status = status, body_size = body_size; "response"),
+            false => error!(target: "stdout", status = status, body_size = body_size; "response"),
+        }
+    } else {
         let status_code = response.status();
         if status_code.as_u16() < 400 {
             // log response
@@ -692,6 +2147,44 @@ async fn handle_request(
     Ok(response)
 }
 
+/// Read the body of the request up to `max_body_size` bytes, and return the request with the buffered body, or `413 Payload Too Large` if the body is larger.
+///
+/// A `content-length` above the limit is rejected before the body is read. A body without `content-length`, such as a chunked body, is rejected as soon as the bytes read exceed the limit, so at most `max_body_size` bytes are buffered.
+async fn limit_body_size(
+    req: Request<Body>,
+    max_body_size: u64,
+) -> Result<Request<Body>, Response<Body>> {
+    let too_large = || {
+        error::payload_too_large(format!(
+            "The request body is larger than the limit of {} bytes.",
+            max_body_size
+        ))
+    };
+
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+    if content_length.is_some_and(|content_length| content_length > max_body_size) {
+        return Err(too_large());
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut body_bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk
+            .map_err(|e| error::bad_request(format!("Failed to read the request body. {}", e)))?;
+
+        if (body_bytes.len() + chunk.len()) as u64 > max_body_size {
+            return Err(too_large());
+        }
+        body_bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(body_bytes)))
+}
+
 fn static_response(path_str: &str, root: String) -> Response<Body> {
     let path = match path_str {
         "/" => "/index.html",
@@ -700,13 +2193,31 @@ fn static_response(path_str: &str, root: String) -> Response<Body> {
 
     let mime = mime_guess::from_path(path);
 
-    match std::fs::read(format!("{root}/{path}")) {
-        Ok(content) => Response::builder()
+    // resolve the path and check that it stays within the root, so that `..` segments can't escape it
+    let file_path = match std::fs::canonicalize(format!("{root}/{path}")) {
+        Ok(file_path) => {
+            let within_root = std::fs::canonicalize(&root)
+                .map(|root| file_path.starts_with(root))
+                .unwrap_or(false);
+            if !within_root {
+                return error::forbidden(format!(
+                    "The path `{}` is outside of the Web UI root.",
+                    path_str
+                ));
+            }
+
+            Some(file_path)
+        }
+        Err(_) => None,
+    };
+
+    match file_path.map(std::fs::read) {
+        Some(Ok(content)) => Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, mime.first_or_text_plain().to_string())
             .body(Body::from(content))
             .unwrap(),
-        Err(_) => {
+        _ => {
             let body = Body::from(std::fs::read(format!("{root}/404.html")).unwrap_or_default());
             Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -738,6 +2249,8 @@ impl fmt::Display for QdrantConfig {
 pub(crate) struct ModelConfig {
     // model name
     name: String,
+    // model alias
+    alias: String,
     // type: chat or embedding
     #[serde(rename = "type")]
     ty: String,
@@ -771,6 +2284,8 @@ pub(crate) struct ServerInfo {
     #[serde(flatten)]
     rag_config: RagConfig,
     qdrant_config: Vec<QdrantConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collection_info: Option<Vec<qdrant::CollectionInfo>>,
     extras: HashMap<String, String>,
 }
 
@@ -786,13 +2301,270 @@ pub(crate) struct ApiServer {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct RagConfig {
-    pub chat_model: ModelConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_model: Option<ModelConfig>,
     pub embedding_model: ModelConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_embedding_models: Vec<ModelConfig>,
     #[serde(rename = "rag_policy")]
     pub policy: MergeRagContextPolicy,
+    pub rag_context_message: RagContextMessage,
 }
 
 #[derive(Debug, Clone, Default)]
 pub(crate) struct KeywordSearchConfig {
     pub url: String,
 }
+
+#[derive(Debug, Clone)]
+pub(crate) struct ConfidenceConfig {
+    pub strong_score: f32,
+    pub strong_chunks: usize,
+}
+impl Default for ConfidenceConfig {
+    fn default() -> Self {
+        Self {
+            strong_score: 0.6,
+            strong_chunks: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmbeddingRoutes {
+    pub default_model: String,
+    pub collection_models: HashMap<String, String>,
+}
+impl EmbeddingRoutes {
+    /// The embedding model of the collection, or the default embedding model if the collection is not mapped.
+    pub(crate) fn model_for(&self, collection_name: &str) -> &str {
+        self.collection_models
+            .get(collection_name)
+            .unwrap_or(&self.default_model)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EmbeddingPrefixes {
+    pub query_prefix: String,
+    pub passage_prefix: String,
+    pub collection_query_prefixes: HashMap<String, String>,
+    pub collection_passage_prefixes: HashMap<String, String>,
+}
+impl EmbeddingPrefixes {
+    /// The query prefix of the collection, or the global query prefix if the collection has none.
+    pub(crate) fn query_prefix_for(&self, collection_name: &str) -> &str {
+        self.collection_query_prefixes
+            .get(collection_name)
+            .unwrap_or(&self.query_prefix)
+    }
+
+    /// The passage prefix of the collection, or the global passage prefix if the collection has none.
+    pub(crate) fn passage_prefix_for(&self, collection_name: &str) -> &str {
+        self.collection_passage_prefixes
+            .get(collection_name)
+            .unwrap_or(&self.passage_prefix)
+    }
+}
+
+/// The probe text of `--tokenizer-check-threshold`. It mixes prose, code, numbers and non-Latin text, on which the tokenizers differ the most.
+const TOKENIZER_PROBE: &str = "Retrieval-augmented generation grounds the answers of a language model in documents fetched at query time. fn main() { let v: Vec<u64> = (1..=42).map(|x| x * 31_337).collect(); println!(\"{:?}\", v); } Invoice #2024-0815: 3,141.59 EUR due 2024-09-30. Die Qualität der Antworten hängt vom Kontext ab. 検索拡張生成は文書に基づいて回答します。 Поиск по документам улучшает ответы.";
+
+/// Embed the probe text with the embedding model, and warn if the number of tokens counted by the model differs from the count of the `cl100k_base` tokenizer by more than the threshold, as a fraction of the `cl100k_base` count. A failed check is logged and doesn't stop the server.
+async fn check_embedding_tokenizer(model_name: &str, threshold: f64) {
+    let estimated_tokens = match tiktoken_rs::cl100k_base() {
+        Ok(tokenizer) => tokenizer.encode_with_special_tokens(TOKENIZER_PROBE).len() as u64,
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to check the tokenizer of the embedding model `{}`. Failed to create the tokenizer for counting tokens. {}", model_name, e);
+
+            return;
+        }
+    };
+
+    let embedding_request = EmbeddingRequest {
+        model: Some(model_name.to_string()),
+        input: InputText::String(TOKENIZER_PROBE.to_string()),
+        encoding_format: None,
+        user: None,
+        vdb_server_url: None,
+        vdb_collection_name: None,
+        vdb_api_key: None,
+    };
+    let model_tokens = match llama_core::embeddings::embeddings(&embedding_request).await {
+        Ok(embedding_response) => embedding_response.usage.prompt_tokens,
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to check the tokenizer of the embedding model `{}`. {}", model_name, e);
+
+            return;
+        }
+    };
+
+    let deviation = (model_tokens as f64 - estimated_tokens as f64) / estimated_tokens as f64;
+    info!(target: "stdout", "tokenizer_check: the embedding model `{}` counts {} tokens in the probe text, and `cl100k_base` counts {} ({:+.0}%)", model_name, model_tokens, estimated_tokens, deviation * 100.0);
+
+    if deviation.abs() > threshold {
+        // log
+        warn!(target: "stdout", "The tokenizer of the embedding model `{}` counts {:+.0}% tokens compared with the `cl100k_base` tokenizer of the token estimates, more than the threshold of {:.0}%. The chunk sizes of `--chunk-capacity`, the query limit of `--max-query-tokens` and the embedding input limits are estimated with `cl100k_base`, so they are approximate for this model; for example, a chunk of 100 estimated tokens takes about {:.0} tokens of the model.", model_name, deviation * 100.0, threshold * 100.0, 100.0 * (1.0 + deviation));
+    }
+}
+
+/// The probe texts of the embedding dimension check, of different token counts.
+const DIMENSION_PROBES: [&str; 2] = [
+    "Hello",
+    "The dimension of the embedding model is found by embedding two texts of different lengths.",
+];
+
+/// Find the dimension of the single vectors of the embedding model by embedding two probe texts of different token counts. A model returning one vector per input returns the same length for both texts. A model returning one vector per token returns the vectors concatenated, so the lengths divided by the token counts give the dimension. Returns `None` if the probe fails or the output is not recognized, in which case the embeddings of the model are not pooled.
+async fn probe_embedding_dimension(model_name: &str) -> Option<usize> {
+    let mut shapes = Vec::with_capacity(DIMENSION_PROBES.len());
+    for probe in DIMENSION_PROBES {
+        let embedding_request = EmbeddingRequest {
+            model: Some(model_name.to_string()),
+            input: InputText::String(probe.to_string()),
+            encoding_format: None,
+            user: None,
+            vdb_server_url: None,
+            vdb_collection_name: None,
+            vdb_api_key: None,
+        };
+        match llama_core::embeddings::embeddings(&embedding_request).await {
+            Ok(embedding_response) => match embedding_response.data.first() {
+                Some(embedding_object) => shapes.push((
+                    embedding_object.embedding.len(),
+                    embedding_response.usage.prompt_tokens as usize,
+                )),
+                None => {
+                    // log
+                    warn!(target: "stdout", "Failed to find the dimension of the embedding model `{}`. No embedding is returned for the probe text.", model_name);
+
+                    return None;
+                }
+            },
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to find the dimension of the embedding model `{}`. {}", model_name, e);
+
+                return None;
+            }
+        }
+    }
+
+    let (short_len, short_tokens) = shapes[0];
+    let (long_len, long_tokens) = shapes[1];
+    if short_len == long_len {
+        info!(target: "stdout", "embedding_dimension: the embedding model `{}` returns one vector of {} dimensions per input", model_name, short_len);
+
+        return Some(short_len);
+    }
+
+    let per_token = |len: usize, tokens: usize| {
+        (tokens > 0 && len.is_multiple_of(tokens)).then(|| len / tokens)
+    };
+    match (
+        per_token(short_len, short_tokens),
+        per_token(long_len, long_tokens),
+    ) {
+        (Some(short_dimension), Some(long_dimension)) if short_dimension == long_dimension => {
+            // log
+            warn!(target: "stdout", "The embedding model `{}` returns one vector of {} dimensions per token. The vectors of each input are pooled into one vector with the `{}` pooling of `--multivector-pooling`, since the Qdrant collections store one vector per point.", model_name, short_dimension, MULTIVECTOR_POOLING.get().copied().unwrap_or_default());
+
+            Some(short_dimension)
+        }
+        _ => {
+            // log
+            warn!(target: "stdout", "The embedding model `{}` returns embeddings of different lengths, {} and {}, for the probe texts of {} and {} tokens, which are neither single vectors nor one vector per token. The embeddings of the model are not pooled.", model_name, short_len, long_len, short_tokens, long_tokens);
+
+            None
+        }
+    }
+}
+
+/// Parse the `collection=prefix` values of `--collection-query-prefix` or `--collection-passage-prefix`. The prefix may be empty to disable the global prefix for the collection.
+fn parse_collection_prefixes(
+    values: &[String],
+    kind: &str,
+    collection_names: &[String],
+) -> Result<HashMap<String, String>, ServerError> {
+    let mut prefixes = HashMap::new();
+    for value in values {
+        let err_msg = match value.split_once('=') {
+            Some(("", _)) | None => format!(
+                "Invalid {} `{}`. The value should be in the format of `collection=prefix`.",
+                kind, value
+            ),
+            Some((collection_name, _))
+                if !collection_names.iter().any(|name| name == collection_name) =>
+            {
+                format!(
+                    "Invalid {} `{}`. The collection `{}` is not one of the Qdrant collections: {}.",
+                    kind,
+                    value,
+                    collection_name,
+                    collection_names.join(", ")
+                )
+            }
+            Some((collection_name, prefix)) => {
+                match prefixes.insert(collection_name.to_string(), prefix.to_string()) {
+                    None => continue,
+                    Some(_) => format!(
+                        "Invalid {} `{}`. The collection `{}` is given more than once.",
+                        kind, value, collection_name
+                    ),
+                }
+            }
+        };
+
+        // log
+        error!(target: "stdout", "{}", &err_msg);
+
+        return Err(ServerError::ArgumentError(err_msg));
+    }
+
+    Ok(prefixes)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ChunkCapacity {
+    pub default_capacity: usize,
+    pub collection_capacities: HashMap<String, usize>,
+}
+impl ChunkCapacity {
+    /// The chunk capacity of the collection, or the default chunk capacity if the collection has none.
+    pub(crate) fn capacity_for(&self, collection_name: &str) -> usize {
+        self.collection_capacities
+            .get(collection_name)
+            .copied()
+            .unwrap_or(self.default_capacity)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RerankConfig {
+    pub url: String,
+    pub model: Option<String>,
+    /// The number of candidates fetched per query. Defaults to 3 times the limit of the collection
+    pub candidates: Option<u64>,
+    /// The number of reranked points kept from each collection. Defaults to the limit of the collection
+    pub top_n: Option<u64>,
+}
+impl RerankConfig {
+    /// The number of candidates fetched per query from a collection with the given limit.
+    pub(crate) fn candidates_for(&self, limit: u64) -> u64 {
+        self.candidates
+            .unwrap_or(limit.saturating_mul(3))
+            .max(limit)
+    }
+
+    /// The number of reranked points kept from a collection with the given limit.
+    pub(crate) fn top_n_for(&self, limit: u64) -> u64 {
+        self.top_n.unwrap_or(limit)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RecencyConfig {
+    pub half_life_days: f64,
+    pub weight: f64,
+}
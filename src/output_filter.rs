@@ -0,0 +1,246 @@
+use futures::{stream, Stream, StreamExt};
+use regex::Regex;
+use serde_json::Value;
+
+/// Number of trailing characters held back in stream mode, so that the patterns spanning chunk boundaries can be matched.
+const LOOKBACK: usize = 64;
+
+/// A regex substitution applied to the completion text, configured as `regex=>replacement`.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputFilter {
+    regex: Regex,
+    replacement: String,
+}
+impl std::str::FromStr for OutputFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, replacement) = s.split_once("=>").ok_or_else(|| {
+            format!(
+                "Invalid output filter: {}. The filter should be in the format of `regex=>replacement`.",
+                s
+            )
+        })?;
+
+        let regex = Regex::new(pattern)
+            .map_err(|e| format!("Invalid regex in the output filter `{}`. {}", s, e))?;
+
+        Ok(OutputFilter {
+            regex,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+impl std::fmt::Display for OutputFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}=>{}", self.regex.as_str(), self.replacement)
+    }
+}
+
+/// Apply the filters to the text in order.
+pub(crate) fn apply_output_filters(text: &str, filters: &[OutputFilter]) -> String {
+    let mut text = text.to_string();
+    for filter in filters {
+        text = filter
+            .regex
+            .replace_all(&text, filter.replacement.as_str())
+            .into_owned();
+    }
+    text
+}
+
+/// Apply the filters to the content of the chunks in a chat completion stream.
+///
+/// The last `LOOKBACK` characters of the content are held back until more content arrives, or until a chunk without content, such as the usage chunk or `data: [DONE]`, is received.
+pub(crate) fn filter_chat_stream<S>(
+    chat_stream: S,
+    filters: &'static [OutputFilter],
+) -> impl Stream<Item = Result<String, String>>
+where
+    S: Stream<Item = Result<String, String>>,
+{
+    let mut state = StreamFilterState::default();
+
+    chat_stream
+        .map(move |item| stream::iter(state.next(item, filters)))
+        .flatten()
+}
+
+#[derive(Debug, Default)]
+struct StreamFilterState {
+    // content that is received but not sent yet
+    pending: String,
+    // the last content chunk, used as the template of the chunk flushing the pending content
+    template: Option<Value>,
+}
+impl StreamFilterState {
+    fn next(
+        &mut self,
+        item: Result<String, String>,
+        filters: &[OutputFilter],
+    ) -> Vec<Result<String, String>> {
+        let data = match &item {
+            Ok(data) => data,
+            Err(_) => return self.flush(filters).into_iter().chain([item]).collect(),
+        };
+
+        let mut chunk = match parse_content_chunk(data) {
+            Some(chunk) => chunk,
+            None => return self.flush(filters).into_iter().chain([item]).collect(),
+        };
+
+        let content = chunk["choices"][0]["delta"]["content"]
+            .as_str()
+            .unwrap_or_default();
+        self.pending.push_str(content);
+
+        // send the content before the lookback window, unless a match spans the window boundary
+        let mut split = match self.pending.char_indices().rev().nth(LOOKBACK - 1) {
+            Some((idx, _)) => idx,
+            None => 0,
+        };
+        for filter in filters {
+            for m in filter.regex.find_iter(&self.pending) {
+                if m.start() < split && split < m.end() {
+                    split = m.start();
+                }
+            }
+        }
+        let output = apply_output_filters(&self.pending[..split], filters);
+        self.pending.drain(..split);
+
+        chunk["choices"][0]["delta"]["content"] = Value::String(output);
+        self.template = Some(chunk.clone());
+
+        vec![Ok(format!("data: {}\n\n", chunk))]
+    }
+
+    /// Send the pending content in a chunk cloned from the last content chunk.
+    fn flush(&mut self, filters: &[OutputFilter]) -> Option<Result<String, String>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let output = apply_output_filters(&self.pending, filters);
+        self.pending.clear();
+
+        let mut chunk = self.template.clone()?;
+        chunk["choices"][0]["delta"]["content"] = Value::String(output);
+
+        Some(Ok(format!("data: {}\n\n", chunk)))
+    }
+}
+
+/// The byte offset of the first occurrence of any of the stop sequences in the text.
+pub(crate) fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+/// End a chat completion stream at the first stop sequence of the request, which is removed from the content.
+///
+/// The content that could be the start of a stop sequence is held back until more content arrives. At a stop sequence, the content before it is sent in a chunk with the `stop` finish reason, followed by `data: [DONE]`, and the chat stream is dropped, which ends the generation.
+pub(crate) fn stop_chat_stream<S>(
+    chat_stream: S,
+    stop: Vec<String>,
+) -> impl Stream<Item = Result<String, String>>
+where
+    S: Stream<Item = Result<String, String>>,
+{
+    let state = StreamStopState {
+        hold: stop.iter().map(|stop| stop.len()).max().unwrap_or(1) - 1,
+        stop,
+        ..Default::default()
+    };
+
+    stream::unfold(
+        (Box::pin(chat_stream), state),
+        |(mut chat_stream, mut state)| async move {
+            if state.stopped {
+                return None;
+            }
+            let item = chat_stream.next().await?;
+            let items = state.next(item);
+
+            Some((stream::iter(items), (chat_stream, state)))
+        },
+    )
+    .flatten()
+}
+
+#[derive(Debug, Default)]
+struct StreamStopState {
+    stop: Vec<String>,
+    // the number of trailing bytes held back, one less than the longest stop sequence
+    hold: usize,
+    // content that is received but not sent yet
+    pending: String,
+    // the last content chunk, used as the template of the chunk flushing the pending content
+    template: Option<Value>,
+    stopped: bool,
+}
+impl StreamStopState {
+    fn next(&mut self, item: Result<String, String>) -> Vec<Result<String, String>> {
+        let data = match &item {
+            Ok(data) => data,
+            Err(_) => return self.flush().into_iter().chain([item]).collect(),
+        };
+
+        let mut chunk = match parse_content_chunk(data) {
+            Some(chunk) => chunk,
+            None => return self.flush().into_iter().chain([item]).collect(),
+        };
+
+        let content = chunk["choices"][0]["delta"]["content"]
+            .as_str()
+            .unwrap_or_default();
+        self.pending.push_str(content);
+
+        if let Some(idx) = find_stop(&self.pending, &self.stop) {
+            chunk["choices"][0]["delta"]["content"] = Value::String(self.pending[..idx].into());
+            chunk["choices"][0]["finish_reason"] = Value::from("stop");
+            self.pending.clear();
+            self.stopped = true;
+
+            return vec![
+                Ok(format!("data: {}\n\n", chunk)),
+                Ok("data: [DONE]\n\n".to_string()),
+            ];
+        }
+
+        // send the content before the held back bytes
+        let mut split = self.pending.len().saturating_sub(self.hold);
+        while !self.pending.is_char_boundary(split) {
+            split -= 1;
+        }
+        let output: String = self.pending.drain(..split).collect();
+
+        chunk["choices"][0]["delta"]["content"] = Value::String(output);
+        self.template = Some(chunk.clone());
+
+        vec![Ok(format!("data: {}\n\n", chunk))]
+    }
+
+    /// Send the pending content in a chunk cloned from the last content chunk.
+    fn flush(&mut self) -> Option<Result<String, String>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let mut chunk = self.template.clone()?;
+        chunk["choices"][0]["delta"]["content"] = Value::String(std::mem::take(&mut self.pending));
+
+        Some(Ok(format!("data: {}\n\n", chunk)))
+    }
+}
+
+/// Parse a `data: {...}` event that carries a chat completion chunk with content.
+fn parse_content_chunk(data: &str) -> Option<Value> {
+    let json = data.trim().strip_prefix("data:")?.trim();
+    let chunk: Value = serde_json::from_str(json).ok()?;
+
+    match chunk["choices"][0]["delta"]["content"].is_string() {
+        true => Some(chunk),
+        false => None,
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::Path;
+
+/// A linear projection that maps the embeddings of the model to the vector size of an existing collection.
+///
+/// The matrix is loaded from a JSON file holding an array of `output_dim` rows of `input_dim` numbers each, so that the projected vector is `matrix * embedding`.
+#[derive(Debug, Clone)]
+pub(crate) struct EmbeddingProjection {
+    matrix: Vec<Vec<f32>>,
+    input_dim: usize,
+}
+impl EmbeddingProjection {
+    /// Load the projection matrix from the JSON file, and check that it is a non-empty rectangular matrix of finite numbers.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            format!(
+                "Failed to read the embedding projection file `{}`. {}",
+                path.display(),
+                e
+            )
+        })?;
+        let matrix: Vec<Vec<f32>> = serde_json::from_str(&content).map_err(|e| {
+            format!(
+                "Invalid embedding projection file `{}`. The file should be a JSON array of rows of numbers. {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let input_dim = match matrix.first() {
+            Some(row) if !row.is_empty() => row.len(),
+            _ => {
+                return Err(format!(
+                    "Invalid embedding projection file `{}`. The matrix is empty.",
+                    path.display()
+                ))
+            }
+        };
+        if let Some(idx) = matrix.iter().position(|row| row.len() != input_dim) {
+            return Err(format!(
+                "Invalid embedding projection file `{}`. Row {} has {} columns, while row 0 has {}.",
+                path.display(),
+                idx,
+                matrix[idx].len(),
+                input_dim
+            ));
+        }
+        if matrix.iter().flatten().any(|x| !x.is_finite()) {
+            return Err(format!(
+                "Invalid embedding projection file `{}`. The matrix contains non-finite numbers.",
+                path.display()
+            ));
+        }
+
+        Ok(Self { matrix, input_dim })
+    }
+
+    /// The dimension of the embeddings of the model.
+    pub(crate) fn input_dim(&self) -> usize {
+        self.input_dim
+    }
+
+    /// The vector size of the collection.
+    pub(crate) fn output_dim(&self) -> usize {
+        self.matrix.len()
+    }
+
+    /// Project the embedding to the vector size of the collection.
+    pub(crate) fn project(&self, embedding: &[f32]) -> Result<Vec<f32>, String> {
+        if embedding.len() != self.input_dim {
+            return Err(format!(
+                "The embedding projection expects embeddings of dimension {}, but the embedding model returns {}.",
+                self.input_dim,
+                embedding.len()
+            ));
+        }
+
+        Ok(self
+            .matrix
+            .iter()
+            .map(|row| row.iter().zip(embedding).map(|(a, b)| a * b).sum())
+            .collect())
+    }
+}
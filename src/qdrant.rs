@@ -0,0 +1,504 @@
+use crate::{error::ServerError, QdrantConfig, QDRANT_API_KEY, QDRANT_RETRY};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::time::Duration;
+
+/// A point to be upserted into a Qdrant collection.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Point {
+    pub(crate) id: Value,
+    pub(crate) vector: Vec<f32>,
+    pub(crate) payload: Map<String, Value>,
+}
+
+/// A point returned by a Qdrant similarity search.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ScoredPoint {
+    /// The id of the point, either an unsigned integer or a UUID string
+    #[serde(default)]
+    pub(crate) id: Value,
+    pub(crate) score: f32,
+    #[serde(default)]
+    pub(crate) payload: Option<Map<String, Value>>,
+}
+impl ScoredPoint {
+    /// Returns the `source` field of the payload, which holds the chunk text.
+    pub(crate) fn source(&self) -> Option<&str> {
+        self.payload
+            .as_ref()
+            .and_then(|payload| payload.get("source"))
+            .and_then(Value::as_str)
+    }
+
+    /// Returns the `timestamp` field of the payload in Unix seconds. The field is either a number of Unix seconds or an RFC 3339 string.
+    pub(crate) fn timestamp(&self) -> Option<f64> {
+        match self.payload.as_ref()?.get("timestamp")? {
+            Value::Number(seconds) => seconds.as_f64(),
+            Value::String(datetime) => chrono::DateTime::parse_from_rfc3339(datetime)
+                .ok()
+                .map(|datetime| datetime.timestamp_millis() as f64 / 1000.0),
+            _ => None,
+        }
+    }
+}
+
+/// The configuration and size of a Qdrant collection, as reported by Qdrant.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct CollectionInfo {
+    pub(crate) collection_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) vector_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) distance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) points_count: Option<u64>,
+    /// The error occurred while fetching the collection info
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/// The API key of the Qdrant instance, used if the request doesn't carry its own `vdb_api_key`: `--qdrant-api-key`, the `QDRANT_API_KEY` environment variable, or the `VDB_API_KEY` environment variable, in that order. Returns `None` if none is set.
+pub(crate) fn default_api_key() -> Option<String> {
+    QDRANT_API_KEY
+        .get()
+        .cloned()
+        .or_else(|| std::env::var("VDB_API_KEY").ok())
+}
+
+fn client_request(
+    method: reqwest::Method,
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+) -> reqwest::RequestBuilder {
+    let mut builder = reqwest::Client::new()
+        .request(method, url.as_ref())
+        .header("Content-Type", "application/json");
+
+    if let Some(api_key) = api_key {
+        if !api_key.is_empty() {
+            builder = builder.header("api-key", api_key);
+        }
+    }
+
+    builder
+}
+
+// Maximum number of characters of the raw response body included in error messages
+const MAX_SNIPPET_LEN: usize = 256;
+
+/// Truncate the raw response body for error messages.
+fn snippet(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(MAX_SNIPPET_LEN) {
+        Some((idx, _)) => format!("{}...", &body[..idx]),
+        None => body.to_string(),
+    }
+}
+
+/// Send the request to Qdrant. If `--qdrant-max-retries` is set, the request is retried with exponential backoff on connection errors and 5xx statuses.
+async fn send(
+    builder: reqwest::RequestBuilder,
+    collection_name: &str,
+) -> Result<Value, ServerError> {
+    let (max_retries, initial_backoff) = QDRANT_RETRY.get().copied().unwrap_or((0, Duration::ZERO));
+
+    let mut builder = builder;
+    let mut attempt = 0;
+    loop {
+        // the request is cloned before it is sent, so that it can be sent again. The bodies of the Qdrant requests are JSON, so they can always be cloned
+        let retry_builder = match attempt < max_retries {
+            true => builder.try_clone(),
+            false => None,
+        };
+
+        let err_msg = match send_once(builder, collection_name).await {
+            Ok(value) => return Ok(value),
+            Err((err_msg, true)) => err_msg,
+            Err((err_msg, false)) => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::Operation(err_msg));
+            }
+        };
+
+        builder = match retry_builder {
+            Some(retry_builder) => retry_builder,
+            None => {
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::Operation(err_msg));
+            }
+        };
+
+        let backoff = retry_backoff(initial_backoff, attempt);
+        attempt += 1;
+
+        // log
+        warn!(target: "stdout", "{} Retry {} of {} in {} ms.", err_msg, attempt, max_retries, backoff.as_millis());
+
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// The backoff before the retry following the given attempt: the initial backoff doubled at each attempt, minus a random jitter of up to half of it, so that the clients failing at once don't retry at once.
+fn retry_backoff(initial_backoff: Duration, attempt: u64) -> Duration {
+    let backoff = initial_backoff.saturating_mul(2u32.saturating_pow(attempt.min(16) as u32));
+    let max_jitter = backoff.as_millis() as u64 / 2;
+    let jitter = match max_jitter {
+        0 => 0,
+        _ => (uuid::Uuid::new_v4().as_u128() as u64) % (max_jitter + 1),
+    };
+
+    backoff.saturating_sub(Duration::from_millis(jitter))
+}
+
+/// Send the request to Qdrant once. On failure, returns the error message and whether the request may succeed if retried, that is, if it failed to reach Qdrant or Qdrant responded with a 5xx status.
+async fn send_once(
+    builder: reqwest::RequestBuilder,
+    collection_name: &str,
+) -> Result<Value, (String, bool)> {
+    let response = builder.send().await.map_err(|e| {
+        let err_msg = format!(
+            "Failed to send request to Qdrant for the collection `{}`. {}",
+            collection_name, e
+        );
+
+        // an invalid request fails the same way again
+        (err_msg, !e.is_builder())
+    })?;
+
+    let status = response.status();
+    let body = response.text().await.map_err(|e| {
+        let err_msg = format!(
+            "Failed to read the response from Qdrant for the collection `{}`. {}",
+            collection_name, e
+        );
+
+        (err_msg, false)
+    })?;
+
+    if !status.is_success() {
+        let err_msg = format!(
+            "Qdrant responded with status {} for the collection `{}`. Response: {}",
+            status.as_u16(),
+            collection_name,
+            snippet(&body)
+        );
+
+        return Err((err_msg, status.is_server_error()));
+    }
+
+    serde_json::from_str(&body).map_err(|e| {
+        let err_msg = format!(
+            "Malformed response from Qdrant for the collection `{}`: {}. Response: {}",
+            collection_name,
+            e,
+            snippet(&body)
+        );
+
+        (err_msg, false)
+    })
+}
+
+/// Check if the collection exists in the Qdrant instance.
+pub(crate) async fn collection_exists(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+) -> Result<bool, ServerError> {
+    let url = format!(
+        "{}/collections/{}/exists",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+
+    let value = send(
+        client_request(reqwest::Method::GET, url, api_key),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    Ok(value
+        .get("result")
+        .and_then(|result| result.get("exists"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false))
+}
+
+/// Get the number of points in the collection.
+pub(crate) async fn points_count(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+) -> Result<u64, ServerError> {
+    let url = format!(
+        "{}/collections/{}",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+
+    let value = send(
+        client_request(reqwest::Method::GET, url, api_key),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    match value
+        .get("result")
+        .and_then(|result| result.get("points_count"))
+        .and_then(Value::as_u64)
+    {
+        Some(count) => Ok(count),
+        None => {
+            let err_msg = format!(
+                "Malformed collection info from Qdrant for the collection `{}`: missing field `points_count`. Response: {}",
+                collection_name.as_ref(),
+                snippet(&value.to_string())
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Get the vector configuration and the number of points of the collection. The vector size and distance are only available for collections with a single unnamed vector.
+pub(crate) async fn collection_info(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+) -> Result<CollectionInfo, ServerError> {
+    let url = format!(
+        "{}/collections/{}",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+
+    let value = send(
+        client_request(reqwest::Method::GET, url, api_key),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    let result = &value["result"];
+    let vectors = &result["config"]["params"]["vectors"];
+
+    Ok(CollectionInfo {
+        collection_name: collection_name.as_ref().to_string(),
+        vector_size: vectors["size"].as_u64(),
+        distance: vectors["distance"].as_str().map(|s| s.to_string()),
+        points_count: result["points_count"].as_u64(),
+        error: None,
+    })
+}
+
+/// Check if all the collections are empty or missing, which means that no document has been ingested yet.
+pub(crate) async fn all_collections_empty(
+    qdrant_config_vec: &[QdrantConfig],
+    api_key: Option<&str>,
+) -> Result<bool, ServerError> {
+    for qdrant_config in qdrant_config_vec {
+        if collection_exists(&qdrant_config.url, api_key, &qdrant_config.collection_name).await?
+            && points_count(&qdrant_config.url, api_key, &qdrant_config.collection_name).await? > 0
+        {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Create a collection with the given vector dimension and the cosine distance.
+pub(crate) async fn create_collection(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+    dim: usize,
+) -> Result<(), ServerError> {
+    info!(target: "stdout", "Create a Qdrant collection named {} of {} dimensions.", collection_name.as_ref(), dim);
+
+    let url = format!(
+        "{}/collections/{}",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+    let params = json!({
+        "vectors": {
+            "size": dim,
+            "distance": "Cosine",
+            "on_disk": true,
+        }
+    });
+
+    send(
+        client_request(reqwest::Method::PUT, url, api_key).json(&params),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Upsert points into the collection.
+pub(crate) async fn upsert_points(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+    points: Vec<Point>,
+) -> Result<(), ServerError> {
+    info!(target: "stdout", "Upsert {} points into the collection `{}`", points.len(), collection_name.as_ref());
+
+    let url = format!(
+        "{}/collections/{}/points?wait=true",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+    let params = json!({ "points": points });
+
+    send(
+        client_request(reqwest::Method::PUT, url, api_key).json(&params),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Search the points similar to the query vector.
+pub(crate) async fn search_points(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+    vector: &[f32],
+    limit: u64,
+    score_threshold: Option<f32>,
+) -> Result<Vec<ScoredPoint>, ServerError> {
+    let url = format!(
+        "{}/collections/{}/points/search",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+    let mut params = json!({
+        "vector": vector,
+        "limit": limit,
+        "with_payload": true,
+        "with_vector": false,
+    });
+    if let Some(score_threshold) = score_threshold {
+        params["score_threshold"] = json!(score_threshold);
+    }
+
+    let value = send(
+        client_request(reqwest::Method::POST, url, api_key).json(&params),
+        collection_name.as_ref(),
+    )
+    .await?;
+
+    match value.get("result") {
+        Some(result) => serde_json::from_value(result.clone()).map_err(|e| {
+            let err_msg = format!(
+                "Malformed search result from Qdrant for the collection `{}`: {}. Response: {}",
+                collection_name.as_ref(),
+                e,
+                snippet(&value.to_string())
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            ServerError::Operation(err_msg)
+        }),
+        None => {
+            let err_msg = format!(
+                "Malformed search result from Qdrant for the collection `{}`: missing field `result`. Response: {}",
+                collection_name.as_ref(),
+                snippet(&value.to_string())
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Scroll the vectors of the points in the collection, up to `max_points`. Only collections with a single unnamed vector are supported.
+pub(crate) async fn scroll_vectors(
+    url: impl AsRef<str>,
+    api_key: Option<&str>,
+    collection_name: impl AsRef<str>,
+    max_points: usize,
+) -> Result<Vec<Vec<f32>>, ServerError> {
+    // Number of points fetched per scroll request
+    const PAGE_SIZE: usize = 1000;
+
+    let url = format!(
+        "{}/collections/{}/points/scroll",
+        url.as_ref().trim_end_matches('/'),
+        collection_name.as_ref()
+    );
+
+    let mut vectors = Vec::new();
+    let mut offset = Value::Null;
+    while vectors.len() < max_points {
+        let mut params = json!({
+            "limit": PAGE_SIZE.min(max_points - vectors.len()),
+            "with_payload": false,
+            "with_vector": true,
+        });
+        if !offset.is_null() {
+            params["offset"] = offset;
+        }
+
+        let value = send(
+            client_request(reqwest::Method::POST, &url, api_key).json(&params),
+            collection_name.as_ref(),
+        )
+        .await?;
+
+        let points = match value["result"]["points"].as_array() {
+            Some(points) => points,
+            None => {
+                let err_msg = format!(
+                    "Malformed scroll result from Qdrant for the collection `{}`: missing field `points`. Response: {}",
+                    collection_name.as_ref(),
+                    snippet(&value.to_string())
+                );
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
+
+                return Err(ServerError::Operation(err_msg));
+            }
+        };
+        for point in points {
+            let vector: Vec<f32> =
+                serde_json::from_value(point["vector"].clone()).map_err(|e| {
+                    let err_msg = format!(
+                        "Unsupported vector of the point {} in the collection `{}`. Only single unnamed vectors are supported. {}",
+                        point["id"],
+                        collection_name.as_ref(),
+                        e
+                    );
+
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
+
+                    ServerError::Operation(err_msg)
+                })?;
+            vectors.push(vector);
+        }
+
+        offset = value["result"]["next_page_offset"].clone();
+        if offset.is_null() || points.is_empty() {
+            break;
+        }
+    }
+
+    Ok(vectors)
+}
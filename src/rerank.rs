@@ -0,0 +1,104 @@
+use crate::error::ServerError;
+use serde::Deserialize;
+use serde_json::json;
+
+/// A score of the response of a reranker service. The services following the Jina and Cohere API, such as the llama.cpp server, return `relevance_score`, and some others return `score`.
+#[derive(Debug, Deserialize)]
+struct RerankResult {
+    index: usize,
+    #[serde(alias = "relevance_score")]
+    score: f32,
+}
+
+/// The response of a reranker service, either an object with a `results` array, or the array itself.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RerankResponse {
+    Results { results: Vec<RerankResult> },
+    Array(Vec<RerankResult>),
+}
+
+/// Score the relevance of each document to the query with the reranker service of `--rerank-url`. Returns the scores in the order of the documents.
+///
+/// The request follows the rerank API of Jina and Cohere, which is also served by the llama.cpp server.
+pub(crate) async fn rerank(
+    url: &str,
+    model: Option<&str>,
+    query: &str,
+    documents: &[&str],
+) -> Result<Vec<f32>, ServerError> {
+    let mut params = json!({
+        "query": query,
+        "documents": documents,
+        "top_n": documents.len(),
+    });
+    if let Some(model) = model {
+        params["model"] = json!(model);
+    }
+
+    let response = reqwest::Client::new()
+        .post(url)
+        .json(&params)
+        .send()
+        .await
+        .map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to send request to the reranker at {}. {}",
+                url, e
+            ))
+        })?;
+
+    let status = response.status();
+    let body = response.text().await.map_err(|e| {
+        ServerError::Operation(format!(
+            "Failed to read the response from the reranker at {}. {}",
+            url, e
+        ))
+    })?;
+    if !status.is_success() {
+        return Err(ServerError::Operation(format!(
+            "The reranker at {} responded with status {}. Response: {}",
+            url,
+            status.as_u16(),
+            body.trim()
+        )));
+    }
+
+    let results = match serde_json::from_str(&body) {
+        Ok(RerankResponse::Results { results }) | Ok(RerankResponse::Array(results)) => results,
+        Err(e) => {
+            return Err(ServerError::Operation(format!(
+                "Malformed response from the reranker at {}: {}.",
+                url, e
+            )))
+        }
+    };
+
+    let mut scores = vec![None; documents.len()];
+    for result in results {
+        match scores.get_mut(result.index) {
+            Some(score) => *score = Some(result.score),
+            None => {
+                return Err(ServerError::Operation(format!(
+                    "Malformed response from the reranker at {}: the index {} is out of the {} documents.",
+                    url,
+                    result.index,
+                    documents.len()
+                )))
+            }
+        }
+    }
+
+    scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, score)| {
+            score.ok_or_else(|| {
+                ServerError::Operation(format!(
+                    "Malformed response from the reranker at {}: the document {} has no score.",
+                    url, index
+                ))
+            })
+        })
+        .collect()
+}
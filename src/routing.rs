@@ -0,0 +1,104 @@
+use crate::{error::ServerError, qdrant};
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, sync::Mutex};
+
+// Maximum number of points averaged into the centroid of a collection
+const MAX_CENTROID_POINTS: usize = 10_000;
+
+/// The Qdrant URL and the name of a collection.
+type CollectionKey = (String, String);
+
+// The centroids of the collections. `None` means the collection is empty
+static CENTROIDS: Lazy<Mutex<HashMap<CollectionKey, Option<Vec<f32>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Compute the centroid of the collection and cache it.
+///
+/// The centroid is the mean of the normalized vectors of up to 10,000 points, so that it points to the average direction of the collection in the cosine space. Returns `None` if the collection is empty or missing.
+pub(crate) async fn refresh_centroid(
+    url: &str,
+    api_key: Option<&str>,
+    collection_name: &str,
+) -> Result<Option<Vec<f32>>, ServerError> {
+    let vectors = match qdrant::collection_exists(url, api_key, collection_name).await? {
+        true => qdrant::scroll_vectors(url, api_key, collection_name, MAX_CENTROID_POINTS).await?,
+        false => Vec::new(),
+    };
+
+    let mut centroid: Option<Vec<f32>> = None;
+    for vector in vectors.iter() {
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+
+        let centroid = centroid.get_or_insert_with(|| vec![0.0; vector.len()]);
+        if centroid.len() != vector.len() {
+            let err_msg = format!(
+                "The points of the collection `{}` have vectors of different dimensions.",
+                collection_name
+            );
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return Err(ServerError::Operation(err_msg));
+        }
+        for (c, x) in centroid.iter_mut().zip(vector) {
+            *c += x / norm;
+        }
+    }
+
+    // log
+    info!(target: "stdout", "Computed the centroid of the collection `{}` from {} point(s).", collection_name, vectors.len());
+
+    if let Ok(mut centroids) = CENTROIDS.lock() {
+        centroids.insert(
+            (url.to_string(), collection_name.to_string()),
+            centroid.clone(),
+        );
+    }
+
+    Ok(centroid)
+}
+
+/// Get the centroid of the collection from the cache, or compute it if it isn't cached yet. Returns `None` if the collection is empty or the centroid fails to compute.
+pub(crate) async fn centroid(
+    url: &str,
+    api_key: Option<&str>,
+    collection_name: &str,
+) -> Option<Vec<f32>> {
+    let cached = CENTROIDS.lock().ok().and_then(|centroids| {
+        centroids
+            .get(&(url.to_string(), collection_name.to_string()))
+            .cloned()
+    });
+    if let Some(centroid) = cached {
+        return centroid;
+    }
+
+    match refresh_centroid(url, api_key, collection_name).await {
+        Ok(centroid) => centroid,
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to compute the centroid of the collection `{}`. {}", collection_name, e);
+
+            None
+        }
+    }
+}
+
+/// The cosine similarity of two vectors. Returns `None` if their dimensions differ or either is zero.
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() {
+        return None;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm_a == 0.0 || norm_b == 0.0 {
+        true => None,
+        false => Some(dot / (norm_a * norm_b)),
+    }
+}
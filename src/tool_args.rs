@@ -0,0 +1,177 @@
+use crate::utils::cl100k_tokenizer;
+use endpoints::chat::{
+    ChatCompletionRequestMessage, JSONSchemaDefine, JSONSchemaType, Tool, ToolCall,
+};
+use serde_json::Value;
+
+/// Validate the arguments of the tool call against the JSON schema of the tool with the same name.
+///
+/// Returns the violations found, separated by `; `. Tools without parameters accept any arguments.
+pub(crate) fn validate_tool_call(tool_call: &ToolCall, tools: &[Tool]) -> Result<(), String> {
+    let tool = tools
+        .iter()
+        .find(|tool| tool.function.name == tool_call.function.name)
+        .ok_or_else(|| format!("Unknown tool `{}`.", tool_call.function.name))?;
+
+    let arguments: Value = serde_json::from_str(&tool_call.function.arguments).map_err(|e| {
+        format!(
+            "The arguments of the tool `{}` are not valid JSON. {}",
+            tool_call.function.name, e
+        )
+    })?;
+
+    let parameters = match &tool.function.parameters {
+        Some(parameters) => parameters,
+        None => return Ok(()),
+    };
+
+    // the parameters are a subset of the JSON schema definition
+    let schema: JSONSchemaDefine = serde_json::to_value(parameters)
+        .and_then(serde_json::from_value)
+        .map_err(|e| {
+            format!(
+                "Failed to read the parameters of the tool `{}`. {}",
+                tool_call.function.name, e
+            )
+        })?;
+
+    let mut violations = Vec::new();
+    check_value(&arguments, &schema, "$", &mut violations);
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "The arguments of the tool `{}` do not conform to its schema: {}",
+            tool_call.function.name,
+            violations.join("; ")
+        )),
+    }
+}
+
+fn check_value(value: &Value, schema: &JSONSchemaDefine, path: &str, violations: &mut Vec<String>) {
+    if let Some(schema_type) = &schema.schema_type {
+        if !check_type(value, schema_type, path, violations) {
+            return;
+        }
+    }
+
+    if let Some(enum_values) = &schema.enum_values {
+        if !enum_values
+            .iter()
+            .any(|enum_value| value.as_str() == Some(enum_value.as_str()))
+        {
+            violations.push(format!(
+                "{}: expected one of {:?}, got {}",
+                path, enum_values, value
+            ));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.minimum.as_ref().and_then(Value::as_f64) {
+            if number < minimum {
+                violations.push(format!("{}: {} is less than {}", path, number, minimum));
+            }
+        }
+        if let Some(maximum) = schema.maximum.as_ref().and_then(Value::as_f64) {
+            if number > maximum {
+                violations.push(format!("{}: {} is greater than {}", path, number, maximum));
+            }
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            for name in schema.required.iter().flatten() {
+                if !object.contains_key(name) {
+                    violations.push(format!("{}: missing required property `{}`", path, name));
+                }
+            }
+            if let Some(properties) = &schema.properties {
+                for (name, value) in object {
+                    if let Some(schema) = properties.get(name) {
+                        check_value(value, schema, &format!("{}.{}", path, name), violations);
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(schema) = &schema.items {
+                for (idx, item) in items.iter().enumerate() {
+                    check_value(item, schema, &format!("{}[{}]", path, idx), violations);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check the type of the value. Returns `false` and records the violation on mismatch.
+fn check_type(
+    value: &Value,
+    schema_type: &JSONSchemaType,
+    path: &str,
+    violations: &mut Vec<String>,
+) -> bool {
+    let matched = match schema_type {
+        JSONSchemaType::Object => value.is_object(),
+        JSONSchemaType::Number => value.is_number(),
+        JSONSchemaType::Integer => value.is_i64() || value.is_u64(),
+        JSONSchemaType::String => value.is_string(),
+        JSONSchemaType::Array => value.is_array(),
+        JSONSchemaType::Null => value.is_null(),
+        JSONSchemaType::Boolean => value.is_boolean(),
+    };
+
+    if !matched {
+        violations.push(format!(
+            "{}: expected {}, got {}",
+            path,
+            serde_json::to_string(schema_type).unwrap_or_default(),
+            value
+        ));
+    }
+
+    matched
+}
+
+/// Check the token cost of the tool definitions against the `max_tools_tokens` limit, and the cost of the tool definitions plus the messages against the context size.
+///
+/// The tokens are counted on the JSON of the tools and the messages with the `cl100k_base` tokenizer, which approximates the cost of the rendered prompt.
+pub(crate) fn check_tools_tokens(
+    tools: &[Tool],
+    messages: &[ChatCompletionRequestMessage],
+    max_tools_tokens: u64,
+    ctx_size: u64,
+) -> Result<(), String> {
+    let tokenizer = cl100k_tokenizer()
+        .map_err(|e| format!("Failed to create the tokenizer for counting tokens. {}", e))?;
+    let num_tokens = |value: Result<String, serde_json::Error>| {
+        value
+            .map(|s| tokenizer.encode_ordinary(&s).len() as u64)
+            .map_err(|e| format!("Failed to serialize the request for counting tokens. {}", e))
+    };
+
+    let tools_tokens = num_tokens(serde_json::to_string(tools))?;
+    if tools_tokens > max_tools_tokens {
+        return Err(format!(
+            "The {} tool definition(s) take about {} tokens, more than the limit of {} tokens. Reduce the number of tools or shorten their descriptions and parameter schemas.",
+            tools.len(),
+            tools_tokens,
+            max_tools_tokens
+        ));
+    }
+
+    let messages_tokens = num_tokens(serde_json::to_string(messages))?;
+    if tools_tokens + messages_tokens >= ctx_size {
+        return Err(format!(
+            "The {} tool definition(s) take about {} tokens and the messages about {} tokens, which leave no room in the context of {} tokens for the retrieved context and the completion. Reduce the number of tools or shorten the conversation.",
+            tools.len(),
+            tools_tokens,
+            messages_tokens,
+            ctx_size
+        ));
+    }
+
+    Ok(())
+}
@@ -1,10 +1,51 @@
+use chat_prompts::{
+    chat::{BuildChatPrompt, ChatPrompt},
+    PromptTemplateType,
+};
+use endpoints::chat::{ChatCompletionRequestMessage, ChatCompletionUserMessageContent};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tiktoken_rs::CoreBPE;
 use url::Url;
 
+// The `cl100k_base` tokenizer of the token estimates, built once on first use
+static CL100K_BASE: Lazy<Result<CoreBPE, String>> =
+    Lazy::new(|| tiktoken_rs::cl100k_base().map_err(|e| e.to_string()));
+
+/// The shared `cl100k_base` tokenizer of the token estimates, so that its BPE tables are built once rather than on every request.
+pub(crate) fn cl100k_tokenizer() -> Result<&'static CoreBPE, String> {
+    CL100K_BASE.as_ref().map_err(|e| e.clone())
+}
+
 pub(crate) fn is_valid_url(url: &str) -> bool {
     Url::parse(url).is_ok()
 }
 
+/// Check if the URL is covered by one of the allowed URLs, that is, it has the same scheme, host and port as an allowed URL, and its path is the path of the allowed URL or lies under it.
+pub(crate) fn url_allowed(url: &str, allowlist: &[Url]) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+
+    allowlist.iter().any(|allowed| {
+        let allowed_path = allowed.path().trim_end_matches('/');
+        url.origin() == allowed.origin()
+            && url
+                .path()
+                .strip_prefix(allowed_path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Reduce the URL to its origin, that is, the scheme, the host and the port, dropping the credentials, the path and the query, which may carry secrets such as API keys. URLs that can't be parsed are replaced entirely.
+pub(crate) fn redact_url(url: &str) -> String {
+    match Url::parse(url).map(|url| url.origin()) {
+        Ok(origin) if origin.is_tuple() => origin.ascii_serialization(),
+        _ => String::from("[redacted]"),
+    }
+}
+
 pub(crate) fn gen_chat_id() -> String {
     format!("chatcmpl-{}", uuid::Uuid::new_v4())
 }
@@ -73,3 +114,606 @@ impl std::str::FromStr for LogLevel {
         }
     }
 }
+
+/// The format of the retrieved context merged into the prompt.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ContextFormat {
+    /// The chunks separated by blank lines.
+    #[default]
+    Plain,
+    /// Each chunk wrapped in a `<chunk>` tag inside a `<context>` tag.
+    Xml,
+    /// Each chunk under a Markdown heading.
+    Markdown,
+}
+impl ContextFormat {
+    /// Format the retrieved chunks into the context.
+    pub(crate) fn format(&self, chunks: &[&str]) -> String {
+        match self {
+            ContextFormat::Plain => chunks
+                .iter()
+                .map(|chunk| format!("{}\n\n", chunk))
+                .collect(),
+            ContextFormat::Xml => {
+                let chunks: String = chunks
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, chunk)| {
+                        format!(
+                            "<chunk index=\"{}\">\n{}\n</chunk>\n",
+                            idx + 1,
+                            escape_xml(chunk.trim())
+                        )
+                    })
+                    .collect();
+                format!("<context>\n{}</context>\n", chunks)
+            }
+            ContextFormat::Markdown => chunks
+                .iter()
+                .enumerate()
+                .map(|(idx, chunk)| format!("### Context {}\n\n{}\n\n", idx + 1, chunk.trim()))
+                .collect(),
+        }
+    }
+}
+impl std::fmt::Display for ContextFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ContextFormat::Plain => write!(f, "plain"),
+            ContextFormat::Xml => write!(f, "xml"),
+            ContextFormat::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+impl std::str::FromStr for ContextFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(ContextFormat::Plain),
+            "xml" => Ok(ContextFormat::Xml),
+            "markdown" => Ok(ContextFormat::Markdown),
+            _ => Err(format!(
+                "Invalid context format: {}. Supported formats: plain, xml, markdown.",
+                s
+            )),
+        }
+    }
+}
+
+/// Escape `&`, `<` and `>` in the text of an XML element, so that a chunk containing `</chunk>` or `</context>` can't break the structure of the context.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The precision of the embedding vectors returned by `/v1/embeddings`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) enum EmbeddingPrecision {
+    /// The full float32 values.
+    #[default]
+    Float32,
+    /// The float16 values, encoded as a base64 string of little-endian bytes.
+    Float16,
+    /// The float32 values rounded to the given number of decimal places.
+    Decimals(u32),
+}
+impl EmbeddingPrecision {
+    // Maximum number of decimal places
+    const MAX_DECIMALS: u32 = 15;
+
+    /// Encode the embedding vector in the precision.
+    pub(crate) fn encode(&self, embedding: &[f64]) -> serde_json::Value {
+        match self {
+            EmbeddingPrecision::Float32 => serde_json::Value::from(embedding.to_vec()),
+            EmbeddingPrecision::Float16 => {
+                let bytes: Vec<u8> = embedding
+                    .iter()
+                    .flat_map(|x| half::f16::from_f64(*x).to_le_bytes())
+                    .collect();
+                serde_json::Value::from(base64::Engine::encode(
+                    &base64::engine::general_purpose::STANDARD,
+                    bytes,
+                ))
+            }
+            EmbeddingPrecision::Decimals(decimals) => {
+                let factor = 10_f64.powi(*decimals as i32);
+                serde_json::Value::from(
+                    embedding
+                        .iter()
+                        .map(|x| (x * factor).round() / factor)
+                        .collect::<Vec<f64>>(),
+                )
+            }
+        }
+    }
+}
+impl std::fmt::Display for EmbeddingPrecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EmbeddingPrecision::Float32 => write!(f, "float32"),
+            EmbeddingPrecision::Float16 => write!(f, "float16"),
+            EmbeddingPrecision::Decimals(decimals) => write!(f, "{}", decimals),
+        }
+    }
+}
+impl std::str::FromStr for EmbeddingPrecision {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "float32" => Ok(EmbeddingPrecision::Float32),
+            "float16" => Ok(EmbeddingPrecision::Float16),
+            decimals => match decimals.parse::<u32>() {
+                Ok(decimals) if decimals <= Self::MAX_DECIMALS => {
+                    Ok(EmbeddingPrecision::Decimals(decimals))
+                }
+                _ => Err(format!(
+                    "Invalid embedding precision: {}. Supported precisions: float32, float16, or the number of decimal places from 0 to {}.",
+                    s,
+                    Self::MAX_DECIMALS
+                )),
+            },
+        }
+    }
+}
+
+/// How the vectors of a multi-vector embedding, one per token, are pooled into one vector.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MultivectorPooling {
+    /// The element-wise mean of the vectors.
+    #[default]
+    Mean,
+    /// The element-wise maximum of the vectors.
+    Max,
+}
+impl MultivectorPooling {
+    /// Pool the vectors of a multi-vector embedding, the consecutive `dimension`-long slices of the embedding, into one vector.
+    pub(crate) fn pool(&self, embedding: &[f64], dimension: usize) -> Vec<f64> {
+        let vectors = embedding.chunks_exact(dimension);
+        let count = vectors.len() as f64;
+
+        let mut pooled = match self {
+            MultivectorPooling::Mean => vec![0.0; dimension],
+            MultivectorPooling::Max => vec![f64::NEG_INFINITY; dimension],
+        };
+        for vector in vectors {
+            for (pooled, value) in pooled.iter_mut().zip(vector) {
+                match self {
+                    MultivectorPooling::Mean => *pooled += value / count,
+                    MultivectorPooling::Max => *pooled = pooled.max(*value),
+                }
+            }
+        }
+
+        pooled
+    }
+}
+impl std::fmt::Display for MultivectorPooling {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MultivectorPooling::Mean => write!(f, "mean"),
+            MultivectorPooling::Max => write!(f, "max"),
+        }
+    }
+}
+
+/// Where the retrieved context is placed in the chat messages.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RagContextMessage {
+    /// Merged into the system or the last user message, as set by `--rag-policy`.
+    #[default]
+    Merged,
+    /// A dedicated tool message after the last user message.
+    Tool,
+}
+impl std::fmt::Display for RagContextMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RagContextMessage::Merged => write!(f, "merged"),
+            RagContextMessage::Tool => write!(f, "tool"),
+        }
+    }
+}
+
+/// Whether the prompt template renders the tool messages, which the other templates drop.
+pub(crate) fn template_supports_tool_message(template: PromptTemplateType) -> bool {
+    matches!(
+        template,
+        PromptTemplateType::ChatML
+            | PromptTemplateType::ChatMLTool
+            | PromptTemplateType::InternLM2Tool
+            | PromptTemplateType::Llama3Tool
+            | PromptTemplateType::GroqLlama3Tool
+            | PromptTemplateType::MistralTool
+            | PromptTemplateType::MistralSmallTool
+            | PromptTemplateType::NemotronTool
+            | PromptTemplateType::FunctionaryV31
+            | PromptTemplateType::FunctionaryV32
+    )
+}
+
+/// The format of the log lines.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    /// Human-readable lines written by the WASI logger.
+    #[default]
+    Text,
+    /// One JSON object per line written to the standard output.
+    Json,
+}
+impl std::fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// The format of the access log lines.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AccessLogFormat {
+    /// The request and the response are logged in separate fields by the logger.
+    #[default]
+    Default,
+    /// One line per request in the Combined Log Format of Apache and nginx, written to the standard output.
+    Combined,
+}
+impl std::fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccessLogFormat::Default => write!(f, "default"),
+            AccessLogFormat::Combined => write!(f, "combined"),
+        }
+    }
+}
+
+/// Format an access log line in the Combined Log Format:
+///
+/// `remote_host - - [time] "request_line" status bytes "referer" "user_agent"`
+///
+/// The time is the time the request is received. The missing values are written as `-`, and the quotes and backslashes in the quoted fields are escaped.
+pub(crate) fn combined_log_line(
+    remote_host: &str,
+    time: chrono::DateTime<chrono::Utc>,
+    request_line: &str,
+    status: u16,
+    bytes: Option<u64>,
+    referer: Option<&str>,
+    user_agent: Option<&str>,
+) -> String {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+        remote_host,
+        time.format("%d/%b/%Y:%H:%M:%S %z"),
+        escape(request_line),
+        status,
+        bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+        referer.map_or("-".to_string(), escape),
+        user_agent.map_or("-".to_string(), escape),
+    )
+}
+
+/// The part of the retrieval query kept when the query is truncated to `--max-query-tokens`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QueryTruncation {
+    /// Keep the beginning of the query, i.e., the oldest user messages.
+    Head,
+    /// Keep the end of the query, i.e., the latest user message.
+    #[default]
+    Tail,
+}
+impl std::fmt::Display for QueryTruncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryTruncation::Head => write!(f, "head"),
+            QueryTruncation::Tail => write!(f, "tail"),
+        }
+    }
+}
+
+/// What to do when a Qdrant or keyword search request of the retrieval exceeds `--retrieval-timeout`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RetrievalTimeoutAction {
+    /// Fail the request with `504 Gateway Timeout`.
+    #[default]
+    Fail,
+    /// Proceed without the context of the upstream that timed out.
+    Proceed,
+}
+impl std::fmt::Display for RetrievalTimeoutAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RetrievalTimeoutAction::Fail => write!(f, "fail"),
+            RetrievalTimeoutAction::Proceed => write!(f, "proceed"),
+        }
+    }
+}
+
+/// Parse a `--info-extra` value in the format of `key=value`. The key must be non-empty and consist of ASCII letters, digits, `_`, `-` and `.`; the value may be empty and contain `=`.
+pub(crate) fn parse_info_extra(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` should be in the format of `key=value`.", s))?;
+
+    if key.is_empty() {
+        return Err(format!("The key of `{}` is empty.", s));
+    }
+    if let Some(c) = key
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(format!(
+            "The key of `{}` contains `{}`. Only ASCII letters, digits, `_`, `-` and `.` are allowed.",
+            s, c
+        ));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Estimate the fixed number of tokens the prompt template and the RAG prompt add to every prompt, by rendering a system message with the RAG prompt and an empty user message.
+///
+/// The tokens are counted with the `cl100k_base` tokenizer, which approximates the tokenizer of the chat model.
+pub(crate) fn estimate_template_overhead(
+    template: PromptTemplateType,
+    rag_prompt: Option<&str>,
+) -> Result<u64, String> {
+    let mut messages = vec![
+        ChatCompletionRequestMessage::new_system_message(rag_prompt.unwrap_or_default(), None),
+        ChatCompletionRequestMessage::new_user_message(
+            ChatCompletionUserMessageContent::Text(String::new()),
+            None,
+        ),
+    ];
+    let prompt = ChatPrompt::from(template)
+        .build(&mut messages)
+        .map_err(|e| format!("Failed to render the prompt template `{}`. {}", template, e))?;
+
+    let tokenizer = tiktoken_rs::cl100k_base()
+        .map_err(|e| format!("Failed to create the tokenizer for counting tokens. {}", e))?;
+
+    Ok(tokenizer.encode_with_special_tokens(&prompt).len() as u64)
+}
+
+/// The marker that ends the assistant turn in the prompt template, or starts the next user turn, used as the default reverse prompt so that the generation stops before the model writes the next turn.
+///
+/// Returns `None` for the templates whose turns end only with the EOS token of the model, which stops the generation anyway.
+pub(crate) fn template_stop_marker(template: PromptTemplateType) -> Option<&'static str> {
+    match template {
+        PromptTemplateType::Llama2Chat
+        | PromptTemplateType::CodeLlama
+        | PromptTemplateType::MistralInstruct
+        | PromptTemplateType::MistralTool
+        | PromptTemplateType::MistralSmallChat
+        | PromptTemplateType::MistralSmallTool
+        | PromptTemplateType::BreezeInstruct
+        | PromptTemplateType::MoxinChat => Some("[INST]"),
+        PromptTemplateType::Llama3Chat
+        | PromptTemplateType::Llama3Tool
+        | PromptTemplateType::GroqLlama3Tool
+        | PromptTemplateType::FunctionaryV31
+        | PromptTemplateType::FunctionaryV32 => Some("<|eot_id|>"),
+        PromptTemplateType::ChatML
+        | PromptTemplateType::ChatMLTool
+        | PromptTemplateType::InternLM2Tool
+        | PromptTemplateType::MiniCPMV
+        | PromptTemplateType::Phi4Chat => Some("<|im_end|>"),
+        PromptTemplateType::Qwen2vl => Some("<|im_start|>"),
+        PromptTemplateType::Zephyr
+        | PromptTemplateType::StableLMZephyr
+        | PromptTemplateType::Falcon3
+        | PromptTemplateType::Glm4Chat => Some("<|user|>"),
+        PromptTemplateType::VicunaChat
+        | PromptTemplateType::Vicuna11Chat
+        | PromptTemplateType::VicunaLlava => Some("USER:"),
+        PromptTemplateType::Phi3Chat | PromptTemplateType::Phi3Instruct => Some("<|end|>"),
+        PromptTemplateType::NemotronChat | PromptTemplateType::NemotronTool => Some("<extra_id_1>"),
+        PromptTemplateType::OpenChat => Some("<|end_of_turn|>"),
+        PromptTemplateType::GemmaInstruct => Some("<end_of_turn>"),
+        PromptTemplateType::Megrez => Some("<|turn_end|>"),
+        PromptTemplateType::HumanAssistant => Some("Human:"),
+        PromptTemplateType::Baichuan2 => Some("用户:"),
+        PromptTemplateType::SolarInstruct | PromptTemplateType::IntelNeural => Some("### User:"),
+        PromptTemplateType::DeepseekChat | PromptTemplateType::DeepseekChat2 => Some("User:"),
+        PromptTemplateType::DeepseekChat25 | PromptTemplateType::DeepseekChat3 => Some("<|User|>"),
+        PromptTemplateType::DeepseekCoder => Some("<|EOT|>"),
+        PromptTemplateType::MistralLite => Some("<|prompter|>"),
+        PromptTemplateType::CodeLlamaSuper => Some("<step>"),
+        PromptTemplateType::Phi2Chat => Some("Alice:"),
+        PromptTemplateType::Octopus => Some("Query:"),
+        _ => None,
+    }
+}
+
+/// Truncate the query to at most `max_tokens` tokens of the `cl100k_base` tokenizer, keeping its head or its tail.
+///
+/// Returns the truncated query with the number of tokens of the original query, or `None` if the query fits.
+pub(crate) fn truncate_query(
+    query: &str,
+    max_tokens: usize,
+    keep: QueryTruncation,
+) -> Result<Option<(String, usize)>, String> {
+    let tokenizer = cl100k_tokenizer()
+        .map_err(|e| format!("Failed to create the tokenizer for the query. {}", e))?;
+
+    let tokens = tokenizer.encode_with_special_tokens(query);
+    if tokens.len() <= max_tokens {
+        return Ok(None);
+    }
+
+    let kept = match keep {
+        QueryTruncation::Head => &tokens[..max_tokens],
+        QueryTruncation::Tail => &tokens[tokens.len() - max_tokens..],
+    };
+    // a multi-byte character may be split at the cut, so the broken character is dropped
+    let truncated = String::from_utf8_lossy(&tokenizer._decode_native(kept))
+        .trim_matches('\u{FFFD}')
+        .to_string();
+
+    Ok(Some((truncated, tokens.len())))
+}
+
+/// The Unicode normalization form applied to the text before embedding.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UnicodeNormalization {
+    /// The text is embedded as is.
+    #[default]
+    None,
+    /// Canonical composition, e.g., `e` followed by a combining acute accent becomes `é`.
+    Nfc,
+    /// Compatibility composition, which additionally folds compatibility characters, e.g., `ﬁ` becomes `fi`.
+    Nfkc,
+}
+impl std::fmt::Display for UnicodeNormalization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            UnicodeNormalization::None => write!(f, "none"),
+            UnicodeNormalization::Nfc => write!(f, "nfc"),
+            UnicodeNormalization::Nfkc => write!(f, "nfkc"),
+        }
+    }
+}
+
+/// Normalize the text to the Unicode normalization form.
+pub(crate) fn normalize_unicode(text: &str, form: UnicodeNormalization) -> String {
+    use unicode_normalization::UnicodeNormalization as _;
+
+    match form {
+        UnicodeNormalization::None => text.to_string(),
+        UnicodeNormalization::Nfc => text.nfc().collect(),
+        UnicodeNormalization::Nfkc => text.nfkc().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+    use endpoints::chat::{Tool, ToolFunction};
+
+    // the templates that render the last user message only, so no turn follows the assistant content
+    const SINGLE_TURN_TEMPLATES: [PromptTemplateType; 3] = [
+        PromptTemplateType::WizardCoder,
+        PromptTemplateType::Phi2Instruct,
+        PromptTemplateType::Phi3Instruct,
+    ];
+    // the templates that are not chat templates
+    const NON_CHAT_TEMPLATES: [PromptTemplateType; 3] = [
+        PromptTemplateType::Embedding,
+        PromptTemplateType::Tts,
+        PromptTemplateType::Null,
+    ];
+    // the EOS and BOS tokens that may end a turn before the marker, and stop the generation anyway
+    const SPECIAL_TOKENS: [&str; 4] = ["</s>", "<s>", "<|endoftext|>", "<|end_of_sentence|>"];
+
+    fn render(template: PromptTemplateType, contents: &[&str]) -> String {
+        let mut messages: Vec<ChatCompletionRequestMessage> = contents
+            .iter()
+            .enumerate()
+            .map(|(idx, content)| match idx % 2 {
+                0 => ChatCompletionRequestMessage::new_user_message(
+                    ChatCompletionUserMessageContent::Text(content.to_string()),
+                    None,
+                ),
+                _ => ChatCompletionRequestMessage::new_assistant_message(
+                    Some(content.to_string()),
+                    None,
+                    None,
+                ),
+            })
+            .collect();
+        // `groq-llama3-tool` requires a tool, while the other tool templates insert the tools before the last user message
+        let tools = [Tool {
+            ty: "function".to_string(),
+            function: ToolFunction {
+                name: "get_weather".to_string(),
+                description: None,
+                parameters: None,
+            },
+        }];
+
+        ChatPrompt::from(template)
+            .build_with_tools(
+                &mut messages,
+                (template == PromptTemplateType::GroqLlama3Tool).then_some(&tools[..]),
+            )
+            .unwrap_or_else(|e| panic!("Failed to render the template `{}`. {}", template, e))
+    }
+
+    // the text of the prompt right after `content`, without the leading whitespace and EOS or BOS tokens
+    fn text_after<'a>(prompt: &'a str, content: &str) -> &'a str {
+        let (_, mut rest) = prompt
+            .split_once(content)
+            .unwrap_or_else(|| panic!("The prompt doesn't contain `{}`: {:?}", content, prompt));
+        loop {
+            let trimmed = rest.trim_start();
+            match SPECIAL_TOKENS
+                .iter()
+                .find_map(|token| trimmed.strip_prefix(token))
+            {
+                Some(stripped) => rest = stripped,
+                None => return trimmed,
+            }
+        }
+    }
+
+    #[test]
+    fn template_stop_marker_ends_the_assistant_turn() {
+        for &template in PromptTemplateType::value_variants() {
+            if NON_CHAT_TEMPLATES.contains(&template) || SINGLE_TURN_TEMPLATES.contains(&template) {
+                continue;
+            }
+
+            let marker = template_stop_marker(template)
+                .unwrap_or_else(|| panic!("No stop marker for the template `{}`.", template));
+            let prompt = render(
+                template,
+                &["first question", "the answer", "second question"],
+            );
+
+            assert!(
+                text_after(&prompt, "the answer").starts_with(marker),
+                "The stop marker `{}` doesn't follow the assistant content in the template `{}`: {:?}",
+                marker,
+                template,
+                prompt
+            );
+        }
+    }
+
+    #[test]
+    fn template_stop_marker_ends_the_single_turn() {
+        for template in SINGLE_TURN_TEMPLATES {
+            let prompt = render(template, &["the question"]);
+
+            // the templates without a marker rely on the EOS token of the model
+            if let Some(marker) = template_stop_marker(template) {
+                assert!(
+                    text_after(&prompt, "the question").starts_with(marker),
+                    "The stop marker `{}` doesn't end the turns in the template `{}`: {:?}",
+                    marker,
+                    template,
+                    prompt
+                );
+            }
+        }
+    }
+}